@@ -0,0 +1,49 @@
+use criterion::{Criterion, criterion_group, criterion_main};
+use mcp_gemini_cli::buffer_pool::{BufferPool, OutputLimits, read_output};
+use std::hint::black_box;
+use std::process::Stdio;
+use tokio::process::Command;
+
+/// A response large enough for buffer growth to matter
+const ECHO_ARGS: [&str; 2] = ["-c", "head -c 65536 /dev/zero | tr '\\0' x"];
+
+fn echo_command() -> Command {
+    let mut cmd = Command::new("sh");
+    cmd.args(ECHO_ARGS)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    cmd
+}
+
+fn bench_subprocess_output(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let pool = BufferPool::new(32);
+    let mut group = c.benchmark_group("subprocess_output");
+
+    group.bench_function("command_output", |b| {
+        b.iter(|| {
+            runtime.block_on(async {
+                let output = echo_command().output().await.unwrap();
+                black_box(output.stdout.len())
+            })
+        })
+    });
+
+    group.bench_function("pooled_buffers", |b| {
+        b.iter(|| {
+            runtime.block_on(async {
+                let child = echo_command().spawn().unwrap();
+                let output = read_output(child, &pool, None, OutputLimits::default(), false)
+                    .await
+                    .unwrap();
+                black_box(output.stdout.len())
+            })
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_subprocess_output);
+criterion_main!(benches);
@@ -0,0 +1,57 @@
+use criterion::{Criterion, criterion_group, criterion_main};
+use mcp_gemini_cli::tools::parse_gemini_output;
+use std::hint::black_box;
+
+fn response_json(response_chars: usize) -> String {
+    serde_json::json!({
+        "response": "x".repeat(response_chars),
+        "stats": {
+            "files": { "totalLinesAdded": 3, "totalLinesRemoved": 1 },
+            "tools": { "totalCalls": 2, "totalDecisions": { "accept": 2, "reject": 0 } },
+        },
+    })
+    .to_string()
+}
+
+fn bench_parse(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse_gemini_output");
+
+    let clean = response_json(4096);
+    group.bench_function("clean_json", |b| {
+        b.iter(|| parse_gemini_output(black_box(&clean)))
+    });
+
+    // Log noise (including small brace-delimited fragments) around a large response
+    let mut mixed = String::new();
+    for i in 0..2000 {
+        mixed.push_str(&format!("[{}] loading {{module-{}}} ...\n", i, i));
+    }
+    mixed.push_str(&response_json(64 * 1024));
+    mixed.push_str("\nDone.\n");
+    group.bench_function("large_mixed_content", |b| {
+        b.iter(|| parse_gemini_output(black_box(&mixed)))
+    });
+
+    // Deeply nested braces that never form valid JSON
+    let nested = format!("{}{}", "{".repeat(20_000), "}".repeat(20_000));
+    group.bench_function("nested_braces", |b| {
+        b.iter(|| parse_gemini_output(black_box(&nested)))
+    });
+
+    // Many balanced but invalid candidates
+    let many_candidates = "{not json} ".repeat(20_000);
+    group.bench_function("many_invalid_candidates", |b| {
+        b.iter(|| parse_gemini_output(black_box(&many_candidates)))
+    });
+
+    // Unbalanced opening braces followed by a valid response
+    let unbalanced = format!("{}\n{}", "{ ".repeat(20_000), response_json(1024));
+    group.bench_function("unbalanced_braces", |b| {
+        b.iter(|| parse_gemini_output(black_box(&unbalanced)))
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_parse);
+criterion_main!(benches);
@@ -0,0 +1,129 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+use crate::audit;
+
+/// Prompt executions currently running, for the `list-active-requests` tool. Each request
+/// is registered for as long as its [`ActiveRequestGuard`] lives.
+#[derive(Debug)]
+pub struct ActiveRequests {
+    next_id: AtomicU64,
+    requests: Mutex<HashMap<u64, ActiveRequest>>,
+}
+
+#[derive(Debug)]
+struct ActiveRequest {
+    tool: &'static str,
+    model: Option<String>,
+    prompt_sha256: String,
+    started: Instant,
+}
+
+/// One running request as reported to clients
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct ActiveRequestInfo {
+    pub id: u64,
+    pub tool: &'static str,
+    pub model: Option<String>,
+    /// sha256 (hex) of the prompt as submitted; the text itself is not exposed
+    pub prompt_sha256: String,
+    pub elapsed_ms: u64,
+}
+
+/// Removes its request from the registry when dropped
+#[derive(Debug)]
+pub struct ActiveRequestGuard<'a> {
+    registry: &'a ActiveRequests,
+    id: u64,
+}
+
+impl Default for ActiveRequests {
+    fn default() -> Self {
+        Self {
+            next_id: AtomicU64::new(1),
+            requests: Mutex::default(),
+        }
+    }
+}
+
+impl ActiveRequests {
+    pub fn register(
+        &self,
+        tool: &'static str,
+        prompt: &str,
+        model: Option<&str>,
+    ) -> ActiveRequestGuard<'_> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.requests.lock().unwrap().insert(
+            id,
+            ActiveRequest {
+                tool,
+                model: model.map(str::to_string),
+                prompt_sha256: audit::sha256_hex(prompt),
+                started: Instant::now(),
+            },
+        );
+        ActiveRequestGuard { registry: self, id }
+    }
+
+    /// Running requests, oldest first
+    pub fn snapshot(&self) -> Vec<ActiveRequestInfo> {
+        let mut requests: Vec<ActiveRequestInfo> = self
+            .requests
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&id, request)| ActiveRequestInfo {
+                id,
+                tool: request.tool,
+                model: request.model.clone(),
+                prompt_sha256: request.prompt_sha256.clone(),
+                elapsed_ms: request.started.elapsed().as_millis() as u64,
+            })
+            .collect();
+        requests.sort_by_key(|request| request.id);
+        requests
+    }
+}
+
+impl ActiveRequestGuard<'_> {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+impl Drop for ActiveRequestGuard<'_> {
+    fn drop(&mut self) {
+        self.registry.requests.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registers_until_dropped() {
+        let registry = ActiveRequests::default();
+        let first = registry.register("prompt-gemini", "hello", Some("gemini-2.5-pro"));
+        let second = registry.register("batch-prompt", "world", None);
+
+        let active = registry.snapshot();
+        assert_eq!(active.len(), 2);
+        assert_eq!(active[0].id, first.id());
+        assert_eq!(active[0].tool, "prompt-gemini");
+        assert_eq!(active[0].model.as_deref(), Some("gemini-2.5-pro"));
+        assert_eq!(active[0].prompt_sha256, audit::sha256_hex("hello"));
+        assert_eq!(active[1].id, second.id());
+
+        drop(first);
+        let active = registry.snapshot();
+        assert_eq!(active.len(), 1);
+        assert_eq!(active[0].tool, "batch-prompt");
+        drop(second);
+        assert!(registry.snapshot().is_empty());
+    }
+}
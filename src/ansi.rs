@@ -0,0 +1,35 @@
+use regex::Regex;
+use std::borrow::Cow;
+use std::sync::LazyLock;
+
+/// CSI sequences (colors, cursor movement), OSC sequences (titles, hyperlinks) terminated
+/// by BEL or ST, and the remaining two-byte escapes
+static ANSI_ESCAPE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"\x1b\[[0-?]*[ -/]*[@-~]|\x1b\][^\x07\x1b]*(?:\x07|\x1b\\)|\x1b[@-Z\\-_]").unwrap()
+});
+
+/// Remove ANSI escape sequences from `text`, borrowing it when there are none
+pub fn strip(text: &str) -> Cow<'_, str> {
+    if !text.contains('\x1b') {
+        return Cow::Borrowed(text);
+    }
+    ANSI_ESCAPE.replace_all(text, "")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strips_escape_sequences() {
+        assert_eq!(
+            strip("\x1b[1;32mDone\x1b[0m: \x1b]8;;https://example.com\x1b\\link\x1b]8;;\x07\x1bM"),
+            "Done: link"
+        );
+        assert_eq!(
+            strip("{\"response\": \"\x1b[33mhi\x1b[39m\"}"),
+            "{\"response\": \"hi\"}"
+        );
+        assert!(matches!(strip("plain [text]"), Cow::Borrowed(_)));
+    }
+}
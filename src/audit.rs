@@ -0,0 +1,203 @@
+use anyhow::Context;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::mpsc;
+
+/// One line of the audit log
+#[derive(Debug, Serialize)]
+pub struct AuditRecord {
+    pub timestamp_ms: u64,
+    pub request_id: u64,
+    pub tool: &'static str,
+    pub command: Option<String>,
+    /// Hex-encoded SHA-256 of the prompt as submitted (before templating)
+    pub prompt_sha256: String,
+    /// Only present when the log was opened with `include_prompts`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt: Option<String>,
+    pub model: Option<String>,
+    /// `"success"` or the error code (e.g. `gemini_cli_timeout`)
+    pub outcome: String,
+    pub duration_ms: u64,
+}
+
+/// What the caller knows about a finished request
+#[derive(Debug, Clone, Copy)]
+pub struct AuditEntry<'a> {
+    pub tool: &'static str,
+    pub command: Option<&'a str>,
+    pub prompt: &'a str,
+    pub model: Option<&'a str>,
+    pub outcome: &'a str,
+    pub duration: Duration,
+}
+
+/// Append-only JSON Lines audit trail. Records are handed to a background task that
+/// writes them through a buffered writer, so logging never blocks a request on disk I/O.
+#[derive(Debug)]
+pub struct AuditLog {
+    sender: mpsc::UnboundedSender<AuditRecord>,
+    include_prompts: bool,
+    next_request_id: AtomicU64,
+}
+
+impl AuditLog {
+    /// Open (or create) `path` for appending and start the writer task. Must be called
+    /// from within a Tokio runtime.
+    pub fn open(path: &Path, include_prompts: bool) -> anyhow::Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open audit log {}", path.display()))?;
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(write_records(
+            BufWriter::new(tokio::fs::File::from_std(file)),
+            receiver,
+        ));
+
+        Ok(Self {
+            sender,
+            include_prompts,
+            next_request_id: AtomicU64::new(1),
+        })
+    }
+
+    /// Queue a record for a finished request, assigning it the next request ID
+    pub fn record(&self, entry: AuditEntry<'_>) {
+        let record = AuditRecord {
+            timestamp_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            request_id: self.next_request_id.fetch_add(1, Ordering::Relaxed),
+            tool: entry.tool,
+            command: entry.command.map(str::to_string),
+            prompt_sha256: sha256_hex(entry.prompt),
+            prompt: self.include_prompts.then(|| entry.prompt.to_string()),
+            model: entry.model.map(str::to_string),
+            outcome: entry.outcome.to_string(),
+            duration_ms: entry.duration.as_millis() as u64,
+        };
+        if self.sender.send(record).is_err() {
+            tracing::error!("Audit log writer has stopped; record dropped");
+        }
+    }
+}
+
+/// Hex sha256 of `text`, for logging prompts without their content
+pub fn sha256_hex(text: &str) -> String {
+    Sha256::digest(text.as_bytes())
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// Write records as they arrive, flushing whenever the queue is drained
+async fn write_records(
+    mut writer: BufWriter<tokio::fs::File>,
+    mut receiver: mpsc::UnboundedReceiver<AuditRecord>,
+) {
+    while let Some(record) = receiver.recv().await {
+        let mut pending = Some(record);
+        while let Some(record) = pending {
+            let mut line = serde_json::to_vec(&record).expect("audit records serialize");
+            line.push(b'\n');
+            if let Err(e) = writer.write_all(&line).await {
+                tracing::error!(error = %e, "Failed to write audit log record");
+            }
+            pending = receiver.try_recv().ok();
+        }
+        if let Err(e) = writer.flush().await {
+            tracing::error!(error = %e, "Failed to flush audit log");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn audit_path(name: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "mcp-gemini-cli-test-{}-{name}.jsonl",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    async fn read_records(path: &Path, expected: usize) -> Vec<serde_json::Value> {
+        for _ in 0..50 {
+            let contents = std::fs::read_to_string(path).unwrap_or_default();
+            if contents.lines().count() >= expected {
+                return contents
+                    .lines()
+                    .map(|line| serde_json::from_str(line).unwrap())
+                    .collect();
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("audit log never reached {expected} records");
+    }
+
+    #[tokio::test]
+    async fn test_records_are_appended_with_prompt_hash() {
+        let path = audit_path("audit-hash");
+        std::fs::write(&path, "{\"existing\":true}\n").unwrap();
+        let audit = AuditLog::open(&path, false).unwrap();
+
+        let entry = AuditEntry {
+            tool: "prompt-gemini",
+            command: Some("gemini"),
+            prompt: "hello",
+            model: Some("gemini-2.5-pro"),
+            outcome: "success",
+            duration: Duration::from_millis(42),
+        };
+        audit.record(entry);
+        audit.record(AuditEntry {
+            command: None,
+            model: None,
+            outcome: "gemini_cli_timeout",
+            duration: Duration::ZERO,
+            ..entry
+        });
+
+        let records = read_records(&path, 3).await;
+        assert_eq!(records[0]["existing"], true);
+        assert_eq!(records[1]["request_id"], 1);
+        assert_eq!(records[2]["request_id"], 2);
+        assert_eq!(records[1]["command"], "gemini");
+        assert_eq!(records[1]["model"], "gemini-2.5-pro");
+        assert_eq!(records[1]["outcome"], "success");
+        assert_eq!(records[1]["duration_ms"], 42);
+        assert_eq!(
+            records[1]["prompt_sha256"],
+            "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+        );
+        assert!(records[1].get("prompt").is_none());
+        assert_eq!(records[2]["outcome"], "gemini_cli_timeout");
+    }
+
+    #[tokio::test]
+    async fn test_full_prompts_are_opt_in() {
+        let path = audit_path("audit-prompts");
+        let audit = AuditLog::open(&path, true).unwrap();
+        audit.record(AuditEntry {
+            tool: "prompt-gemini",
+            command: None,
+            prompt: "secret plan",
+            model: None,
+            outcome: "success",
+            duration: Duration::ZERO,
+        });
+
+        let records = read_records(&path, 1).await;
+        assert_eq!(records[0]["prompt"], "secret plan");
+    }
+}
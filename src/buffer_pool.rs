@@ -0,0 +1,305 @@
+use crossbeam_queue::ArrayQueue;
+use std::ops::{Deref, DerefMut};
+use std::process::ExitStatus;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::process::Child;
+
+use crate::process_group::ProcessGroupGuard;
+
+/// Buffers that grew beyond this are dropped instead of being returned to the pool,
+/// so one huge response doesn't pin its allocation for the lifetime of the server
+const MAX_POOLED_BUFFER_BYTES: usize = 1024 * 1024;
+
+/// Reusable byte buffers for reading subprocess output
+#[derive(Debug)]
+pub struct BufferPool {
+    /// `None` when pooling is disabled (capacity 0)
+    buffers: Option<ArrayQueue<Vec<u8>>>,
+}
+
+/// A buffer borrowed from a [`BufferPool`]; cleared and returned on drop
+#[derive(Debug)]
+pub struct PooledBuffer<'a> {
+    buffer: Vec<u8>,
+    pool: &'a BufferPool,
+}
+
+/// Most bytes of each stream [`read_output`] keeps; `None` is unlimited
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OutputLimits {
+    pub stdout: Option<usize>,
+    pub stderr: Option<usize>,
+}
+
+/// Output of a finished subprocess, read into pooled buffers
+#[derive(Debug)]
+pub struct PooledOutput<'a> {
+    pub status: ExitStatus,
+    /// The timeout elapsed and the process was killed; the buffers hold what was
+    /// read up to that point
+    pub timed_out: bool,
+    pub stdout: PooledBuffer<'a>,
+    pub stderr: PooledBuffer<'a>,
+    /// Bytes read past the stdout limit and discarded; the buffer holds only the kept
+    /// output
+    pub stdout_truncated: usize,
+    /// Bytes read past the stderr limit and discarded, as for stdout
+    pub stderr_truncated: usize,
+}
+
+impl BufferPool {
+    /// Keep at most `capacity` idle buffers (0 disables pooling)
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            buffers: (capacity > 0).then(|| ArrayQueue::new(capacity)),
+        }
+    }
+
+    /// Take an idle buffer, or allocate a new one when the pool is empty
+    pub fn get(&self) -> PooledBuffer<'_> {
+        let buffer = self
+            .buffers
+            .as_ref()
+            .and_then(ArrayQueue::pop)
+            .unwrap_or_default();
+        PooledBuffer { buffer, pool: self }
+    }
+
+    /// Number of idle buffers currently held
+    pub fn idle(&self) -> usize {
+        self.buffers.as_ref().map_or(0, ArrayQueue::len)
+    }
+
+    fn put(&self, mut buffer: Vec<u8>) {
+        if let Some(buffers) = &self.buffers
+            && buffer.capacity() <= MAX_POOLED_BUFFER_BYTES
+        {
+            buffer.clear();
+            // A full pool just drops the buffer
+            let _ = buffers.push(buffer);
+        }
+    }
+}
+
+impl Deref for PooledBuffer<'_> {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        &self.buffer
+    }
+}
+
+impl DerefMut for PooledBuffer<'_> {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.buffer
+    }
+}
+
+impl Drop for PooledBuffer<'_> {
+    fn drop(&mut self) {
+        self.pool.put(std::mem::take(&mut self.buffer));
+    }
+}
+
+/// Read `pipe` to the end, appending at most `limit` bytes to `buffer` and counting the
+/// rest in `truncated`. Bytes past the limit are still read, so the child never blocks on
+/// a full pipe.
+async fn read_limited(
+    pipe: &mut (impl AsyncRead + Unpin),
+    buffer: &mut Vec<u8>,
+    limit: Option<usize>,
+    truncated: &mut usize,
+) -> std::io::Result<()> {
+    let Some(limit) = limit else {
+        return pipe.read_to_end(buffer).await.map(drop);
+    };
+    (&mut *pipe).take(limit as u64).read_to_end(buffer).await?;
+    // On the heap: an array would bloat every future awaiting this one
+    let mut discard = vec![0; 8 * 1024];
+    loop {
+        match pipe.read(&mut discard).await? {
+            0 => return Ok(()),
+            read => *truncated += read,
+        }
+    }
+}
+
+/// Wait for `child` while reading its piped stdout and stderr into buffers from `pool`.
+/// The pooled equivalent of `Command::output`, except that output is read incrementally,
+/// so when `timeout` elapses the child is killed and the output so far is kept. Each
+/// stream keeps at most its `limits` bytes. With `kill_group`, the child's whole process
+/// group is killed on timeout or when the future is dropped (see [`ProcessGroupGuard`]).
+pub async fn read_output<'a>(
+    mut child: Child,
+    pool: &'a BufferPool,
+    timeout: Option<Duration>,
+    limits: OutputLimits,
+    kill_group: bool,
+) -> std::io::Result<PooledOutput<'a>> {
+    let mut group = ProcessGroupGuard::new(&child, kill_group);
+    let mut stdout = pool.get();
+    let mut stderr = pool.get();
+    let (mut stdout_truncated, mut stderr_truncated) = (0, 0);
+    let mut stdout_pipe = child.stdout.take();
+    let mut stderr_pipe = child.stderr.take();
+
+    // Both pipes must be drained concurrently or a chatty child can block on a full pipe
+    let read_stdout = async {
+        match &mut stdout_pipe {
+            Some(pipe) => {
+                read_limited(pipe, &mut stdout, limits.stdout, &mut stdout_truncated).await
+            }
+            None => Ok(()),
+        }
+    };
+    let read_stderr = async {
+        match &mut stderr_pipe {
+            Some(pipe) => {
+                read_limited(pipe, &mut stderr, limits.stderr, &mut stderr_truncated).await
+            }
+            None => Ok(()),
+        }
+    };
+    let run = async {
+        tokio::try_join!(child.wait(), read_stdout, read_stderr).map(|(status, _, _)| status)
+    };
+
+    let (status, timed_out) = match timeout {
+        Some(timeout) => match tokio::time::timeout(timeout, run).await {
+            Ok(status) => (status?, false),
+            Err(_) => {
+                group.kill();
+                child.kill().await?;
+                (child.wait().await?, true)
+            }
+        },
+        None => (run.await?, false),
+    };
+    if !timed_out {
+        group.disarm();
+    }
+
+    Ok(PooledOutput {
+        status,
+        timed_out,
+        stdout,
+        stderr,
+        stdout_truncated,
+        stderr_truncated,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Stdio;
+    use tokio::process::Command;
+
+    #[test]
+    fn test_buffers_are_reused() {
+        let pool = BufferPool::new(2);
+        let mut buffer = pool.get();
+        buffer.extend_from_slice(b"hello");
+        let capacity = buffer.capacity();
+        drop(buffer);
+        assert_eq!(pool.idle(), 1);
+
+        let buffer = pool.get();
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.capacity(), capacity);
+        assert_eq!(pool.idle(), 0);
+    }
+
+    #[test]
+    fn test_pool_is_bounded() {
+        let pool = BufferPool::new(1);
+        let buffers = [pool.get(), pool.get()];
+        drop(buffers);
+        assert_eq!(pool.idle(), 1);
+
+        let mut large = pool.get();
+        large.reserve(MAX_POOLED_BUFFER_BYTES + 1);
+        drop(large);
+        assert_eq!(pool.idle(), 0);
+    }
+
+    #[test]
+    fn test_disabled_pool() {
+        let pool = BufferPool::new(0);
+        drop(pool.get());
+        assert_eq!(pool.idle(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_read_output() {
+        let pool = BufferPool::new(4);
+        let child = Command::new("sh")
+            .args(["-c", "echo out; echo err >&2"])
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+
+        let output = read_output(child, &pool, None, OutputLimits::default(), false)
+            .await
+            .unwrap();
+        assert!(output.status.success());
+        assert!(!output.timed_out);
+        assert_eq!(&output.stdout[..], b"out\n");
+        assert_eq!(&output.stderr[..], b"err\n");
+        drop(output);
+        assert_eq!(pool.idle(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_read_output_keeps_partial_output_on_timeout() {
+        let pool = BufferPool::new(4);
+        let child = Command::new("sh")
+            .args(["-c", "echo partial; exec sleep 5"])
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+
+        let output = read_output(
+            child,
+            &pool,
+            Some(Duration::from_millis(300)),
+            OutputLimits::default(),
+            false,
+        )
+        .await
+        .unwrap();
+        assert!(output.timed_out);
+        assert!(!output.status.success());
+        assert_eq!(&output.stdout[..], b"partial\n");
+    }
+
+    #[tokio::test]
+    async fn test_read_output_limits_each_stream() {
+        let pool = BufferPool::new(4);
+        let child = Command::new("sh")
+            .args(["-c", "printf 0123456789; printf abcdefghij >&2; printf x"])
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+
+        let limits = OutputLimits {
+            stdout: Some(4),
+            stderr: Some(20),
+        };
+        let output = read_output(child, &pool, None, limits, false)
+            .await
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(&output.stdout[..], b"0123");
+        assert_eq!(output.stdout_truncated, 7);
+        assert_eq!(&output.stderr[..], b"abcdefghij");
+        assert_eq!(output.stderr_truncated, 0);
+    }
+}
@@ -0,0 +1,199 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Thresholds controlling when the circuit opens and how long it stays open
+#[derive(Debug, Clone, Copy)]
+pub struct CircuitBreakerConfig {
+    /// Consecutive failures within `window` that open the circuit (0 disables the breaker)
+    pub failure_threshold: u32,
+    /// Failures older than this no longer count towards the threshold
+    pub window: Duration,
+    /// How long the circuit stays open before a trial call is let through
+    pub cooldown: Duration,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            window: Duration::from_secs(60),
+            cooldown: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum CircuitState {
+    Closed {
+        failures: u32,
+        first_failure: Option<Instant>,
+    },
+    Open {
+        until: Instant,
+    },
+    HalfOpen {
+        since: Instant,
+    },
+}
+
+/// Fast-fails calls after repeated subprocess failures instead of spawning doomed processes
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    config: CircuitBreakerConfig,
+    state: Mutex<CircuitState>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: CircuitBreakerConfig) -> Self {
+        Self {
+            config,
+            state: Mutex::new(CircuitState::Closed {
+                failures: 0,
+                first_failure: None,
+            }),
+        }
+    }
+
+    /// Check whether a call may proceed. Returns the remaining cooldown when the circuit is open.
+    pub fn try_acquire(&self) -> Result<(), Duration> {
+        if self.config.failure_threshold == 0 {
+            return Ok(());
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        match *state {
+            CircuitState::Closed { .. } => Ok(()),
+            CircuitState::Open { until } if now >= until => {
+                // Cooldown elapsed: let a single trial call through
+                *state = CircuitState::HalfOpen { since: now };
+                Ok(())
+            }
+            CircuitState::Open { until } => Err(until - now),
+            // A trial that never reported back (e.g. the request was dropped) should not
+            // keep the circuit half-open forever
+            CircuitState::HalfOpen { since } if now - since >= self.config.cooldown => {
+                *state = CircuitState::HalfOpen { since: now };
+                Ok(())
+            }
+            CircuitState::HalfOpen { since } => Err(self.config.cooldown - (now - since)),
+        }
+    }
+
+    pub fn record_success(&self) {
+        *self.state.lock().unwrap() = CircuitState::Closed {
+            failures: 0,
+            first_failure: None,
+        };
+    }
+
+    pub fn record_failure(&self) {
+        if self.config.failure_threshold == 0 {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        match *state {
+            CircuitState::Closed {
+                failures,
+                first_failure,
+            } => {
+                let failures = match first_failure {
+                    Some(first) if now - first <= self.config.window => failures + 1,
+                    _ => 1,
+                };
+                if failures >= self.config.failure_threshold {
+                    tracing::warn!(
+                        failures,
+                        cooldown_secs = self.config.cooldown.as_secs(),
+                        "Circuit breaker opened after repeated Gemini CLI failures"
+                    );
+                    *state = CircuitState::Open {
+                        until: now + self.config.cooldown,
+                    };
+                } else {
+                    *state = CircuitState::Closed {
+                        failures,
+                        first_failure: first_failure
+                            .filter(|first| now - *first <= self.config.window)
+                            .or(Some(now)),
+                    };
+                }
+            }
+            CircuitState::HalfOpen { .. } => {
+                tracing::warn!("Circuit breaker trial call failed, reopening");
+                *state = CircuitState::Open {
+                    until: now + self.config.cooldown,
+                };
+            }
+            CircuitState::Open { .. } => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker(failure_threshold: u32, cooldown: Duration) -> CircuitBreaker {
+        CircuitBreaker::new(CircuitBreakerConfig {
+            failure_threshold,
+            window: Duration::from_secs(60),
+            cooldown,
+        })
+    }
+
+    #[test]
+    fn test_opens_after_threshold() {
+        let breaker = breaker(2, Duration::from_secs(60));
+        assert!(breaker.try_acquire().is_ok());
+        breaker.record_failure();
+        assert!(breaker.try_acquire().is_ok());
+        breaker.record_failure();
+        assert!(breaker.try_acquire().is_err());
+    }
+
+    #[test]
+    fn test_success_resets_failures() {
+        let breaker = breaker(2, Duration::from_secs(60));
+        breaker.record_failure();
+        breaker.record_success();
+        breaker.record_failure();
+        assert!(breaker.try_acquire().is_ok());
+    }
+
+    #[test]
+    fn test_half_open_after_cooldown() {
+        let breaker = breaker(1, Duration::from_millis(10));
+        breaker.record_failure();
+        assert!(breaker.try_acquire().is_err());
+
+        std::thread::sleep(Duration::from_millis(20));
+        // First caller gets the trial, concurrent callers are still rejected
+        assert!(breaker.try_acquire().is_ok());
+        assert!(breaker.try_acquire().is_err());
+
+        breaker.record_success();
+        assert!(breaker.try_acquire().is_ok());
+    }
+
+    #[test]
+    fn test_failed_trial_reopens() {
+        let breaker = breaker(1, Duration::from_millis(10));
+        breaker.record_failure();
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(breaker.try_acquire().is_ok());
+        breaker.record_failure();
+        assert!(breaker.try_acquire().is_err());
+    }
+
+    #[test]
+    fn test_zero_threshold_disables() {
+        let breaker = breaker(0, Duration::from_secs(60));
+        for _ in 0..10 {
+            breaker.record_failure();
+        }
+        assert!(breaker.try_acquire().is_ok());
+    }
+}
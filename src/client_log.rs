@@ -0,0 +1,82 @@
+use rmcp::model::{LoggingLevel, LoggingMessageNotificationParam};
+use rmcp::{Peer, RoleServer};
+use std::sync::{Mutex, OnceLock};
+
+/// Sends MCP log notifications (`notifications/message`) to the connected client,
+/// filtered by the level it chose with `logging/setLevel`
+pub struct ClientLogger {
+    /// Set once the client has initialized the session
+    peer: OnceLock<Peer<RoleServer>>,
+    level: Mutex<LoggingLevel>,
+}
+
+impl Default for ClientLogger {
+    fn default() -> Self {
+        Self {
+            peer: OnceLock::new(),
+            level: Mutex::new(LoggingLevel::Info),
+        }
+    }
+}
+
+impl ClientLogger {
+    pub fn set_peer(&self, peer: Peer<RoleServer>) {
+        let _ = self.peer.set(peer);
+    }
+
+    pub fn set_level(&self, level: LoggingLevel) {
+        *self.level.lock().unwrap() = level;
+    }
+
+    /// Whether messages at `level` pass the client's minimum level.
+    /// `LoggingLevel` is declared from least to most severe.
+    pub fn enabled(&self, level: LoggingLevel) -> bool {
+        level as u8 >= *self.level.lock().unwrap() as u8
+    }
+
+    /// Send `data` to the client; a no-op before initialization or below the client's level
+    pub async fn log(&self, level: LoggingLevel, logger: &str, data: serde_json::Value) {
+        let Some(peer) = self.peer.get() else {
+            return;
+        };
+        if !self.enabled(level) {
+            return;
+        }
+
+        let param = LoggingMessageNotificationParam {
+            level,
+            logger: Some(logger.to_string()),
+            data,
+        };
+        if let Err(e) = peer.notify_logging_message(param).await {
+            tracing::debug!(error = %e, "Failed to send log notification to the client");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_level_filtering() {
+        let logger = ClientLogger::default();
+        assert!(!logger.enabled(LoggingLevel::Debug));
+        assert!(logger.enabled(LoggingLevel::Info));
+        assert!(logger.enabled(LoggingLevel::Error));
+
+        logger.set_level(LoggingLevel::Warning);
+        assert!(!logger.enabled(LoggingLevel::Notice));
+        assert!(logger.enabled(LoggingLevel::Warning));
+
+        logger.set_level(LoggingLevel::Debug);
+        assert!(logger.enabled(LoggingLevel::Debug));
+    }
+
+    #[tokio::test]
+    async fn test_log_without_peer_is_noop() {
+        ClientLogger::default()
+            .log(LoggingLevel::Error, "test", serde_json::json!("ignored"))
+            .await;
+    }
+}
@@ -0,0 +1,124 @@
+use futures::FutureExt;
+use futures::future::{BoxFuture, WeakShared};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+type InFlight<T> = Arc<Mutex<HashMap<String, WeakShared<BoxFuture<'static, T>>>>>;
+
+/// Single-flight execution for identical requests arriving close together. The first
+/// caller for a key waits out the window before executing, and every caller with the same
+/// key that arrives before the execution finishes shares its result.
+#[derive(Debug)]
+pub struct Coalescer<T> {
+    in_flight: InFlight<T>,
+}
+
+impl<T> Default for Coalescer<T> {
+    fn default() -> Self {
+        Self {
+            in_flight: Arc::default(),
+        }
+    }
+}
+
+impl<T: Clone + Send + Sync + 'static> Coalescer<T> {
+    /// Run `execute` for `key`, or join the execution already pending for it. Returns the
+    /// result and whether it came from another caller's execution. A zero `window`
+    /// disables coalescing.
+    ///
+    /// The execution is dropped (and stops) once every caller waiting on it has gone.
+    pub async fn run<F>(&self, key: String, window: Duration, execute: F) -> (T, bool)
+    where
+        F: Future<Output = T> + Send + 'static,
+    {
+        if window.is_zero() {
+            return (execute.await, false);
+        }
+
+        let (shared, joined) = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            match in_flight.get(&key).and_then(WeakShared::upgrade) {
+                Some(shared) => (shared, true),
+                None => {
+                    let entries = self.in_flight.clone();
+                    let entry_key = key.clone();
+                    let shared = async move {
+                        tokio::time::sleep(window).await;
+                        let result = execute.await;
+                        entries.lock().unwrap().remove(&entry_key);
+                        result
+                    }
+                    .boxed()
+                    .shared();
+                    in_flight.retain(|_, pending| pending.upgrade().is_some());
+                    in_flight.insert(key, shared.downgrade().expect("future is pending"));
+                    (shared, false)
+                }
+            }
+        };
+        (shared.await, joined)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test]
+    async fn test_identical_keys_share_one_execution() {
+        let coalescer = Arc::new(Coalescer::default());
+        let executions = Arc::new(AtomicUsize::new(0));
+        let run = |key: &str| {
+            let coalescer = coalescer.clone();
+            let executions = executions.clone();
+            let key = key.to_string();
+            async move {
+                coalescer
+                    .run(key.clone(), Duration::from_millis(50), async move {
+                        executions.fetch_add(1, Ordering::SeqCst);
+                        key
+                    })
+                    .await
+            }
+        };
+
+        let (first, second, other) = tokio::join!(run("a"), run("a"), run("b"));
+        assert_eq!(first, ("a".to_string(), false));
+        assert_eq!(second, ("a".to_string(), true));
+        assert_eq!(other, ("b".to_string(), false));
+        assert_eq!(executions.load(Ordering::SeqCst), 2);
+
+        // Finished executions are not reused
+        assert_eq!(run("a").await, ("a".to_string(), false));
+        assert_eq!(executions.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_zero_window_disables() {
+        let coalescer = Coalescer::default();
+        let (first, second) = tokio::join!(
+            coalescer.run("a".to_string(), Duration::ZERO, async { 1 }),
+            coalescer.run("a".to_string(), Duration::ZERO, async { 2 }),
+        );
+        assert_eq!((first, second), ((1, false), (2, false)));
+    }
+
+    #[tokio::test]
+    async fn test_abandoned_execution_is_restarted() {
+        let coalescer = Coalescer::default();
+        let abandoned = tokio::time::timeout(
+            Duration::from_millis(10),
+            coalescer.run("a".to_string(), Duration::from_secs(60), async { 1 }),
+        )
+        .await;
+        assert!(abandoned.is_err());
+
+        let result = coalescer
+            .run("a".to_string(), Duration::from_millis(1), async { 2 })
+            .await;
+        assert_eq!(result, (2, false));
+    }
+}
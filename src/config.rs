@@ -0,0 +1,744 @@
+use anyhow::Context;
+use encoding_rs::Encoding;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::circuit_breaker::CircuitBreakerConfig;
+use crate::redact::Redactor;
+use crate::retry_budget::RetryBudgetConfig;
+use crate::retry_jitter::RetryJitter;
+use crate::subprocess_stream::Buffering;
+
+/// Limits on the work [`crate::tools::parse_gemini_output_with_budget`] does looking for
+/// the response in mixed content, so pathological output can't make parsing expensive
+#[derive(Debug, Clone, Copy)]
+pub struct ExtractionBudget {
+    /// Lines examined by the line-by-line strategy
+    pub max_lines: usize,
+    /// Largest embedded `{...}` span or line handed to the JSON parser
+    pub max_candidate_bytes: usize,
+}
+
+impl Default for ExtractionBudget {
+    fn default() -> Self {
+        Self {
+            max_lines: 10_000,
+            max_candidate_bytes: 8 * 1024 * 1024,
+        }
+    }
+}
+
+/// Default `client_identifier`: this server's name and version
+pub const DEFAULT_CLIENT_IDENTIFIER: &str = concat!("mcp-gemini-cli/", env!("CARGO_PKG_VERSION"));
+
+/// Flags passed to gemini-cli in read-only mode: without `--yolo`, the default approval
+/// mode leaves tools that modify files unavailable to non-interactive runs
+pub const DEFAULT_READ_ONLY_ARGS: &str = "--approval-mode default";
+
+/// Fragments of flag and variable names whose values are redacted by [`redact_command`]
+const SECRET_NAME_MARKERS: &[&str] = &["key", "token", "secret", "password", "credential", "auth"];
+
+/// Replace secrets in a command line with `[REDACTED]`: values of `NAME=value` and
+/// `--flag=value` arguments and of the argument after `--flag`, when the name looks like
+/// it holds a credential (e.g. `GEMINI_API_KEY=...`, `--token ...`)
+pub fn redact_command(command: &str) -> String {
+    let is_secret = |name: &str| {
+        let name = name.to_ascii_lowercase();
+        SECRET_NAME_MARKERS
+            .iter()
+            .any(|marker| name.contains(marker))
+    };
+    let mut redact_next = false;
+    command
+        .split_whitespace()
+        .map(|part| {
+            if std::mem::take(&mut redact_next) {
+                return "[REDACTED]".to_string();
+            }
+            match part.split_once('=') {
+                Some((name, _)) if is_secret(name) => format!("{}=[REDACTED]", name),
+                Some(_) => part.to_string(),
+                None => {
+                    redact_next = part.starts_with('-') && is_secret(part);
+                    part.to_string()
+                }
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// How gemini-cli output is requested and returned
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    /// Run with `--output-format json` and return the parsed response
+    #[default]
+    Json,
+    /// Return gemini-cli's stdout unchanged
+    Text,
+    /// Run with `--output-format json` and return the parsed response serialized as YAML
+    Yaml,
+}
+
+/// Server-wide settings shared by every `GeminiCli` session
+#[derive(Debug, Clone)]
+pub struct GeminiCliConfig {
+    /// Command used when a request does not select a backend
+    pub gemini_cli_command: String,
+    /// Workspace path for Gemini CLI (falls back to the GEMINI_WORKSPACE env var)
+    pub workspace: Option<String>,
+    /// Named backend commands selectable per request
+    pub backends: HashMap<String, String>,
+    /// Backend used when a request does not name one
+    pub default_backend: Option<String>,
+    /// Model passed as `--model` when a request does not set one
+    pub default_model: Option<String>,
+    /// Command retried once when the primary command is missing or hits a 5xx error
+    pub fallback_command: Option<String>,
+    /// Thresholds for the circuit breaker around the subprocess
+    pub circuit_breaker: CircuitBreakerConfig,
+    /// Share of calls that may be retried with the fallback command, across all sessions
+    pub retry_budget: RetryBudgetConfig,
+    /// Randomization of the `retry_after_secs` suggested to clients
+    pub retry_jitter: RetryJitter,
+    /// Output format used when a request does not set `output_format`
+    pub default_output_format: OutputFormat,
+    /// Retry JSON that fails to parse with comments and trailing commas removed
+    pub lenient_json: bool,
+    /// Limits on searching mixed output for the JSON response
+    pub extraction_budget: ExtractionBudget,
+    /// Share (0.0 to 1.0) of successful prompt-gemini requests logged; failures are always logged
+    pub request_log_sampling: f64,
+    /// Maximum prompt length in characters
+    pub max_prompt_chars: Option<usize>,
+    /// Maximum prompt length in UTF-8 bytes (guards against argv limits)
+    pub max_prompt_bytes: Option<usize>,
+    /// Maximum number of concurrent gemini-cli executions (unlimited when unset)
+    pub max_concurrency: Option<usize>,
+    /// Maximum number of requests waiting for an execution slot
+    pub max_queue_depth: usize,
+    /// Successful read-only prompt-gemini results kept for identical requests (0 disables
+    /// caching)
+    pub response_cache_max_entries: usize,
+    /// Upper bound on the total serialized size of cached results, in bytes
+    pub response_cache_max_bytes: usize,
+    /// Maximum number of simultaneous prompt-gemini calls per session (unlimited when unset)
+    pub max_in_flight_per_session: Option<usize>,
+    /// Run gemini-cli without `--yolo`, passing `read_only_args` instead, so it can't edit
+    /// files without approval. Command line only, so a config reload can't turn it off.
+    pub read_only: bool,
+    /// Flags appended in read-only mode (whitespace-separated)
+    pub read_only_args: String,
+    /// Identical prompt-gemini requests from one session arriving within this window share
+    /// one execution (0 disables coalescing)
+    pub coalesce_window_ms: u64,
+    /// Pass `--sandbox` to gemini-cli; requests may enable it but not disable it
+    pub default_sandbox: bool,
+    /// Remove ANSI escape sequences from gemini-cli's output before it is parsed or returned
+    pub strip_ansi: bool,
+    /// Send clients a log notification with their queue position while they wait for an
+    /// execution slot
+    pub notify_queue_position: bool,
+    /// Flag placed before the prompt; empty passes the prompt as a positional argument
+    pub prompt_flag: String,
+    /// Flag added for requests with `all_files` (wrappers may name it differently)
+    pub all_files_flag: String,
+    /// Arguments the `diagnose` tool runs the command with (split on whitespace)
+    pub diagnose_subcommand: String,
+    /// Identifies this server in gemini-cli's environment (`GEMINI_CLIENT_IDENTIFIER`) for
+    /// attribution and quota tracking; not set when empty
+    pub client_identifier: String,
+    /// Flag that makes the wrapper report the model's reasoning, added for requests with
+    /// `include_thoughts` (none when unset)
+    pub thoughts_flag: Option<String>,
+    /// Flag passing a request's `language` to the wrapper; when unset the language is
+    /// requested in the prompt instead
+    pub language_flag: Option<String>,
+    /// Flag passing a request's `candidate_count` to the wrapper; counts above 1 are
+    /// rejected when unset
+    pub candidates_flag: Option<String>,
+    /// Separator inserted before the managed flags (e.g. `--` for task runners).
+    /// When unset, `--` is used for `task` commands only.
+    pub args_separator: Option<String>,
+    /// TOML file whose settings are layered over the command line values (reloadable)
+    pub config_file: Option<PathBuf>,
+    /// Seconds a gemini-cli execution may run before it is killed (unlimited when unset)
+    pub timeout_secs: Option<u64>,
+    /// Seconds an execution with the given model may run, replacing `timeout_secs`
+    pub model_timeouts: HashMap<String, u64>,
+    /// Upper bound on a request's `deadline_ms`, in seconds
+    pub max_deadline_secs: u64,
+    /// Minimum gap between consecutive gemini-cli launches across all sessions, in
+    /// milliseconds (0 disables)
+    pub min_spawn_interval_ms: u64,
+    /// Niceness applied to spawned gemini-cli processes (Unix only)
+    pub subprocess_nice: Option<i32>,
+    /// Run gemini-cli in its own process group and kill the whole group on timeout or
+    /// cancellation (Unix only)
+    pub kill_process_group: bool,
+    /// How streamed gemini-cli output is split into deltas
+    pub stream_buffering: Buffering,
+    /// File whose contents are the system instruction applied to every prompt
+    pub system_prompt_file: Option<PathBuf>,
+    /// Contents of `system_prompt_file`, read by [`GeminiCliConfig::resolve`]
+    pub system_prompt: Option<String>,
+    /// Text placed before every prompt (separated by a blank line)
+    pub prompt_prefix: Option<String>,
+    /// Text placed after every prompt (separated by a blank line)
+    pub prompt_suffix: Option<String>,
+    /// Directories every workspace must resolve to a path under (no restriction when empty).
+    /// Canonicalized by [`GeminiCliConfig::resolve`].
+    pub allowed_workspace_roots: Vec<PathBuf>,
+    /// Command that prints the token count of the prompt given as its last argument
+    pub count_tokens_command: Option<String>,
+    /// Command the response text is piped through (stdin to stdout) before it is returned
+    pub post_process_command: Option<String>,
+    /// Directory of `.txt`/`.md` prompt templates served through MCP prompts
+    pub prompts_dir: Option<PathBuf>,
+    /// Seconds the post-process command may run before the call fails
+    pub post_process_timeout_secs: u64,
+    /// Description of the prompt-gemini tool shown in `tools/list` (built-in text when unset)
+    pub tool_description: Option<String>,
+    /// Prompts matching any of these are rejected before gemini-cli runs (command line only)
+    pub deny_prompt_patterns: Vec<Regex>,
+    /// Scrubs secrets from error payloads returned to clients
+    pub redactor: Redactor,
+    /// Masks `--redact-output-pattern` matches in prompt-gemini results
+    pub output_redactor: Redactor,
+    /// Character encoding gemini-cli writes its output in
+    pub output_encoding: &'static Encoding,
+    /// Idle subprocess output buffers kept for reuse (0 disables pooling); capped at two
+    /// per execution slot when `max_concurrency` is set
+    pub output_buffer_pool_size: usize,
+    /// Most bytes of gemini-cli's stdout kept per execution (unlimited when unset)
+    pub max_stdout_bytes: Option<usize>,
+    /// Most bytes of gemini-cli's stderr kept per execution (unlimited when unset)
+    pub max_stderr_bytes: Option<usize>,
+}
+
+/// Settings that can be set (and reloaded) from the `--config` TOML file.
+/// Limits backed by shared runtime state (circuit breaker, queue) require a restart.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    pub gemini_cli_command: Option<String>,
+    pub workspace: Option<String>,
+    pub backends: Option<HashMap<String, String>>,
+    pub default_backend: Option<String>,
+    pub default_model: Option<String>,
+    pub fallback_command: Option<String>,
+    pub default_output_format: Option<OutputFormat>,
+    pub lenient_json: Option<bool>,
+    pub strip_ansi: Option<bool>,
+    pub notify_queue_position: Option<bool>,
+    pub default_sandbox: Option<bool>,
+    pub request_log_sampling: Option<f64>,
+    pub max_prompt_chars: Option<usize>,
+    pub max_prompt_bytes: Option<usize>,
+    pub max_stdout_bytes: Option<usize>,
+    pub max_stderr_bytes: Option<usize>,
+    pub max_in_flight_per_session: Option<usize>,
+    pub prompt_flag: Option<String>,
+    pub diagnose_subcommand: Option<String>,
+    pub client_identifier: Option<String>,
+    pub all_files_flag: Option<String>,
+    pub thoughts_flag: Option<String>,
+    pub language_flag: Option<String>,
+    pub candidates_flag: Option<String>,
+    pub args_separator: Option<String>,
+    pub timeout_secs: Option<u64>,
+    pub model_timeouts: Option<HashMap<String, u64>>,
+    pub max_deadline_secs: Option<u64>,
+    pub min_spawn_interval_ms: Option<u64>,
+    pub subprocess_nice: Option<i32>,
+    pub kill_process_group: Option<bool>,
+    pub retry_jitter: Option<RetryJitter>,
+    pub stream_buffering: Option<Buffering>,
+    pub allowed_workspace_roots: Option<Vec<PathBuf>>,
+    pub system_prompt_file: Option<PathBuf>,
+    pub prompt_prefix: Option<String>,
+    pub prompt_suffix: Option<String>,
+    pub output_encoding: Option<String>,
+    pub tool_description: Option<String>,
+    pub count_tokens_command: Option<String>,
+    pub post_process_command: Option<String>,
+    pub post_process_timeout_secs: Option<u64>,
+    pub prompts_dir: Option<PathBuf>,
+}
+
+/// Look up an encoding by its WHATWG label (e.g. "utf-8", "windows-1252", "shift_jis")
+pub fn parse_encoding(label: &str) -> Result<&'static Encoding, String> {
+    Encoding::for_label(label.trim().as_bytes())
+        .ok_or_else(|| format!("Unknown output encoding '{}'", label))
+}
+
+impl ConfigFile {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        toml::from_str(&contents)
+            .with_context(|| format!("Failed to parse config file {}", path.display()))
+    }
+}
+
+impl Default for GeminiCliConfig {
+    fn default() -> Self {
+        Self {
+            gemini_cli_command: "gemini-cli".to_string(),
+            workspace: None,
+            backends: HashMap::new(),
+            default_backend: None,
+            default_model: None,
+            fallback_command: None,
+            circuit_breaker: CircuitBreakerConfig::default(),
+            retry_budget: RetryBudgetConfig::default(),
+            retry_jitter: RetryJitter::default(),
+            default_output_format: OutputFormat::default(),
+            lenient_json: false,
+            strip_ansi: false,
+            notify_queue_position: false,
+            default_sandbox: false,
+            extraction_budget: ExtractionBudget::default(),
+            request_log_sampling: 1.0,
+            max_prompt_chars: None,
+            max_prompt_bytes: None,
+            max_concurrency: None,
+            max_queue_depth: 64,
+            response_cache_max_entries: 0,
+            response_cache_max_bytes: 64 * 1024 * 1024,
+            max_in_flight_per_session: None,
+            coalesce_window_ms: 0,
+            read_only: false,
+            read_only_args: DEFAULT_READ_ONLY_ARGS.to_string(),
+            prompt_flag: "--prompt".to_string(),
+            diagnose_subcommand: "doctor".to_string(),
+            client_identifier: DEFAULT_CLIENT_IDENTIFIER.to_string(),
+            all_files_flag: "--all-files".to_string(),
+            thoughts_flag: None,
+            language_flag: None,
+            candidates_flag: None,
+            args_separator: None,
+            config_file: None,
+            timeout_secs: None,
+            model_timeouts: HashMap::new(),
+            max_deadline_secs: 3600,
+            min_spawn_interval_ms: 0,
+            subprocess_nice: None,
+            kill_process_group: false,
+            stream_buffering: Buffering::default(),
+            allowed_workspace_roots: Vec::new(),
+            system_prompt_file: None,
+            system_prompt: None,
+            prompt_prefix: None,
+            prompt_suffix: None,
+            tool_description: None,
+            count_tokens_command: None,
+            post_process_command: None,
+            post_process_timeout_secs: 30,
+            prompts_dir: None,
+            deny_prompt_patterns: Vec::new(),
+            redactor: Redactor::default(),
+            output_redactor: Redactor::default(),
+            output_encoding: encoding_rs::UTF_8,
+            output_buffer_pool_size: 32,
+            max_stdout_bytes: None,
+            max_stderr_bytes: None,
+        }
+    }
+}
+
+impl GeminiCliConfig {
+    /// Resolve the effective configuration: these settings overlaid with the
+    /// config file (if any), validated
+    pub fn resolve(&self) -> anyhow::Result<Self> {
+        let mut config = self.clone();
+
+        if let Some(path) = &self.config_file {
+            let file = ConfigFile::load(path)?;
+            macro_rules! overlay {
+                ($($field:ident),*) => {
+                    $(if let Some(value) = file.$field {
+                        config.$field = value;
+                    })*
+                };
+            }
+            macro_rules! overlay_option {
+                ($($field:ident),*) => {
+                    $(if file.$field.is_some() {
+                        config.$field = file.$field;
+                    })*
+                };
+            }
+            overlay!(
+                gemini_cli_command,
+                backends,
+                default_output_format,
+                lenient_json,
+                strip_ansi,
+                notify_queue_position,
+                default_sandbox,
+                request_log_sampling,
+                prompt_flag,
+                all_files_flag,
+                diagnose_subcommand,
+                client_identifier,
+                allowed_workspace_roots,
+                post_process_timeout_secs,
+                model_timeouts,
+                max_deadline_secs,
+                min_spawn_interval_ms,
+                kill_process_group,
+                stream_buffering,
+                retry_jitter
+            );
+            overlay_option!(
+                workspace,
+                default_backend,
+                default_model,
+                fallback_command,
+                max_prompt_chars,
+                max_prompt_bytes,
+                max_stdout_bytes,
+                max_stderr_bytes,
+                max_in_flight_per_session,
+                args_separator,
+                thoughts_flag,
+                language_flag,
+                candidates_flag,
+                timeout_secs,
+                subprocess_nice,
+                system_prompt_file,
+                prompt_prefix,
+                prompt_suffix,
+                tool_description,
+                count_tokens_command,
+                post_process_command,
+                prompts_dir
+            );
+            if let Some(label) = &file.output_encoding {
+                config.output_encoding = parse_encoding(label).map_err(anyhow::Error::msg)?;
+            }
+        }
+
+        // Canonical roots make the prefix check immune to `..` and symlinks
+        config.allowed_workspace_roots = config
+            .allowed_workspace_roots
+            .iter()
+            .map(|root| {
+                std::fs::canonicalize(root).with_context(|| {
+                    format!(
+                        "Allowed workspace root {} is not accessible",
+                        root.display()
+                    )
+                })
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        // Read on every resolve, so a reload picks up edits to the file
+        config.system_prompt = config
+            .system_prompt_file
+            .as_ref()
+            .map(|path| {
+                std::fs::read_to_string(path).with_context(|| {
+                    format!("System prompt file {} cannot be read", path.display())
+                })
+            })
+            .transpose()?;
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// The settings as JSON for the `show-config` tool, with secrets in commands redacted
+    pub fn redacted_json(&self) -> serde_json::Value {
+        let redact = |command: &Option<String>| command.as_deref().map(redact_command);
+        let backends: HashMap<&str, String> = self
+            .backends
+            .iter()
+            .map(|(name, command)| (name.as_str(), redact_command(command)))
+            .collect();
+        serde_json::json!({
+            "gemini_cli_command": redact_command(&self.gemini_cli_command),
+            "workspace": self.workspace,
+            "backends": backends,
+            "default_backend": self.default_backend,
+            "default_model": self.default_model,
+            "fallback_command": redact(&self.fallback_command),
+            "count_tokens_command": redact(&self.count_tokens_command),
+            "post_process_command": redact(&self.post_process_command),
+            "post_process_timeout_secs": self.post_process_timeout_secs,
+            "prompts_dir": self.prompts_dir,
+            "circuit_breaker": {
+                "failure_threshold": self.circuit_breaker.failure_threshold,
+                "window_secs": self.circuit_breaker.window.as_secs(),
+                "cooldown_secs": self.circuit_breaker.cooldown.as_secs(),
+            },
+            "retry_budget": {
+                "percent": self.retry_budget.percent,
+                "window_secs": self.retry_budget.window.as_secs(),
+                "min_retries": self.retry_budget.min_retries,
+            },
+            "retry_jitter": self.retry_jitter,
+            "default_output_format": self.default_output_format,
+            "lenient_json": self.lenient_json,
+            "strip_ansi": self.strip_ansi,
+            "notify_queue_position": self.notify_queue_position,
+            "default_sandbox": self.default_sandbox,
+            "request_log_sampling": self.request_log_sampling,
+            "extraction_budget": {
+                "max_lines": self.extraction_budget.max_lines,
+                "max_candidate_bytes": self.extraction_budget.max_candidate_bytes,
+            },
+            "max_prompt_chars": self.max_prompt_chars,
+            "max_prompt_bytes": self.max_prompt_bytes,
+            "max_concurrency": self.max_concurrency,
+            "max_queue_depth": self.max_queue_depth,
+            "response_cache": {
+                "max_entries": self.response_cache_max_entries,
+                "max_bytes": self.response_cache_max_bytes,
+            },
+            "max_in_flight_per_session": self.max_in_flight_per_session,
+            "coalesce_window_ms": self.coalesce_window_ms,
+            "read_only": self.read_only,
+            "read_only_args": self.read_only.then(|| redact_command(&self.read_only_args)),
+            "prompt_flag": self.prompt_flag,
+            "all_files_flag": self.all_files_flag,
+            "diagnose_subcommand": self.diagnose_subcommand,
+            "client_identifier": self.client_identifier,
+            "thoughts_flag": self.thoughts_flag,
+            "language_flag": self.language_flag,
+            "candidates_flag": self.candidates_flag,
+            "args_separator": self.args_separator,
+            "config_file": self.config_file,
+            "timeout_secs": self.timeout_secs,
+            "model_timeouts": self.model_timeouts,
+            "max_deadline_secs": self.max_deadline_secs,
+            "min_spawn_interval_ms": self.min_spawn_interval_ms,
+            "subprocess_nice": self.subprocess_nice,
+            "kill_process_group": self.kill_process_group,
+            "stream_buffering": self.stream_buffering,
+            "system_prompt_file": self.system_prompt_file,
+            "prompt_prefix": self.prompt_prefix,
+            "prompt_suffix": self.prompt_suffix,
+            "allowed_workspace_roots": self.allowed_workspace_roots,
+            "tool_description": self.tool_description,
+            // Only the count: the patterns themselves would show how to get around them
+            "deny_prompt_patterns": self.deny_prompt_patterns.len(),
+            "redact_output_patterns": self.output_redactor.pattern_count(),
+            "output_encoding": self.output_encoding.name(),
+            "output_buffer_pool_size": self.output_buffer_pool_size,
+            "max_stdout_bytes": self.max_stdout_bytes,
+            "max_stderr_bytes": self.max_stderr_bytes,
+        })
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if let Some(default_backend) = &self.default_backend
+            && !self.backends.contains_key(default_backend)
+        {
+            anyhow::bail!(
+                "Default backend '{}' is not defined; add it with --backend {}=COMMAND",
+                default_backend,
+                default_backend
+            );
+        }
+        if self
+            .default_model
+            .as_ref()
+            .is_some_and(|model| model.trim().is_empty())
+        {
+            anyhow::bail!("Default model must not be empty");
+        }
+        if let Some(nice) = self.subprocess_nice
+            && !(-20..=19).contains(&nice)
+        {
+            anyhow::bail!("Subprocess nice value {} is outside -20..=19", nice);
+        }
+        if !(0.0..=1.0).contains(&self.request_log_sampling) {
+            anyhow::bail!(
+                "Request log sampling rate {} is outside 0.0..=1.0",
+                self.request_log_sampling
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_config(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "mcp-gemini-cli-config-{}-{}.toml",
+            std::process::id(),
+            name
+        ));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_resolve_without_file() {
+        let config = GeminiCliConfig::default().resolve().unwrap();
+        assert_eq!(config.gemini_cli_command, "gemini-cli");
+    }
+
+    #[test]
+    fn test_file_overrides_command_line() {
+        let path = write_config(
+            "overrides",
+            r#"
+gemini_cli_command = "gemini"
+default_output_format = "text"
+max_prompt_chars = 100
+timeout_secs = 120
+output_encoding = "latin1"
+
+[backends]
+fast = "gemini --model gemini-2.5-flash"
+"#,
+        );
+        let config = GeminiCliConfig {
+            gemini_cli_command: "from-cli".to_string(),
+            workspace: Some("/workspace".to_string()),
+            config_file: Some(path),
+            ..Default::default()
+        }
+        .resolve()
+        .unwrap();
+
+        assert_eq!(config.gemini_cli_command, "gemini");
+        assert_eq!(config.default_output_format, OutputFormat::Text);
+        assert_eq!(config.max_prompt_chars, Some(100));
+        assert_eq!(config.timeout_secs, Some(120));
+        assert_eq!(config.output_encoding, encoding_rs::WINDOWS_1252);
+        assert_eq!(config.backends["fast"], "gemini --model gemini-2.5-flash");
+        // Settings absent from the file keep their command line values
+        assert_eq!(config.workspace.as_deref(), Some("/workspace"));
+    }
+
+    #[test]
+    fn test_rejects_unknown_keys() {
+        let path = write_config("unknown", "gemini_command = \"typo\"\n");
+        let config = GeminiCliConfig {
+            config_file: Some(path),
+            ..Default::default()
+        };
+        assert!(config.resolve().is_err());
+    }
+
+    #[test]
+    fn test_canonicalizes_allowed_workspace_roots() {
+        let tmp = std::env::temp_dir();
+        let config = GeminiCliConfig {
+            allowed_workspace_roots: vec![tmp.join(".").join("..").join(tmp.file_name().unwrap())],
+            ..Default::default()
+        }
+        .resolve()
+        .unwrap();
+        assert_eq!(
+            config.allowed_workspace_roots,
+            vec![std::fs::canonicalize(&tmp).unwrap()]
+        );
+
+        let config = GeminiCliConfig {
+            allowed_workspace_roots: vec![PathBuf::from("/nonexistent/workspace/root")],
+            ..Default::default()
+        };
+        assert!(config.resolve().is_err());
+    }
+
+    #[test]
+    fn test_reads_system_prompt_file() {
+        let path = write_config("system-prompt", "You are a careful reviewer.\n");
+        let config = GeminiCliConfig {
+            system_prompt_file: Some(path.clone()),
+            ..Default::default()
+        };
+        let resolved = config.resolve().unwrap();
+        assert_eq!(
+            resolved.system_prompt.as_deref(),
+            Some("You are a careful reviewer.\n")
+        );
+
+        // Edits are picked up the next time the configuration is resolved
+        std::fs::write(&path, "Answer in French.").unwrap();
+        assert_eq!(
+            config.resolve().unwrap().system_prompt.as_deref(),
+            Some("Answer in French.")
+        );
+
+        let error = GeminiCliConfig {
+            system_prompt_file: Some(PathBuf::from("/nonexistent/system-prompt.md")),
+            ..Default::default()
+        }
+        .resolve()
+        .unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("System prompt file /nonexistent/system-prompt.md cannot be read")
+        );
+    }
+
+    #[test]
+    fn test_rejects_undefined_default_backend() {
+        let config = GeminiCliConfig {
+            default_backend: Some("missing".to_string()),
+            ..Default::default()
+        };
+        assert!(config.resolve().is_err());
+    }
+
+    #[test]
+    fn test_rejects_empty_default_model() {
+        let config = GeminiCliConfig {
+            default_model: Some(" ".to_string()),
+            ..Default::default()
+        };
+        assert!(config.resolve().is_err());
+    }
+
+    #[test]
+    fn test_rejects_sampling_rate_out_of_range() {
+        let path = write_config("sampling", "request_log_sampling = 1.5\n");
+        let config = GeminiCliConfig {
+            config_file: Some(path),
+            ..Default::default()
+        };
+        assert!(config.resolve().is_err());
+    }
+
+    #[test]
+    fn test_redact_command() {
+        assert_eq!(
+            redact_command("env GEMINI_API_KEY=abc123 gemini --model pro"),
+            "env GEMINI_API_KEY=[REDACTED] gemini --model pro"
+        );
+        assert_eq!(
+            redact_command("wrapper --auth-token abc --token=def --verbose"),
+            "wrapper --auth-token [REDACTED] --token=[REDACTED] --verbose"
+        );
+        assert_eq!(redact_command("gemini --yolo"), "gemini --yolo");
+    }
+
+    #[test]
+    fn test_redacted_json() {
+        let config = GeminiCliConfig {
+            backends: HashMap::from([("fast".to_string(), "gemini --api-key secret".to_string())]),
+            fallback_command: Some("OAUTH_TOKEN=xyz gemini".to_string()),
+            ..Default::default()
+        };
+        let json = config.redacted_json();
+        assert_eq!(json["gemini_cli_command"], "gemini-cli");
+        assert_eq!(json["backends"]["fast"], "gemini --api-key [REDACTED]");
+        assert_eq!(json["fallback_command"], "OAUTH_TOKEN=[REDACTED] gemini");
+        assert_eq!(json["default_output_format"], "json");
+        assert_eq!(json["output_encoding"], "UTF-8");
+        assert!(!json.to_string().contains("secret"));
+    }
+}
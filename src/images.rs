@@ -0,0 +1,175 @@
+use base64::Engine;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Largest image accepted, as a file or decoded data URL
+pub const MAX_IMAGE_BYTES: u64 = 20 * 1024 * 1024;
+
+/// Model name prefixes known not to accept image input
+const TEXT_ONLY_MODEL_PREFIXES: &[&str] = &["gemini-1.0-pro", "gemini-embedding", "text-embedding"];
+
+/// An image ready to pass to gemini-cli. Decoded data URLs live in a temporary file
+/// that is removed on drop.
+#[derive(Debug)]
+pub struct PreparedImage {
+    pub path: PathBuf,
+    temporary: bool,
+}
+
+impl Drop for PreparedImage {
+    fn drop(&mut self) {
+        if self.temporary {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Whether `model` can take image input; unknown models are assumed to
+pub fn model_supports_images(model: &str) -> bool {
+    !TEXT_ONLY_MODEL_PREFIXES
+        .iter()
+        .any(|prefix| model.starts_with(prefix))
+}
+
+/// Resolve an image given as a `data:image/...;base64,` URL or a file path (relative
+/// paths are resolved against `base_dir`). Files must be under one of `allowed_roots`.
+pub fn prepare_image(
+    input: &str,
+    base_dir: Option<&Path>,
+    allowed_roots: &[PathBuf],
+) -> Result<PreparedImage, String> {
+    if let Some(data_url) = input.strip_prefix("data:") {
+        return write_data_url(data_url);
+    }
+
+    let path = match base_dir {
+        Some(base_dir) => base_dir.join(input),
+        None => PathBuf::from(input),
+    };
+    // Canonicalizing resolves `..` and symlinks before the prefix check
+    let canonical = std::fs::canonicalize(&path)
+        .map_err(|e| format!("Image '{}' cannot be read: {}", input, e))?;
+    if !allowed_roots.iter().any(|root| canonical.starts_with(root)) {
+        return Err(format!(
+            "Image '{}' is not under any allowed workspace root",
+            input
+        ));
+    }
+    let metadata = std::fs::metadata(&canonical)
+        .map_err(|e| format!("Image '{}' cannot be read: {}", input, e))?;
+    if !metadata.is_file() {
+        return Err(format!("Image '{}' is not a file", input));
+    }
+    if metadata.len() > MAX_IMAGE_BYTES {
+        return Err(format!(
+            "Image '{}' is {} bytes, exceeding the maximum of {}",
+            input,
+            metadata.len(),
+            MAX_IMAGE_BYTES
+        ));
+    }
+
+    Ok(PreparedImage {
+        path: canonical,
+        temporary: false,
+    })
+}
+
+/// Decode `image/<type>;base64,<data>` into a temporary file
+fn write_data_url(data_url: &str) -> Result<PreparedImage, String> {
+    let (media_type, data) = data_url
+        .split_once(";base64,")
+        .ok_or("Image data URLs must be base64 encoded (data:image/<type>;base64,...)")?;
+    let extension = media_type
+        .strip_prefix("image/")
+        .filter(|subtype| {
+            !subtype.is_empty()
+                && subtype
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '+')
+        })
+        .ok_or_else(|| format!("Data URL media type '{}' is not an image", media_type))?;
+
+    // Reject oversized input before decoding it (base64 encodes 3 bytes in 4 characters)
+    if data.len() as u64 / 4 * 3 > MAX_IMAGE_BYTES {
+        return Err(format!(
+            "Image data URL exceeds the maximum of {} bytes",
+            MAX_IMAGE_BYTES
+        ));
+    }
+    let bytes = base64::engine::general_purpose::STANDARD
+        .decode(data.trim())
+        .map_err(|e| format!("Image data URL is not valid base64: {}", e))?;
+
+    // The temp dir is shared, so the name is unguessable and the file must not exist
+    // yet: an existing file or a planted symlink fails instead of being written through
+    let path = std::env::temp_dir().join(format!(
+        "mcp-gemini-cli-image-{:016x}.{}",
+        rand::random::<u64>(),
+        extension.split('+').next().unwrap_or(extension)
+    ));
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let write_error =
+        |e: std::io::Error| format!("Failed to write image data URL to a temporary file: {}", e);
+    let mut file = options.open(&path).map_err(write_error)?;
+    // Created, so removed on drop even if writing fails
+    let image = PreparedImage {
+        path,
+        temporary: true,
+    };
+    file.write_all(&bytes).map_err(write_error)?;
+    Ok(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_data_url_is_written_and_removed() {
+        let image = prepare_image("data:image/png;base64,aGVsbG8=", None, &[]).unwrap();
+        let path = image.path.clone();
+        assert_eq!(path.extension().unwrap(), "png");
+        assert_eq!(std::fs::read(&path).unwrap(), b"hello");
+        drop(image);
+        assert!(!path.exists());
+
+        assert!(prepare_image("data:text/plain;base64,aGVsbG8=", None, &[]).is_err());
+        assert!(prepare_image("data:image/png,hello", None, &[]).is_err());
+        assert!(prepare_image("data:image/png;base64,!!!", None, &[]).is_err());
+    }
+
+    #[test]
+    fn test_image_files() {
+        let tmp = std::fs::canonicalize(std::env::temp_dir()).unwrap();
+        let name = format!("mcp-gemini-cli-test-{}-image.png", std::process::id());
+        std::fs::write(tmp.join(&name), b"png").unwrap();
+
+        let image = prepare_image(&name, Some(&tmp), std::slice::from_ref(&tmp)).unwrap();
+        assert_eq!(image.path, tmp.join(&name));
+        drop(image);
+        // Files given by path are never removed
+        assert!(tmp.join(&name).exists());
+
+        let error = prepare_image(&name, Some(&tmp), &[PathBuf::from("/nonexistent")]);
+        assert!(
+            error
+                .unwrap_err()
+                .contains("not under any allowed workspace root")
+        );
+        // Without allowed roots no file is accepted
+        assert!(prepare_image(&name, Some(&tmp), &[]).is_err());
+        assert!(prepare_image("missing.png", Some(&tmp), std::slice::from_ref(&tmp)).is_err());
+        assert!(prepare_image(".", Some(&tmp), std::slice::from_ref(&tmp)).is_err());
+    }
+
+    #[test]
+    fn test_model_supports_images() {
+        assert!(model_supports_images("gemini-2.5-pro"));
+        assert!(!model_supports_images("gemini-1.0-pro-001"));
+        assert!(!model_supports_images("text-embedding-004"));
+    }
+}
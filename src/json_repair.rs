@@ -0,0 +1,92 @@
+/// Remove `//` line comments, `/* */` block comments and trailing commas before `}` or `]`
+/// from JSON-like `text`, leaving string contents untouched. Returns `None` when there was
+/// nothing to remove. The result is not validated; parsing it is left to the caller.
+pub fn repair(text: &str) -> Option<String> {
+    let mut repaired = String::with_capacity(text.len());
+    let mut changed = false;
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut chars = text.char_indices().peekable();
+
+    while let Some((i, c)) = chars.next() {
+        if in_string {
+            repaired.push(c);
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+
+        match c {
+            '"' => {
+                in_string = true;
+                repaired.push(c);
+            }
+            '/' if text[i..].starts_with("//") => {
+                changed = true;
+                // The newline is kept, so line-based parsing still sees the same lines
+                while chars.next_if(|&(_, c)| c != '\n').is_some() {}
+            }
+            '/' if text[i..].starts_with("/*") => {
+                changed = true;
+                chars.next();
+                let mut previous = ' ';
+                for (_, c) in chars.by_ref() {
+                    if previous == '*' && c == '/' {
+                        break;
+                    }
+                    previous = c;
+                }
+            }
+            '}' | ']' => {
+                // Comments were already dropped, so only whitespace can follow the comma
+                let trimmed = repaired.trim_end();
+                if trimmed.ends_with(',') {
+                    changed = true;
+                    let comma = trimmed.len() - 1;
+                    repaired.remove(comma);
+                }
+                repaired.push(c);
+            }
+            _ => repaired.push(c),
+        }
+    }
+
+    changed.then_some(repaired)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_removes_trailing_commas_and_comments() {
+        let text = r#"{
+  // the answer
+  "response": "a, b // c /* d */", /* inline */
+  "list": [1, 2,],
+  "stats": {"models": {},},
+}"#;
+        let repaired = repair(text).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&repaired).unwrap();
+        assert_eq!(
+            value,
+            serde_json::json!({
+                "response": "a, b // c /* d */",
+                "list": [1, 2],
+                "stats": {"models": {}},
+            })
+        );
+    }
+
+    #[test]
+    fn test_leaves_valid_json_and_escapes_alone() {
+        assert_eq!(repair(r#"{"response": "say \"hi,\" ]"}"#), None);
+        assert_eq!(repair("plain text"), None);
+        // An unclosed block comment runs to the end of the input
+        assert_eq!(repair("{\"a\": 1} /* tail").unwrap(), "{\"a\": 1} ");
+    }
+}
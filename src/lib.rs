@@ -0,0 +1,32 @@
+// The show-config JSON is one json! invocation with many fields
+#![recursion_limit = "256"]
+
+pub mod active_requests;
+pub mod ansi;
+pub mod audit;
+pub mod buffer_pool;
+pub mod circuit_breaker;
+pub mod client_log;
+pub mod coalesce;
+pub mod config;
+pub mod images;
+pub mod json_repair;
+pub mod languages;
+pub mod metrics;
+pub mod openai;
+pub mod process_group;
+pub mod prompts;
+pub mod queue;
+pub mod redact;
+pub mod resource_usage;
+pub mod response_cache;
+pub mod retry_budget;
+pub mod retry_jitter;
+pub mod sampling;
+pub mod spawn_throttle;
+pub mod startup;
+pub mod state;
+pub mod subprocess_stream;
+pub mod template;
+pub mod tools;
+pub mod workspace_files;
@@ -1,9 +1,58 @@
 use clap::Parser;
+use regex::Regex;
 use rmcp::transport::sse_server::{SseServer, SseServerConfig};
+use std::path::PathBuf;
+use std::sync::Arc;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
-mod tools;
-use tools::GeminiCli;
+use encoding_rs::Encoding;
+use mcp_gemini_cli::audit::AuditLog;
+use mcp_gemini_cli::circuit_breaker::CircuitBreakerConfig;
+use mcp_gemini_cli::config::{
+    DEFAULT_CLIENT_IDENTIFIER, DEFAULT_READ_ONLY_ARGS, ExtractionBudget, GeminiCliConfig,
+    OutputFormat, parse_encoding,
+};
+use mcp_gemini_cli::openai;
+use mcp_gemini_cli::redact::{DEFAULT_SECRET_ENV_VARS, Redactor};
+use mcp_gemini_cli::retry_budget::RetryBudgetConfig;
+use mcp_gemini_cli::retry_jitter::RetryJitter;
+use mcp_gemini_cli::startup;
+use mcp_gemini_cli::state::ServerState;
+use mcp_gemini_cli::subprocess_stream::Buffering;
+use mcp_gemini_cli::tools::{DEBUG_REQUEST_DIRECTIVE, GeminiCli};
+use std::time::Duration;
+
+/// Parse a `name=command` backend definition
+fn parse_backend(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((name, command)) if !name.trim().is_empty() && !command.trim().is_empty() => {
+            Ok((name.trim().to_string(), command.trim().to_string()))
+        }
+        _ => Err(format!("expected NAME=COMMAND, got '{}'", value)),
+    }
+}
+
+/// Parse a `model=secs` timeout override
+fn parse_model_timeout(value: &str) -> Result<(String, u64), String> {
+    match value.split_once('=') {
+        Some((model, secs)) if !model.trim().is_empty() => match secs.trim().parse() {
+            Ok(secs) => Ok((model.trim().to_string(), secs)),
+            Err(_) => Err(format!("expected MODEL=SECS, got '{}'", value)),
+        },
+        _ => Err(format!("expected MODEL=SECS, got '{}'", value)),
+    }
+}
+
+/// Parse a sampling rate between 0.0 and 1.0
+fn parse_sampling_rate(value: &str) -> Result<f64, String> {
+    match value.trim().parse::<f64>() {
+        Ok(rate) if (0.0..=1.0).contains(&rate) => Ok(rate),
+        _ => Err(format!(
+            "expected a number from 0.0 to 1.0, got '{}'",
+            value
+        )),
+    }
+}
 
 #[derive(Parser)]
 #[command(author, version, about)]
@@ -16,34 +65,552 @@ struct Args {
     #[arg(long, env = "GEMINI_WORKSPACE")]
     workspace: Option<String>,
 
+    /// Named backend command as NAME=COMMAND (repeatable), selectable per request
+    #[arg(long = "backend", value_name = "NAME=COMMAND", value_parser = parse_backend)]
+    backends: Vec<(String, String)>,
+
+    /// Backend used when a request does not name one (defaults to --gemini-cli-command)
+    #[arg(long, env = "GEMINI_DEFAULT_BACKEND")]
+    default_backend: Option<String>,
+
+    /// Model passed as --model when a request does not set one
+    #[arg(long, env = "GEMINI_DEFAULT_MODEL")]
+    default_model: Option<String>,
+
+    /// Command to retry with when the primary command is not found or returns a 5xx error
+    #[arg(long, env = "GEMINI_FALLBACK_COMMAND")]
+    fallback_command: Option<String>,
+
+    /// Consecutive failures that open the circuit breaker (0 disables it)
+    #[arg(long, env = "GEMINI_CIRCUIT_BREAKER_THRESHOLD", default_value = "5")]
+    circuit_breaker_threshold: u32,
+
+    /// Window in seconds within which failures count towards the threshold
+    #[arg(long, env = "GEMINI_CIRCUIT_BREAKER_WINDOW_SECS", default_value = "60")]
+    circuit_breaker_window_secs: u64,
+
+    /// Seconds the circuit stays open before a trial call is allowed
+    #[arg(
+        long,
+        env = "GEMINI_CIRCUIT_BREAKER_COOLDOWN_SECS",
+        default_value = "30"
+    )]
+    circuit_breaker_cooldown_secs: u64,
+
+    /// Fallback retries allowed as a percentage of calls in the window (0 disables the budget)
+    #[arg(long, env = "GEMINI_RETRY_BUDGET_PERCENT", default_value = "20")]
+    retry_budget_percent: u32,
+
+    /// Window in seconds over which calls and retries are counted for the retry budget
+    #[arg(long, env = "GEMINI_RETRY_BUDGET_WINDOW_SECS", default_value = "60")]
+    retry_budget_window_secs: u64,
+
+    /// Retries allowed per window regardless of the percentage
+    #[arg(long, env = "GEMINI_RETRY_BUDGET_MIN_RETRIES", default_value = "3")]
+    retry_budget_min_retries: u32,
+
+    /// Randomize the retry_after_secs suggested to clients on rate limiting and open
+    /// circuits, so clients that failed together don't retry together: `full` adds up to
+    /// the delay again, `equal` up to half of it; the suggestion never drops below the delay
+    #[arg(
+        long,
+        env = "GEMINI_RETRY_JITTER",
+        value_enum,
+        default_value_t = RetryJitter::None
+    )]
+    retry_jitter: RetryJitter,
+
+    /// Output format used when a request does not set `output_format`
+    #[arg(
+        long,
+        env = "GEMINI_DEFAULT_OUTPUT_FORMAT",
+        value_enum,
+        default_value_t = OutputFormat::Json
+    )]
+    default_output_format: OutputFormat,
+
+    /// Reject prompts longer than this many characters
+    #[arg(long, env = "GEMINI_MAX_PROMPT_CHARS")]
+    max_prompt_chars: Option<usize>,
+
+    /// Reject prompts longer than this many bytes
+    #[arg(long, env = "GEMINI_MAX_PROMPT_BYTES")]
+    max_prompt_bytes: Option<usize>,
+
+    /// Maximum number of concurrent gemini-cli executions (unlimited when unset; at least 1)
+    #[arg(long, env = "GEMINI_MAX_CONCURRENCY", value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    max_concurrency: Option<usize>,
+
+    /// Maximum number of requests waiting in FIFO order when all execution slots are taken
+    #[arg(long, env = "GEMINI_MAX_QUEUE_DEPTH", default_value = "64")]
+    max_queue_depth: usize,
+
+    /// Successful prompt-gemini results cached for identical requests under --read-only,
+    /// evicting the least recently used (0 disables caching)
+    #[arg(long, env = "GEMINI_RESPONSE_CACHE_MAX_ENTRIES", default_value = "0")]
+    response_cache_max_entries: usize,
+
+    /// Upper bound on the total size of cached results, in bytes
+    #[arg(
+        long,
+        env = "GEMINI_RESPONSE_CACHE_MAX_BYTES",
+        default_value = "67108864"
+    )]
+    response_cache_max_bytes: usize,
+
+    /// When Gemini CLI's JSON output fails to parse, retry with `//` and `/* */` comments and
+    /// trailing commas removed before reporting a parse error
+    #[arg(long, env = "GEMINI_LENIENT_JSON")]
+    lenient_json: bool,
+
+    /// Pass --sandbox to Gemini CLI so its tools run isolated; requests cannot turn it off.
+    /// (GEMINI_SANDBOX itself is read by Gemini CLI, hence the different variable.)
+    #[arg(long = "sandbox", env = "GEMINI_DEFAULT_SANDBOX")]
+    default_sandbox: bool,
+
+    /// Remove ANSI escape sequences (colors, cursor movement) from Gemini CLI's output before
+    /// it is parsed or returned, for wrappers that color their output even when piped
+    #[arg(long, env = "GEMINI_STRIP_ANSI")]
+    strip_ansi: bool,
+
+    /// While a request waits for an execution slot, tell the client its queue position with
+    /// an MCP log notification instead of waiting silently
+    #[arg(long, env = "GEMINI_NOTIFY_QUEUE_POSITION")]
+    notify_queue_position: bool,
+
+    /// Fraction (0.0 to 1.0) of successful prompt-gemini requests logged at info level, to
+    /// limit log volume at high throughput; failed requests are always logged
+    #[arg(long, env = "GEMINI_REQUEST_LOG_SAMPLING", default_value = "1.0", value_parser = parse_sampling_rate)]
+    request_log_sampling: f64,
+
+    /// Lines of Gemini CLI output scanned for a JSON response line by line before parsing
+    /// gives up with "exceeded extraction budget"
+    #[arg(long, env = "GEMINI_JSON_MAX_LINES", default_value = "10000")]
+    json_max_lines: usize,
+
+    /// Largest embedded JSON object or line (in bytes) handed to the JSON parser when
+    /// searching mixed output; bigger ones are skipped
+    #[arg(
+        long,
+        env = "GEMINI_JSON_MAX_CANDIDATE_BYTES",
+        default_value = "8388608"
+    )]
+    json_max_candidate_bytes: usize,
+
+    /// Maximum number of simultaneous prompt-gemini calls per client session
+    #[arg(long, env = "GEMINI_MAX_IN_FLIGHT_PER_SESSION")]
+    max_in_flight_per_session: Option<usize>,
+
+    /// Identical prompt-gemini requests from one session arriving within this many milliseconds
+    /// share a single Gemini CLI execution (0 disables coalescing)
+    #[arg(long, env = "GEMINI_COALESCE_WINDOW_MS", default_value = "0")]
+    coalesce_window_ms: u64,
+
+    /// Don't pass `--yolo` to Gemini CLI; pass the read-only args instead so it can't
+    /// modify files (best effort: depends on the CLI or wrapper honouring them)
+    #[arg(long, env = "GEMINI_READ_ONLY")]
+    read_only: bool,
+
+    /// Flags passed to Gemini CLI in read-only mode; an empty value passes none
+    #[arg(
+        long,
+        env = "GEMINI_READ_ONLY_ARGS",
+        default_value = DEFAULT_READ_ONLY_ARGS,
+        allow_hyphen_values = true
+    )]
+    read_only_args: String,
+
+    /// Flag used to pass the prompt; an empty value passes it as a positional argument
+    #[arg(
+        long,
+        env = "GEMINI_PROMPT_FLAG",
+        default_value = "--prompt",
+        allow_hyphen_values = true
+    )]
+    prompt_flag: String,
+
+    /// Arguments the diagnose tool runs the Gemini CLI command with, e.g. `doctor` or
+    /// `auth status` for wrappers that name their diagnostics differently
+    #[arg(long, env = "GEMINI_DIAGNOSE_SUBCOMMAND", default_value = "doctor")]
+    diagnose_subcommand: String,
+
+    /// Identifier passed to Gemini CLI processes in the GEMINI_CLIENT_IDENTIFIER
+    /// environment variable, to attribute API usage to this server (empty to not set it)
+    #[arg(long, env = "GEMINI_CLIENT_IDENTIFIER", default_value = DEFAULT_CLIENT_IDENTIFIER)]
+    client_identifier: String,
+
+    /// Flag passed for requests with `all_files: true`, for wrappers that name gemini-cli's
+    /// `--all-files` differently
+    #[arg(
+        long,
+        env = "GEMINI_ALL_FILES_FLAG",
+        default_value = "--all-files",
+        allow_hyphen_values = true
+    )]
+    all_files_flag: String,
+
+    /// Flag that makes the Gemini CLI wrapper include the model's reasoning in its JSON output
+    /// (as `thoughts`), added for requests with `include_thoughts`; none by default
+    #[arg(long, env = "GEMINI_THOUGHTS_FLAG", allow_hyphen_values = true)]
+    thoughts_flag: Option<String>,
+
+    /// Flag the Gemini CLI wrapper takes a response language with, followed by a request's
+    /// `language` code; without it the language is requested in the prompt
+    #[arg(long, env = "GEMINI_LANGUAGE_FLAG", allow_hyphen_values = true)]
+    language_flag: Option<String>,
+
+    /// Flag, followed by the count, that makes the Gemini CLI wrapper generate a request's
+    /// `candidate_count` candidates and report them as `candidates` in its JSON output; counts
+    /// above 1 are rejected when unset
+    #[arg(long, env = "GEMINI_CANDIDATES_FLAG", allow_hyphen_values = true)]
+    candidates_flag: Option<String>,
+
+    /// Separator inserted before the managed flags for task-runner wrappers (just, make, npm run);
+    /// an empty value disables it. Defaults to `--` for `task` commands only.
+    #[arg(long, env = "GEMINI_ARGS_SEPARATOR", allow_hyphen_values = true)]
+    args_separator: Option<String>,
+
+    /// Seconds to wait at startup for the Gemini CLI command to appear on PATH (0 disables)
+    #[arg(long, env = "GEMINI_STARTUP_WAIT_SECS", default_value = "0")]
+    startup_wait_secs: u64,
+
+    /// Before serving, check that the Gemini CLI command resolves, `--version` works, a
+    /// trivial prompt succeeds and JSON output parses; exit with an error if any check fails
+    #[arg(long, env = "GEMINI_SELF_TEST")]
+    self_test: bool,
+
+    /// After startup, run the prewarm prompt once in the background so the first request
+    /// doesn't pay Gemini CLI's cold-start cost
+    #[arg(long, env = "GEMINI_PREWARM")]
+    prewarm: bool,
+
+    /// Prompt sent by --prewarm
+    #[arg(long, env = "GEMINI_PREWARM_PROMPT", default_value = "hi")]
+    prewarm_prompt: String,
+
+    /// File holding a system instruction applied to every prompt-gemini call, e.g. a baseline
+    /// persona or policy; read at startup and on config reload
+    #[arg(long, env = "GEMINI_SYSTEM_PROMPT_FILE")]
+    system_prompt_file: Option<PathBuf>,
+
+    /// Text placed before every prompt, e.g. house style or safety instructions
+    #[arg(long, env = "GEMINI_PROMPT_PREFIX")]
+    prompt_prefix: Option<String>,
+
+    /// Text placed after every prompt
+    #[arg(long, env = "GEMINI_PROMPT_SUFFIX")]
+    prompt_suffix: Option<String>,
+
+    /// Directory workspaces must be under (repeatable); any other workspace is rejected
+    #[arg(
+        long = "allowed-workspace-root",
+        env = "GEMINI_ALLOWED_WORKSPACE_ROOTS",
+        value_delimiter = ','
+    )]
+    allowed_workspace_roots: Vec<PathBuf>,
+
+    /// Environment variable whose value is scrubbed from error payloads (repeatable), in
+    /// addition to GEMINI_API_KEY, GOOGLE_API_KEY and other well-known secrets
+    #[arg(
+        long = "redact-env-var",
+        env = "GEMINI_REDACT_ENV_VARS",
+        value_delimiter = ','
+    )]
+    redact_env_vars: Vec<String>,
+
+    /// Regex whose matches are scrubbed from error payloads (repeatable; the environment
+    /// variable holds a single pattern, since patterns may contain commas)
+    #[arg(long = "redact-pattern", env = "GEMINI_REDACT_PATTERN")]
+    redact_patterns: Vec<Regex>,
+
+    /// Regex for prompt content the server refuses (repeatable; the environment variable holds
+    /// a single pattern). Matching prompts fail with gemini_prompt_denied before Gemini CLI runs.
+    #[arg(long = "deny-prompt-pattern", env = "GEMINI_DENY_PROMPT_PATTERN")]
+    deny_prompt_patterns: Vec<Regex>,
+
+    /// Regex whose matches are replaced with [REDACTED] in prompt-gemini responses, e.g. secrets
+    /// or PII a model might echo (repeatable; the environment variable holds a single pattern)
+    #[arg(long = "redact-output-pattern", env = "GEMINI_REDACT_OUTPUT_PATTERN")]
+    redact_output_patterns: Vec<Regex>,
+
+    /// Seconds a Gemini CLI execution may run before it is killed (unlimited when unset)
+    #[arg(long, env = "GEMINI_TIMEOUT_SECS")]
+    timeout_secs: Option<u64>,
+
+    /// Timeout for one model as MODEL=SECS (repeatable), replacing --timeout-secs for
+    /// requests using that model, e.g. tighter deadlines for fast models
+    #[arg(long = "model-timeout", value_name = "MODEL=SECS", value_parser = parse_model_timeout)]
+    model_timeouts: Vec<(String, u64)>,
+
+    /// Longest deadline a request may set with `deadline_ms`, in seconds; longer deadlines
+    /// are capped to it
+    #[arg(long, env = "GEMINI_MAX_DEADLINE_SECS", default_value = "3600")]
+    max_deadline_secs: u64,
+
+    /// Minimum gap in milliseconds between consecutive Gemini CLI launches, across all
+    /// sessions, for strict API rate limits; launches wait for their turn (0 disables)
+    #[arg(long, env = "GEMINI_MIN_SPAWN_INTERVAL_MS", default_value = "0")]
+    min_spawn_interval_ms: u64,
+
+    /// Niceness (-20 to 19) for spawned Gemini CLI processes, e.g. 10 to keep the server
+    /// responsive on shared hosts; ignored on non-Unix platforms
+    #[arg(long, env = "GEMINI_SUBPROCESS_NICE", allow_hyphen_values = true, value_parser = clap::value_parser!(i32).range(-20..=19))]
+    subprocess_nice: Option<i32>,
+
+    /// Run each Gemini CLI process in its own process group and kill the whole group on
+    /// timeout or cancellation, so processes it started (e.g. tools) don't keep running;
+    /// ignored on non-Unix platforms
+    #[arg(long, env = "GEMINI_KILL_PROCESS_GROUP")]
+    kill_process_group: bool,
+
+    /// How streamed Gemini CLI output is split into deltas: `chunk` forwards each read as
+    /// it arrives, `line` forwards complete lines
+    #[arg(
+        long,
+        env = "GEMINI_STREAM_BUFFERING",
+        value_enum,
+        default_value_t = Buffering::Chunk
+    )]
+    stream_buffering: Buffering,
+
+    /// Command printing the token count of a prompt (given as its last argument), used by
+    /// estimate-tokens; a ~4 characters per token heuristic is used when unset or failing
+    #[arg(long, env = "GEMINI_COUNT_TOKENS_COMMAND")]
+    count_tokens_command: Option<String>,
+
+    /// Command the response text is piped through (on stdin) before it is returned, e.g. for
+    /// redaction or formatting; its stdout replaces the response
+    #[arg(long, env = "GEMINI_POST_PROCESS_COMMAND")]
+    post_process_command: Option<String>,
+
+    /// Directory of prompt templates (`.txt`/`.md` files with `{{variable}}` placeholders)
+    /// offered to clients through MCP prompts, named after the file
+    #[arg(long, env = "GEMINI_PROMPTS_DIR")]
+    prompts_dir: Option<PathBuf>,
+
+    /// Seconds the post-process command may run before the call fails
+    #[arg(long, env = "GEMINI_POST_PROCESS_TIMEOUT_SECS", default_value = "30")]
+    post_process_timeout_secs: u64,
+
+    /// Description of the prompt-gemini tool shown to clients, e.g. to name the wrapped model
+    #[arg(long, env = "GEMINI_TOOL_DESCRIPTION")]
+    tool_description: Option<String>,
+
+    /// Character encoding of Gemini CLI's output (a WHATWG label such as windows-1252 or
+    /// shift_jis), transcoded to UTF-8 before parsing
+    #[arg(long, env = "GEMINI_OUTPUT_ENCODING", default_value = "utf-8", value_parser = parse_encoding)]
+    output_encoding: &'static Encoding,
+
+    /// Idle subprocess output buffers kept for reuse (0 disables pooling)
+    #[arg(long, env = "GEMINI_OUTPUT_BUFFER_POOL_SIZE", default_value = "32")]
+    output_buffer_pool_size: usize,
+
+    /// Keep at most this many bytes of each Gemini CLI process's stdout; the rest is
+    /// discarded and replaced with a marker line
+    #[arg(long, env = "GEMINI_MAX_STDOUT_BYTES")]
+    max_stdout_bytes: Option<usize>,
+
+    /// Keep at most this many bytes of each Gemini CLI process's stderr, as for stdout
+    #[arg(long, env = "GEMINI_MAX_STDERR_BYTES")]
+    max_stderr_bytes: Option<usize>,
+
+    /// TOML file overriding the settings above; re-read on SIGHUP or via the reload-config tool
+    #[arg(long, env = "GEMINI_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Append a JSON line per prompt execution (timestamp, request ID, command, prompt
+    /// SHA-256, model, outcome, duration) to this file
+    #[arg(long, env = "GEMINI_AUDIT_LOG")]
+    audit_log: Option<PathBuf>,
+
+    /// Store the full prompt text in the audit log instead of only its hash
+    #[arg(long, env = "GEMINI_AUDIT_LOG_PROMPTS", requires = "audit_log")]
+    audit_log_prompts: bool,
+
+    /// Suppress the startup banner and log startup at debug level (errors are unaffected)
+    #[arg(long, short, env = "MCP_GEMINI_CLI_QUIET")]
+    quiet: bool,
+
     /// Hostname to bind the server to
     #[arg(long, env = "MCP_GEMINI_CLI_HOSTNAME", default_value = "127.0.0.1")]
     hostname: String,
 
+    /// Bind to all interfaces (0.0.0.0), e.g. to be reachable from outside a container
+    #[arg(long, env = "MCP_GEMINI_CLI_BIND_ANY", conflicts_with = "hostname")]
+    bind_any: bool,
+
+    /// Serve an OpenAI-compatible POST /v1/chat/completions endpoint backed by prompt-gemini
+    #[arg(long, env = "MCP_GEMINI_CLI_ENABLE_OPENAI_SHIM")]
+    enable_openai_shim: bool,
+
     /// Port to bind the server to
     #[arg(long, env = "MCP_GEMINI_CLI_PORT", default_value = "8000")]
     port: u16,
+
+    /// When the port is in use, try up to this many following ports before giving up
+    #[arg(long, env = "MCP_GEMINI_CLI_PORT_RETRY", default_value = "0")]
+    port_retry: u16,
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let args = Args::parse();
 
+    let gemini_config = GeminiCliConfig {
+        gemini_cli_command: args.gemini_cli_command.clone(),
+        workspace: args.workspace.clone(),
+        backends: args.backends.iter().cloned().collect(),
+        default_backend: args.default_backend.clone(),
+        default_model: args.default_model.clone(),
+        fallback_command: args.fallback_command.clone(),
+        circuit_breaker: CircuitBreakerConfig {
+            failure_threshold: args.circuit_breaker_threshold,
+            window: Duration::from_secs(args.circuit_breaker_window_secs),
+            cooldown: Duration::from_secs(args.circuit_breaker_cooldown_secs),
+        },
+        retry_budget: RetryBudgetConfig {
+            percent: args.retry_budget_percent,
+            window: Duration::from_secs(args.retry_budget_window_secs),
+            min_retries: args.retry_budget_min_retries,
+        },
+        retry_jitter: args.retry_jitter,
+        default_output_format: args.default_output_format,
+        max_prompt_chars: args.max_prompt_chars,
+        max_prompt_bytes: args.max_prompt_bytes,
+        max_concurrency: args.max_concurrency,
+        max_queue_depth: args.max_queue_depth,
+        response_cache_max_entries: args.response_cache_max_entries,
+        response_cache_max_bytes: args.response_cache_max_bytes,
+        max_in_flight_per_session: args.max_in_flight_per_session,
+        coalesce_window_ms: args.coalesce_window_ms,
+        read_only: args.read_only,
+        lenient_json: args.lenient_json,
+        strip_ansi: args.strip_ansi,
+        notify_queue_position: args.notify_queue_position,
+        default_sandbox: args.default_sandbox,
+        request_log_sampling: args.request_log_sampling,
+        extraction_budget: ExtractionBudget {
+            max_lines: args.json_max_lines,
+            max_candidate_bytes: args.json_max_candidate_bytes,
+        },
+        read_only_args: args.read_only_args.clone(),
+        prompt_flag: args.prompt_flag.clone(),
+        diagnose_subcommand: args.diagnose_subcommand.clone(),
+        client_identifier: args.client_identifier.clone(),
+        all_files_flag: args.all_files_flag.clone(),
+        thoughts_flag: args.thoughts_flag.clone(),
+        language_flag: args.language_flag.clone(),
+        candidates_flag: args.candidates_flag.clone(),
+        args_separator: args.args_separator.clone(),
+        config_file: args.config.clone(),
+        output_buffer_pool_size: args.output_buffer_pool_size,
+        max_stdout_bytes: args.max_stdout_bytes,
+        max_stderr_bytes: args.max_stderr_bytes,
+        timeout_secs: args.timeout_secs,
+        model_timeouts: args.model_timeouts.iter().cloned().collect(),
+        max_deadline_secs: args.max_deadline_secs,
+        min_spawn_interval_ms: args.min_spawn_interval_ms,
+        subprocess_nice: args.subprocess_nice,
+        kill_process_group: args.kill_process_group,
+        stream_buffering: args.stream_buffering,
+        output_encoding: args.output_encoding,
+        tool_description: args.tool_description.clone(),
+        count_tokens_command: args.count_tokens_command.clone(),
+        prompts_dir: args.prompts_dir.clone(),
+        post_process_command: args.post_process_command.clone(),
+        post_process_timeout_secs: args.post_process_timeout_secs,
+        allowed_workspace_roots: args.allowed_workspace_roots.clone(),
+        deny_prompt_patterns: args.deny_prompt_patterns.clone(),
+        output_redactor: Redactor::from_patterns(args.redact_output_patterns.clone()),
+        redactor: Redactor::from_env(
+            &DEFAULT_SECRET_ENV_VARS
+                .iter()
+                .map(|name| name.to_string())
+                .chain(args.redact_env_vars.iter().cloned())
+                .collect::<Vec<_>>(),
+            args.redact_patterns.clone(),
+        ),
+        system_prompt_file: args.system_prompt_file.clone(),
+        system_prompt: None,
+        prompt_prefix: args.prompt_prefix.clone(),
+        prompt_suffix: args.prompt_suffix.clone(),
+    };
+
+    // Apply the config file (if any) and validate before accepting connections
+    let mut state = ServerState::new(gemini_config);
+    state.reload_config()?;
+    if let Some(path) = &args.audit_log {
+        state.audit_log = Some(AuditLog::open(path, args.audit_log_prompts)?);
+    }
+    let state = Arc::new(state);
+
     // Initialize tracing
     tracing_subscriber::registry()
         .with(
             tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| "info".to_string().into()),
+                .unwrap_or_else(|_| "info".to_string().into())
+                .add_directive(DEBUG_REQUEST_DIRECTIVE.parse()?),
         )
         .with(tracing_subscriber::fmt::layer())
         .init();
 
-    let bind_address = format!("{}:{}", args.hostname, args.port);
-    tracing::info!("Starting MCP SSE Server on {}", bind_address);
+    #[cfg(not(unix))]
+    if args.subprocess_nice.is_some() {
+        tracing::warn!("--subprocess-nice is only supported on Unix and will be ignored");
+    }
+    #[cfg(not(unix))]
+    if args.kill_process_group {
+        tracing::warn!("--kill-process-group is only supported on Unix and will be ignored");
+    }
+
+    // In container startup races the binary can appear on PATH after the server starts
+    if args.startup_wait_secs > 0 {
+        let command = state.config().gemini_cli_command.clone();
+        if !startup::wait_for_command(&command, Duration::from_secs(args.startup_wait_secs)).await {
+            tracing::warn!(
+                command,
+                startup_wait_secs = args.startup_wait_secs,
+                "Gemini CLI command still not found; starting anyway"
+            );
+        }
+    }
+
+    // Fail at boot rather than on the first client request
+    if args.self_test {
+        let checks = startup::self_test(state.clone()).await;
+        let failed: Vec<_> = checks
+            .iter()
+            .filter(|check| check.result.is_err())
+            .map(|check| check.name)
+            .collect();
+        if !failed.is_empty() {
+            anyhow::bail!("Self-test failed: {}", failed.join(", "));
+        }
+        tracing::info!("Self-test passed");
+    }
+
+    let hostname = if args.bind_any {
+        tracing::warn!(
+            "--bind-any exposes the server on every network interface and no authentication is \
+            configured: anyone who can reach port {} can run prompts through Gemini CLI",
+            args.port
+        );
+        "0.0.0.0"
+    } else {
+        args.hostname.as_str()
+    };
+    let bind_address = startup::resolve_bind_address(hostname, args.port).await?;
+    let listener = startup::bind_listener(bind_address, args.port_retry).await?;
+    let bind_address = listener.local_addr()?;
+    if args.quiet {
+        tracing::debug!("Starting MCP SSE Server on {}", bind_address);
+    } else {
+        tracing::info!("Starting MCP SSE Server on {}", bind_address);
+    }
 
     // Configure SSE server
     let config = SseServerConfig {
-        bind: bind_address.parse()?,
+        bind: bind_address,
         sse_path: "/sse".to_string(),
         post_path: "/message".to_string(),
         ct: tokio_util::sync::CancellationToken::new(),
@@ -51,12 +618,21 @@ async fn main() -> anyhow::Result<()> {
     };
 
     let (sse_server, router) = SseServer::new(config);
+    let router = if args.enable_openai_shim {
+        router.merge(openai::router(state.clone()))
+    } else {
+        router
+    };
 
     // Start the HTTP server
-    let listener = tokio::net::TcpListener::bind(sse_server.config.bind).await?;
     let ct = sse_server.config.ct.child_token();
 
-    let server = axum::serve(listener, router).with_graceful_shutdown(async move {
+    // Connect info lets tools log the peer address of each request
+    let server = axum::serve(
+        listener,
+        router.into_make_service_with_connect_info::<std::net::SocketAddr>(),
+    )
+    .with_graceful_shutdown(async move {
         ct.cancelled().await;
         tracing::info!("SSE server gracefully shutting down");
     });
@@ -67,16 +643,51 @@ async fn main() -> anyhow::Result<()> {
         }
     });
 
+    // Reload the config file on SIGHUP
+    #[cfg(unix)]
+    {
+        let state = state.clone();
+        let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                match state.reload_config() {
+                    Ok(_) => tracing::info!("Configuration reloaded on SIGHUP"),
+                    Err(e) => {
+                        tracing::error!(error = %format!("{:#}", e), "Configuration reload failed")
+                    }
+                }
+            }
+        });
+    }
+
+    // Requests may enable the sandbox even when it is off by default, so always check that
+    // Gemini CLI has it; only a warning is logged
+    tokio::spawn(startup::check_sandbox_support(state.clone()));
+
+    // Warm up in the background; the server is already accepting connections
+    if args.prewarm {
+        tokio::spawn(startup::prewarm(state.clone(), args.prewarm_prompt.clone()));
+    }
+
     // Start the MCP service with GeminiCli tools
-    let gemini_cli_command = args.gemini_cli_command.clone();
-    let workspace = args.workspace.clone();
-    let ct = sse_server.with_service(move || GeminiCli::new(gemini_cli_command.clone(), workspace.clone()));
-
-    tracing::info!("MCP SSE Server running!");
-    tracing::info!("SSE endpoint: http://{}/sse", bind_address);
-    tracing::info!("Message endpoint: http://{}/message", bind_address);
-    tracing::info!("Test with MCP Inspector: https://github.com/modelcontextprotocol/inspector");
-    tracing::info!("Press Ctrl+C to stop");
+    let ct = sse_server.with_service(move || GeminiCli::with_state(state.clone()));
+
+    if !args.quiet {
+        tracing::info!("MCP SSE Server running!");
+        tracing::info!("SSE endpoint: http://{}/sse", bind_address);
+        tracing::info!("Message endpoint: http://{}/message", bind_address);
+        if args.enable_openai_shim {
+            tracing::info!(
+                "OpenAI-compatible endpoint: http://{}{}",
+                bind_address,
+                openai::CHAT_COMPLETIONS_PATH
+            );
+        }
+        tracing::info!(
+            "Test with MCP Inspector: https://github.com/modelcontextprotocol/inspector"
+        );
+        tracing::info!("Press Ctrl+C to stop");
+    }
 
     // Wait for shutdown signal
     tokio::signal::ctrl_c().await?;
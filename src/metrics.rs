@@ -0,0 +1,154 @@
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Number of most recent latencies kept for percentile estimates
+const LATENCY_RESERVOIR_SIZE: usize = 1024;
+
+#[derive(Debug, Default)]
+struct MetricsInner {
+    total_calls: u64,
+    successes: u64,
+    failures: u64,
+    timeouts: u64,
+    total_latency: Duration,
+    recent_latencies_ms: VecDeque<f64>,
+}
+
+/// How a recorded call ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallOutcome {
+    Success,
+    /// Gemini CLI was killed at the timeout; not counted as a failure
+    Timeout,
+    Failure,
+}
+
+/// Execution counters for `prompt_gemini`, shared by all sessions
+#[derive(Debug, Default)]
+pub struct Metrics {
+    inner: Mutex<MetricsInner>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LatencySnapshot {
+    pub average_ms: f64,
+    pub p50_ms: f64,
+    pub p90_ms: f64,
+    pub p99_ms: f64,
+    pub max_ms: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MetricsSnapshot {
+    pub total_calls: u64,
+    pub successes: u64,
+    pub failures: u64,
+    pub timeouts: u64,
+    pub latency: LatencySnapshot,
+}
+
+/// Nearest-rank percentile over an ascending slice
+fn percentile(sorted: &[f64], pct: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let rank = ((pct / 100.0) * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+impl Metrics {
+    pub fn record(&self, latency: Duration, outcome: CallOutcome) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.total_calls += 1;
+        match outcome {
+            CallOutcome::Success => inner.successes += 1,
+            CallOutcome::Timeout => inner.timeouts += 1,
+            CallOutcome::Failure => inner.failures += 1,
+        }
+        inner.total_latency += latency;
+
+        if inner.recent_latencies_ms.len() == LATENCY_RESERVOIR_SIZE {
+            inner.recent_latencies_ms.pop_front();
+        }
+        inner
+            .recent_latencies_ms
+            .push_back(latency.as_secs_f64() * 1000.0);
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let inner = self.inner.lock().unwrap();
+        let mut sorted: Vec<f64> = inner.recent_latencies_ms.iter().copied().collect();
+        sorted.sort_by(f64::total_cmp);
+
+        let average_ms = if inner.total_calls == 0 {
+            0.0
+        } else {
+            inner.total_latency.as_secs_f64() * 1000.0 / inner.total_calls as f64
+        };
+
+        MetricsSnapshot {
+            total_calls: inner.total_calls,
+            successes: inner.successes,
+            failures: inner.failures,
+            timeouts: inner.timeouts,
+            latency: LatencySnapshot {
+                average_ms,
+                p50_ms: percentile(&sorted, 50.0),
+                p90_ms: percentile(&sorted, 90.0),
+                p99_ms: percentile(&sorted, 99.0),
+                max_ms: sorted.last().copied().unwrap_or(0.0),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_empty_snapshot() {
+        let snapshot = Metrics::default().snapshot();
+        assert_eq!(snapshot.total_calls, 0);
+        assert_eq!(snapshot.latency.average_ms, 0.0);
+        assert_eq!(snapshot.latency.p99_ms, 0.0);
+    }
+
+    #[test]
+    fn test_counts_and_percentiles() {
+        let metrics = Metrics::default();
+        for ms in 1..=100 {
+            let outcome = match ms % 10 {
+                0 => CallOutcome::Failure,
+                5 => CallOutcome::Timeout,
+                _ => CallOutcome::Success,
+            };
+            metrics.record(Duration::from_millis(ms), outcome);
+        }
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.total_calls, 100);
+        assert_eq!(snapshot.successes, 80);
+        assert_eq!(snapshot.failures, 10);
+        assert_eq!(snapshot.timeouts, 10);
+        assert_eq!(snapshot.latency.p50_ms, 50.0);
+        assert_eq!(snapshot.latency.p90_ms, 90.0);
+        assert_eq!(snapshot.latency.p99_ms, 99.0);
+        assert_eq!(snapshot.latency.max_ms, 100.0);
+        assert!((snapshot.latency.average_ms - 50.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_reservoir_is_bounded() {
+        let metrics = Metrics::default();
+        for _ in 0..(LATENCY_RESERVOIR_SIZE + 10) {
+            metrics.record(Duration::from_millis(1), CallOutcome::Success);
+        }
+        assert_eq!(
+            metrics.inner.lock().unwrap().recent_latencies_ms.len(),
+            LATENCY_RESERVOIR_SIZE
+        );
+    }
+}
@@ -0,0 +1,445 @@
+use axum::{
+    Json, Router,
+    extract::State,
+    http::StatusCode,
+    response::{
+        IntoResponse, Response,
+        sse::{Event, Sse},
+    },
+    routing::post,
+};
+use futures::{Stream, StreamExt, stream};
+use rmcp::ErrorData as McpError;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::state::ServerState;
+use crate::tools::{GeminiCli, GeminiJsonResponse, PromptGeminiArgs, StreamEvent, is_client_error};
+
+/// Path of the OpenAI-compatible chat completions endpoint
+pub const CHAT_COMPLETIONS_PATH: &str = "/v1/chat/completions";
+
+/// Suffix of the `id` returned with each completion
+static NEXT_COMPLETION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// The subset of an OpenAI chat completion request the shim understands
+#[derive(Debug, Deserialize)]
+pub struct ChatCompletionRequest {
+    /// Passed to gemini-cli as `--model`; empty uses the server's default
+    #[serde(default)]
+    pub model: String,
+    pub messages: Vec<ChatMessage>,
+    #[serde(default)]
+    pub stream: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ChatMessage {
+    pub role: String,
+    pub content: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ChatCompletion {
+    pub id: String,
+    pub object: &'static str,
+    pub created: u64,
+    pub model: String,
+    pub choices: Vec<ChatChoice>,
+    pub usage: Option<Usage>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ChatChoice {
+    pub index: u32,
+    pub message: ChatMessage,
+    pub finish_reason: &'static str,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Usage {
+    pub prompt_tokens: i64,
+    pub completion_tokens: i64,
+    pub total_tokens: i64,
+}
+
+/// An error in the OpenAI error shape: `{"error": {"message", "type", "code"}}`
+#[derive(Debug)]
+pub struct OpenAiError {
+    status: StatusCode,
+    message: String,
+    error_type: &'static str,
+    code: String,
+    details: Option<serde_json::Value>,
+}
+
+impl OpenAiError {
+    fn invalid_request(message: impl Into<String>) -> Self {
+        Self {
+            status: StatusCode::BAD_REQUEST,
+            message: message.into(),
+            error_type: "invalid_request_error",
+            code: "invalid_request".to_string(),
+            details: None,
+        }
+    }
+
+    fn body(&self) -> serde_json::Value {
+        serde_json::json!({
+            "error": {
+                "message": self.message,
+                "type": self.error_type,
+                "param": null,
+                "code": self.code,
+                "details": self.details,
+            }
+        })
+    }
+}
+
+impl From<McpError> for OpenAiError {
+    fn from(error: McpError) -> Self {
+        let code = error.message.to_string();
+        let (status, error_type) = match code.as_str() {
+            _ if is_client_error(&error) => (StatusCode::BAD_REQUEST, "invalid_request_error"),
+            "gemini_queue_full" | "gemini_session_busy" | "gemini_rate_limited" => {
+                (StatusCode::TOO_MANY_REQUESTS, "rate_limit_error")
+            }
+            "gemini_circuit_open" => (StatusCode::SERVICE_UNAVAILABLE, "server_error"),
+            _ => (StatusCode::INTERNAL_SERVER_ERROR, "server_error"),
+        };
+        Self {
+            status,
+            message: error
+                .data
+                .as_ref()
+                .and_then(|data| data.get("error"))
+                .and_then(|message| message.as_str())
+                .unwrap_or(&code)
+                .to_string(),
+            error_type,
+            code,
+            details: error.data,
+        }
+    }
+}
+
+impl IntoResponse for OpenAiError {
+    fn into_response(self) -> Response {
+        let body = self.body();
+        (self.status, Json(body)).into_response()
+    }
+}
+
+/// Routes for the OpenAI-compatible shim, to be merged into the HTTP server
+pub fn router(state: Arc<ServerState>) -> Router {
+    Router::new()
+        .route(CHAT_COMPLETIONS_PATH, post(chat_completions))
+        .with_state(state)
+}
+
+/// Translate a single-message chat completion into a prompt-gemini call, answered as
+/// one JSON body or, with `stream: true`, as `chat.completion.chunk` server-sent events
+pub async fn chat_completions(
+    State(state): State<Arc<ServerState>>,
+    Json(request): Json<ChatCompletionRequest>,
+) -> Result<Response, OpenAiError> {
+    let [message] = request.messages.as_slice() else {
+        return Err(OpenAiError::invalid_request(format!(
+            "Exactly one message is supported, got {}",
+            request.messages.len()
+        )));
+    };
+    if message.role != "user" {
+        return Err(OpenAiError::invalid_request(format!(
+            "The message role must be \"user\", got \"{}\"",
+            message.role
+        )));
+    }
+
+    let model = (!request.model.is_empty()).then(|| request.model.clone());
+    let args = PromptGeminiArgs {
+        prompt: message.content.clone(),
+        model: model.clone(),
+        output_format: Some("json".to_string()),
+        ..Default::default()
+    };
+    let header = CompletionHeader {
+        id: format!(
+            "chatcmpl-{}",
+            NEXT_COMPLETION_ID.fetch_add(1, Ordering::Relaxed)
+        ),
+        created: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+        model: model
+            .or_else(|| state.config().default_model.clone())
+            .unwrap_or_else(|| "gemini-cli".to_string()),
+    };
+    let gemini_cli = GeminiCli::with_state(state);
+
+    if request.stream {
+        let events = gemini_cli.prompt_stream(args).await?;
+        return Ok(Sse::new(completion_chunks(header, events)).into_response());
+    }
+
+    let result = gemini_cli.prompt(args).await?;
+    let content = result
+        .content
+        .first()
+        .and_then(|content| content.raw.as_text())
+        .map(|text| text.text.clone())
+        .unwrap_or_default();
+    let usage = result
+        .structured_content
+        .and_then(|structured| serde_json::from_value::<GeminiJsonResponse>(structured).ok())
+        .and_then(|response| response.stats)
+        .and_then(|stats| stats.models)
+        .map(|models| {
+            let mut usage = Usage::default();
+            for tokens in models.values().filter_map(|model| model.tokens.as_ref()) {
+                usage.prompt_tokens += i64::from(tokens.prompt.unwrap_or(0));
+                usage.completion_tokens += i64::from(tokens.candidates.unwrap_or(0));
+                usage.total_tokens += i64::from(tokens.total.unwrap_or(0));
+            }
+            usage
+        });
+
+    Ok(Json(ChatCompletion {
+        id: header.id,
+        object: "chat.completion",
+        created: header.created,
+        model: header.model,
+        choices: vec![ChatChoice {
+            index: 0,
+            message: ChatMessage {
+                role: "assistant".to_string(),
+                content,
+            },
+            finish_reason: "stop",
+        }],
+        usage,
+    })
+    .into_response())
+}
+
+/// Fields repeated in every chunk of a completion
+#[derive(Debug)]
+struct CompletionHeader {
+    id: String,
+    created: u64,
+    model: String,
+}
+
+impl CompletionHeader {
+    fn chunk(&self, delta: serde_json::Value, finish_reason: Option<&str>) -> Event {
+        Event::default().data(
+            serde_json::json!({
+                "id": self.id,
+                "object": "chat.completion.chunk",
+                "created": self.created,
+                "model": self.model,
+                "choices": [{
+                    "index": 0,
+                    "delta": delta,
+                    "finish_reason": finish_reason,
+                }],
+            })
+            .to_string(),
+        )
+    }
+}
+
+/// Map gemini-cli output to OpenAI stream chunks: the assistant role, one content delta per
+/// output read, a final `stop` chunk (or an error object) and `[DONE]`. When the client
+/// disconnects the stream (and with it the receiver) is dropped, killing gemini-cli.
+fn completion_chunks(
+    header: CompletionHeader,
+    events: tokio::sync::mpsc::Receiver<StreamEvent>,
+) -> impl Stream<Item = Result<Event, std::convert::Infallible>> {
+    let role = header.chunk(serde_json::json!({ "role": "assistant" }), None);
+    let events = stream::unfold(events, |mut events| async move {
+        events.recv().await.map(|event| (event, events))
+    });
+
+    stream::once(async { role })
+        .chain(events.flat_map(move |event| {
+            let chunks = match event {
+                StreamEvent::Output(text) => {
+                    vec![header.chunk(serde_json::json!({ "content": text }), None)]
+                }
+                StreamEvent::Finished(Ok(())) => vec![
+                    header.chunk(serde_json::json!({}), Some("stop")),
+                    Event::default().data("[DONE]"),
+                ],
+                StreamEvent::Finished(Err(error)) => vec![
+                    Event::default().data(OpenAiError::from(error).body().to_string()),
+                    Event::default().data("[DONE]"),
+                ],
+            };
+            stream::iter(chunks)
+        }))
+        .map(Ok)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::GeminiCliConfig;
+
+    fn state(command: &str) -> Arc<ServerState> {
+        Arc::new(ServerState::new(GeminiCliConfig {
+            gemini_cli_command: command.to_string(),
+            ..Default::default()
+        }))
+    }
+
+    fn request(messages: serde_json::Value) -> Json<ChatCompletionRequest> {
+        Json(
+            serde_json::from_value(serde_json::json!({
+                "model": "gemini-2.5-pro",
+                "messages": messages,
+            }))
+            .unwrap(),
+        )
+    }
+
+    async fn body_text(response: Response) -> (StatusCode, String) {
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    async fn error_body(error: OpenAiError) -> (StatusCode, serde_json::Value) {
+        let (status, body) = body_text(error.into_response()).await;
+        (status, serde_json::from_str(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_chat_completion() {
+        let response = r#"{"response": "Hi there", "stats": {"models": {"gemini-2.5-pro": {"tokens": {"prompt": 3, "candidates": 2, "total": 5}}}}}"#;
+        let response = chat_completions(
+            State(state(&format!("echo {}", response))),
+            request(serde_json::json!([{"role": "user", "content": "Hello"}])),
+        )
+        .await
+        .unwrap();
+        let (status, body) = body_text(response).await;
+        let completion: serde_json::Value = serde_json::from_str(&body).unwrap();
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(completion["object"], "chat.completion");
+        assert_eq!(completion["model"], "gemini-2.5-pro");
+        assert_eq!(completion["choices"][0]["message"]["role"], "assistant");
+        assert_eq!(completion["choices"][0]["message"]["content"], "Hi there");
+        assert_eq!(completion["usage"]["prompt_tokens"], 3);
+        assert_eq!(completion["usage"]["completion_tokens"], 2);
+        assert_eq!(completion["usage"]["total_tokens"], 5);
+    }
+
+    #[tokio::test]
+    async fn test_rejects_unsupported_requests() {
+        for messages in [
+            serde_json::json!([]),
+            serde_json::json!([{"role": "system", "content": "Be brief"}]),
+            serde_json::json!([
+                {"role": "user", "content": "a"},
+                {"role": "user", "content": "b"}
+            ]),
+        ] {
+            let error = chat_completions(State(state("echo")), request(messages))
+                .await
+                .unwrap_err();
+            let (status, body) = error_body(error).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+            assert_eq!(body["error"]["type"], "invalid_request_error");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_maps_gemini_errors() {
+        let error = chat_completions(
+            State(state("nonexistent_command_12345")),
+            request(serde_json::json!([{"role": "user", "content": "Hello"}])),
+        )
+        .await
+        .unwrap_err();
+        let (status, body) = error_body(error).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(body["error"]["code"], "gemini_cli_command_failed");
+    }
+
+    fn stream_data(body: &str) -> Vec<&str> {
+        body.lines()
+            .filter_map(|line| line.strip_prefix("data: "))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_streaming_chat_completion() {
+        let command = std::env::temp_dir().join(format!(
+            "mcp-gemini-cli-test-{}-openai-stream.sh",
+            std::process::id()
+        ));
+        std::fs::write(
+            &command,
+            "#!/bin/sh\nprintf 'Hel'\nsleep 0.2\nprintf 'lo'\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(
+            &command,
+            std::os::unix::fs::PermissionsExt::from_mode(0o755),
+        )
+        .unwrap();
+
+        let mut request = request(serde_json::json!([{"role": "user", "content": "Hi"}]));
+        request.stream = true;
+        let response = chat_completions(State(state(&command.to_string_lossy())), request)
+            .await
+            .unwrap();
+        let (status, body) = body_text(response).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let data = stream_data(&body);
+        assert_eq!(data.last(), Some(&"[DONE]"));
+        let chunks: Vec<serde_json::Value> = data[..data.len() - 1]
+            .iter()
+            .map(|chunk| serde_json::from_str(chunk).unwrap())
+            .collect();
+        assert!(
+            chunks
+                .iter()
+                .all(|chunk| chunk["object"] == "chat.completion.chunk")
+        );
+        assert_eq!(chunks[0]["choices"][0]["delta"]["role"], "assistant");
+        let content: String = chunks
+            .iter()
+            .filter_map(|chunk| chunk["choices"][0]["delta"]["content"].as_str())
+            .collect();
+        assert_eq!(content, "Hello");
+        assert_eq!(
+            chunks.last().unwrap()["choices"][0]["finish_reason"],
+            "stop"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_streaming_reports_failures() {
+        let mut request = request(serde_json::json!([{"role": "user", "content": "Hi"}]));
+        request.stream = true;
+        let response = chat_completions(State(state("false")), request)
+            .await
+            .unwrap();
+        let (_, body) = body_text(response).await;
+
+        let data = stream_data(&body);
+        assert_eq!(data, [data[0], data[1], "[DONE]"]);
+        let error: serde_json::Value = serde_json::from_str(data[1]).unwrap();
+        assert_eq!(error["error"]["code"], "gemini_cli_execution_failed");
+    }
+}
@@ -0,0 +1,113 @@
+use tokio::process::Child;
+
+/// Kills the process group of a child spawned as its own group leader (`process_group(0)`)
+/// when dropped, so processes the child started, such as gemini-cli's tools, don't outlive
+/// a timeout or cancellation. Disarm it once the child has exited on its own. A no-op
+/// outside Unix, where children aren't spawned in their own group.
+#[derive(Debug)]
+pub struct ProcessGroupGuard {
+    pgid: Option<u32>,
+}
+
+impl ProcessGroupGuard {
+    /// Guard `child`'s group; does nothing unless `enabled`
+    pub fn new(child: &Child, enabled: bool) -> Self {
+        Self {
+            pgid: if enabled && cfg!(unix) {
+                child.id()
+            } else {
+                None
+            },
+        }
+    }
+
+    /// Send SIGKILL to every process in the group. Call it before the child is reaped, while
+    /// its pid still names the group.
+    pub fn kill(&mut self) {
+        let Some(pgid) = self.pgid.take() else {
+            return;
+        };
+        #[cfg(unix)]
+        {
+            // SAFETY: kill has no memory safety requirements; a negative pid names the group
+            if unsafe { libc::kill(-(pgid as libc::pid_t), libc::SIGKILL) } == 0 {
+                tracing::debug!(pgid, "Killed Gemini CLI process group");
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = pgid;
+    }
+
+    /// The child exited on its own; leave anything it left running alone
+    pub fn disarm(&mut self) {
+        self.pgid = None;
+    }
+}
+
+impl Drop for ProcessGroupGuard {
+    fn drop(&mut self) {
+        self.kill();
+    }
+}
+
+/// Whether `pid` has exited; killed children are reaped in the background and orphans
+/// may linger as zombies, so a zombie counts
+#[cfg(all(test, target_os = "linux"))]
+pub(crate) fn exited(pid: &str) -> bool {
+    match std::fs::read_to_string(format!("/proc/{}/stat", pid.trim())) {
+        Err(_) => true,
+        Ok(stat) => stat.split_whitespace().nth(2) == Some("Z"),
+    }
+}
+
+/// Poll for up to a second until `pid` has [`exited`], returning whether it did
+#[cfg(all(test, target_os = "linux"))]
+pub(crate) async fn wait_for_exit(pid: &str) -> bool {
+    for _ in 0..50 {
+        if exited(pid) {
+            return true;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    exited(pid)
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, BufReader};
+    use tokio::process::Command;
+
+    #[tokio::test]
+    async fn test_kills_grandchildren() {
+        let mut child = Command::new("sh")
+            .args(["-c", "sleep 30 & echo $!; exec sleep 30"])
+            .process_group(0)
+            .kill_on_drop(true)
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        let stdout = BufReader::new(child.stdout.take().unwrap());
+        let grandchild = stdout.lines().next_line().await.unwrap().unwrap();
+        assert!(!exited(&grandchild));
+
+        ProcessGroupGuard::new(&child, true).kill();
+        child.wait().await.unwrap();
+        assert!(wait_for_exit(&grandchild).await, "grandchild survived");
+    }
+
+    #[tokio::test]
+    async fn test_disabled_and_disarmed_guards_do_nothing() {
+        let mut child = Command::new("sleep")
+            .arg("30")
+            .process_group(0)
+            .kill_on_drop(true)
+            .spawn()
+            .unwrap();
+        drop(ProcessGroupGuard::new(&child, false));
+        let mut guard = ProcessGroupGuard::new(&child, true);
+        guard.disarm();
+        drop(guard);
+        assert!(child.try_wait().unwrap().is_none());
+    }
+}
@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::template::{self, MissingVariables};
+
+/// File extensions read from the prompts directory
+const PROMPT_EXTENSIONS: &[&str] = &["txt", "md"];
+
+/// A reusable prompt loaded from the prompts directory
+#[derive(Debug, Clone, PartialEq)]
+pub struct PromptTemplate {
+    /// File name without its extension
+    pub name: String,
+    pub template: String,
+    /// `{{variable}}` placeholders in order of first use; all are required
+    pub variables: Vec<String>,
+}
+
+impl PromptTemplate {
+    pub fn new(name: String, template: String) -> Self {
+        // Strict rendering without variables reports exactly the placeholders, honouring
+        // the same escapes as substitution
+        let variables = template::render(&template, &HashMap::new(), true)
+            .err()
+            .map(|MissingVariables(names)| names)
+            .unwrap_or_default();
+        Self {
+            name,
+            template,
+            variables,
+        }
+    }
+
+    /// Fill every placeholder; any variable left without a value is an error
+    pub fn render(&self, variables: &HashMap<String, String>) -> Result<String, MissingVariables> {
+        template::render(&self.template, variables, true)
+    }
+}
+
+/// Load the `.txt` and `.md` files directly under `dir`, sorted by name. Read on every
+/// request, so templates can be edited without restarting the server.
+pub fn load_prompts(dir: &Path) -> Result<Vec<PromptTemplate>, String> {
+    let entries = std::fs::read_dir(dir)
+        .map_err(|e| format!("Prompts directory {} cannot be read: {}", dir.display(), e))?;
+    let mut prompts = Vec::new();
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let is_prompt = path.extension().is_some_and(|extension| {
+            PROMPT_EXTENSIONS
+                .iter()
+                .any(|prompt_extension| extension == *prompt_extension)
+        });
+        if !is_prompt || !path.is_file() {
+            continue;
+        }
+        let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) else {
+            continue;
+        };
+        let template = std::fs::read_to_string(&path)
+            .map_err(|e| format!("Prompt {} cannot be read: {}", path.display(), e))?;
+        prompts.push(PromptTemplate::new(name.to_string(), template));
+    }
+    prompts.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(prompts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_and_render_prompts() {
+        let dir = std::env::temp_dir().join(format!(
+            "mcp-gemini-cli-test-{}-prompts",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("nested.md")).unwrap();
+        std::fs::write(
+            dir.join("review.md"),
+            "Review {{file}} for {{ focus }}; keep \\{{braces}} and {{file}}",
+        )
+        .unwrap();
+        std::fs::write(dir.join("explain.txt"), "Explain this").unwrap();
+        std::fs::write(dir.join("notes.json"), "{}").unwrap();
+
+        let prompts = load_prompts(&dir).unwrap();
+        let names: Vec<_> = prompts.iter().map(|prompt| prompt.name.as_str()).collect();
+        assert_eq!(names, ["explain", "review"]);
+        assert!(prompts[0].variables.is_empty());
+        assert_eq!(prompts[1].variables, ["file", "focus"]);
+
+        let mut variables = HashMap::from([("file".to_string(), "main.rs".to_string())]);
+        assert_eq!(
+            prompts[1].render(&variables),
+            Err(MissingVariables(vec!["focus".to_string()]))
+        );
+        variables.insert("focus".to_string(), "errors".to_string());
+        assert_eq!(
+            prompts[1].render(&variables).unwrap(),
+            "Review main.rs for errors; keep {{braces}} and main.rs"
+        );
+
+        assert!(load_prompts(&dir.join("missing")).is_err());
+    }
+}
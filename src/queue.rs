@@ -0,0 +1,186 @@
+use serde::Serialize;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+/// Returned when every permit is taken and the wait queue is at capacity
+#[derive(Debug)]
+pub struct QueueFull {
+    pub max_queue_depth: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct QueueSnapshot {
+    pub max_concurrency: Option<usize>,
+    pub max_queue_depth: usize,
+    pub in_flight: usize,
+    pub queued: usize,
+}
+
+/// Limits concurrent gemini-cli executions; excess requests wait in FIFO order
+/// (tokio's semaphore is fair) up to `max_queue_depth`
+#[derive(Debug)]
+pub struct RequestQueue {
+    semaphore: Option<Semaphore>,
+    max_concurrency: Option<usize>,
+    max_queue_depth: usize,
+    queued: AtomicUsize,
+}
+
+/// Decrements the queued count even if the waiting request is cancelled
+struct QueuedGuard<'a>(&'a AtomicUsize);
+
+impl Drop for QueuedGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl RequestQueue {
+    /// `max_concurrency` of `None` disables the limit entirely
+    pub fn new(max_concurrency: Option<usize>, max_queue_depth: usize) -> Self {
+        Self {
+            semaphore: max_concurrency.map(Semaphore::new),
+            max_concurrency,
+            max_queue_depth,
+            queued: AtomicUsize::new(0),
+        }
+    }
+
+    /// Wait for an execution slot. The returned permit (if any) must be held for
+    /// the duration of the execution.
+    pub async fn acquire(&self) -> Result<Option<SemaphorePermit<'_>>, QueueFull> {
+        self.acquire_notifying(|_| std::future::ready(())).await
+    }
+
+    /// [`RequestQueue::acquire`], calling `on_queued` with the request's 1-based position in
+    /// the queue when it has to wait. Its future runs while the request waits.
+    pub async fn acquire_notifying<F: Future<Output = ()>>(
+        &self,
+        on_queued: impl FnOnce(usize) -> F,
+    ) -> Result<Option<SemaphorePermit<'_>>, QueueFull> {
+        let Some(semaphore) = &self.semaphore else {
+            return Ok(None);
+        };
+
+        if let Ok(permit) = semaphore.try_acquire() {
+            return Ok(Some(permit));
+        }
+
+        // Reserve a queue slot atomically so concurrent callers can't overshoot the depth
+        let ahead = self
+            .queued
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |queued| {
+                (queued < self.max_queue_depth).then_some(queued + 1)
+            })
+            .map_err(|_| QueueFull {
+                max_queue_depth: self.max_queue_depth,
+            })?;
+        let _guard = QueuedGuard(&self.queued);
+
+        // The queue is FIFO, so everyone already waiting is ahead of this request
+        let (permit, ()) = tokio::join!(semaphore.acquire(), on_queued(ahead + 1));
+        Ok(Some(
+            permit.expect("request queue semaphore is never closed"),
+        ))
+    }
+
+    pub fn snapshot(&self) -> QueueSnapshot {
+        let in_flight = match (&self.semaphore, self.max_concurrency) {
+            (Some(semaphore), Some(max)) => max - semaphore.available_permits(),
+            _ => 0,
+        };
+        QueueSnapshot {
+            max_concurrency: self.max_concurrency,
+            max_queue_depth: self.max_queue_depth,
+            in_flight,
+            queued: self.queued.load(Ordering::SeqCst),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_unlimited_queue() {
+        let queue = RequestQueue::new(None, 0);
+        assert!(queue.acquire().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_rejects_when_queue_full() {
+        let queue = RequestQueue::new(Some(1), 0);
+        let _permit = queue.acquire().await.unwrap();
+        assert!(queue.acquire().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_fifo_ordering() {
+        let queue = Arc::new(RequestQueue::new(Some(1), 10));
+        let permit = queue.acquire().await.unwrap();
+        let order = Arc::new(std::sync::Mutex::new(Vec::new()));
+
+        let mut handles = Vec::new();
+        for i in 0..3 {
+            let waiter_queue = queue.clone();
+            let order = order.clone();
+            handles.push(tokio::spawn(async move {
+                let _permit = waiter_queue.acquire().await.unwrap();
+                order.lock().unwrap().push(i);
+            }));
+            // Make sure each waiter is enqueued before the next one
+            while queue.snapshot().queued != i + 1 {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        }
+
+        assert_eq!(queue.snapshot().in_flight, 1);
+        drop(permit);
+        for handle in handles {
+            handle.await.unwrap();
+        }
+        assert_eq!(*order.lock().unwrap(), vec![0, 1, 2]);
+        assert_eq!(queue.snapshot().queued, 0);
+    }
+
+    #[tokio::test]
+    async fn test_reports_queue_position() {
+        let queue = Arc::new(RequestQueue::new(Some(1), 10));
+        let permit = queue.acquire().await.unwrap();
+        let positions = Arc::new(std::sync::Mutex::new(Vec::new()));
+
+        let mut handles = Vec::new();
+        for i in 0..2 {
+            let waiter_queue = queue.clone();
+            let positions = positions.clone();
+            handles.push(tokio::spawn(async move {
+                let _permit = waiter_queue
+                    .acquire_notifying(|position| async move {
+                        positions.lock().unwrap().push(position);
+                    })
+                    .await
+                    .unwrap();
+            }));
+            while queue.snapshot().queued != i + 1 {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        }
+
+        drop(permit);
+        for handle in handles {
+            handle.await.unwrap();
+        }
+        assert_eq!(*positions.lock().unwrap(), vec![1, 2]);
+
+        // Requests that get a slot straight away are never reported
+        let reported = std::sync::atomic::AtomicBool::new(false);
+        let _permit = queue
+            .acquire_notifying(|_| async { reported.store(true, Ordering::SeqCst) })
+            .await
+            .unwrap();
+        assert!(!reported.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,214 @@
+use regex::Regex;
+use std::borrow::Cow;
+
+use crate::config::redact_command;
+
+/// Environment variables whose values are always scrubbed from error payloads
+pub const DEFAULT_SECRET_ENV_VARS: &[&str] = &[
+    "GEMINI_API_KEY",
+    "GOOGLE_API_KEY",
+    "GOOGLE_GENAI_API_KEY",
+    "GOOGLE_CLOUD_ACCESS_TOKEN",
+];
+
+/// Replacement for every redacted value
+const REDACTED: &str = "[REDACTED]";
+
+/// Values shorter than this are not treated as secrets; scrubbing them would mangle
+/// unrelated text
+const MIN_SECRET_LEN: usize = 4;
+
+/// Longest text [`StreamRedactor`] holds back waiting for a line break
+const MAX_STREAM_TAIL_BYTES: usize = 64 * 1024;
+
+/// Keys of error data holding command lines, which also get [`redact_command`]
+const COMMAND_KEYS: &[&str] = &["command", "primary_command", "fallback_command"];
+
+/// Scrubs secrets from text returned to clients or logged: the values of secret
+/// environment variables and anything matching the configured patterns
+#[derive(Debug, Clone, Default)]
+pub struct Redactor {
+    secrets: Vec<String>,
+    patterns: Vec<Regex>,
+}
+
+impl Redactor {
+    /// Read the values of `env_vars` (unset or very short ones are skipped)
+    pub fn from_env<S: AsRef<str>>(env_vars: &[S], patterns: Vec<Regex>) -> Self {
+        let mut secrets: Vec<String> = env_vars
+            .iter()
+            .filter_map(|name| std::env::var(name.as_ref()).ok())
+            .filter(|value| value.len() >= MIN_SECRET_LEN)
+            .collect();
+        // Longer secrets first, so one containing another is scrubbed whole
+        secrets.sort_by_key(|secret| std::cmp::Reverse(secret.len()));
+        secrets.dedup();
+        Self { secrets, patterns }
+    }
+
+    /// Scrub only matches of `patterns`
+    pub fn from_patterns(patterns: Vec<Regex>) -> Self {
+        Self {
+            secrets: Vec::new(),
+            patterns,
+        }
+    }
+
+    pub fn pattern_count(&self) -> usize {
+        self.patterns.len()
+    }
+
+    /// Whether there is nothing to scrub
+    pub fn is_empty(&self) -> bool {
+        self.secrets.is_empty() && self.patterns.is_empty()
+    }
+
+    pub fn redact<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        let mut text = Cow::Borrowed(text);
+        for secret in &self.secrets {
+            if text.contains(secret.as_str()) {
+                text = Cow::Owned(text.replace(secret.as_str(), REDACTED));
+            }
+        }
+        for pattern in &self.patterns {
+            if let Cow::Owned(replaced) = pattern.replace_all(&text, REDACTED) {
+                text = Cow::Owned(replaced);
+            }
+        }
+        text
+    }
+
+    /// Redact every string in `value`, additionally applying [`redact_command`] to
+    /// command lines
+    pub fn redact_json(&self, value: &mut serde_json::Value) {
+        self.redact_json_inner(value, false);
+    }
+
+    fn redact_json_inner(&self, value: &mut serde_json::Value, is_command: bool) {
+        match value {
+            serde_json::Value::String(text) => {
+                let redacted = if is_command {
+                    self.redact(&redact_command(text)).into_owned()
+                } else {
+                    match self.redact(text) {
+                        Cow::Borrowed(_) => return,
+                        Cow::Owned(redacted) => redacted,
+                    }
+                };
+                *text = redacted;
+            }
+            serde_json::Value::Array(items) => {
+                for item in items {
+                    self.redact_json_inner(item, false);
+                }
+            }
+            serde_json::Value::Object(fields) => {
+                for (key, field) in fields {
+                    self.redact_json_inner(field, COMMAND_KEYS.contains(&key.as_str()));
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Applies a [`Redactor`] to text arriving in pieces. Text is held back until a line
+/// break, so a match split between pieces of one line is still caught; matches
+/// spanning lines are not.
+pub struct StreamRedactor<'a> {
+    redactor: &'a Redactor,
+    tail: String,
+}
+
+impl<'a> StreamRedactor<'a> {
+    pub fn new(redactor: &'a Redactor) -> Self {
+        Self {
+            redactor,
+            tail: String::new(),
+        }
+    }
+
+    /// Add `text`, returning the redacted text that can be released now
+    pub fn push(&mut self, text: &str) -> String {
+        if self.redactor.is_empty() {
+            return text.to_string();
+        }
+        self.tail.push_str(text);
+        let release = match self.tail.rfind('\n') {
+            Some(newline) => newline + 1,
+            None if self.tail.len() >= MAX_STREAM_TAIL_BYTES => self.tail.len(),
+            None => return String::new(),
+        };
+        let released: String = self.tail.drain(..release).collect();
+        self.redactor.redact(&released).into_owned()
+    }
+
+    /// Redact and release whatever is still held back
+    pub fn finish(&mut self) -> String {
+        let tail = std::mem::take(&mut self.tail);
+        self.redactor.redact(&tail).into_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn redactor(secrets: &[&str], patterns: &[&str]) -> Redactor {
+        Redactor {
+            secrets: secrets.iter().map(|secret| secret.to_string()).collect(),
+            patterns: patterns
+                .iter()
+                .map(|pattern| Regex::new(pattern).unwrap())
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_redacts_secrets_and_patterns() {
+        let redactor = redactor(&["s3cr3t-key"], &[r"sk-[A-Za-z0-9]+"]);
+        assert_eq!(
+            redactor.redact("auth failed for s3cr3t-key (sk-abc123)"),
+            "auth failed for [REDACTED] ([REDACTED])"
+        );
+        assert!(matches!(redactor.redact("nothing here"), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn test_redacts_json_and_commands() {
+        let redactor = redactor(&["s3cr3t-key"], &[]);
+        let mut data = serde_json::json!({
+            "command": "gemini --api-key abc",
+            "stderr": "invalid key s3cr3t-key",
+            "nested": [{"raw_output": "s3cr3t-key"}],
+            "code": 401,
+        });
+        redactor.redact_json(&mut data);
+        assert_eq!(
+            data,
+            serde_json::json!({
+                "command": "gemini --api-key [REDACTED]",
+                "stderr": "invalid key [REDACTED]",
+                "nested": [{"raw_output": "[REDACTED]"}],
+                "code": 401,
+            })
+        );
+    }
+
+    #[test]
+    fn test_stream_redactor_catches_split_matches() {
+        let redactor = redactor(&[], &[r"token-[0-9]+"]);
+        let mut stream = StreamRedactor::new(&redactor);
+        assert_eq!(stream.push("a tok"), "");
+        assert_eq!(stream.push("en-12"), "");
+        assert_eq!(stream.push("3\nnext tok"), "a [REDACTED]\n");
+        assert_eq!(stream.push("en-4"), "");
+        assert_eq!(stream.finish(), "next [REDACTED]");
+    }
+
+    #[test]
+    fn test_from_env_skips_short_and_unset_values() {
+        let redactor = Redactor::from_env(&["PATH", "MCP_GEMINI_CLI_TEST_UNSET_VAR"], vec![]);
+        assert_eq!(redactor.secrets.len(), 1);
+    }
+}
@@ -0,0 +1,110 @@
+use serde::Serialize;
+use std::time::{Duration, Instant};
+
+/// Resources measured around one gemini-cli execution. Only the wall time is specific to
+/// it: `getrusage(RUSAGE_CHILDREN)` covers every child of the server, so the CPU and
+/// memory figures are aggregates.
+#[derive(Debug, Clone, Serialize)]
+pub struct ResourceUsage {
+    pub wall_time_ms: u64,
+    /// User and system CPU time of all subprocesses reaped during the execution,
+    /// including concurrent ones (Unix only)
+    pub cpu_user_ms: Option<u64>,
+    pub cpu_system_ms: Option<u64>,
+    /// Largest resident set size of any subprocess the server has waited for so far,
+    /// in KiB. The kernel only tracks this high-water mark for children as a whole.
+    pub max_child_rss_kb: Option<u64>,
+}
+
+/// Measures a subprocess from just before it is spawned until it has been waited for
+#[derive(Debug)]
+pub struct UsageMeter {
+    started: Instant,
+    before: Option<ChildrenUsage>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ChildrenUsage {
+    user: Duration,
+    system: Duration,
+    max_rss_kb: u64,
+}
+
+impl UsageMeter {
+    pub fn start() -> Self {
+        Self {
+            started: Instant::now(),
+            before: children_usage(),
+        }
+    }
+
+    /// Usage since [`UsageMeter::start`]. CPU time is the difference in the usage of all
+    /// waited-for children, so it includes other executions that finished meanwhile.
+    pub fn finish(self) -> ResourceUsage {
+        let after = children_usage();
+        let cpu = self.before.zip(after);
+        ResourceUsage {
+            wall_time_ms: self.started.elapsed().as_millis() as u64,
+            cpu_user_ms: cpu
+                .map(|(before, after)| after.user.saturating_sub(before.user).as_millis() as u64),
+            cpu_system_ms: cpu.map(|(before, after)| {
+                after.system.saturating_sub(before.system).as_millis() as u64
+            }),
+            max_child_rss_kb: after.map(|after| after.max_rss_kb),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn children_usage() -> Option<ChildrenUsage> {
+    let mut usage = std::mem::MaybeUninit::<libc::rusage>::uninit();
+    // SAFETY: getrusage fills the struct when it returns 0
+    let usage = unsafe {
+        if libc::getrusage(libc::RUSAGE_CHILDREN, usage.as_mut_ptr()) != 0 {
+            return None;
+        }
+        usage.assume_init()
+    };
+    let duration = |time: libc::timeval| {
+        Duration::from_secs(time.tv_sec as u64) + Duration::from_micros(time.tv_usec as u64)
+    };
+    // macOS reports bytes, other Unixes KiB
+    let max_rss_kb = if cfg!(target_os = "macos") {
+        usage.ru_maxrss as u64 / 1024
+    } else {
+        usage.ru_maxrss as u64
+    };
+    Some(ChildrenUsage {
+        user: duration(usage.ru_utime),
+        system: duration(usage.ru_stime),
+        max_rss_kb,
+    })
+}
+
+#[cfg(not(unix))]
+fn children_usage() -> Option<ChildrenUsage> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_measures_child_cpu_time() {
+        let meter = UsageMeter::start();
+        let status = std::process::Command::new("sh")
+            .args(["-c", "i=0; while [ $i -lt 20000 ]; do i=$((i+1)); done"])
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let usage = meter.finish();
+
+        assert!(usage.wall_time_ms > 0);
+        if cfg!(unix) {
+            let cpu = usage.cpu_user_ms.unwrap() + usage.cpu_system_ms.unwrap();
+            assert!(cpu > 0);
+            assert!(usage.max_child_rss_kb.unwrap() > 0);
+        }
+    }
+}
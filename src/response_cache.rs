@@ -0,0 +1,194 @@
+use lru::LruCache;
+use rmcp::model::CallToolResult;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Successful prompt-gemini results kept for identical later requests, bounded by both
+/// entry count and total size; the least recently used entries are evicted first
+#[derive(Debug)]
+pub struct ResponseCache {
+    inner: Mutex<Inner>,
+    max_bytes: usize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Debug)]
+struct Inner {
+    /// Results with their serialized size
+    entries: LruCache<String, (CallToolResult, usize)>,
+    bytes: usize,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct CacheSnapshot {
+    pub entries: usize,
+    pub bytes: usize,
+    pub max_entries: usize,
+    pub max_bytes: usize,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// sha256 (hex) of everything that determines a response, e.g. the command and the
+/// request's effective arguments
+pub fn cache_key(parts: &serde_json::Value) -> String {
+    content_digest(parts.to_string().as_bytes())
+}
+
+/// sha256 (hex) of `bytes`, e.g. a file a request refers to by path
+pub fn content_digest(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+impl ResponseCache {
+    pub fn new(max_entries: NonZeroUsize, max_bytes: usize) -> Self {
+        Self {
+            inner: Mutex::new(Inner {
+                entries: LruCache::new(max_entries),
+                bytes: 0,
+            }),
+            max_bytes,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Look up `key`, marking it as most recently used and counting the hit or miss
+    pub fn get(&self, key: &str) -> Option<CallToolResult> {
+        let cached = self
+            .inner
+            .lock()
+            .unwrap()
+            .entries
+            .get(key)
+            .map(|(result, _)| result.clone());
+        let counter = if cached.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        cached
+    }
+
+    /// Store `result`, evicting the least recently used entries until it fits. Results
+    /// larger than the whole cache are not stored.
+    pub fn insert(&self, key: String, result: CallToolResult) {
+        let size = serde_json::to_vec(&result).map_or(usize::MAX, |json| json.len());
+        if size > self.max_bytes {
+            tracing::debug!(size, "Response too large to cache");
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        if let Some((_, replaced)) = inner.entries.pop(&key) {
+            inner.bytes -= replaced;
+        }
+        while inner.bytes + size > self.max_bytes {
+            let Some((_, (_, evicted))) = inner.entries.pop_lru() else {
+                break;
+            };
+            inner.bytes -= evicted;
+        }
+        if let Some((_, (_, evicted))) = inner.entries.push(key, (result, size)) {
+            inner.bytes -= evicted;
+        }
+        inner.bytes += size;
+    }
+
+    /// Remove every entry, returning how many there were. Hit and miss counts are kept.
+    pub fn clear(&self) -> usize {
+        let mut inner = self.inner.lock().unwrap();
+        let removed = inner.entries.len();
+        inner.entries.clear();
+        inner.bytes = 0;
+        removed
+    }
+
+    pub fn snapshot(&self) -> CacheSnapshot {
+        let inner = self.inner.lock().unwrap();
+        CacheSnapshot {
+            entries: inner.entries.len(),
+            bytes: inner.bytes,
+            max_entries: inner.entries.cap().get(),
+            max_bytes: self.max_bytes,
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rmcp::model::Content;
+
+    fn result(text: &str) -> CallToolResult {
+        CallToolResult::success(vec![Content::text(text)])
+    }
+
+    fn size(text: &str) -> usize {
+        serde_json::to_vec(&result(text)).unwrap().len()
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used_entries() {
+        let cache = ResponseCache::new(NonZeroUsize::new(2).unwrap(), usize::MAX);
+        cache.insert("a".to_string(), result("first"));
+        cache.insert("b".to_string(), result("second"));
+        assert!(cache.get("a").is_some());
+        cache.insert("c".to_string(), result("third"));
+
+        assert!(cache.get("b").is_none());
+        assert_eq!(cache.get("a"), Some(result("first")));
+        assert_eq!(cache.get("c"), Some(result("third")));
+        let snapshot = cache.snapshot();
+        assert_eq!(snapshot.entries, 2);
+        assert_eq!(snapshot.bytes, size("first") + size("third"));
+        assert_eq!((snapshot.hits, snapshot.misses), (3, 1));
+
+        assert_eq!(cache.clear(), 2);
+        assert!(cache.get("a").is_none());
+        assert_eq!(cache.snapshot().bytes, 0);
+    }
+
+    #[test]
+    fn test_bounded_by_total_bytes() {
+        let max_bytes = size("aaaa") * 2;
+        let cache = ResponseCache::new(NonZeroUsize::new(10).unwrap(), max_bytes);
+        cache.insert("a".to_string(), result("aaaa"));
+        cache.insert("b".to_string(), result("bbbb"));
+        cache.insert("c".to_string(), result("cccc"));
+        assert!(cache.get("a").is_none());
+        assert_eq!(cache.snapshot().entries, 2);
+        assert!(cache.snapshot().bytes <= max_bytes);
+
+        // Replacing an entry doesn't count its old size twice
+        cache.insert("c".to_string(), result("dddd"));
+        assert_eq!(cache.snapshot().bytes, max_bytes);
+
+        cache.insert("big".to_string(), result(&"x".repeat(max_bytes)));
+        assert!(cache.get("big").is_none());
+        assert_eq!(cache.snapshot().entries, 2);
+    }
+
+    #[test]
+    fn test_cache_key() {
+        let key = cache_key(&serde_json::json!({"command": "gemini", "prompt": "hi"}));
+        assert_eq!(key.len(), 64);
+        assert_eq!(
+            key,
+            cache_key(&serde_json::json!({"prompt": "hi", "command": "gemini"}))
+        );
+        assert_ne!(
+            key,
+            cache_key(&serde_json::json!({"command": "gemini", "prompt": "hello"}))
+        );
+    }
+}
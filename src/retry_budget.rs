@@ -0,0 +1,163 @@
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How many retries the server may make relative to the calls it receives
+#[derive(Debug, Clone, Copy)]
+pub struct RetryBudgetConfig {
+    /// Retries allowed as a percentage of calls within `window` (0 disables the budget)
+    pub percent: u32,
+    /// Calls and retries older than this no longer count
+    pub window: Duration,
+    /// Retries always allowed per window, so a quiet server can still fail over
+    pub min_retries: u32,
+}
+
+impl Default for RetryBudgetConfig {
+    fn default() -> Self {
+        Self {
+            percent: 20,
+            window: Duration::from_secs(60),
+            min_retries: 3,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Window {
+    calls: VecDeque<Instant>,
+    retries: VecDeque<Instant>,
+}
+
+impl Window {
+    fn prune(&mut self, now: Instant, window: Duration) {
+        for times in [&mut self.calls, &mut self.retries] {
+            while times.front().is_some_and(|time| now - *time > window) {
+                times.pop_front();
+            }
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct RetryBudgetSnapshot {
+    pub enabled: bool,
+    pub percent: u32,
+    pub window_secs: u64,
+    pub calls_in_window: usize,
+    pub retries_in_window: usize,
+    pub retries_allowed: usize,
+}
+
+/// Caps retries across all sessions at a share of recent calls, so a degraded backend
+/// isn't hit with a retry storm on top of the original traffic
+#[derive(Debug)]
+pub struct RetryBudget {
+    config: RetryBudgetConfig,
+    window: Mutex<Window>,
+}
+
+impl RetryBudget {
+    pub fn new(config: RetryBudgetConfig) -> Self {
+        Self {
+            config,
+            window: Mutex::new(Window::default()),
+        }
+    }
+
+    /// Count a call (not a retry) towards the budget
+    pub fn record_call(&self) {
+        if self.config.percent == 0 {
+            return;
+        }
+        let mut window = self.window.lock().unwrap();
+        let now = Instant::now();
+        window.prune(now, self.config.window);
+        window.calls.push_back(now);
+    }
+
+    /// Take a retry from the budget. Returns `false` when it is exhausted and the caller
+    /// should fail instead of retrying.
+    pub fn try_acquire_retry(&self) -> bool {
+        if self.config.percent == 0 {
+            return true;
+        }
+        let mut window = self.window.lock().unwrap();
+        let now = Instant::now();
+        window.prune(now, self.config.window);
+        if window.retries.len() >= self.retries_allowed(window.calls.len()) {
+            return false;
+        }
+        window.retries.push_back(now);
+        true
+    }
+
+    fn retries_allowed(&self, calls: usize) -> usize {
+        (calls * self.config.percent as usize / 100).max(self.config.min_retries as usize)
+    }
+
+    pub fn snapshot(&self) -> RetryBudgetSnapshot {
+        let mut window = self.window.lock().unwrap();
+        window.prune(Instant::now(), self.config.window);
+        RetryBudgetSnapshot {
+            enabled: self.config.percent > 0,
+            percent: self.config.percent,
+            window_secs: self.config.window.as_secs(),
+            calls_in_window: window.calls.len(),
+            retries_in_window: window.retries.len(),
+            retries_allowed: self.retries_allowed(window.calls.len()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn budget(percent: u32, min_retries: u32, window: Duration) -> RetryBudget {
+        RetryBudget::new(RetryBudgetConfig {
+            percent,
+            window,
+            min_retries,
+        })
+    }
+
+    #[test]
+    fn test_retries_limited_to_share_of_calls() {
+        let budget = budget(20, 1, Duration::from_secs(60));
+        for _ in 0..10 {
+            budget.record_call();
+        }
+        assert!(budget.try_acquire_retry());
+        assert!(budget.try_acquire_retry());
+        assert!(!budget.try_acquire_retry());
+
+        let snapshot = budget.snapshot();
+        assert_eq!(snapshot.calls_in_window, 10);
+        assert_eq!(snapshot.retries_in_window, 2);
+        assert_eq!(snapshot.retries_allowed, 2);
+    }
+
+    #[test]
+    fn test_minimum_retries_and_window() {
+        let budget = budget(10, 2, Duration::from_millis(10));
+        budget.record_call();
+        assert!(budget.try_acquire_retry());
+        assert!(budget.try_acquire_retry());
+        assert!(!budget.try_acquire_retry());
+
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(budget.try_acquire_retry());
+        assert_eq!(budget.snapshot().calls_in_window, 0);
+    }
+
+    #[test]
+    fn test_zero_percent_disables() {
+        let budget = budget(0, 0, Duration::from_secs(60));
+        for _ in 0..10 {
+            assert!(budget.try_acquire_retry());
+        }
+        assert!(!budget.snapshot().enabled);
+    }
+}
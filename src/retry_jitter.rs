@@ -0,0 +1,69 @@
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// How retry delays suggested to clients (`retry_after_secs`) are randomized, so clients
+/// that failed together, e.g. during a brief API outage, don't all retry at once. The
+/// delay is a minimum (the API's quota window, the circuit's cooldown), so jitter is only
+/// ever added to it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum RetryJitter {
+    /// The delay as computed
+    #[default]
+    None,
+    /// The delay plus anywhere up to the delay again
+    Full,
+    /// The delay plus anywhere up to half of it
+    Equal,
+}
+
+impl RetryJitter {
+    pub fn apply(self, delay: Duration) -> Duration {
+        self.apply_with(delay, rand::random())
+    }
+
+    /// [`RetryJitter::apply`] in whole seconds, rounded up
+    pub fn apply_secs(self, secs: u64) -> u64 {
+        self.apply(Duration::from_secs(secs)).as_secs_f64().ceil() as u64
+    }
+
+    /// `sample` is the random draw, in `[0, 1)`
+    fn apply_with(self, delay: Duration, sample: f64) -> Duration {
+        match self {
+            Self::None => delay,
+            Self::Full => delay + delay.mul_f64(sample),
+            Self::Equal => delay + (delay / 2).mul_f64(sample),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_jitter_bounds() {
+        let delay = Duration::from_secs(30);
+        for sample in [0.0, 0.5, 0.999] {
+            assert_eq!(RetryJitter::None.apply_with(delay, sample), delay);
+        }
+        // Never below the delay
+        assert_eq!(RetryJitter::Full.apply_with(delay, 0.0), delay);
+        assert_eq!(
+            RetryJitter::Full.apply_with(delay, 0.5),
+            Duration::from_secs(45)
+        );
+        assert_eq!(RetryJitter::Equal.apply_with(delay, 0.0), delay);
+        assert_eq!(
+            RetryJitter::Equal.apply_with(delay, 0.5),
+            Duration::from_secs_f64(37.5)
+        );
+
+        for _ in 0..100 {
+            assert!((30..=60).contains(&RetryJitter::Full.apply_secs(30)));
+            assert!((30..=45).contains(&RetryJitter::Equal.apply_secs(30)));
+        }
+        assert_eq!(RetryJitter::None.apply_secs(30), 30);
+    }
+}
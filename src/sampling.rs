@@ -0,0 +1,42 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Picks which events to log at a given rate. Deterministic rather than random: with rate
+/// `r` the n-th event is logged when `floor(n * r)` increases, spreading the logged events
+/// evenly (a rate of 0.25 logs every fourth one).
+#[derive(Debug, Default)]
+pub struct LogSampler {
+    events: AtomicU64,
+}
+
+impl LogSampler {
+    /// Count an event and return whether it should be logged. `rate` is clamped to 0.0..=1.0.
+    pub fn sample(&self, rate: f64) -> bool {
+        let rate = rate.clamp(0.0, 1.0);
+        if rate >= 1.0 {
+            return true;
+        }
+        let n = self.events.fetch_add(1, Ordering::Relaxed) + 1;
+        ((n as f64) * rate).floor() > (((n - 1) as f64) * rate).floor()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sample_rates() {
+        let count = |rate: f64| {
+            let sampler = LogSampler::default();
+            (0..1000).filter(|_| sampler.sample(rate)).count()
+        };
+        assert_eq!(count(1.0), 1000);
+        assert_eq!(count(0.25), 250);
+        assert_eq!(count(0.001), 1);
+        assert_eq!(count(0.0), 0);
+
+        let sampler = LogSampler::default();
+        let logged: Vec<bool> = (0..4).map(|_| sampler.sample(0.5)).collect();
+        assert_eq!(logged, [false, true, false, true]);
+    }
+}
@@ -0,0 +1,122 @@
+use serde::Serialize;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Keeps consecutive gemini-cli launches at least a minimum interval apart across all
+/// sessions, for strict API rate limits. Each launch reserves the next free slot, so
+/// waiting launches start in the order they arrived.
+#[derive(Debug, Default)]
+pub struct SpawnThrottle {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    /// Earliest time the next launch may start
+    next_slot: Option<Instant>,
+    waiting: usize,
+    throttled_launches: u64,
+    total_wait: Duration,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct SpawnThrottleSnapshot {
+    /// Launches currently waiting for their slot
+    pub waiting: usize,
+    /// How long a launch starting now would wait
+    pub current_wait_ms: u64,
+    /// Launches that had to wait, and how long they waited in total
+    pub throttled_launches: u64,
+    pub total_wait_ms: u64,
+}
+
+/// Decrements the waiting count even if the waiting launch is cancelled
+struct WaitingGuard<'a>(&'a Mutex<Inner>);
+
+impl Drop for WaitingGuard<'_> {
+    fn drop(&mut self) {
+        self.0.lock().unwrap().waiting -= 1;
+    }
+}
+
+impl SpawnThrottle {
+    /// Wait until a launch is allowed, `interval` after the previous one, and return how
+    /// long that took. A zero interval never waits. A cancelled wait still uses its slot.
+    pub async fn wait(&self, interval: Duration) -> Duration {
+        if interval.is_zero() {
+            return Duration::ZERO;
+        }
+        let now = Instant::now();
+        let slot = {
+            let mut inner = self.inner.lock().unwrap();
+            let slot = inner.next_slot.map_or(now, |next| next.max(now));
+            inner.next_slot = Some(slot + interval);
+            if slot == now {
+                return Duration::ZERO;
+            }
+            inner.waiting += 1;
+            slot
+        };
+        let _waiting = WaitingGuard(&self.inner);
+        tokio::time::sleep_until(slot).await;
+
+        let waited = now.elapsed();
+        let mut inner = self.inner.lock().unwrap();
+        inner.throttled_launches += 1;
+        inner.total_wait += waited;
+        waited
+    }
+
+    pub fn snapshot(&self) -> SpawnThrottleSnapshot {
+        let inner = self.inner.lock().unwrap();
+        SpawnThrottleSnapshot {
+            waiting: inner.waiting,
+            current_wait_ms: inner.next_slot.map_or(0, |next| {
+                next.saturating_duration_since(Instant::now()).as_millis() as u64
+            }),
+            throttled_launches: inner.throttled_launches,
+            total_wait_ms: inner.total_wait.as_millis() as u64,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_spaces_launches() {
+        let throttle = Arc::new(SpawnThrottle::default());
+        let interval = Duration::from_millis(100);
+        let started = Instant::now();
+
+        assert_eq!(throttle.wait(interval).await, Duration::ZERO);
+        let waits: Vec<_> = (0..2)
+            .map(|_| {
+                let throttle = throttle.clone();
+                tokio::spawn(async move { throttle.wait(interval).await })
+            })
+            .collect();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let snapshot = throttle.snapshot();
+        assert_eq!(snapshot.waiting, 2);
+        assert!((200..=300).contains(&snapshot.current_wait_ms));
+
+        for wait in waits {
+            wait.await.unwrap();
+        }
+        // The second waiting launch started two intervals after the first launch
+        assert!(started.elapsed() >= interval * 2);
+        let snapshot = throttle.snapshot();
+        assert_eq!(snapshot.waiting, 0);
+        assert_eq!(snapshot.throttled_launches, 2);
+        assert!(snapshot.total_wait_ms >= 250);
+
+        // Once the interval has passed, launches go straight through
+        tokio::time::sleep(interval).await;
+        assert_eq!(throttle.wait(interval).await, Duration::ZERO);
+        assert_eq!(throttle.wait(Duration::ZERO).await, Duration::ZERO);
+    }
+}
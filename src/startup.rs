@@ -0,0 +1,388 @@
+use anyhow::Context;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::state::ServerState;
+use crate::tools::{GeminiCli, PromptGeminiArgs};
+
+/// First and longest delay between readiness checks
+const INITIAL_POLL_INTERVAL: Duration = Duration::from_millis(100);
+const MAX_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Whether the executable of `command` (its first word) can be found: either a path to
+/// an existing file, or a name present in one of the `PATH` directories
+pub fn command_resolvable(command: &str) -> bool {
+    let Some(program) = command.split_whitespace().next() else {
+        return false;
+    };
+
+    if program.contains(std::path::MAIN_SEPARATOR) {
+        return Path::new(program).is_file();
+    }
+
+    std::env::var_os("PATH")
+        .is_some_and(|path| std::env::split_paths(&path).any(|dir| dir.join(program).is_file()))
+}
+
+/// Poll with exponential backoff until `command` is resolvable or `timeout` elapses.
+/// Returns whether the command was found.
+pub async fn wait_for_command(command: &str, timeout: Duration) -> bool {
+    let started = Instant::now();
+    let mut interval = INITIAL_POLL_INTERVAL;
+
+    loop {
+        if command_resolvable(command) {
+            if !started.elapsed().is_zero() {
+                tracing::info!(
+                    command,
+                    waited_ms = started.elapsed().as_millis() as u64,
+                    "Gemini CLI command is available"
+                );
+            }
+            return true;
+        }
+
+        let remaining = timeout.saturating_sub(started.elapsed());
+        if remaining.is_zero() {
+            return false;
+        }
+
+        tracing::info!(
+            command,
+            remaining_secs = remaining.as_secs(),
+            "Waiting for Gemini CLI command to appear on PATH"
+        );
+        tokio::time::sleep(interval.min(remaining)).await;
+        interval = (interval * 2).min(MAX_POLL_INTERVAL);
+    }
+}
+
+/// Resolve `hostname` (an IP address or a name such as `localhost`) to the address the
+/// server binds, preferring IPv4 when a name resolves to both families
+pub async fn resolve_bind_address(hostname: &str, port: u16) -> anyhow::Result<SocketAddr> {
+    let addresses: Vec<SocketAddr> = tokio::net::lookup_host((hostname, port))
+        .await
+        .with_context(|| format!("Could not resolve --hostname '{hostname}'"))?
+        .collect();
+
+    addresses
+        .iter()
+        .find(|address| address.is_ipv4())
+        .or_else(|| addresses.first())
+        .copied()
+        .with_context(|| format!("--hostname '{hostname}' did not resolve to any address"))
+}
+
+/// Bind the server's listener to `address`, trying up to `port_retry` following ports
+/// when it is already in use. Exhausting them (or `port_retry` of 0) is reported with
+/// the address and how to pick another port rather than the bare OS error.
+pub async fn bind_listener(
+    address: SocketAddr,
+    port_retry: u16,
+) -> anyhow::Result<tokio::net::TcpListener> {
+    // Port 0 asks the OS for a free port, so there is nothing to retry
+    let last_port = match address.port() {
+        0 => 0,
+        port => port.saturating_add(port_retry),
+    };
+    for port in address.port()..=last_port {
+        let candidate = SocketAddr::new(address.ip(), port);
+        match tokio::net::TcpListener::bind(candidate).await {
+            Ok(listener) => return Ok(listener),
+            Err(e) if e.kind() == std::io::ErrorKind::AddrInUse => {
+                tracing::debug!(address = %candidate, "Address already in use");
+            }
+            Err(e) => return Err(e).with_context(|| format!("Could not bind to {candidate}")),
+        }
+    }
+    if last_port == address.port() {
+        anyhow::bail!(
+            "Address {address} is already in use, probably by another server. Choose a \
+            different port with --port, or pass --port-retry N to try the next N ports"
+        );
+    }
+    anyhow::bail!(
+        "Ports {} to {} on {} are all in use. Choose a different port with --port",
+        address.port(),
+        last_port,
+        address.ip()
+    )
+}
+
+/// Run `prompt` once so gemini-cli's caches and credentials are warm before the first
+/// real request. The outcome is only logged; it counts towards the metrics like any call.
+pub async fn prewarm(state: Arc<ServerState>, prompt: String) -> bool {
+    let started = Instant::now();
+    let args = PromptGeminiArgs {
+        prompt,
+        ..Default::default()
+    };
+    match GeminiCli::with_state(state).prompt(args).await {
+        Ok(_) => {
+            tracing::info!(
+                duration_ms = started.elapsed().as_millis() as u64,
+                "Gemini CLI prewarm succeeded"
+            );
+            true
+        }
+        Err(e) => {
+            tracing::warn!(
+                duration_ms = started.elapsed().as_millis() as u64,
+                error = %e.message,
+                data = ?e.data,
+                "Gemini CLI prewarm failed"
+            );
+            false
+        }
+    }
+}
+
+/// Probe the default command's `--help` for `--sandbox`, warning when it isn't listed, as
+/// requests with `sandbox` would then fail. Returns whether the flag was found.
+pub async fn check_sandbox_support(state: Arc<ServerState>) -> bool {
+    let gemini_cli = GeminiCli::with_state(state);
+    match gemini_cli.help().await {
+        Ok(help) if help.contains("--sandbox") => {
+            tracing::debug!("Gemini CLI supports --sandbox");
+            true
+        }
+        Ok(_) => {
+            tracing::warn!(
+                "Gemini CLI's --help does not list --sandbox; prompts with sandbox enabled may fail"
+            );
+            false
+        }
+        Err(error) => {
+            tracing::warn!(error, "Could not check Gemini CLI for --sandbox support");
+            false
+        }
+    }
+}
+
+/// Prompt sent by the self-test's prompt checks
+const SELF_TEST_PROMPT: &str = "Reply with the single word OK";
+
+/// Outcome of one startup self-test check
+#[derive(Debug)]
+pub struct SelfTestCheck {
+    pub name: &'static str,
+    /// What was found when the check passed, or why it failed
+    pub result: Result<String, String>,
+}
+
+/// Check, in order, that the default command is resolvable, that `--version` works, that
+/// a trivial prompt succeeds and that JSON output parses. Every check runs and is logged,
+/// so a single boot reports everything that is misconfigured.
+pub async fn self_test(state: Arc<ServerState>) -> Vec<SelfTestCheck> {
+    let gemini_cli = GeminiCli::with_state(state);
+    let prompt_check = |output_format: &str| {
+        let gemini_cli = gemini_cli.clone();
+        let args = PromptGeminiArgs {
+            prompt: SELF_TEST_PROMPT.to_string(),
+            output_format: Some(output_format.to_string()),
+            ..Default::default()
+        };
+        async move {
+            let started = Instant::now();
+            match gemini_cli.probe_prompt(args).await {
+                Ok(_) => Ok(format!("responded in {} ms", started.elapsed().as_millis())),
+                Err(e) => Err(match e.data {
+                    Some(data) => format!("{}: {}", e.message, data),
+                    None => e.message.to_string(),
+                }),
+            }
+        }
+    };
+
+    let resolvable = gemini_cli.default_command().and_then(|command| {
+        if command_resolvable(&command) {
+            Ok(command)
+        } else {
+            Err(format!("'{}' was not found on PATH", command))
+        }
+    });
+    let checks = vec![
+        SelfTestCheck {
+            name: "command_resolvable",
+            result: resolvable,
+        },
+        SelfTestCheck {
+            name: "version",
+            result: gemini_cli.version().await,
+        },
+        SelfTestCheck {
+            name: "prompt",
+            result: prompt_check("text").await,
+        },
+        SelfTestCheck {
+            name: "json_output",
+            result: prompt_check("json").await,
+        },
+    ];
+
+    for check in &checks {
+        match &check.result {
+            Ok(detail) => tracing::info!(check = check.name, detail, "Self-test check passed"),
+            Err(error) => tracing::error!(check = check.name, error, "Self-test check failed"),
+        }
+    }
+    checks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command_resolvable() {
+        assert!(command_resolvable("sh -c true"));
+        assert!(command_resolvable("/bin/sh"));
+        assert!(!command_resolvable("nonexistent_command_12345"));
+        assert!(!command_resolvable("/nonexistent/gemini"));
+        assert!(!command_resolvable("   "));
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_command_appearing() {
+        let path = std::env::temp_dir().join(format!(
+            "mcp-gemini-cli-test-{}-late-binary",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let command = path.to_string_lossy().into_owned();
+
+        let creator = {
+            let path = path.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                std::fs::write(&path, "#!/bin/sh\n").unwrap();
+            })
+        };
+
+        assert!(wait_for_command(&command, Duration::from_secs(5)).await);
+        creator.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_command_times_out() {
+        let started = Instant::now();
+        assert!(!wait_for_command("nonexistent_command_12345", Duration::from_millis(300)).await);
+        assert!(started.elapsed() >= Duration::from_millis(300));
+    }
+
+    #[tokio::test]
+    async fn test_resolve_bind_address() {
+        let address = resolve_bind_address("127.0.0.1", 8000).await.unwrap();
+        assert_eq!(address, "127.0.0.1:8000".parse().unwrap());
+        assert!(
+            resolve_bind_address("localhost", 0)
+                .await
+                .unwrap()
+                .ip()
+                .is_loopback()
+        );
+        assert_eq!(
+            resolve_bind_address("::1", 9000).await.unwrap(),
+            "[::1]:9000".parse().unwrap()
+        );
+
+        let error = resolve_bind_address("not a host", 8000).await.unwrap_err();
+        assert!(error.to_string().contains("'not a host'"));
+    }
+
+    #[tokio::test]
+    async fn test_bind_listener_port_in_use() {
+        let taken = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = taken.local_addr().unwrap();
+
+        let error = bind_listener(address, 0).await.unwrap_err().to_string();
+        assert!(error.contains(&format!("Address {address} is already in use")));
+        assert!(error.contains("--port"));
+
+        // The next free port is used when retrying (unless the ports after it are taken too)
+        if let Ok(listener) = bind_listener(address, 10).await {
+            assert_ne!(listener.local_addr().unwrap().port(), address.port());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_prewarm() {
+        let state = |command: &str| {
+            Arc::new(ServerState::new(crate::config::GeminiCliConfig {
+                gemini_cli_command: command.to_string(),
+                ..Default::default()
+            }))
+        };
+
+        let warmed = state(r#"echo {"response": "hello"}"#);
+        assert!(prewarm(warmed.clone(), "hi".to_string()).await);
+        assert_eq!(warmed.metrics.snapshot().successes, 1);
+
+        assert!(!prewarm(state("nonexistent_command_12345"), "hi".to_string()).await);
+    }
+
+    #[tokio::test]
+    async fn test_check_sandbox_support() {
+        let state = |command: &str| {
+            Arc::new(ServerState::new(crate::config::GeminiCliConfig {
+                gemini_cli_command: command.to_string(),
+                ..Default::default()
+            }))
+        };
+
+        assert!(check_sandbox_support(state("echo -s, --sandbox  Run in sandbox")).await);
+        assert!(!check_sandbox_support(state("echo")).await);
+        assert!(!check_sandbox_support(state("nonexistent_command_12345")).await);
+    }
+
+    #[tokio::test]
+    async fn test_self_test() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let script = std::env::temp_dir().join(format!(
+            "mcp-gemini-cli-test-{}-self-test.sh",
+            std::process::id()
+        ));
+        std::fs::write(
+            &script,
+            "#!/bin/sh\ncase \"$*\" in\n  --version) echo 0.9.0 ;;\n  *--output-format*) echo '{\"response\": \"OK\"}' ;;\n  *) echo OK ;;\nesac\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let state = |command: &str| {
+            Arc::new(ServerState::new(crate::config::GeminiCliConfig {
+                gemini_cli_command: command.to_string(),
+                ..Default::default()
+            }))
+        };
+
+        let state_under_test = state(&script.to_string_lossy());
+        let checks = self_test(state_under_test.clone()).await;
+        // The self-test's prompts are not production traffic
+        assert_eq!(state_under_test.metrics_snapshot().calls.total_calls, 0);
+        let names: Vec<_> = checks.iter().map(|check| check.name).collect();
+        assert_eq!(
+            names,
+            ["command_resolvable", "version", "prompt", "json_output"]
+        );
+        assert!(
+            checks.iter().all(|check| check.result.is_ok()),
+            "{checks:?}"
+        );
+        assert_eq!(checks[1].result.as_deref(), Ok("0.9.0"));
+
+        // Plain text where JSON is expected fails only the JSON check
+        let checks = self_test(state("echo")).await;
+        let failed: Vec<_> = checks
+            .iter()
+            .filter(|check| check.result.is_err())
+            .map(|check| check.name)
+            .collect();
+        assert_eq!(failed, ["json_output"]);
+
+        let checks = self_test(state("nonexistent_command_12345")).await;
+        assert!(checks.iter().all(|check| check.result.is_err()));
+    }
+}
@@ -0,0 +1,110 @@
+use rmcp::ErrorData as McpError;
+use rmcp::model::CallToolResult;
+use serde::Serialize;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, RwLock};
+
+use crate::active_requests::ActiveRequests;
+use crate::audit::AuditLog;
+use crate::buffer_pool::BufferPool;
+use crate::circuit_breaker::CircuitBreaker;
+use crate::coalesce::Coalescer;
+use crate::config::GeminiCliConfig;
+use crate::metrics::{Metrics, MetricsSnapshot};
+use crate::queue::{QueueSnapshot, RequestQueue};
+use crate::response_cache::{CacheSnapshot, ResponseCache};
+use crate::retry_budget::{RetryBudget, RetryBudgetSnapshot};
+use crate::sampling::LogSampler;
+use crate::spawn_throttle::{SpawnThrottle, SpawnThrottleSnapshot};
+
+/// Runtime state shared by every `GeminiCli` session
+#[derive(Debug)]
+pub struct ServerState {
+    /// Settings from the command line, before the config file is applied
+    cli_config: GeminiCliConfig,
+    /// Effective configuration; swapped atomically on reload
+    config: RwLock<Arc<GeminiCliConfig>>,
+    pub circuit_breaker: CircuitBreaker,
+    pub retry_budget: RetryBudget,
+    pub metrics: Metrics,
+    pub queue: RequestQueue,
+    pub buffer_pool: BufferPool,
+    /// Pending prompt-gemini executions that identical requests can join
+    pub coalescer: Coalescer<Result<CallToolResult, McpError>>,
+    /// Results served again to identical requests; `None` when caching is disabled
+    pub response_cache: Option<ResponseCache>,
+    /// Set from `--audit-log`; not affected by config reloads
+    pub audit_log: Option<AuditLog>,
+    /// Picks the successful requests logged under `request_log_sampling`
+    pub request_log_sampler: LogSampler,
+    /// Prompt executions in progress, listed by `list-active-requests`
+    pub active_requests: ActiveRequests,
+    /// Spaces gemini-cli launches `min_spawn_interval_ms` apart
+    pub spawn_throttle: SpawnThrottle,
+}
+
+/// Everything reported by the `server-metrics` tool
+#[derive(Debug, Serialize)]
+pub struct ServerMetrics {
+    #[serde(flatten)]
+    pub calls: MetricsSnapshot,
+    pub queue: QueueSnapshot,
+    pub retry_budget: RetryBudgetSnapshot,
+    pub spawn_throttle: SpawnThrottleSnapshot,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_cache: Option<CacheSnapshot>,
+}
+
+impl ServerState {
+    /// Create the shared state. The config file (if any) is not read until
+    /// [`ServerState::reload_config`] is called.
+    pub fn new(cli_config: GeminiCliConfig) -> Self {
+        Self {
+            circuit_breaker: CircuitBreaker::new(cli_config.circuit_breaker),
+            retry_budget: RetryBudget::new(cli_config.retry_budget),
+            metrics: Metrics::default(),
+            queue: RequestQueue::new(cli_config.max_concurrency, cli_config.max_queue_depth),
+            // Each execution reads stdout and stderr
+            buffer_pool: BufferPool::new(
+                cli_config
+                    .max_concurrency
+                    .map_or(cli_config.output_buffer_pool_size, |max| {
+                        cli_config.output_buffer_pool_size.min(2 * max)
+                    }),
+            ),
+            coalescer: Coalescer::default(),
+            response_cache: NonZeroUsize::new(cli_config.response_cache_max_entries).map(
+                |max_entries| ResponseCache::new(max_entries, cli_config.response_cache_max_bytes),
+            ),
+            audit_log: None,
+            request_log_sampler: LogSampler::default(),
+            active_requests: ActiveRequests::default(),
+            spawn_throttle: SpawnThrottle::default(),
+            config: RwLock::new(Arc::new(cli_config.clone())),
+            cli_config,
+        }
+    }
+
+    /// Current effective configuration
+    pub fn config(&self) -> Arc<GeminiCliConfig> {
+        self.config.read().unwrap().clone()
+    }
+
+    /// Re-read the config file and swap in the new configuration. Requests already
+    /// in flight keep the configuration they started with.
+    pub fn reload_config(&self) -> anyhow::Result<Arc<GeminiCliConfig>> {
+        let config = Arc::new(self.cli_config.resolve()?);
+        *self.config.write().unwrap() = config.clone();
+        Ok(config)
+    }
+
+    pub fn metrics_snapshot(&self) -> ServerMetrics {
+        ServerMetrics {
+            calls: self.metrics.snapshot(),
+            queue: self.queue.snapshot(),
+            retry_budget: self.retry_budget.snapshot(),
+            spawn_throttle: self.spawn_throttle.snapshot(),
+            response_cache: self.response_cache.as_ref().map(ResponseCache::snapshot),
+        }
+    }
+}
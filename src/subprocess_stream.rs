@@ -0,0 +1,201 @@
+use bytes::{Bytes, BytesMut};
+use clap::ValueEnum;
+use futures::Stream;
+use futures::stream::{self, BoxStream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::pin::Pin;
+use std::process::{ExitStatus, Stdio};
+use std::task::{Context, Poll};
+use tokio::io::AsyncReadExt;
+use tokio::process::{Child, ChildStdout, Command};
+use tokio::task::JoinHandle;
+
+/// Bytes requested from stdout per read
+const READ_BYTES: usize = 8 * 1024;
+
+/// Longest line [`Buffering::Line`] holds back; longer lines are flushed in pieces
+pub const MAX_LINE_BYTES: usize = 64 * 1024;
+
+/// How a [`SubprocessStream`] groups stdout into items
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Buffering {
+    /// Whatever each read returned, as soon as it arrives
+    #[default]
+    Chunk,
+    /// One complete line per item, newline included; a final line without one is flushed
+    /// when stdout closes
+    Line,
+}
+
+/// A spawned subprocess whose stdout is read as a stream of items as they are written,
+/// while stderr is collected in the background. Dropping it drops the child, which kills
+/// it when the command was built with `kill_on_drop`.
+pub struct SubprocessStream {
+    child: Child,
+    stdout: BoxStream<'static, std::io::Result<Bytes>>,
+    stderr: Option<JoinHandle<std::io::Result<Vec<u8>>>>,
+}
+
+/// Spawn `cmd` with stdout and stderr piped and stream its stdout
+pub fn spawn_streaming(
+    cmd: &mut Command,
+    buffering: Buffering,
+) -> std::io::Result<SubprocessStream> {
+    let mut child = cmd.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+    let stdout = child.stdout.take().expect("stdout is piped");
+    let mut stderr = child.stderr.take().expect("stderr is piped");
+    // Drained concurrently, or a chatty child could block on a full pipe
+    let stderr = tokio::spawn(async move {
+        let mut buffer = Vec::new();
+        stderr.read_to_end(&mut buffer).await?;
+        Ok(buffer)
+    });
+    Ok(SubprocessStream {
+        child,
+        stdout: read_stdout(stdout, buffering).boxed(),
+        stderr: Some(stderr),
+    })
+}
+
+struct ReadState {
+    stdout: ChildStdout,
+    buffering: Buffering,
+    pending: BytesMut,
+    eof: bool,
+}
+
+fn read_stdout(
+    stdout: ChildStdout,
+    buffering: Buffering,
+) -> impl Stream<Item = std::io::Result<Bytes>> {
+    let state = ReadState {
+        stdout,
+        buffering,
+        pending: BytesMut::new(),
+        eof: false,
+    };
+    stream::try_unfold(state, |mut state| async move {
+        loop {
+            if let Some(item) = state.next_item() {
+                return Ok(Some((item, state)));
+            }
+            if state.eof {
+                return Ok(None);
+            }
+            state.pending.reserve(READ_BYTES);
+            if state.stdout.read_buf(&mut state.pending).await? == 0 {
+                state.eof = true;
+            }
+        }
+    })
+}
+
+impl ReadState {
+    /// The next item the buffered output allows, if any
+    fn next_item(&mut self) -> Option<Bytes> {
+        if self.pending.is_empty() {
+            return None;
+        }
+        let len = match self.buffering {
+            Buffering::Chunk => self.pending.len(),
+            Buffering::Line => match self.pending.iter().position(|&byte| byte == b'\n') {
+                Some(newline) => (newline + 1).min(MAX_LINE_BYTES),
+                None if self.pending.len() >= MAX_LINE_BYTES => MAX_LINE_BYTES,
+                None if self.eof => self.pending.len(),
+                None => return None,
+            },
+        };
+        Some(self.pending.split_to(len).freeze())
+    }
+}
+
+impl SubprocessStream {
+    pub fn child(&self) -> &Child {
+        &self.child
+    }
+
+    /// Wait for the process to exit, returning its status and everything it wrote to
+    /// stderr. Stdout not consumed yet is discarded. Waits for stderr to close, which a
+    /// background process may hold open, so callers should bound it with a timeout.
+    pub async fn finish(mut self) -> std::io::Result<(ExitStatus, Vec<u8>)> {
+        let status = self.child.wait().await?;
+        let stderr = match self.stderr.take() {
+            Some(stderr) => stderr.await.map_err(std::io::Error::other)??,
+            None => Vec::new(),
+        };
+        Ok((status, stderr))
+    }
+}
+
+impl Stream for SubprocessStream {
+    type Item = std::io::Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.stdout.poll_next_unpin(cx)
+    }
+}
+
+impl Drop for SubprocessStream {
+    fn drop(&mut self) {
+        if let Some(stderr) = self.stderr.take() {
+            stderr.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn items(script: &str, buffering: Buffering) -> (Vec<Bytes>, ExitStatus, Vec<u8>) {
+        let mut stream =
+            spawn_streaming(Command::new("sh").args(["-c", script]), buffering).unwrap();
+        let mut items = Vec::new();
+        while let Some(item) = stream.next().await {
+            items.push(item.unwrap());
+        }
+        let (status, stderr) = stream.finish().await.unwrap();
+        (items, status, stderr)
+    }
+
+    #[tokio::test]
+    async fn test_line_buffering() {
+        let (items, status, stderr) = items(
+            "printf 'one\\ntw'; sleep 0.1; printf 'o\\nthree\\nfour'; echo oops >&2; exit 2",
+            Buffering::Line,
+        )
+        .await;
+        assert_eq!(items, ["one\n", "two\n", "three\n", "four"]);
+        assert_eq!(status.code(), Some(2));
+        assert_eq!(stderr, b"oops\n");
+    }
+
+    #[tokio::test]
+    async fn test_chunk_buffering_yields_output_as_written() {
+        let mut stream = spawn_streaming(
+            Command::new("sh")
+                .args(["-c", "printf first; exec sleep 5"])
+                .kill_on_drop(true),
+            Buffering::Chunk,
+        )
+        .unwrap();
+        // Arrives while the process is still running
+        let first = tokio::time::timeout(std::time::Duration::from_secs(2), stream.next())
+            .await
+            .unwrap();
+        assert_eq!(first.unwrap().unwrap(), "first");
+    }
+
+    #[tokio::test]
+    async fn test_long_lines_are_flushed_in_pieces() {
+        let (items, _, _) = items(
+            &format!("head -c {} /dev/zero | tr '\\0' x", MAX_LINE_BYTES + 10),
+            Buffering::Line,
+        )
+        .await;
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].len(), MAX_LINE_BYTES);
+        assert_eq!(items[1].len(), 10);
+    }
+}
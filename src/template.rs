@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+
+/// Placeholders in strict mode that had no matching variable
+#[derive(Debug, PartialEq)]
+pub struct MissingVariables(pub Vec<String>);
+
+impl std::fmt::Display for MissingVariables {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Missing template variables: {}", self.0.join(", "))
+    }
+}
+
+/// Substitute `{{name}}` placeholders (surrounding whitespace in the name is ignored) with
+/// `variables`. `\{{` and `\}}` produce literal `{{` and `}}`. Substituted values are not
+/// scanned again. Unknown placeholders are left intact, or reported when `strict` is set.
+pub fn render(
+    template: &str,
+    variables: &HashMap<String, String>,
+    strict: bool,
+) -> Result<String, MissingVariables> {
+    let mut rendered = String::with_capacity(template.len());
+    let mut missing = Vec::new();
+    let mut rest = template;
+
+    while let Some(pos) = rest.find(['\\', '{']) {
+        rendered.push_str(&rest[..pos]);
+        rest = &rest[pos..];
+
+        if let Some(after) = rest.strip_prefix("\\{{") {
+            rendered.push_str("{{");
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("\\}}") {
+            rendered.push_str("}}");
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("{{")
+            && let Some(end) = after.find("}}")
+        {
+            let name = after[..end].trim();
+            match variables.get(name) {
+                Some(value) => rendered.push_str(value),
+                None => {
+                    if strict && !missing.iter().any(|m| m == name) {
+                        missing.push(name.to_string());
+                    }
+                    rendered.push_str(&rest[..end + 4]);
+                }
+            }
+            rest = &after[end + 2..];
+        } else {
+            // A lone `\` or `{` (including an unclosed `{{`) is ordinary text
+            rendered.push_str(&rest[..1]);
+            rest = &rest[1..];
+        }
+    }
+    rendered.push_str(rest);
+
+    if missing.is_empty() {
+        Ok(rendered)
+    } else {
+        Err(MissingVariables(missing))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variables(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_substitutes_variables() {
+        let vars = variables(&[("lang", "Rust"), ("file", "main.rs")]);
+        assert_eq!(
+            render("Review {{file}} ({{ lang }}), {{file}} only", &vars, false).unwrap(),
+            "Review main.rs (Rust), main.rs only"
+        );
+    }
+
+    #[test]
+    fn test_unmatched_placeholders() {
+        let vars = variables(&[("a", "1")]);
+        assert_eq!(
+            render("{{a}} {{b}} {{ c }}", &vars, false).unwrap(),
+            "1 {{b}} {{ c }}"
+        );
+        assert_eq!(
+            render("{{a}} {{b}} {{ c }} {{b}}", &vars, true),
+            Err(MissingVariables(vec!["b".to_string(), "c".to_string()]))
+        );
+    }
+
+    #[test]
+    fn test_escapes_and_plain_braces() {
+        let vars = variables(&[("x", "{{y}}"), ("y", "no")]);
+        assert_eq!(
+            render(r"\{{x\}} = {{x}}; fn f() { {{ }", &vars, true).unwrap(),
+            r"{{x}} = {{y}}; fn f() { {{ }"
+        );
+        assert_eq!(render(r"C:\path \n", &vars, true).unwrap(), r"C:\path \n");
+    }
+}
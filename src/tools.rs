@@ -1,3 +1,6 @@
+use base64::Engine;
+use clap::ValueEnum;
+use futures::StreamExt;
 use rmcp::{
     ErrorData as McpError, RoleServer, ServerHandler,
     handler::server::{router::tool::ToolRouter, wrapper::Parameters},
@@ -5,27 +8,272 @@ use rmcp::{
     service::RequestContext,
     tool, tool_handler, tool_router,
 };
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt;
 use tokio::process::Command;
+use tokio::sync::{SemaphorePermit, mpsc};
+use tokio_util::sync::CancellationToken;
+use tracing::Instrument;
+
+use crate::ansi;
+use crate::audit::{self, AuditEntry};
+use crate::buffer_pool::{OutputLimits, PooledOutput, read_output};
+use crate::client_log::ClientLogger;
+use crate::config::{ExtractionBudget, GeminiCliConfig, OutputFormat, redact_command};
+use crate::images::{self, PreparedImage};
+use crate::json_repair;
+use crate::languages;
+use crate::metrics::CallOutcome;
+use crate::process_group::ProcessGroupGuard;
+use crate::prompts::{self, PromptTemplate};
+use crate::redact::StreamRedactor;
+use crate::resource_usage::{ResourceUsage, UsageMeter};
+use crate::response_cache;
+use crate::state::ServerState;
+use crate::subprocess_stream::spawn_streaming;
+use crate::template::{self, MissingVariables};
+use crate::workspace_files;
+use encoding_rs::Encoding;
+
+/// Rough token estimate used when no tokenizer is available (~4 chars per token)
+fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(4)
+}
+
+/// How long the count-tokens command may run before falling back to the heuristic
+const COUNT_TOKENS_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long `--version` may run in the startup self-test
+const VERSION_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Longest the `diagnose` tool waits for the diagnostics subcommand
+const DIAGNOSE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Workspaces with more files than this get a token cost warning with `all_files`
+const ALL_FILES_WARN_FILES: usize = 500;
+
+/// Read a token count from count-tokens output: a bare number, or a JSON object with
+/// `totalTokens`, `total_tokens` or `tokens`
+fn parse_token_count(output: &str) -> Option<u64> {
+    let output = output.trim();
+    if let Ok(count) = output.parse() {
+        return Some(count);
+    }
+    let value: serde_json::Value = serde_json::from_str(output).ok()?;
+    ["totalTokens", "total_tokens", "tokens"]
+        .iter()
+        .find_map(|key| value.get(key)?.as_u64())
+}
 
 // Allow dead code for JSON schema structs - they define complete API schemas for future extensibility
 #[allow(dead_code)]
 
-#[derive(Debug, Deserialize, schemars::JsonSchema)]
+#[derive(Debug, Clone, Default, Deserialize, Serialize, schemars::JsonSchema)]
 pub struct PromptGeminiArgs {
     /// The prompt to send to Gemini CLI
     pub prompt: String,
+    /// Values substituted for `{{name}}` placeholders in the prompt (`\{{` and `\}}` are
+    /// literal braces)
+    pub variables: Option<HashMap<String, String>>,
+    /// Reject prompts with placeholders that have no matching variable instead of leaving
+    /// them intact
+    pub strict_templating: Option<bool>,
+    /// Named backend to run the prompt with (defaults to the server's default backend)
+    pub backend: Option<String>,
+    /// Model to run the prompt with, passed to gemini-cli as `--model` (defaults to the
+    /// server's default model)
+    pub model: Option<String>,
+    /// Output format: "json" (parsed response), "text" (raw output) or "yaml" (parsed response
+    /// and stats as YAML); defaults to the server setting
+    pub output_format: Option<String>,
+    /// Add a summary of the lines gemini-cli added/removed to the result `_meta`
+    /// (as `file_changes`) when it edited files
+    pub include_file_changes: Option<bool>,
+    /// Keep at most this many lines of gemini-cli output (applied before parsing)
+    pub max_output_lines: Option<usize>,
+    /// Which lines `max_output_lines` keeps: "head" (first lines, default) or "tail" (last lines)
+    pub output_lines_from: Option<String>,
+    /// Append gemini-cli's untrimmed stdout as an extra text content block, for debugging parsing
+    pub include_raw: Option<bool>,
+    /// When gemini-cli's JSON output cannot be parsed, return it as text (marked
+    /// `json_fallback` in `_meta`) instead of a `gemini_json_parse_error` error
+    pub fallback_to_text: Option<bool>,
+    /// When the server timeout kills gemini-cli, return the stdout collected so far (marked
+    /// `partial` in `_meta`) instead of a `gemini_cli_timeout` error
+    pub return_partial_on_timeout: Option<bool>,
+    /// Include every file in the workspace in the context (gemini-cli's `--all-files`), for
+    /// repository-wide questions; costly in tokens on large workspaces
+    pub all_files: Option<bool>,
+    /// System instruction for this call, replacing the server's `--system-prompt-file`
+    /// (an empty string sends none)
+    pub system_prompt: Option<String>,
+    /// How gemini-cli's tool calls are approved: "manual" (ask before each one), "auto"
+    /// (approve file edits) or "yolo" (approve everything, the server default). Only "manual"
+    /// is accepted when the server runs read-only.
+    pub approval_mode: Option<String>,
+    /// Return the model's reasoning, when the wrapper reports it, as an extra text content item
+    /// marked `_meta.content_type: "thoughts"` (json and yaml output formats)
+    pub include_thoughts: Option<bool>,
+    /// Number of candidate responses to generate (1 to 8), for best-of-N sampling. Above 1,
+    /// each candidate is returned as its own content item marked `_meta.candidate` (json
+    /// output format without `response_json_pointer`, and needs the server's
+    /// `--candidates-flag`)
+    pub candidate_count: Option<u32>,
+    /// Log this request in detail (the full command line and gemini-cli's raw output, secrets
+    /// redacted) at debug level, whatever the server's log level
+    pub debug: Option<bool>,
+    /// Run gemini-cli's tools in its sandbox (`--sandbox`), limiting what tool calls can
+    /// touch; defaults to the server setting, which `false` cannot override
+    pub sandbox: Option<bool>,
+    /// ISO 639-1 code of the language to respond in (e.g. "fr" or "pt-BR"), passed with the
+    /// server's `--language-flag` or otherwise added to the prompt as an instruction
+    pub language: Option<String>,
+    /// MIME type of the expected response (e.g. "text/markdown" or "text/x-rust"), returned
+    /// as `_meta.mime_type` on the response text so clients can render it; generation is
+    /// unaffected
+    pub content_type: Option<String>,
+    /// Milliseconds this call's gemini-cli execution may run before it is killed, replacing
+    /// the server timeout (capped at the server's maximum deadline)
+    pub deadline_ms: Option<u64>,
+    /// RFC 6901 JSON pointer (e.g. "/items/0/name") applied to the response text when the
+    /// model answered with JSON; the selected value is returned instead of the whole response.
+    /// Not allowed with the text output format
+    pub response_json_pointer: Option<String>,
+    /// Images for multimodal models, each a file path (relative to the workspace, and within
+    /// the allowed workspace roots) or a `data:image/<type>;base64,` URL; passed to gemini-cli
+    /// as `--image`
+    pub images: Option<Vec<String>>,
+    /// Return fenced code blocks in the response as separate content items (with the fence's
+    /// language in `_meta.language`) between the surrounding text items
+    pub split_code_blocks: Option<bool>,
+    /// Return gemini-cli's raw stdout base64-encoded (marked with `_meta.encoding: "base64"`)
+    /// instead of the parsed response, for lossless transport of unusual output
+    pub encode_base64: Option<bool>,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct CompareModelsArgs {
+    /// The prompt to send to every model
+    pub prompt: String,
+    /// Models to compare, each passed to gemini-cli as `--model`
+    pub models: Vec<String>,
+    /// Named backend to run the prompts with (defaults to the server's default backend)
+    pub backend: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize, schemars::JsonSchema)]
+pub struct ListWorkspaceFilesArgs {
+    /// Only return paths matching this glob, relative to the workspace (e.g. "src/**/*.rs")
+    pub glob: Option<String>,
+    /// How many directory levels to descend (default 10)
+    pub max_depth: Option<usize>,
+    /// Maximum number of paths to return (default 1000, at most 10000)
+    pub max_files: Option<usize>,
+    /// Skip files excluded by .gitignore files
+    pub respect_gitignore: Option<bool>,
+}
+
+#[derive(Debug, Default, Deserialize, schemars::JsonSchema)]
+pub struct DiagnoseArgs {
+    /// Named backend whose command is diagnosed (defaults to the server's default backend)
+    pub backend: Option<String>,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct BatchPromptArgs {
+    /// Prompts to run, each as a separate Gemini CLI execution: a string, or an object with
+    /// `prompt` and optional `model` and `output_format` overriding the batch's
+    pub prompts: Vec<BatchPromptItem>,
+    /// Model for prompts that don't set one (defaults to the server's default model)
+    pub model: Option<String>,
+    /// Output format for prompts that don't set one: "json", "text" or "yaml"
+    pub output_format: Option<String>,
+    /// Named backend to run the prompts with (defaults to the server's default backend)
+    pub backend: Option<String>,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+#[serde(untagged)]
+pub enum BatchPromptItem {
+    Prompt(String),
+    WithOverrides {
+        prompt: String,
+        model: Option<String>,
+        output_format: Option<String>,
+    },
+}
+
+/// Response or error of one execution in a multi-prompt call
+#[derive(Debug, Serialize)]
+pub struct PromptOutcome {
+    pub response: Option<String>,
+    /// Error code and details when this execution failed
+    pub error: Option<serde_json::Value>,
+    pub stats: Option<serde_json::Value>,
+}
+
+impl PromptOutcome {
+    /// `None` is an execution that panicked
+    fn from_result(result: Option<Result<CallToolResult, McpError>>) -> Self {
+        match result {
+            Some(Ok(result)) => Self {
+                response: result
+                    .content
+                    .first()
+                    .and_then(|content| content.raw.as_text())
+                    .map(|text| text.text.clone()),
+                error: None,
+                stats: result
+                    .structured_content
+                    .and_then(|structured| structured.get("stats").cloned()),
+            },
+            Some(Err(error)) => Self {
+                response: None,
+                error: Some(serde_json::json!({
+                    "code": error.message,
+                    "data": error.data,
+                })),
+                stats: None,
+            },
+            None => Self {
+                response: None,
+                error: Some(serde_json::json!({ "code": "gemini_execution_panicked" })),
+                stats: None,
+            },
+        }
+    }
+}
+
+/// One model's outcome in a `compare-models` call
+#[derive(Debug, Serialize)]
+pub struct ModelComparison {
+    pub model: String,
+    #[serde(flatten)]
+    pub outcome: PromptOutcome,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize, schemars::JsonSchema)]
 #[allow(dead_code)]
 pub struct GeminiJsonResponse {
     pub response: String,
     pub stats: Option<GeminiStats>,
     pub error: Option<GeminiErrorResponse>,
+    /// Reasoning reported by wrappers that expose it: text, or a list of text parts
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thoughts: Option<serde_json::Value>,
+    /// Every candidate's response, for requests with a `candidate_count` above 1;
+    /// `response` is the first
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub candidates: Option<Vec<String>>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize, schemars::JsonSchema)]
 pub struct GeminiErrorResponse {
     #[serde(rename = "type")]
     pub error_type: String,
@@ -33,7 +281,7 @@ pub struct GeminiErrorResponse {
     pub code: Option<i32>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize, schemars::JsonSchema)]
 #[allow(dead_code)]
 pub struct GeminiStats {
     pub models: Option<std::collections::HashMap<String, ModelStats>>,
@@ -41,14 +289,14 @@ pub struct GeminiStats {
     pub files: Option<FileStats>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize, schemars::JsonSchema)]
 #[allow(dead_code)]
 pub struct ModelStats {
     pub api: Option<ApiStats>,
     pub tokens: Option<TokenStats>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize, schemars::JsonSchema)]
 #[allow(dead_code)]
 pub struct ApiStats {
     #[serde(rename = "totalRequests")]
@@ -59,7 +307,7 @@ pub struct ApiStats {
     pub total_latency_ms: Option<i32>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize, schemars::JsonSchema)]
 #[allow(dead_code)]
 pub struct TokenStats {
     pub prompt: Option<i32>,
@@ -70,7 +318,7 @@ pub struct TokenStats {
     pub tool: Option<i32>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize, schemars::JsonSchema)]
 #[allow(dead_code)]
 pub struct ToolStats {
     #[serde(rename = "totalCalls")]
@@ -87,7 +335,7 @@ pub struct ToolStats {
     pub by_name: Option<std::collections::HashMap<String, ToolDetailStats>>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize, schemars::JsonSchema)]
 #[allow(dead_code)]
 pub struct DecisionStats {
     pub accept: Option<i32>,
@@ -96,7 +344,7 @@ pub struct DecisionStats {
     pub auto_accept: Option<i32>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize, schemars::JsonSchema)]
 #[allow(dead_code)]
 pub struct ToolDetailStats {
     pub count: Option<i32>,
@@ -107,7 +355,7 @@ pub struct ToolDetailStats {
     pub decisions: Option<DecisionStats>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize, schemars::JsonSchema)]
 #[allow(dead_code)]
 pub struct FileStats {
     #[serde(rename = "totalLinesAdded")]
@@ -116,494 +364,7845 @@ pub struct FileStats {
     pub total_lines_removed: Option<i32>,
 }
 
+/// Tool-call decisions summed over every prompt in a session
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct DecisionTotals {
+    /// Prompts that reported decision stats
+    pub prompts: u64,
+    pub accept: u64,
+    pub reject: u64,
+    pub modify: u64,
+    pub auto_accept: u64,
+}
+
+impl DecisionTotals {
+    fn add(&mut self, decisions: &DecisionStats) {
+        let count = |value: Option<i32>| value.unwrap_or(0).max(0) as u64;
+        self.prompts += 1;
+        self.accept += count(decisions.accept);
+        self.reject += count(decisions.reject);
+        self.modify += count(decisions.modify);
+        self.auto_accept += count(decisions.auto_accept);
+    }
+
+    /// Share of each decision kind among all decisions (all zero when there were none)
+    fn rates(&self) -> serde_json::Value {
+        let total = self.accept + self.reject + self.modify + self.auto_accept;
+        let rate = |count: u64| {
+            if total == 0 {
+                0.0
+            } else {
+                count as f64 / total as f64
+            }
+        };
+        serde_json::json!({
+            "accept": rate(self.accept),
+            "reject": rate(self.reject),
+            "modify": rate(self.modify),
+            "auto_accept": rate(self.auto_accept),
+        })
+    }
+}
+
+/// Stats reported by gemini-cli, tracked per session
+#[derive(Debug, Default)]
+struct SessionStats {
+    /// File changes reported by the most recent prompt that edited files
+    last_file_changes: Option<FileStats>,
+    /// Tool-call decisions reported by the most recent prompt that made any
+    last_decisions: Option<DecisionStats>,
+    decision_totals: DecisionTotals,
+    /// prompt-gemini calls currently running in this session
+    in_flight: usize,
+    /// Resources used by the most recent gemini-cli process that ran to completion
+    last_resource_usage: Option<ResourceUsage>,
+}
+
+/// A prompt-gemini call counted against its session's in-flight limit; released on drop
+struct InFlightGuard(Arc<Mutex<SessionStats>>);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.lock().unwrap().in_flight -= 1;
+    }
+}
+
+impl SessionStats {
+    fn record(&mut self, stats: &GeminiStats) {
+        if let Some(files) = &stats.files {
+            self.last_file_changes = Some(files.clone());
+        }
+        if let Some(decisions) = stats
+            .tools
+            .as_ref()
+            .and_then(|tools| tools.total_decisions.as_ref())
+        {
+            self.decision_totals.add(decisions);
+            self.last_decisions = Some(decisions.clone());
+        }
+    }
+}
+
+/// Backoff suggested to clients when a rate-limit error does not say how long to wait
+const DEFAULT_RATE_LIMIT_BACKOFF_SECS: u64 = 30;
+
+/// Whether a Gemini API error indicates rate limiting / quota exhaustion
+fn is_rate_limit_error(error: &GeminiErrorResponse) -> bool {
+    error.code == Some(429)
+        || error.error_type.to_ascii_lowercase().contains("ratelimit")
+        || error.message.contains("RESOURCE_EXHAUSTED")
+}
+
+/// Whether a Gemini API error indicates missing or rejected credentials
+fn is_auth_error(error: &GeminiErrorResponse) -> bool {
+    matches!(error.code, Some(401 | 403)) || error.error_type.to_ascii_lowercase().contains("auth")
+}
+
+/// Classify an API error from Gemini CLI's JSON output. Rate limiting and authentication
+/// failures have their own codes; any other 4xx rejects the request itself (e.g. an unknown
+/// model or an out-of-range parameter), so it is the client's to fix.
+fn api_error(error: &GeminiErrorResponse, data: serde_json::Value) -> McpError {
+    if is_rate_limit_error(error) {
+        McpError::internal_error("gemini_rate_limited", Some(data))
+    } else if is_auth_error(error) {
+        McpError::internal_error("gemini_auth_error", Some(data))
+    } else if error.code.is_some_and(|code| (400..500).contains(&code)) {
+        McpError::invalid_params("gemini_invalid_request", Some(data))
+    } else {
+        McpError::internal_error("gemini_api_error", Some(data))
+    }
+}
+
+/// How a prompt execution ended, for [`crate::metrics::Metrics`]
+fn call_outcome<T>(result: &Result<T, McpError>) -> CallOutcome {
+    match result {
+        Ok(_) => CallOutcome::Success,
+        Err(error) if error.message == "gemini_cli_timeout" => CallOutcome::Timeout,
+        Err(_) => CallOutcome::Failure,
+    }
+}
+
+/// Whether a failure was caused by the request rather than the server or backend, so
+/// retrying it unchanged would fail the same way
+pub fn is_client_error(error: &McpError) -> bool {
+    error.code == ErrorCode::INVALID_PARAMS
+}
+
+/// Extract a retry delay from a rate-limit message, e.g. "Please retry in 17.5s",
+/// "retry after 30 seconds", `"retryDelay": "17s"` or "Retry-After: 20".
+/// Fractional delays are rounded up to whole seconds.
+fn parse_retry_after_secs(message: &str) -> Option<u64> {
+    const MARKERS: [&str; 5] = [
+        "retry in",
+        "retry after",
+        "try again in",
+        "retry-after:",
+        "retrydelay",
+    ];
+
+    let lower = message.to_ascii_lowercase();
+    for marker in MARKERS {
+        let Some(pos) = lower.find(marker) else {
+            continue;
+        };
+
+        // Skip separators such as spaces, quotes and colons up to the number
+        let rest = lower[pos + marker.len()..].trim_start_matches(|c: char| !c.is_ascii_digit());
+        let number_len = rest
+            .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .unwrap_or(rest.len());
+        let Ok(value) = rest[..number_len].parse::<f64>() else {
+            continue;
+        };
+
+        let unit = rest[number_len..].trim_start();
+        let secs = if unit.starts_with("ms") || unit.starts_with("milli") {
+            value / 1000.0
+        } else if unit.starts_with('m') {
+            value * 60.0
+        } else if unit.starts_with('h') {
+            value * 3600.0
+        } else {
+            value
+        };
+        return Some(secs.ceil() as u64);
+    }
+    None
+}
+
 #[derive(Debug)]
-enum ParseResult {
+pub enum ParseResult {
     JsonSuccess(Box<GeminiJsonResponse>),
     ParseError { raw_output: String, error: String },
 }
 
-fn parse_gemini_output(raw_output: &str) -> ParseResult {
+/// [`parse_gemini_output_with_budget`] with the default budget
+pub fn parse_gemini_output(raw_output: &str) -> ParseResult {
+    parse_gemini_output_with_budget(raw_output, &ExtractionBudget::default())
+}
+
+pub fn parse_gemini_output_with_budget(raw_output: &str, budget: &ExtractionBudget) -> ParseResult {
     // Try multiple JSON parsing strategies
     if let Ok(json_response) = serde_json::from_str::<GeminiJsonResponse>(raw_output.trim()) {
         return ParseResult::JsonSuccess(Box::new(json_response));
     }
 
-    // Try extracting JSON objects from mixed content
-    if let Some(json_str) = extract_json_from_mixed_content(raw_output) 
-        && let Ok(json_response) = serde_json::from_str::<GeminiJsonResponse>(&json_str) {
-        return ParseResult::JsonSuccess(Box::new(json_response));
+    // Try JSON objects embedded in mixed content, most likely candidate first; usually
+    // the first candidate parses, so the output is only parsed once. The number of
+    // attempts is capped so adversarial output can't trigger thousands of parses.
+    let (candidates, oversized) = json_object_candidates(raw_output, budget.max_candidate_bytes);
+    let mut exceeded = oversized || candidates.len() > MAX_JSON_CANDIDATES;
+    for json_str in candidates.into_iter().take(MAX_JSON_CANDIDATES) {
+        if let Ok(json_response) = serde_json::from_str::<GeminiJsonResponse>(json_str) {
+            return ParseResult::JsonSuccess(Box::new(json_response));
+        }
     }
 
     // Try line-by-line parsing
-    for line in raw_output.lines() {
+    let mut lines = raw_output.lines();
+    for line in lines.by_ref().take(budget.max_lines) {
         let line = line.trim();
+        if line.len() > budget.max_candidate_bytes {
+            exceeded = true;
+            continue;
+        }
         if line.starts_with('{') 
             && let Ok(json_response) = serde_json::from_str::<GeminiJsonResponse>(line) {
             return ParseResult::JsonSuccess(Box::new(json_response));
         }
     }
+    exceeded |= lines.next().is_some();
 
     // JSON parsing failed
+    let error = if exceeded {
+        format!(
+            "Failed to parse JSON from gemini CLI output: exceeded extraction budget \
+            (max_lines {}, max_candidate_bytes {}, at most {} embedded objects)",
+            budget.max_lines, budget.max_candidate_bytes, MAX_JSON_CANDIDATES
+        )
+    } else {
+        "Failed to parse JSON from gemini CLI output".to_string()
+    };
     ParseResult::ParseError {
         raw_output: raw_output.to_string(),
-        error: "Failed to parse JSON from gemini CLI output".to_string(),
+        error,
+    }
+}
+
+/// [`parse_gemini_output_with_budget`], falling back to the same strategies on the output
+/// with comments and trailing commas removed (see [`json_repair::repair`]) when strict
+/// parsing fails
+pub fn parse_gemini_output_lenient(raw_output: &str, budget: &ExtractionBudget) -> ParseResult {
+    let strict = parse_gemini_output_with_budget(raw_output, budget);
+    if let ParseResult::ParseError { .. } = strict
+        && let Some(repaired) = json_repair::repair(raw_output)
+        && let ParseResult::JsonSuccess(json_response) =
+            parse_gemini_output_with_budget(&repaired, budget)
+    {
+        tracing::debug!("Parsed Gemini CLI output after removing comments and trailing commas");
+        return ParseResult::JsonSuccess(json_response);
     }
+    strict
+}
+
+/// Embedded JSON objects tried before falling back to line-by-line parsing
+const MAX_JSON_CANDIDATES: usize = 16;
+
+/// The JSON object most likely to be gemini-cli's response in mixed content (see
+/// [`json_object_candidates`]). Not validated; parsing it is left to the caller.
+#[cfg(test)]
+fn extract_json_from_mixed_content(content: &str) -> Option<&str> {
+    let max_candidate_bytes = ExtractionBudget::default().max_candidate_bytes;
+    json_object_candidates(content, max_candidate_bytes)
+        .0
+        .into_iter()
+        .next()
 }
 
-fn extract_json_from_mixed_content(content: &str) -> Option<String> {
-    // Look for JSON objects in mixed content
-    let mut brace_count = 0;
-    let mut start_pos = None;
+/// Balanced top-level `{...}` spans in `content` that look like JSON objects, largest
+/// first (the response object dwarfs any JSON-looking log line). A single linear scan:
+/// braces inside JSON strings are ignored and candidates are not validated here. Spans
+/// over `max_candidate_bytes` are dropped, which the returned flag reports.
+fn json_object_candidates(content: &str, max_candidate_bytes: usize) -> (Vec<&str>, bool) {
+    let bytes = content.as_bytes();
+    let mut candidates = Vec::new();
+    let mut depth = 0usize;
+    let mut start = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+
+    // Braces and quotes are ASCII, so scanning bytes keeps slices on char boundaries
+    for (i, &byte) in bytes.iter().enumerate() {
+        if in_string {
+            match byte {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
 
-    for (i, ch) in content.char_indices() {
-        match ch {
-            '{' => {
-                if brace_count == 0 {
-                    start_pos = Some(i);
+        match byte {
+            b'{' => {
+                if depth == 0 {
+                    start = i;
                 }
-                brace_count += 1;
-            }
-            '}' => {
-                brace_count -= 1;
-                if brace_count == 0 && let Some(start) = start_pos {
-                    let json_str = &content[start..=i];
-                    // Validate it's actually JSON
-                    if serde_json::from_str::<serde_json::Value>(json_str).is_ok() {
-                        return Some(json_str.to_string());
-                    }
+                depth += 1;
+            }
+            // Strings only matter inside a candidate; quotes in surrounding log text are ignored
+            b'"' if depth > 0 => in_string = true,
+            b'}' if depth > 0 => {
+                depth -= 1;
+                if depth == 0 {
+                    candidates.push(&content[start..=i]);
                 }
             }
             _ => {}
         }
     }
-    None
+
+    // Cheap pre-filter so brace-delimited log text (and oversized spans) aren't handed to
+    // the JSON parser
+    candidates.retain(|candidate| candidate[1..].trim_start().starts_with('"'));
+    let before = candidates.len();
+    candidates.retain(|candidate| candidate.len() <= max_candidate_bytes);
+    let oversized = candidates.len() < before;
+    // Stable sort: equally sized candidates keep their order of appearance
+    candidates.sort_by_key(|candidate| std::cmp::Reverse(candidate.len()));
+    (candidates, oversized)
 }
 
-#[derive(Clone)]
-pub struct GeminiCli {
-    tool_router: ToolRouter<GeminiCli>,
-    gemini_cli_command: String,
-    workspace: Option<String>,
+/// Longest stderr excerpt logged for successful runs
+const MAX_LOGGED_STDERR_CHARS: usize = 2000;
+
+/// Most candidates a request may ask for with `candidate_count`
+const MAX_CANDIDATE_COUNT: u32 = 8;
+
+/// Most prompts one `batch-prompt` call may run
+const MAX_BATCH_PROMPTS: usize = 32;
+
+/// Most models one `compare-models` call may compare
+const MAX_COMPARE_MODELS: usize = 8;
+
+/// `text` ending with a marker line when `truncated` bytes of it were discarded by an
+/// output limit
+fn mark_truncated(mut text: String, truncated: usize) -> String {
+    if truncated > 0 {
+        text.push_str(&format!("\n[... {} bytes truncated ...]", truncated));
+    }
+    text
 }
 
-#[tool_router]
-impl GeminiCli {
-    pub fn new(gemini_cli_command: String, workspace: Option<String>) -> Self {
-        Self {
-            tool_router: Self::tool_router(),
-            gemini_cli_command,
-            workspace,
-        }
+/// First `max_chars` characters of `text`, with an ellipsis when anything was cut
+fn truncate_chars(text: &str, max_chars: usize) -> std::borrow::Cow<'_, str> {
+    match text.char_indices().nth(max_chars) {
+        Some((end, _)) => format!("{}...", &text[..end]).into(),
+        None => text.into(),
     }
+}
 
-    #[tool(
-        name = "prompt-gemini",
-        description = "Send a prompt to Gemini CLI and return the JSON response"
-    )]
-    async fn prompt_gemini(
-        &self,
-        Parameters(args): Parameters<PromptGeminiArgs>,
-    ) -> Result<CallToolResult, McpError> {
-        // Parse command string to handle commands with arguments (e.g., "task ai:run")
-        let parts: Vec<&str> = self.gemini_cli_command.split_whitespace().collect();
-        if parts.is_empty() {
-            return Err(McpError::internal_error(
-                "empty_gemini_command",
-                Some(serde_json::json!({
-                    "error": "Gemini CLI command is empty"
-                })),
-            ));
-        }
+/// Lines kept and dropped by [`truncate_lines`]
+#[derive(Debug, PartialEq)]
+struct LineTruncation {
+    total_lines: usize,
+    kept_lines: usize,
+}
 
-        // Execute gemini-cli command
-        let mut cmd = Command::new(parts[0]);
-        if parts.len() > 1 {
-            cmd.args(&parts[1..]);
-        }
+/// Keep the first (or, with `from_tail`, last) `max_lines` lines of `output`, replacing the
+/// rest with a marker line. Returns `None` when the output already fits.
+fn truncate_lines(
+    output: &str,
+    max_lines: usize,
+    from_tail: bool,
+) -> Option<(String, LineTruncation)> {
+    let lines: Vec<&str> = output.lines().collect();
+    if lines.len() <= max_lines {
+        return None;
+    }
 
-        // For task runner, use -- separator to pass CLI args
-        if parts[0] == "task" {
-            cmd.arg("--")
-                .arg("--yolo")
-                .arg("--prompt")
-                .arg(&args.prompt)
-                .arg("--output-format")
-                .arg("json");
-        } else {
-            // For other commands, use --prompt flag directly
-            cmd.arg("--yolo")
-                .arg("--prompt")
-                .arg(&args.prompt)
-                .arg("--output-format")
-                .arg("json");
-        }
+    let marker = format!("[... {} lines truncated ...]", lines.len() - max_lines);
+    let truncated = if from_tail {
+        std::iter::once(marker.as_str())
+            .chain(lines[lines.len() - max_lines..].iter().copied())
+            .collect::<Vec<_>>()
+            .join("\n")
+    } else {
+        lines[..max_lines]
+            .iter()
+            .copied()
+            .chain(std::iter::once(marker.as_str()))
+            .collect::<Vec<_>>()
+            .join("\n")
+    };
 
-        // Use workspace from struct, falling back to environment variable
-        let workspace = self
-            .workspace
-            .as_ref()
-            .cloned()
-            .or_else(|| std::env::var("GEMINI_WORKSPACE").ok());
+    Some((
+        truncated,
+        LineTruncation {
+            total_lines: lines.len(),
+            kept_lines: max_lines,
+        },
+    ))
+}
 
-        if let Some(ws) = workspace {
-            cmd.env("GEMINI_WORKSPACE", ws);
-        }
+/// Parse `response` as JSON and return the value at `pointer`: strings as-is, anything
+/// else serialized as JSON
+fn select_json_pointer(response: &str, pointer: &str) -> Result<String, McpError> {
+    let value: serde_json::Value = serde_json::from_str(response.trim()).map_err(|e| {
+        McpError::internal_error(
+            "gemini_response_not_json",
+            Some(serde_json::json!({
+                "error": format!("response_json_pointer was set but the response is not JSON: {}", e),
+                "response": response,
+            })),
+        )
+    })?;
 
-        let output = cmd.output().await;
+    match value.pointer(pointer) {
+        Some(serde_json::Value::String(text)) => Ok(text.clone()),
+        Some(selected) => Ok(selected.to_string()),
+        None => Err(McpError::internal_error(
+            "gemini_json_pointer_not_found",
+            Some(serde_json::json!({
+                "error": format!("JSON pointer '{}' does not match any value in the response", pointer),
+                "pointer": pointer,
+                "response": value,
+            })),
+        )),
+    }
+}
 
-        match output {
-            Ok(output) => {
-                if output.status.success() {
-                    // Convert output to string, handling potential UTF-8 issues
-                    let raw_response = String::from_utf8_lossy(&output.stdout);
-                    let raw_response = raw_response.trim();
+/// Incremental output of [`GeminiCli::prompt_stream`]
+#[derive(Debug)]
+pub enum StreamEvent {
+    /// Text gemini-cli wrote to stdout
+    Output(String),
+    /// The execution ended; always the last event
+    Finished(Result<(), McpError>),
+}
 
-                    if raw_response.is_empty() {
-                        return Ok(CallToolResult::success(vec![Content::text(
-                            "Gemini CLI returned empty response".to_string(),
-                        )]));
-                    }
+/// Transcode subprocess output in `encoding` to UTF-8. Output that isn't valid in a
+/// non-UTF-8 encoding falls back to lossy UTF-8, in case gemini-cli wrote UTF-8 anyway.
+/// A byte order mark overrides `encoding`.
+fn decode_output<'a>(bytes: &'a [u8], encoding: &'static Encoding) -> std::borrow::Cow<'a, str> {
+    if encoding == encoding_rs::UTF_8 {
+        return String::from_utf8_lossy(bytes);
+    }
+    let (text, used, had_errors) = encoding.decode(bytes);
+    if had_errors {
+        tracing::warn!(
+            encoding = used.name(),
+            "Gemini CLI output is not valid in the configured encoding, decoding as UTF-8"
+        );
+        return String::from_utf8_lossy(bytes);
+    }
+    text
+}
 
-                    // Parse response as JSON
-                    match parse_gemini_output(raw_response) {
-                        ParseResult::JsonSuccess(json_response) => {
-                            // Check if there's an error in the JSON response
-                            if let Some(error) = json_response.error {
-                                return Err(McpError::internal_error(
-                                    "gemini_api_error",
-                                    Some(serde_json::json!({
-                                        "error_type": error.error_type,
-                                        "message": error.message,
-                                        "code": error.code,
-                                        "prompt": args.prompt
-                                    })),
-                                ));
-                            }
+/// Decode the next piece of streamed output with `decoder` (`last` flushes it)
+fn decode_chunk(decoder: &mut encoding_rs::Decoder, bytes: &[u8], last: bool) -> String {
+    let capacity = decoder
+        .max_utf8_buffer_length(bytes.len())
+        .unwrap_or(bytes.len() * 3 + 4);
+    let mut text = String::with_capacity(capacity);
+    let _ = decoder.decode_to_string(bytes, &mut text, last);
+    text
+}
 
-                            // Return the response content
-                            Ok(CallToolResult::success(vec![Content::text(
-                                json_response.response,
-                            )]))
-                        }
-                        ParseResult::ParseError { raw_output, error } => {
-                            // JSON parsing failed, return error with raw output for debugging
-                            Err(McpError::internal_error(
-                                "gemini_json_parse_error",
-                                Some(serde_json::json!({
-                                    "parse_error": error,
-                                    "raw_output": raw_output,
-                                    "prompt": args.prompt
-                                })),
-                            ))
-                        }
-                    }
-                } else {
-                    // Handle non-zero exit code
-                    let stderr = String::from_utf8_lossy(&output.stderr);
-                    let _error_msg = if stderr.trim().is_empty() {
-                        format!(
-                            "Gemini CLI exited with code {}",
-                            output.status.code().unwrap_or(-1)
-                        )
-                    } else {
-                        format!("Gemini CLI error: {}", stderr.trim())
-                    };
+/// Remove and return the longest valid UTF-8 prefix of `pending`, leaving an incomplete
+/// trailing character for the next read. Invalid bytes are replaced.
+fn take_utf8_prefix(pending: &mut Vec<u8>) -> String {
+    let valid_up_to = match std::str::from_utf8(pending) {
+        Ok(_) => pending.len(),
+        Err(e) if e.error_len().is_none() => e.valid_up_to(),
+        Err(_) => {
+            let text = String::from_utf8_lossy(pending).into_owned();
+            pending.clear();
+            return text;
+        }
+    };
+    let rest = pending.split_off(valid_up_to);
+    String::from_utf8(std::mem::replace(pending, rest)).expect("validated above")
+}
 
-                    Err(McpError::internal_error(
-                        "gemini_cli_execution_failed",
-                        Some(serde_json::json!({
-                            "exit_code": output.status.code(),
-                            "stderr": stderr.trim(),
-                            "prompt": args.prompt
-                        })),
-                    ))
-                }
-            }
-            Err(e) => {
-                // Handle command execution failure (e.g., command not found)
-                let _error_msg = if e.kind() == std::io::ErrorKind::NotFound {
-                    format!(
-                        "Gemini CLI command '{}' not found. Please ensure it's installed and accessible.",
-                        self.gemini_cli_command
-                    )
-                } else {
-                    format!("Failed to execute Gemini CLI: {}", e)
-                };
+/// Add `value` under `key` to the result's `_meta`, keeping existing entries
+fn insert_meta(result: &mut CallToolResult, key: &str, value: serde_json::Value) {
+    result
+        .meta
+        .get_or_insert_with(Meta::new)
+        .insert(key.to_string(), value);
+}
 
-                Err(McpError::internal_error(
-                    "gemini_cli_command_failed",
-                    Some(serde_json::json!({
-                        "command": self.gemini_cli_command,
-                        "error": e.to_string(),
-                        "prompt": args.prompt
-                    })),
-                ))
+/// Split `text` at triple-backtick fences into text items and code items. Code items hold
+/// the code without its fences and carry `_meta` `{"content_type": "code", "language": ...}`;
+/// an unclosed fence is left in the text.
+fn split_code_blocks(text: &str) -> Vec<Content> {
+    fn push_text(contents: &mut Vec<Content>, text: &str) {
+        let text = text.trim_matches(['\r', '\n']);
+        if !text.trim().is_empty() {
+            contents.push(Content::text(text));
+        }
+    }
+
+    let mut contents = Vec::new();
+    let mut text_start = 0;
+    let mut fence: Option<(usize, usize, &str)> = None;
+    let mut offset = 0;
+    for line in text.split_inclusive('\n') {
+        let line_start = offset;
+        offset += line.len();
+        let trimmed = line.trim();
+        let Some(info) = trimmed.strip_prefix("```") else {
+            continue;
+        };
+        match fence {
+            None => {
+                let language = info.split_whitespace().next().unwrap_or("");
+                fence = Some((line_start, offset, language));
             }
+            Some((fence_start, code_start, language)) if info.is_empty() => {
+                push_text(&mut contents, &text[text_start..fence_start]);
+                let code = text[code_start..line_start]
+                    .strip_suffix('\n')
+                    .map(|code| code.strip_suffix('\r').unwrap_or(code))
+                    .unwrap_or(&text[code_start..line_start]);
+                let mut meta = Meta::new();
+                meta.insert("content_type".to_string(), serde_json::json!("code"));
+                if !language.is_empty() {
+                    meta.insert("language".to_string(), serde_json::json!(language));
+                }
+                let mut content = Content::text(code);
+                if let RawContent::Text(text_content) = &mut content.raw {
+                    text_content.meta = Some(meta);
+                }
+                contents.push(content);
+                text_start = offset;
+                fence = None;
+            }
+            Some(_) => {}
         }
     }
+    push_text(&mut contents, &text[text_start..]);
+    if contents.is_empty() {
+        contents.push(Content::text(text));
+    }
+    contents
 }
 
-#[tool_handler]
-impl ServerHandler for GeminiCli {
-    fn get_info(&self) -> ServerInfo {
-        ServerInfo {
-            protocol_version: ProtocolVersion::V_2024_11_05,
-            capabilities: ServerCapabilities::builder().enable_tools().build(),
-            server_info: Implementation::from_build_env(),
-            instructions: Some(
-                "This server provides Gemini CLI integration with structured JSON output. \
-                Tools: prompt_gemini (send prompts to Gemini CLI and receive JSON responses)."
-                    .to_string(),
-            ),
+/// Reasoning from a response's `thoughts` as text. Parts that are objects contribute their
+/// `text` or `description` field (or their JSON when they have neither).
+fn thoughts_text(thoughts: &serde_json::Value) -> Option<String> {
+    let part_text = |part: &serde_json::Value| match part {
+        serde_json::Value::String(text) => text.clone(),
+        serde_json::Value::Object(fields) => fields
+            .get("text")
+            .or_else(|| fields.get("description"))
+            .and_then(|text| text.as_str())
+            .map_or_else(|| part.to_string(), str::to_string),
+        other => other.to_string(),
+    };
+    let text = match thoughts {
+        serde_json::Value::Null => return None,
+        serde_json::Value::Array(parts) => parts
+            .iter()
+            .map(part_text)
+            .filter(|text| !text.trim().is_empty())
+            .collect::<Vec<_>>()
+            .join("\n\n"),
+        other => part_text(other),
+    };
+    let text = text.trim();
+    (!text.is_empty()).then(|| text.to_string())
+}
+
+/// The response as one text item, or split at code fences when the request asks for it
+fn response_contents(text: &str, args: &PromptGeminiArgs) -> Vec<Content> {
+    let mut contents = if args.split_code_blocks.unwrap_or(false) {
+        split_code_blocks(text)
+    } else {
+        vec![Content::text(text)]
+    };
+    // Code blocks keep their own `_meta`, whose language is more specific
+    if let Some(content_type) = &args.content_type {
+        for content in &mut contents {
+            if let RawContent::Text(text_content) = &mut content.raw
+                && text_content.meta.is_none()
+            {
+                let mut meta = Meta::new();
+                meta.insert("mime_type".to_string(), serde_json::json!(content_type));
+                text_content.meta = Some(meta);
+            }
         }
     }
+    contents
+}
 
-    async fn initialize(
-        &self,
-        _request: InitializeRequestParam,
-        _context: RequestContext<RoleServer>,
-    ) -> Result<InitializeResult, McpError> {
-        Ok(self.get_info())
+/// Top-level media types a `content_type` may have
+const MEDIA_TYPES: &[&str] = &[
+    "application",
+    "audio",
+    "font",
+    "image",
+    "message",
+    "model",
+    "multipart",
+    "text",
+    "video",
+];
+
+/// Whether `value` looks like a MIME type: a known top-level type and a subtype made of
+/// RFC 6838 name characters, optionally followed by `; name=value` parameters
+fn is_mime_type(value: &str) -> bool {
+    fn is_name(name: &str) -> bool {
+        name.len() <= 127
+            && name.starts_with(|c: char| c.is_ascii_alphanumeric())
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "!#$&-^_.+".contains(c))
     }
+
+    let mut parts = value.split(';');
+    let essence = parts.next().unwrap_or_default().trim();
+    let Some((media_type, subtype)) = essence.split_once('/') else {
+        return false;
+    };
+    MEDIA_TYPES.contains(&media_type.to_ascii_lowercase().as_str())
+        && is_name(subtype)
+        && parts.all(|parameter| {
+            parameter
+                .split_once('=')
+                .is_some_and(|(name, value)| is_name(name.trim()) && !value.trim().is_empty())
+        })
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use rmcp::handler::server::wrapper::Parameters;
+/// Log filter directive that enables debug logging within the span of a prompt-gemini
+/// request made with `debug: true`
+pub const DEBUG_REQUEST_DIRECTIVE: &str = "mcp_gemini_cli[prompt_gemini{debug=true}]=debug";
 
-    #[tokio::test]
-    async fn test_prompt_gemini_command_not_found() {
-        let gemini_cli = GeminiCli::new("nonexistent_command_12345".to_string(), None);
-        let args = PromptGeminiArgs {
-            prompt: "test prompt".to_string(),
-        };
+/// Values accepted for `approval_mode`, with the gemini-cli flags each one passes
+const APPROVAL_MODES: &[(&str, &[&str])] = &[
+    ("manual", &["--approval-mode", "default"]),
+    ("auto", &["--approval-mode", "auto_edit"]),
+    ("yolo", &["--yolo"]),
+];
 
-        let result = gemini_cli.prompt_gemini(Parameters(args)).await;
-        assert!(result.is_err());
+/// Approval mode allowed in read-only mode, where gemini-cli must not edit files unasked
+const READ_ONLY_APPROVAL_MODE: &str = "manual";
+
+/// Values accepted for `output_format`
+fn output_format_names() -> Vec<String> {
+    OutputFormat::value_variants()
+        .iter()
+        .filter_map(|format| format.to_possible_value())
+        .map(|value| value.get_name().to_string())
+        .collect()
+}
+
+/// Values accepted for `approval_mode`
+fn approval_mode_names() -> Vec<&'static str> {
+    APPROVAL_MODES.iter().map(|(name, _)| *name).collect()
+}
+
+/// Error for a gemini-cli process that could not be spawned. When the program was not
+/// found, the data also says how it was looked up (see [`command_lookup`]).
+fn command_failed(command: &str, error: &std::io::Error, prompt: &str) -> McpError {
+    let mut data = serde_json::json!({
+        "command": command,
+        "error": error.to_string(),
+        "kind": format!("{:?}", error.kind()),
+        "prompt": prompt
+    });
+    if error.kind() == std::io::ErrorKind::NotFound
+        && let Some(program) = command.split_whitespace().next()
+    {
+        data["lookup"] = command_lookup(program);
     }
+    McpError::internal_error("gemini_cli_command_failed", Some(data))
+}
 
-    #[tokio::test]
+/// How `program` is resolved, to make deployment mistakes obvious: relative paths depend
+/// on the server's working directory, bare names on `PATH`
+fn command_lookup(program: &str) -> serde_json::Value {
+    let cwd = std::env::current_dir().ok();
+    let path = Path::new(program);
+    if path.is_absolute() {
+        serde_json::json!({
+            "path_kind": "absolute",
+            "exists": path.exists(),
+        })
+    } else if program.contains(std::path::MAIN_SEPARATOR) || program.contains('/') {
+        serde_json::json!({
+            "path_kind": "relative",
+            "working_directory": cwd,
+            "resolved_path": cwd.as_ref().map(|cwd| cwd.join(path)),
+            "exists": cwd.is_some_and(|cwd| cwd.join(path).exists()),
+        })
+    } else {
+        let searched: Vec<PathBuf> = std::env::var_os("PATH")
+            .map(|path| std::env::split_paths(&path).collect())
+            .unwrap_or_default();
+        serde_json::json!({
+            "path_kind": "path_lookup",
+            "working_directory": cwd,
+            "searched_directories": searched,
+        })
+    }
+}
+
+/// `bytes` base64-encoded in a single text block carrying `_meta.encoding: "base64"`
+fn base64_result(bytes: &[u8]) -> CallToolResult {
+    let mut content = Content::text(base64::engine::general_purpose::STANDARD.encode(bytes));
+    if let RawContent::Text(text_content) = &mut content.raw {
+        let mut meta = Meta::new();
+        meta.insert("encoding".to_string(), serde_json::json!("base64"));
+        text_content.meta = Some(meta);
+    }
+    let mut result = CallToolResult::success(vec![content]);
+    insert_meta(&mut result, "encoding", serde_json::json!("base64"));
+    result
+}
+
+/// Where a tool call came from, for log correlation across clients
+#[derive(Debug, PartialEq)]
+struct RequestPeer {
+    /// Socket address of the HTTP client, or `stdio` when the call didn't arrive over HTTP
+    address: String,
+    /// `X-Forwarded-For` header, when a proxy sits in front of the server
+    forwarded_for: Option<String>,
+}
+
+impl RequestPeer {
+    /// Read the peer from the HTTP request parts the SSE transport attaches to each message
+    fn from_extensions(extensions: &Extensions) -> Self {
+        let Some(parts) = extensions.get::<axum::http::request::Parts>() else {
+            return Self {
+                address: "stdio".to_string(),
+                forwarded_for: None,
+            };
+        };
+        Self {
+            address: parts
+                .extensions
+                .get::<axum::extract::ConnectInfo<std::net::SocketAddr>>()
+                .map_or_else(|| "unknown".to_string(), |info| info.0.to_string()),
+            forwarded_for: parts
+                .headers
+                .get("x-forwarded-for")
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string),
+        }
+    }
+}
+
+/// Whether a failed invocation is worth retrying with the fallback command:
+/// the command could not be found, or the Gemini API reported a server-side (5xx) error.
+/// Auth and invalid-request errors would fail the same way on any command.
+fn is_failover_error(error: &McpError) -> bool {
+    let data = error.data.as_ref();
+    match error.message.as_ref() {
+        "gemini_cli_command_failed" => {
+            data.and_then(|d| d.get("kind")).and_then(|k| k.as_str()) == Some("NotFound")
+        }
+        "gemini_api_error" => data
+            .and_then(|d| d.get("code"))
+            .and_then(|c| c.as_i64())
+            .is_some_and(|code| code >= 500),
+        _ => false,
+    }
+}
+
+/// A prompt exceeding one of the configured length limits
+#[derive(Debug)]
+struct PromptTooLong {
+    unit: &'static str,
+    actual: usize,
+    max: usize,
+}
+
+impl std::fmt::Display for PromptTooLong {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Prompt is {} {}, exceeding the maximum of {}",
+            self.actual, self.unit, self.max
+        )
+    }
+}
+
+#[derive(Clone)]
+pub struct GeminiCli {
+    tool_router: Arc<ToolRouter<GeminiCli>>,
+    /// Configuration snapshot; refreshed from `state` at the start of each tool call
+    config: Arc<GeminiCliConfig>,
+    state: Arc<ServerState>,
+    /// Stats reported by gemini-cli over this session's prompts
+    session_stats: Arc<Mutex<SessionStats>>,
+    /// MCP log notifications to this session's client
+    client_log: Arc<ClientLogger>,
+}
+
+#[tool_router]
+impl GeminiCli {
+    /// Convenience constructor for a single command with default settings
+    #[cfg(test)]
+    pub fn new(gemini_cli_command: String, workspace: Option<String>) -> Self {
+        Self::with_config(Arc::new(GeminiCliConfig {
+            gemini_cli_command,
+            workspace,
+            ..Default::default()
+        }))
+    }
+
+    /// Create a standalone session with its own server state
+    #[cfg(test)]
+    pub fn with_config(config: Arc<GeminiCliConfig>) -> Self {
+        Self::with_state(Arc::new(ServerState::new((*config).clone())))
+    }
+
+    /// Create a session sharing server-wide state (configuration, circuit breaker, ...)
+    /// with other sessions
+    pub fn with_state(state: Arc<ServerState>) -> Self {
+        let config = state.config();
+        let mut tool_router = Self::tool_router();
+        if let Some(description) = &config.tool_description
+            && let Some(route) = tool_router.map.get_mut("prompt-gemini")
+        {
+            route.attr.description = Some(description.clone().into());
+        }
+        if state.response_cache.is_none() {
+            tool_router.remove_route("clear-cache");
+        }
+
+        Self {
+            tool_router: Arc::new(tool_router),
+            config,
+            state,
+            session_stats: Arc::default(),
+            client_log: Arc::default(),
+        }
+    }
+
+    /// Run a prompt exactly as the `prompt-gemini` tool does, for callers outside MCP
+    /// (e.g. the OpenAI-compatible endpoint). Dropping the future kills the subprocess.
+    pub async fn prompt(&self, args: PromptGeminiArgs) -> Result<CallToolResult, McpError> {
+        self.prompt_gemini(Parameters(args), CancellationToken::new())
+            .await
+    }
+
+    /// Run a prompt directly, bypassing the response cache, queue, spawn throttle, metrics,
+    /// audit log and circuit breaker, e.g. for the startup self-test, whose prompts must
+    /// not count as production traffic
+    pub async fn probe_prompt(&self, args: PromptGeminiArgs) -> Result<CallToolResult, McpError> {
+        let this = self.with_current_config();
+        let (args, command) = this.prepare_prompt(args)?;
+        this.run_command(&command, &args)
+            .await
+            .map_err(|e| this.redact_error(e))
+    }
+
+    /// Run a prompt with gemini-cli's text output, delivering stdout as it is written.
+    /// Validation errors are returned directly; anything after that arrives as the final
+    /// [`StreamEvent::Finished`]. Dropping the receiver kills the subprocess.
+    pub async fn prompt_stream(
+        &self,
+        args: PromptGeminiArgs,
+    ) -> Result<mpsc::Receiver<StreamEvent>, McpError> {
+        let this = self.with_current_config();
+        let original_args = args.clone();
+        let (mut args, command) = this
+            .prepare_prompt(args)
+            .map_err(|e| this.redact_error(e))?;
+        let images = this
+            .prepare_images(&mut args)
+            .map_err(|e| this.redact_error(e))?;
+
+        let (sender, receiver) = mpsc::channel(16);
+        tokio::spawn(async move {
+            // Temporary image files must outlive the execution
+            let _images = images;
+            let started = Instant::now();
+            let _active = this.state.active_requests.register(
+                "prompt-stream",
+                &original_args.prompt,
+                this.model(&args),
+            );
+            let result = async {
+                // Throttle before taking a slot, so a waiting launch does not hold one
+                this.wait_for_spawn_slot().await;
+                let _permit = this.acquire_execution().await?;
+                let result = this.stream_command(&command, &args, &sender).await;
+                match &result {
+                    Ok(_) => this.state.circuit_breaker.record_success(),
+                    Err(_) => this.state.circuit_breaker.record_failure(),
+                }
+                result
+            }
+            .await
+            .map_err(|e| this.redact_error(e));
+            this.state
+                .metrics
+                .record(started.elapsed(), call_outcome(&result));
+            this.audit(
+                "prompt-stream",
+                &original_args,
+                result.as_ref().err(),
+                started.elapsed(),
+            );
+            let _ = sender.send(StreamEvent::Finished(result)).await;
+        });
+        Ok(receiver)
+    }
+
+    /// Spawn gemini-cli and forward its stdout to `sender` until it exits, the timeout
+    /// elapses or the receiver goes away
+    async fn stream_command(
+        &self,
+        command: &str,
+        args: &PromptGeminiArgs,
+        sender: &mpsc::Sender<StreamEvent>,
+    ) -> Result<(), McpError> {
+        let mut cmd = self.build_command(command, args, OutputFormat::Text)?;
+        let meter = UsageMeter::start();
+        let mut stream = spawn_streaming(&mut cmd, self.config.stream_buffering)
+            .map_err(|e| command_failed(command, &e, &args.prompt))?;
+        // Declared after the stream, so it is dropped (killing the group) first
+        let mut group = ProcessGroupGuard::new(stream.child(), self.config.kill_process_group);
+
+        let forward_stdout = async {
+            let mut pending = Vec::new();
+            let mut redactor = StreamRedactor::new(&self.config.output_redactor);
+            // Other encodings go through a decoder that carries partial characters over
+            let mut decoder = (self.config.output_encoding != encoding_rs::UTF_8)
+                .then(|| self.config.output_encoding.new_decoder());
+            loop {
+                let chunk = tokio::select! {
+                    chunk = stream.next() => chunk,
+                    _ = sender.closed() => return Ok(false),
+                };
+                let Some(chunk) = chunk.transpose()? else {
+                    break;
+                };
+                let text = match &mut decoder {
+                    Some(decoder) => decode_chunk(decoder, &chunk, false),
+                    None => {
+                        pending.extend_from_slice(&chunk);
+                        take_utf8_prefix(&mut pending)
+                    }
+                };
+                let text = redactor.push(&text);
+                if !text.is_empty() && sender.send(StreamEvent::Output(text)).await.is_err() {
+                    return Ok(false);
+                }
+            }
+            let rest = match &mut decoder {
+                Some(decoder) => decode_chunk(decoder, &[], true),
+                None => String::from_utf8_lossy(&pending).into_owned(),
+            };
+            let rest = redactor.push(&rest) + &redactor.finish();
+            if !rest.is_empty() {
+                let _ = sender.send(StreamEvent::Output(rest)).await;
+            }
+            Ok::<_, std::io::Error>(true)
+        };
+        let timeout = self.execution_timeout(args);
+        let deadline = async {
+            match timeout {
+                Some(timeout) => tokio::time::sleep(timeout).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::pin!(deadline);
+        let timed_out = || {
+            tracing::warn!(
+                command,
+                timeout_ms = timeout.map(|t| t.as_millis() as u64),
+                "Gemini CLI timed out and was killed"
+            );
+            McpError::internal_error(
+                "gemini_cli_timeout",
+                Some(serde_json::json!({
+                    "timeout_secs": self.timeout_secs(args),
+                    "deadline_ms": args.deadline_ms.and(timeout.map(|t| t.as_millis() as u64)),
+                    "prompt": args.prompt
+                })),
+            )
+        };
+
+        // Returning early drops the stream and with it the child, which kills it
+        // (`kill_on_drop`)
+        let forwarded = tokio::select! {
+            forwarded = forward_stdout => forwarded,
+            _ = &mut deadline => return Err(timed_out()),
+        };
+        if !forwarded.map_err(|e| command_failed(command, &e, &args.prompt))? {
+            tracing::info!("Stream receiver closed, stopping Gemini CLI");
+            return Err(McpError::internal_error(
+                "gemini_request_cancelled",
+                Some(serde_json::json!({
+                    "error": "The client cancelled the request or disconnected"
+                })),
+            ));
+        }
+
+        // A process that closed stdout may still hold stderr open, so the deadline
+        // applies until stderr is drained too
+        let finished = tokio::select! {
+            finished = stream.finish() => finished,
+            _ = &mut deadline => return Err(timed_out()),
+        };
+        let (status, stderr) = finished.map_err(|e| command_failed(command, &e, &args.prompt))?;
+        group.disarm();
+        self.session_stats.lock().unwrap().last_resource_usage = Some(meter.finish());
+        if !status.success() {
+            let stderr = decode_output(&stderr, self.config.output_encoding);
+            return Err(McpError::internal_error(
+                "gemini_cli_execution_failed",
+                Some(serde_json::json!({
+                    "exit_code": status.code(),
+                    "stderr": stderr.trim(),
+                    "prompt": args.prompt
+                })),
+            ));
+        }
+        Ok(())
+    }
+
+    /// A handle pinned to the current configuration, so a reload mid-request
+    /// does not change the settings an in-flight call runs with
+    fn with_current_config(&self) -> Self {
+        Self {
+            config: self.state.config(),
+            ..self.clone()
+        }
+    }
+
+    /// Count a prompt-gemini call against `max_in_flight_per_session`
+    fn enter_session(&self) -> Result<InFlightGuard, McpError> {
+        let mut session_stats = self.session_stats.lock().unwrap();
+        if let Some(max) = self.config.max_in_flight_per_session
+            && session_stats.in_flight >= max
+        {
+            return Err(McpError::internal_error(
+                "gemini_session_busy",
+                Some(serde_json::json!({
+                    "error": "This session already has the maximum number of prompts running",
+                    "max_in_flight_per_session": max,
+                })),
+            ));
+        }
+        session_stats.in_flight += 1;
+        Ok(InFlightGuard(self.session_stats.clone()))
+    }
+
+    /// Append a record for a finished prompt execution to the audit log, if enabled
+    fn audit(
+        &self,
+        tool: &'static str,
+        args: &PromptGeminiArgs,
+        error: Option<&McpError>,
+        duration: Duration,
+    ) {
+        if let Some(audit_log) = &self.state.audit_log {
+            let command = self
+                .resolve_command(args.backend.as_deref())
+                .ok()
+                .map(|command| self.redact_command_line(command));
+            audit_log.record(AuditEntry {
+                tool,
+                command: command.as_deref(),
+                prompt: &args.prompt,
+                model: self.model(args),
+                outcome: error.map_or("success", |e| &e.message),
+                duration,
+            });
+        }
+    }
+
+    /// Resolve the command to run for the requested backend, falling back to the
+    /// configured default backend and finally to `gemini_cli_command`
+    fn resolve_command(&self, backend: Option<&str>) -> Result<&str, String> {
+        match backend.or(self.config.default_backend.as_deref()) {
+            Some(name) => self
+                .config
+                .backends
+                .get(name)
+                .map(String::as_str)
+                .ok_or_else(|| format!("Unknown backend '{}'", name)),
+            None => Ok(&self.config.gemini_cli_command),
+        }
+    }
+
+    /// Model requested by `args`, falling back to the server default
+    fn model<'a>(&'a self, args: &'a PromptGeminiArgs) -> Option<&'a str> {
+        args.model
+            .as_deref()
+            .or(self.config.default_model.as_deref())
+    }
+
+    /// Output format requested by `args`, falling back to the server default. Unknown
+    /// values are rejected by [`GeminiCli::check_output_format`] before execution.
+    fn output_format(&self, args: &PromptGeminiArgs) -> OutputFormat {
+        args.output_format
+            .as_deref()
+            .and_then(|format| OutputFormat::from_str(format, false).ok())
+            .unwrap_or(self.config.default_output_format)
+    }
+
+    /// How long an execution for `args` may run: the request's deadline capped at the
+    /// server maximum, otherwise the server timeout
+    fn execution_timeout(&self, args: &PromptGeminiArgs) -> Option<Duration> {
+        match args.deadline_ms {
+            Some(deadline_ms) => Some(
+                Duration::from_millis(deadline_ms)
+                    .min(Duration::from_secs(self.config.max_deadline_secs)),
+            ),
+            None => self.timeout_secs(args).map(Duration::from_secs),
+        }
+    }
+
+    /// Server timeout for the request's model: its `--model-timeout` when configured,
+    /// otherwise the global `--timeout-secs`
+    fn timeout_secs(&self, args: &PromptGeminiArgs) -> Option<u64> {
+        self.model(args)
+            .and_then(|model| self.config.model_timeouts.get(model).copied())
+            .or(self.config.timeout_secs)
+    }
+
+    /// Reject an `output_format` other than the supported ones, which would otherwise
+    /// silently fall back to another format
+    fn check_output_format(args: &PromptGeminiArgs) -> Result<(), String> {
+        match args.output_format.as_deref() {
+            Some(format) if OutputFormat::from_str(format, false).is_err() => Err(format!(
+                "Unsupported output_format '{}'; expected one of: {}",
+                format,
+                output_format_names().join(", ")
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Reject a `candidate_count` out of range, or above 1 when the candidates can't be
+    /// requested or returned separately
+    fn check_candidate_count(&self, args: &PromptGeminiArgs) -> Result<(), String> {
+        let Some(count) = args.candidate_count else {
+            return Ok(());
+        };
+        if !(1..=MAX_CANDIDATE_COUNT).contains(&count) {
+            return Err(format!(
+                "candidate_count must be between 1 and {}",
+                MAX_CANDIDATE_COUNT
+            ));
+        }
+        if count > 1 && self.config.candidates_flag.is_none() {
+            return Err(
+                "candidate_count above 1 is not supported: the server has no --candidates-flag"
+                    .to_string(),
+            );
+        }
+        if count > 1 && self.output_format(args) != OutputFormat::Json {
+            return Err("candidate_count above 1 needs the json output format".to_string());
+        }
+        if count > 1 && args.response_json_pointer.is_some() {
+            return Err(
+                "candidate_count above 1 cannot be combined with response_json_pointer".to_string(),
+            );
+        }
+        Ok(())
+    }
+
+    /// Reject an unknown `approval_mode`, or one that would let gemini-cli edit files
+    /// when the server is read-only
+    fn check_approval_mode(&self, args: &PromptGeminiArgs) -> Result<(), String> {
+        let Some(mode) = args.approval_mode.as_deref() else {
+            return Ok(());
+        };
+        if !APPROVAL_MODES.iter().any(|(name, _)| *name == mode) {
+            return Err(format!(
+                "Unsupported approval_mode '{}'; expected one of: {}",
+                mode,
+                approval_mode_names().join(", ")
+            ));
+        }
+        if self.config.read_only && mode != READ_ONLY_APPROVAL_MODE {
+            return Err(format!(
+                "approval_mode '{}' is not allowed in read-only mode; only '{}' is",
+                mode, READ_ONLY_APPROVAL_MODE
+            ));
+        }
+        Ok(())
+    }
+
+    /// A request may turn the sandbox on, but not off when the server runs with `--sandbox`
+    fn check_sandbox(&self, args: &PromptGeminiArgs) -> Result<(), String> {
+        if self.config.default_sandbox && args.sandbox == Some(false) {
+            return Err(
+                "sandbox: false is not allowed; this server always runs Gemini CLI in its sandbox"
+                    .to_string(),
+            );
+        }
+        Ok(())
+    }
+
+    /// Workspace passed to gemini-cli: the configured one, falling back to the
+    /// GEMINI_WORKSPACE environment variable
+    fn workspace(&self) -> Result<Option<String>, McpError> {
+        let Some(workspace) = self
+            .config
+            .workspace
+            .clone()
+            .or_else(|| std::env::var("GEMINI_WORKSPACE").ok())
+        else {
+            return Ok(None);
+        };
+        self.allowed_workspace(workspace).map(Some)
+    }
+
+    /// `workspace`, canonicalized, when it is under an allowed root. This is the single
+    /// place workspaces are checked against the allowed roots.
+    fn allowed_workspace(&self, workspace: String) -> Result<String, McpError> {
+        let roots = &self.config.allowed_workspace_roots;
+        if roots.is_empty() {
+            return Ok(workspace);
+        }
+
+        // The workspace comes from the server's configuration, not the request
+        let not_allowed = |error: String| {
+            McpError::internal_error(
+                "gemini_workspace_not_allowed",
+                Some(serde_json::json!({
+                    "error": error,
+                    "workspace": workspace,
+                    "allowed_roots": roots,
+                })),
+            )
+        };
+        // Canonicalizing resolves `..` and symlinks before the prefix check
+        let canonical = std::fs::canonicalize(&workspace)
+            .map_err(|e| not_allowed(format!("Workspace cannot be resolved: {}", e)))?;
+        if !roots.iter().any(|root| canonical.starts_with(root)) {
+            return Err(not_allowed(
+                "Workspace is not under any allowed workspace root".to_string(),
+            ));
+        }
+
+        Ok(canonical.to_string_lossy().into_owned())
+    }
+
+    /// Templates from the prompts directory; none when it is not configured
+    fn prompt_templates(&self) -> Result<Vec<PromptTemplate>, McpError> {
+        let Some(dir) = &self.config.prompts_dir else {
+            return Ok(Vec::new());
+        };
+        prompts::load_prompts(dir).map_err(|error| {
+            McpError::internal_error(
+                "gemini_prompts_unavailable",
+                Some(serde_json::json!({ "prompts_dir": dir, "error": error })),
+            )
+        })
+    }
+
+    /// Fill the named template with `arguments` for `prompts/get`. Non-string argument
+    /// values are substituted as JSON.
+    fn get_prompt_template(
+        &self,
+        name: &str,
+        arguments: Option<JsonObject>,
+    ) -> Result<GetPromptResult, McpError> {
+        let templates = self.prompt_templates()?;
+        let Some(template) = templates.iter().find(|template| template.name == name) else {
+            return Err(McpError::invalid_params(
+                "gemini_prompt_not_found",
+                Some(serde_json::json!({
+                    "name": name,
+                    "available": templates.iter().map(|template| &template.name).collect::<Vec<_>>(),
+                })),
+            ));
+        };
+        let variables: HashMap<String, String> = arguments
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(key, value)| match value {
+                serde_json::Value::String(value) => (key, value),
+                value => (key, value.to_string()),
+            })
+            .collect();
+        let text = template.render(&variables).map_err(|missing| {
+            McpError::invalid_params(
+                "gemini_prompt_missing_arguments",
+                Some(serde_json::json!({
+                    "name": name,
+                    "missing_arguments": missing.0,
+                })),
+            )
+        })?;
+        Ok(GetPromptResult {
+            description: None,
+            messages: vec![PromptMessage::new_text(PromptMessageRole::User, text)],
+        })
+    }
+
+    /// Directory gemini-cli runs in: the workspace, or without one the server's own
+    /// directory. Either way it must be under an allowed root.
+    fn workspace_dir(&self) -> Result<PathBuf, McpError> {
+        if let Some(workspace) = self.workspace()? {
+            return Ok(PathBuf::from(workspace));
+        }
+        let current_dir = std::env::current_dir().map_err(|e| {
+            McpError::internal_error(
+                "gemini_workspace_unavailable",
+                Some(serde_json::json!({ "error": e.to_string() })),
+            )
+        })?;
+        self.allowed_workspace(current_dir.to_string_lossy().into_owned())
+            .map(PathBuf::from)
+    }
+
+    /// The client's prompt with template variables substituted, which validation applies to
+    fn render_template(&self, args: &PromptGeminiArgs) -> Result<String, MissingVariables> {
+        let strict = args.strict_templating.unwrap_or(false);
+        Ok(match &args.variables {
+            Some(variables) => template::render(&args.prompt, variables, strict)?,
+            None if strict => template::render(&args.prompt, &HashMap::new(), strict)?,
+            None => args.prompt.clone(),
+        })
+    }
+
+    /// Wrap a rendered prompt in the system prompt and the server's prefix and suffix
+    fn wrap_prompt(&self, mut prompt: String, args: &PromptGeminiArgs) -> String {
+        // gemini-cli has no system prompt flag, so the instruction leads the prompt
+        let system_prompt = args
+            .system_prompt
+            .as_deref()
+            .or(self.config.system_prompt.as_deref())
+            .map(str::trim)
+            .unwrap_or_default();
+        let prefix = self.config.prompt_prefix.as_deref().unwrap_or_default();
+        let suffix = self.config.prompt_suffix.as_deref().unwrap_or_default();
+        // Without a wrapper flag the language is asked for in the prompt itself
+        let language = match (&self.config.language_flag, args.language.as_deref()) {
+            (None, Some(code)) => languages::language_name(code)
+                .map(|name| format!("Respond in {} ({}).", name, code))
+                .unwrap_or_default(),
+            _ => String::new(),
+        };
+        if !system_prompt.is_empty()
+            || !prefix.is_empty()
+            || !suffix.is_empty()
+            || !language.is_empty()
+        {
+            tracing::debug!(
+                prefix,
+                suffix,
+                language,
+                "Wrapping prompt with the system prompt, server prefix/suffix and language instruction"
+            );
+            // The language instruction belongs to the request, so the operator's suffix stays last
+            prompt = [system_prompt, prefix, prompt.as_str(), &language, suffix]
+                .into_iter()
+                .filter(|part| !part.is_empty())
+                .collect::<Vec<_>>()
+                .join("\n\n");
+        }
+        prompt
+    }
+
+    /// The first `--deny-prompt-pattern` the prompt matches
+    fn denied_pattern(&self, prompt: &str) -> Option<&regex::Regex> {
+        self.config
+            .deny_prompt_patterns
+            .iter()
+            .find(|pattern| pattern.is_match(prompt))
+    }
+
+    /// Check the prompt against the configured character and byte limits
+    fn check_prompt_length(&self, prompt: &str) -> Result<(), PromptTooLong> {
+        if let Some(max) = self.config.max_prompt_chars {
+            let actual = prompt.chars().count();
+            if actual > max {
+                return Err(PromptTooLong {
+                    unit: "characters",
+                    actual,
+                    max,
+                });
+            }
+        }
+
+        if let Some(max) = self.config.max_prompt_bytes
+            && prompt.len() > max
+        {
+            return Err(PromptTooLong {
+                unit: "bytes",
+                actual: prompt.len(),
+                max,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Validation rules applied to every prompt before gemini-cli is spawned.
+    /// Returns the list of reasons the prompt was rejected (empty when valid).
+    fn prompt_validation_errors(&self, args: &PromptGeminiArgs) -> Vec<String> {
+        let mut reasons = Vec::new();
+
+        if args.prompt.trim().is_empty() {
+            reasons.push("Prompt is empty".to_string());
+        }
+
+        // NUL bytes cannot be passed through argv and would fail at spawn time
+        if args.prompt.contains('\0') {
+            reasons.push("Prompt contains NUL bytes".to_string());
+        }
+
+        if let Err(too_long) = self.check_prompt_length(&args.prompt) {
+            reasons.push(too_long.to_string());
+        }
+
+        if self.denied_pattern(&args.prompt).is_some() {
+            reasons.push("Prompt contains content this server does not allow".to_string());
+        }
+
+        if let Err(reason) = self.resolve_command(args.backend.as_deref()) {
+            reasons.push(reason);
+        }
+
+        if let Some(language) = &args.language
+            && languages::language_name(language).is_none()
+        {
+            reasons.push(format!(
+                "language '{}' is not an ISO 639-1 code (e.g. 'fr' or 'pt-BR')",
+                language
+            ));
+        }
+
+        if let Some(pointer) = &args.response_json_pointer
+            && !pointer.is_empty()
+            && !pointer.starts_with('/')
+        {
+            reasons.push(format!(
+                "response_json_pointer '{}' must be empty or start with '/'",
+                pointer
+            ));
+        }
+        if args.response_json_pointer.is_some() && self.output_format(args) == OutputFormat::Text {
+            reasons.push(
+                "response_json_pointer needs the json or yaml output format, not text".to_string(),
+            );
+        }
+
+        if let Some(content_type) = &args.content_type
+            && !is_mime_type(content_type)
+        {
+            reasons.push(format!(
+                "content_type '{}' is not a MIME type (e.g. 'text/markdown')",
+                content_type
+            ));
+        }
+
+        reasons.extend(self.check_candidate_count(args).err());
+
+        reasons
+    }
+
+    #[tool(
+        name = "validate-prompt",
+        description = "Check a prompt against the server's validation rules without running Gemini CLI"
+    )]
+    async fn validate_prompt(
+        &self,
+        Parameters(args): Parameters<PromptGeminiArgs>,
+    ) -> Result<CallToolResult, McpError> {
+        let this = self.with_current_config();
+        let mut args = args;
+        let mut reasons = Vec::new();
+        match this.render_template(&args) {
+            Ok(prompt) => args.prompt = prompt,
+            Err(missing) => reasons.push(missing.to_string()),
+        }
+        reasons.extend(this.prompt_validation_errors(&args));
+        reasons.extend(Self::check_output_format(&args).err());
+        reasons.extend(this.check_approval_mode(&args).err());
+        reasons.extend(this.check_sandbox(&args).err());
+        // The sizes are of what would be sent
+        args.prompt = this.wrap_prompt(args.prompt.clone(), &args);
+
+        Ok(CallToolResult::success(vec![Content::json(
+            serde_json::json!({
+                "valid": reasons.is_empty(),
+                "reasons": reasons,
+                "prompt_chars": args.prompt.chars().count(),
+                "estimated_tokens": estimate_tokens(&args.prompt),
+            }),
+        )?]))
+    }
+
+    #[tool(
+        name = "debug-args",
+        description = "Return prompt_gemini arguments as the server parsed them, without running Gemini CLI, to check how a client's input deserializes"
+    )]
+    async fn debug_args(
+        &self,
+        Parameters(args): Parameters<PromptGeminiArgs>,
+    ) -> Result<CallToolResult, McpError> {
+        Ok(CallToolResult::success(vec![Content::json(args)?]))
+    }
+
+    #[tool(
+        name = "estimate-tokens",
+        description = "Estimate how many tokens a prompt will use, with Gemini CLI's token counter when configured or a ~4 characters per token heuristic"
+    )]
+    async fn estimate_tokens(
+        &self,
+        Parameters(args): Parameters<PromptGeminiArgs>,
+    ) -> Result<CallToolResult, McpError> {
+        let this = self.with_current_config();
+        let mut args = args;
+        args.prompt = this.render_template(&args).map_err(|missing| {
+            McpError::invalid_params(
+                "gemini_template_error",
+                Some(serde_json::json!({
+                    "error": missing.to_string(),
+                    "missing_variables": missing.0,
+                })),
+            )
+        })?;
+        // The count-tokens command sends the prompt to the API, so it is held to the
+        // same rules as prompt-gemini
+        this.check_prompt_allowed(&args.prompt)?;
+        args.prompt = this.wrap_prompt(std::mem::take(&mut args.prompt), &args);
+
+        let counted = match &this.config.count_tokens_command {
+            Some(command) => Some(this.count_tokens(command, &args).await),
+            None => None,
+        };
+        let estimate = match counted {
+            Some(Ok(count)) => serde_json::json!({
+                "estimated_tokens": count,
+                "method": "count_tokens_command",
+            }),
+            Some(Err(reason)) => {
+                tracing::warn!(reason = %reason, "Token counting failed, using the heuristic");
+                serde_json::json!({
+                    "estimated_tokens": estimate_tokens(&args.prompt),
+                    "method": "heuristic",
+                    "fallback_reason": reason,
+                })
+            }
+            None => serde_json::json!({
+                "estimated_tokens": estimate_tokens(&args.prompt),
+                "method": "heuristic",
+            }),
+        };
+
+        let mut estimate = estimate;
+        estimate["prompt_chars"] = serde_json::json!(args.prompt.chars().count());
+        Ok(CallToolResult::success(vec![Content::json(estimate)?]))
+    }
+
+    #[tool(
+        name = "prompt-gemini",
+        description = "Send a prompt to Gemini CLI and return the response (parsed JSON by default, or raw text)"
+    )]
+    async fn prompt_gemini_tool(
+        &self,
+        args: Parameters<PromptGeminiArgs>,
+        ct: CancellationToken,
+        extensions: Extensions,
+    ) -> Result<CallToolResult, McpError> {
+        let peer = RequestPeer::from_extensions(&extensions);
+        // `debug` is matched by DEBUG_REQUEST_DIRECTIVE to log this request at debug level
+        let span = tracing::info_span!(
+            "prompt_gemini",
+            peer = %peer.address,
+            forwarded_for = peer.forwarded_for.as_deref(),
+            debug = args.0.debug.unwrap_or(false),
+        );
+        self.prompt_gemini(args, ct).instrument(span).await
+    }
+
+    /// The `prompt-gemini` tool, without the request's transport details
+    async fn prompt_gemini(
+        &self,
+        Parameters(args): Parameters<PromptGeminiArgs>,
+        ct: CancellationToken,
+    ) -> Result<CallToolResult, McpError> {
+        let started = Instant::now();
+        let this = self.with_current_config();
+        self.client_log
+            .log(
+                LoggingLevel::Info,
+                "prompt-gemini",
+                serde_json::json!({
+                    "event": "start",
+                    "prompt_chars": args.prompt.chars().count(),
+                    "backend": args.backend,
+                    "model": this.model(&args),
+                }),
+            )
+            .await;
+        let _active =
+            self.state
+                .active_requests
+                .register("prompt-gemini", &args.prompt, this.model(&args));
+        // The token fires when the client cancels the request or disconnects; dropping
+        // the in-flight future kills the gemini-cli subprocess (`kill_on_drop`)
+        let args_for_audit = args.clone();
+        let result = match this.enter_session() {
+            Ok(_in_flight) => tokio::select! {
+                result = this.coalesced_prompt(args) => result,
+                _ = ct.cancelled() => {
+                    tracing::info!("Prompt cancelled by the client, stopping Gemini CLI");
+                    Err(McpError::internal_error(
+                        "gemini_request_cancelled",
+                        Some(serde_json::json!({
+                            "error": "The client cancelled the request or disconnected"
+                        })),
+                    ))
+                }
+            },
+            Err(busy) => Err(busy),
+        };
+        self.state
+            .metrics
+            .record(started.elapsed(), call_outcome(&result));
+        this.audit(
+            "prompt-gemini",
+            &args_for_audit,
+            result.as_ref().err(),
+            started.elapsed(),
+        );
+        let duration_ms = started.elapsed().as_millis() as u64;
+        match &result {
+            Ok(_) => {
+                if self
+                    .state
+                    .request_log_sampler
+                    .sample(this.config.request_log_sampling)
+                {
+                    tracing::info!(
+                        duration_ms,
+                        model = this.model(&args_for_audit),
+                        "prompt-gemini request succeeded"
+                    );
+                }
+            }
+            Err(e) => {
+                tracing::warn!(duration_ms, model = this.model(&args_for_audit), error = %e.message, "prompt-gemini request failed")
+            }
+        }
+
+        let (level, error) = match &result {
+            Ok(_) => (LoggingLevel::Info, None),
+            Err(e) => (LoggingLevel::Error, Some(e.message.clone())),
+        };
+        self.client_log
+            .log(
+                level,
+                "prompt-gemini",
+                serde_json::json!({
+                    "event": "completed",
+                    "success": result.is_ok(),
+                    "error": error,
+                    "duration_ms": started.elapsed().as_millis() as u64,
+                }),
+            )
+            .await;
+        result
+    }
+
+    #[tool(
+        name = "ping",
+        description = "Check connectivity: returns the current timestamp and server version without running Gemini CLI"
+    )]
+    async fn ping(&self) -> Result<CallToolResult, McpError> {
+        let server = Implementation::from_build_env();
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+
+        Ok(CallToolResult::success(vec![Content::json(
+            serde_json::json!({
+                "timestamp_ms": timestamp_ms,
+                "server_name": server.name,
+                "server_version": server.version,
+            }),
+        )?]))
+    }
+
+    #[tool(
+        name = "compare-models",
+        description = "Send one prompt to several models in parallel and return each model's response, error and stats"
+    )]
+    async fn compare_models(
+        &self,
+        Parameters(args): Parameters<CompareModelsArgs>,
+        ct: CancellationToken,
+    ) -> Result<CallToolResult, McpError> {
+        if args.models.is_empty() {
+            return Err(McpError::invalid_params(
+                "gemini_invalid_models",
+                Some(serde_json::json!({ "error": "At least one model is required" })),
+            ));
+        }
+        if args.models.len() > MAX_COMPARE_MODELS {
+            return Err(McpError::invalid_params(
+                "gemini_invalid_models",
+                Some(serde_json::json!({
+                    "error": format!("At most {} models can be compared at once", MAX_COMPARE_MODELS),
+                    "models": args.models.len(),
+                    "max_models": MAX_COMPARE_MODELS,
+                })),
+            ));
+        }
+        let mut seen = HashSet::new();
+        if let Some(duplicate) = args
+            .models
+            .iter()
+            .find(|model| !seen.insert(model.as_str()))
+        {
+            return Err(McpError::invalid_params(
+                "gemini_invalid_models",
+                Some(serde_json::json!({
+                    "error": format!("Model '{}' is listed more than once", duplicate),
+                })),
+            ));
+        }
+
+        let executions = args
+            .models
+            .iter()
+            .map(|model| PromptGeminiArgs {
+                prompt: args.prompt.clone(),
+                backend: args.backend.clone(),
+                model: Some(model.clone()),
+                output_format: Some("json".to_string()),
+                ..Default::default()
+            })
+            .collect();
+        let results = self
+            .run_in_parallel("compare-models", executions, ct)
+            .await?;
+
+        let comparisons: Vec<ModelComparison> = args
+            .models
+            .into_iter()
+            .zip(results)
+            .map(|(model, outcome)| ModelComparison { model, outcome })
+            .collect();
+
+        Ok(CallToolResult::success(vec![Content::json(comparisons)?]))
+    }
+
+    #[tool(
+        name = "batch-prompt",
+        description = "Run several prompts in parallel and return each one's response or error, in order; a failing prompt doesn't fail the batch"
+    )]
+    async fn batch_prompt(
+        &self,
+        Parameters(args): Parameters<BatchPromptArgs>,
+        ct: CancellationToken,
+    ) -> Result<CallToolResult, McpError> {
+        if args.prompts.is_empty() {
+            return Err(McpError::invalid_params(
+                "gemini_invalid_prompts",
+                Some(serde_json::json!({ "error": "At least one prompt is required" })),
+            ));
+        }
+        if args.prompts.len() > MAX_BATCH_PROMPTS {
+            return Err(McpError::invalid_params(
+                "gemini_invalid_prompts",
+                Some(serde_json::json!({
+                    "error": format!("At most {} prompts can be run in one batch", MAX_BATCH_PROMPTS),
+                    "prompts": args.prompts.len(),
+                    "max_prompts": MAX_BATCH_PROMPTS,
+                })),
+            ));
+        }
+
+        let executions = args
+            .prompts
+            .into_iter()
+            .map(|item| match item {
+                BatchPromptItem::Prompt(prompt) => PromptGeminiArgs {
+                    prompt,
+                    backend: args.backend.clone(),
+                    model: args.model.clone(),
+                    output_format: args.output_format.clone(),
+                    ..Default::default()
+                },
+                BatchPromptItem::WithOverrides {
+                    prompt,
+                    model,
+                    output_format,
+                } => PromptGeminiArgs {
+                    prompt,
+                    backend: args.backend.clone(),
+                    model: model.or_else(|| args.model.clone()),
+                    output_format: output_format.or_else(|| args.output_format.clone()),
+                    ..Default::default()
+                },
+            })
+            .collect();
+        let outcomes = self.run_in_parallel("batch-prompt", executions, ct).await?;
+
+        Ok(CallToolResult::success(vec![Content::json(outcomes)?]))
+    }
+
+    #[tool(
+        name = "list-workspace-files",
+        description = "List files in the workspace Gemini CLI runs in, as relative paths, optionally filtered by a glob"
+    )]
+    async fn list_workspace_files(
+        &self,
+        Parameters(args): Parameters<ListWorkspaceFilesArgs>,
+    ) -> Result<CallToolResult, McpError> {
+        if let Some(glob) = &args.glob {
+            workspace_files::glob_matcher(glob).map_err(|error| {
+                McpError::invalid_params(
+                    "gemini_invalid_glob",
+                    Some(serde_json::json!({ "error": error, "glob": glob })),
+                )
+            })?;
+        }
+        let workspace = self.with_current_config().workspace_dir()?;
+        let options = workspace_files::ListOptions {
+            glob: args.glob,
+            max_depth: args.max_depth.unwrap_or(workspace_files::DEFAULT_MAX_DEPTH),
+            max_files: args
+                .max_files
+                .unwrap_or(workspace_files::DEFAULT_MAX_FILES)
+                .min(workspace_files::MAX_FILES_LIMIT),
+            respect_gitignore: args.respect_gitignore.unwrap_or(false),
+        };
+
+        let root = workspace.clone();
+        let listing =
+            tokio::task::spawn_blocking(move || workspace_files::list_files(&root, &options))
+                .await
+                .map_err(|e| e.to_string())
+                .and_then(|listing| listing)
+                .map_err(|error| {
+                    McpError::internal_error(
+                        "gemini_workspace_listing_failed",
+                        Some(serde_json::json!({
+                            "workspace": workspace,
+                            "error": error,
+                        })),
+                    )
+                })?;
+
+        Ok(CallToolResult::success(vec![Content::json(
+            serde_json::json!({
+                "workspace": workspace,
+                "count": listing.files.len(),
+                "files": listing.files,
+                "truncated": listing.truncated,
+            }),
+        )?]))
+    }
+
+    #[tool(
+        name = "server-metrics",
+        description = "Return prompt execution metrics: call counts, failures, latency percentiles, queue depth and the retry budget"
+    )]
+    async fn server_metrics(&self) -> Result<CallToolResult, McpError> {
+        Ok(CallToolResult::success(vec![Content::json(
+            self.state.metrics_snapshot(),
+        )?]))
+    }
+
+    #[tool(
+        name = "diagnose",
+        description = "Run Gemini CLI's diagnostics subcommand (e.g. doctor) and return its exit code and output, to check auth and environment; a failing run is returned as the report, not an error"
+    )]
+    async fn diagnose(
+        &self,
+        Parameters(args): Parameters<DiagnoseArgs>,
+    ) -> Result<CallToolResult, McpError> {
+        let this = self.with_current_config();
+        let command = this
+            .resolve_command(args.backend.as_deref())
+            .map_err(|reason| this.unknown_backend(reason))?
+            .to_string();
+        let mut cmd = this.base_command(&command)?;
+        cmd.args(this.config.diagnose_subcommand.split_whitespace())
+            .kill_on_drop(true)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        let command_line = this.command_line(&cmd);
+        let started = Instant::now();
+        let output = tokio::time::timeout(DIAGNOSE_TIMEOUT, cmd.output()).await;
+        let duration_ms = started.elapsed().as_millis() as u64;
+
+        // Failing to run is a finding too, e.g. a command missing from PATH
+        let redactor = &this.config.redactor;
+        let report = match output {
+            Ok(Ok(output)) => serde_json::json!({
+                "command": command_line,
+                "success": output.status.success(),
+                "exit_code": output.status.code(),
+                "duration_ms": duration_ms,
+                "stdout": redactor.redact(decode_output(&output.stdout, this.config.output_encoding).trim()),
+                "stderr": redactor.redact(decode_output(&output.stderr, this.config.output_encoding).trim()),
+            }),
+            Ok(Err(e)) => {
+                let mut report = serde_json::json!({
+                    "command": command_line,
+                    "success": false,
+                    "duration_ms": duration_ms,
+                    "error": format!("Failed to run: {}", e),
+                });
+                if e.kind() == std::io::ErrorKind::NotFound
+                    && let Some(program) = command.split_whitespace().next()
+                {
+                    report["lookup"] = command_lookup(program);
+                }
+                report
+            }
+            Err(_) => serde_json::json!({
+                "command": command_line,
+                "success": false,
+                "duration_ms": duration_ms,
+                "error": format!("Timed out after {} seconds", DIAGNOSE_TIMEOUT.as_secs()),
+            }),
+        };
+        if report["success"] != true {
+            tracing::warn!(command = %command_line, "Gemini CLI diagnostics reported a problem");
+        }
+        Ok(CallToolResult::success(vec![Content::json(report)?]))
+    }
+
+    #[tool(
+        name = "list-active-requests",
+        description = "List the prompt executions currently running: request id, tool, model, prompt sha256 and elapsed time, oldest first"
+    )]
+    async fn list_active_requests(&self) -> Result<CallToolResult, McpError> {
+        Ok(CallToolResult::success(vec![Content::json(
+            serde_json::json!({ "requests": self.state.active_requests.snapshot() }),
+        )?]))
+    }
+
+    #[tool(
+        name = "clear-cache",
+        description = "Empty the response cache and return the number of entries removed"
+    )]
+    async fn clear_cache(&self) -> Result<CallToolResult, McpError> {
+        // Only listed when caching is enabled
+        let Some(cache) = &self.state.response_cache else {
+            return Err(McpError::internal_error(
+                "gemini_cache_disabled",
+                Some(serde_json::json!({
+                    "error": "The response cache is disabled; enable it with --response-cache-max-entries"
+                })),
+            ));
+        };
+        let removed = cache.clear();
+        tracing::info!(removed, "Response cache cleared");
+        Ok(CallToolResult::success(vec![Content::json(
+            serde_json::json!({ "removed": removed }),
+        )?]))
+    }
+
+    #[tool(
+        name = "last-file-changes",
+        description = "Return the lines added/removed reported by the most recent prompt in this session that edited files"
+    )]
+    async fn last_file_changes(&self) -> Result<CallToolResult, McpError> {
+        let file_changes = self.session_stats.lock().unwrap().last_file_changes.clone();
+        Ok(CallToolResult::success(vec![Content::json(
+            serde_json::json!({ "file_changes": file_changes }),
+        )?]))
+    }
+
+    #[tool(
+        name = "resource-usage",
+        description = "Return the wall time of the most recent Gemini CLI execution in this session, with CPU time and peak memory aggregated over all of the server's subprocesses (not per process)"
+    )]
+    async fn resource_usage(&self) -> Result<CallToolResult, McpError> {
+        let resource_usage = self
+            .session_stats
+            .lock()
+            .unwrap()
+            .last_resource_usage
+            .clone();
+        Ok(CallToolResult::success(vec![Content::json(
+            serde_json::json!({ "resource_usage": resource_usage }),
+        )?]))
+    }
+
+    #[tool(
+        name = "tool-decisions",
+        description = "Return how often Gemini's proposed tool calls were accepted, rejected, modified or auto-accepted: for the most recent prompt and cumulatively for this session"
+    )]
+    async fn tool_decisions(&self) -> Result<CallToolResult, McpError> {
+        let session_stats = self.session_stats.lock().unwrap();
+        let totals = &session_stats.decision_totals;
+
+        Ok(CallToolResult::success(vec![Content::json(
+            serde_json::json!({
+                "last": session_stats.last_decisions,
+                "cumulative": totals,
+                "cumulative_rates": totals.rates(),
+            }),
+        )?]))
+    }
+
+    #[tool(
+        name = "describe-tools",
+        description = "List the available tools with their JSON schemas, and describe each prompt-gemini parameter and its allowed values"
+    )]
+    async fn describe_tools(&self) -> Result<CallToolResult, McpError> {
+        let config = self.state.config();
+        let schema =
+            serde_json::to_value(schemars::schema_for!(PromptGeminiArgs)).map_err(|e| {
+                McpError::internal_error(
+                    "schema_serialization_failed",
+                    Some(serde_json::json!({ "error": e.to_string() })),
+                )
+            })?;
+
+        let output_schema = serde_json::to_value(schemars::schema_for!(GeminiJsonResponse))
+            .map_err(|e| {
+                McpError::internal_error(
+                    "schema_serialization_failed",
+                    Some(serde_json::json!({ "error": e.to_string() })),
+                )
+            })?;
+
+        let mut backends: Vec<&String> = config.backends.keys().collect();
+        backends.sort();
+
+        let mut parameters = serde_json::Map::new();
+        if let Some(properties) = schema["properties"].as_object() {
+            for (name, property) in properties {
+                let mut parameter = serde_json::json!({
+                    "description": property["description"],
+                    "required": schema["required"]
+                        .as_array()
+                        .is_some_and(|required| required.contains(&serde_json::json!(name))),
+                });
+                let allowed_values = match name.as_str() {
+                    "output_format" => Some(serde_json::json!(output_format_names())),
+                    "approval_mode" => Some(serde_json::json!(approval_mode_names())),
+                    "backend" => Some(serde_json::json!(backends)),
+                    _ => None,
+                };
+                if let Some(allowed_values) = allowed_values {
+                    parameter["allowed_values"] = allowed_values;
+                }
+                parameters.insert(name.clone(), parameter);
+            }
+        }
+
+        Ok(CallToolResult::success(vec![Content::json(
+            serde_json::json!({
+                "tools": self.tool_router.list_all(),
+                "prompt_gemini": {
+                    "schema": schema,
+                    "parameters": parameters,
+                    // Shape of `structuredContent` (and the YAML output) for parsed responses
+                    "output_schema": output_schema,
+                },
+            }),
+        )?]))
+    }
+
+    #[tool(
+        name = "show-config",
+        description = "Return the effective server configuration (command line, environment and config file combined), with secrets in commands redacted"
+    )]
+    async fn show_config(&self) -> Result<CallToolResult, McpError> {
+        let this = self.with_current_config();
+        let mut effective = this.config.redacted_json();
+        // Report the workspace actually used, including the GEMINI_WORKSPACE fallback
+        if let Ok(workspace) = this.workspace() {
+            effective["workspace"] = serde_json::json!(workspace);
+        }
+        Ok(CallToolResult::success(vec![Content::json(effective)?]))
+    }
+
+    #[tool(
+        name = "reload-config",
+        description = "Re-read the --config file and apply it to new requests without restarting"
+    )]
+    async fn reload_config(&self) -> Result<CallToolResult, McpError> {
+        let Some(config_file) = self.state.config().config_file.clone() else {
+            return Err(McpError::internal_error(
+                "gemini_config_reload_failed",
+                Some(serde_json::json!({
+                    "error": "No --config file was given at startup"
+                })),
+            ));
+        };
+
+        self.state.reload_config().map_err(|e| {
+            McpError::internal_error(
+                "gemini_config_reload_failed",
+                Some(serde_json::json!({
+                    "config_file": config_file,
+                    "error": format!("{:#}", e),
+                })),
+            )
+        })?;
+        tracing::info!(config_file = %config_file.display(), "Configuration reloaded");
+
+        Ok(CallToolResult::success(vec![Content::json(
+            serde_json::json!({
+                "reloaded": true,
+                "config_file": config_file,
+            }),
+        )?]))
+    }
+
+    /// Run [`GeminiCli::handle_prompt`], sharing the execution with identical requests from
+    /// the same session that arrive within the coalescing window. Joined results are marked
+    /// `_meta.coalesced`.
+    async fn coalesced_prompt(&self, args: PromptGeminiArgs) -> Result<CallToolResult, McpError> {
+        let window = Duration::from_millis(self.config.coalesce_window_ms);
+        if window.is_zero() {
+            return self.handle_prompt(args).await;
+        }
+
+        // Going through a `Value` sorts map keys (e.g. `variables`), so equal arguments
+        // give equal keys. The session is part of the key, so per-session limits and stats
+        // apply to the session that asked; its stats outlive the execution, so the
+        // address is not reused while the key is pending.
+        let key = format!(
+            "{:p}:{}",
+            Arc::as_ptr(&self.session_stats),
+            serde_json::to_value(&args)
+                .map(|args| args.to_string())
+                .unwrap_or_default()
+        );
+        let this = self.clone();
+        let (result, joined) = self
+            .state
+            .coalescer
+            .run(key, window, async move { this.handle_prompt(args).await })
+            .await;
+        if joined {
+            tracing::debug!("Coalesced prompt-gemini request with an identical pending one");
+            return result.map(|mut result| {
+                insert_meta(&mut result, "coalesced", serde_json::json!(true));
+                result
+            });
+        }
+        result
+    }
+
+    /// Execute prompts in parallel for a multi-prompt tool, returning their outcomes in
+    /// order. Each execution goes through the same queue, circuit breaker, metrics and
+    /// per-session limit as prompt-gemini, so the concurrency limits still apply.
+    async fn run_in_parallel(
+        &self,
+        tool: &'static str,
+        executions: Vec<PromptGeminiArgs>,
+        ct: CancellationToken,
+    ) -> Result<Vec<PromptOutcome>, McpError> {
+        let this = self.with_current_config();
+        let count = executions.len();
+        let mut join_set = tokio::task::JoinSet::new();
+        for (index, prompt_args) in executions.into_iter().enumerate() {
+            let this = this.clone();
+            // Taken in order, so the executions past `max_in_flight_per_session` are the
+            // last ones
+            let in_flight = this.enter_session();
+            join_set.spawn(async move {
+                let started = Instant::now();
+                let _active = this.state.active_requests.register(
+                    tool,
+                    &prompt_args.prompt,
+                    this.model(&prompt_args),
+                );
+                let result = match in_flight {
+                    Ok(_in_flight) => this.handle_prompt(prompt_args.clone()).await,
+                    Err(busy) => Err(busy),
+                };
+                this.state
+                    .metrics
+                    .record(started.elapsed(), call_outcome(&result));
+                this.audit(tool, &prompt_args, result.as_ref().err(), started.elapsed());
+                (index, result)
+            });
+        }
+
+        let mut results: Vec<Option<Result<CallToolResult, McpError>>> =
+            (0..count).map(|_| None).collect();
+        loop {
+            // Dropping the join set aborts the remaining executions (and kills their subprocesses)
+            let joined = tokio::select! {
+                joined = join_set.join_next() => joined,
+                _ = ct.cancelled() => {
+                    return Err(McpError::internal_error(
+                        "gemini_request_cancelled",
+                        Some(serde_json::json!({
+                            "error": "The client cancelled the request or disconnected"
+                        })),
+                    ));
+                }
+            };
+            match joined {
+                Some(Ok((index, result))) => results[index] = Some(result),
+                Some(Err(e)) => tracing::error!(error = %e, tool, "Parallel execution panicked"),
+                None => break,
+            }
+        }
+        Ok(results
+            .into_iter()
+            .map(PromptOutcome::from_result)
+            .collect())
+    }
+
+    /// Validate, execute and map a prompt request to a tool result, with secrets
+    /// scrubbed from any error
+    async fn handle_prompt(&self, args: PromptGeminiArgs) -> Result<CallToolResult, McpError> {
+        self.execute_prompt(args)
+            .await
+            .map(|result| self.redact_output(result))
+            .map_err(|e| self.redact_error(e))
+    }
+
+    /// [`GeminiCli::handle_prompt`] before redaction
+    async fn execute_prompt(&self, args: PromptGeminiArgs) -> Result<CallToolResult, McpError> {
+        let (mut args, command) = self.prepare_prompt(args)?;
+        let requested = args.clone();
+        let images = self.prepare_images(&mut args)?;
+        // A hit replays only the text, not the files a run may have changed, so only
+        // read-only runs (which never use yolo) are cached
+        let cache_key = match &self.state.response_cache {
+            Some(_) if self.config.read_only => {
+                self.response_cache_key(&command, &requested, &images).await
+            }
+            _ => None,
+        };
+        if let (Some(cache), Some(key)) = (&self.state.response_cache, &cache_key)
+            && let Some(mut result) = cache.get(key)
+        {
+            tracing::debug!("Serving prompt-gemini result from the response cache");
+            insert_meta(&mut result, "cached", serde_json::json!(true));
+            return Ok(result);
+        }
+
+        let all_files_warning = match args.all_files {
+            Some(true) => self.all_files_warning().await,
+            _ => None,
+        };
+
+        // Throttle before taking a slot, so a waiting launch does not hold one
+        self.wait_for_spawn_slot().await;
+        // Hold the execution slot until gemini-cli (and any failover) has finished
+        let _permit = self.acquire_execution().await?;
+        let result = self.execute_with_failover(&command, &args).await;
+        match &result {
+            // The backend answered; only the request was at fault
+            Ok(_) => self.state.circuit_breaker.record_success(),
+            Err(error) if is_client_error(error) => self.state.circuit_breaker.record_success(),
+            Err(_) => self.state.circuit_breaker.record_failure(),
+        }
+        result.map(|mut result| {
+            if let Some(warning) = all_files_warning {
+                insert_meta(&mut result, "all_files_warning", warning);
+            }
+            // Partial output from a timed-out execution isn't a complete answer
+            let partial = result
+                .meta
+                .as_ref()
+                .is_some_and(|meta| meta.get("partial").is_some());
+            if let (Some(cache), Some(key)) = (&self.state.response_cache, cache_key)
+                && !partial
+            {
+                cache.insert(key, result.clone());
+            }
+            result
+        })
+    }
+
+    /// Response cache key for a prompt as submitted (after templating), including the
+    /// contents of its images, which their paths alone don't capture. Other workspace files
+    /// are not hashed. `None` (don't cache) when an image can't be read.
+    async fn response_cache_key(
+        &self,
+        command: &str,
+        args: &PromptGeminiArgs,
+        images: &[PreparedImage],
+    ) -> Option<String> {
+        let mut image_digests = Vec::with_capacity(images.len());
+        for image in images {
+            let bytes = tokio::fs::read(&image.path).await.ok()?;
+            image_digests.push(response_cache::content_digest(&bytes));
+        }
+        Some(response_cache::cache_key(&serde_json::json!({
+            "command": command,
+            "args": args,
+            "images": image_digests,
+            "model": self.model(args),
+            "output_format": self.output_format(args),
+            "workspace": self.workspace().ok().flatten(),
+        })))
+    }
+
+    /// Warning for `all_files` on a workspace large enough to make the prompt expensive.
+    /// Counts files gemini-cli would include (those not excluded by `.gitignore`), stopping
+    /// at the threshold.
+    async fn all_files_warning(&self) -> Option<serde_json::Value> {
+        let workspace = self.workspace_dir().ok()?;
+        let options = workspace_files::ListOptions {
+            glob: None,
+            max_depth: usize::MAX,
+            max_files: ALL_FILES_WARN_FILES,
+            respect_gitignore: true,
+        };
+        let root = workspace.clone();
+        let listing =
+            tokio::task::spawn_blocking(move || workspace_files::list_files(&root, &options))
+                .await
+                .ok()?
+                .ok()?;
+        if !listing.truncated {
+            return None;
+        }
+        tracing::warn!(
+            workspace = %workspace.display(),
+            file_threshold = ALL_FILES_WARN_FILES,
+            "all_files requested on a large workspace; the prompt may use many tokens"
+        );
+        Some(serde_json::json!({
+            "workspace": workspace,
+            "file_threshold": ALL_FILES_WARN_FILES,
+            "warning": format!(
+                "The workspace has more than {} files; including all of them may use many tokens",
+                ALL_FILES_WARN_FILES
+            ),
+        }))
+    }
+
+    /// Scrub secrets from an error's data before it reaches the client. Output patterns
+    /// apply too, as errors can carry gemini-cli's output (e.g. `raw_output`).
+    fn redact_error(&self, mut error: McpError) -> McpError {
+        if let Some(data) = &mut error.data {
+            self.config.redactor.redact_json(data);
+            self.config.output_redactor.redact_json(data);
+        }
+        error
+    }
+
+    /// Mask `--redact-output-pattern` matches in every text item and the structured content
+    /// of a result. Base64-encoded items were already redacted before encoding.
+    fn redact_output(&self, mut result: CallToolResult) -> CallToolResult {
+        let redactor = &self.config.output_redactor;
+        if redactor.is_empty() {
+            return result;
+        }
+        for content in &mut result.content {
+            if let RawContent::Text(text_content) = &mut content.raw
+                && text_content
+                    .meta
+                    .as_ref()
+                    .is_none_or(|meta| meta.get("encoding").is_none())
+                && let Cow::Owned(redacted) = redactor.redact(&text_content.text)
+            {
+                text_content.text = redacted;
+            }
+        }
+        if let Some(structured_content) = &mut result.structured_content {
+            redactor.redact_json(structured_content);
+        }
+        result
+    }
+
+    /// Render and validate a prompt request, returning the final arguments and the
+    /// command to run them with
+    fn prepare_prompt(
+        &self,
+        mut args: PromptGeminiArgs,
+    ) -> Result<(PromptGeminiArgs, String), McpError> {
+        Self::check_output_format(&args).map_err(|error| {
+            McpError::invalid_params(
+                "gemini_invalid_output_format",
+                Some(serde_json::json!({
+                    "error": error,
+                    "output_format": args.output_format,
+                    "allowed_values": output_format_names(),
+                })),
+            )
+        })?;
+        self.check_approval_mode(&args).map_err(|error| {
+            McpError::invalid_params(
+                "gemini_invalid_approval_mode",
+                Some(serde_json::json!({
+                    "error": error,
+                    "approval_mode": args.approval_mode,
+                    "read_only": self.config.read_only,
+                    "allowed_values": approval_mode_names(),
+                })),
+            )
+        })?;
+        self.check_sandbox(&args).map_err(|error| {
+            McpError::invalid_params(
+                "gemini_sandbox_required",
+                Some(serde_json::json!({ "error": error })),
+            )
+        })?;
+
+        // Validation applies to the client's prompt, not the text the server wraps it in
+        args.prompt = self.render_template(&args).map_err(|missing| {
+            McpError::invalid_params(
+                "gemini_template_error",
+                Some(serde_json::json!({
+                    "error": missing.to_string(),
+                    "missing_variables": missing.0,
+                })),
+            )
+        })?;
+
+        self.check_prompt_allowed(&args.prompt)?;
+
+        let reasons = self.prompt_validation_errors(&args);
+        if !reasons.is_empty() {
+            return Err(McpError::invalid_params(
+                "gemini_invalid_prompt",
+                Some(serde_json::json!({
+                    "reasons": reasons,
+                    "prompt": args.prompt
+                })),
+            ));
+        }
+        args.prompt = self.wrap_prompt(std::mem::take(&mut args.prompt), &args);
+
+        let command = self
+            .resolve_command(args.backend.as_deref())
+            .map_err(|reason| self.unknown_backend(reason))?
+            .to_string();
+        Ok((args, command))
+    }
+
+    /// Reject a client's prompt matching a deny pattern or over the length limit
+    fn check_prompt_allowed(&self, prompt: &str) -> Result<(), McpError> {
+        if let Some(pattern) = self.denied_pattern(prompt) {
+            // Hashed, so the audit trail doesn't keep the disallowed content
+            tracing::warn!(
+                prompt_sha256 = %audit::sha256_hex(prompt),
+                pattern = pattern.as_str(),
+                "Prompt denied by a deny pattern"
+            );
+            return Err(McpError::invalid_params(
+                "gemini_prompt_denied",
+                Some(serde_json::json!({
+                    "error": "The prompt contains content this server does not allow",
+                })),
+            ));
+        }
+
+        if let Err(too_long) = self.check_prompt_length(prompt) {
+            return Err(McpError::invalid_params(
+                "gemini_prompt_too_long",
+                Some(serde_json::json!({
+                    "error": too_long.to_string(),
+                    "unit": too_long.unit,
+                    "length": too_long.actual,
+                    "max_length": too_long.max,
+                })),
+            ));
+        }
+        Ok(())
+    }
+
+    fn unknown_backend(&self, reason: String) -> McpError {
+        let mut available: Vec<&String> = self.config.backends.keys().collect();
+        available.sort();
+        McpError::invalid_params(
+            "gemini_unknown_backend",
+            Some(serde_json::json!({
+                "error": reason,
+                "available_backends": available
+            })),
+        )
+    }
+
+    /// Run the configured count-tokens command (with `--model` when one applies and the
+    /// prompt as the last argument, after `--`) and read the count from its output. Like
+    /// gemini-cli, it waits for the spawn interval and an execution slot.
+    async fn count_tokens(&self, command: &str, args: &PromptGeminiArgs) -> Result<u64, String> {
+        let mut parts = command.split_whitespace();
+        let program = parts.next().ok_or("Count-tokens command is empty")?;
+        let mut cmd = Command::new(program);
+        cmd.args(parts);
+        if let Some(model) = self.model(args) {
+            cmd.arg("--model").arg(model);
+        }
+        cmd.arg("--")
+            .arg(&args.prompt)
+            .kill_on_drop(true)
+            .stdin(Stdio::null());
+
+        self.wait_for_spawn_slot().await;
+        let _permit = self.state.queue.acquire().await.map_err(|_| {
+            "All execution slots are busy and the request queue is full".to_string()
+        })?;
+        let output = tokio::time::timeout(COUNT_TOKENS_TIMEOUT, cmd.output())
+            .await
+            .map_err(|_| format!("Timed out after {}s", COUNT_TOKENS_TIMEOUT.as_secs()))?
+            .map_err(|e| format!("Failed to run '{}': {}", command, e))?;
+        if !output.status.success() {
+            return Err(format!(
+                "'{}' exited with code {}: {}",
+                command,
+                output.status.code().unwrap_or(-1),
+                truncate_chars(String::from_utf8_lossy(&output.stderr).trim(), 500)
+            ));
+        }
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        parse_token_count(&stdout).ok_or_else(|| {
+            format!(
+                "Unrecognized output: {}",
+                truncate_chars(stdout.trim(), 500)
+            )
+        })
+    }
+
+    /// Content items for each of several candidates, post-processed separately, with their
+    /// index in `_meta.candidate`
+    async fn candidate_contents(
+        &self,
+        candidates: Vec<String>,
+        args: &PromptGeminiArgs,
+    ) -> Result<Vec<Content>, McpError> {
+        let mut contents = Vec::new();
+        for (index, candidate) in candidates.into_iter().enumerate() {
+            let candidate = self.post_process(candidate).await?;
+            for mut content in response_contents(&candidate, args) {
+                if let RawContent::Text(text_content) = &mut content.raw {
+                    text_content
+                        .meta
+                        .get_or_insert_with(Meta::new)
+                        .insert("candidate".to_string(), serde_json::json!(index));
+                }
+                contents.push(content);
+            }
+        }
+        Ok(contents)
+    }
+
+    /// Pipe `text` through the post-process command, if one is configured, and return its
+    /// stdout. Fails with `gemini_post_process_failed` when the command can't run, times
+    /// out or exits non-zero.
+    async fn post_process(&self, text: String) -> Result<String, McpError> {
+        let Some(command) = &self.config.post_process_command else {
+            return Ok(text);
+        };
+        let failed = |error: String, stderr: Option<String>| {
+            McpError::internal_error(
+                "gemini_post_process_failed",
+                Some(serde_json::json!({
+                    "command": command,
+                    "error": error,
+                    "stderr": stderr,
+                })),
+            )
+        };
+
+        let mut parts = command.split_whitespace();
+        let program = parts
+            .next()
+            .ok_or_else(|| failed("Post-process command is empty".to_string(), None))?;
+        let mut child = Command::new(program)
+            .args(parts)
+            .kill_on_drop(true)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| failed(format!("Failed to run '{}': {}", command, e), None))?;
+
+        let timeout = Duration::from_secs(self.config.post_process_timeout_secs);
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        let write = async move {
+            // A hook that exits without reading its input is not an error in itself
+            let _ = stdin.write_all(text.as_bytes()).await;
+        };
+        // Written while the output is read, so a hook streaming its output back
+        // can't fill its stdout pipe while we wait to finish writing
+        let run = async move { tokio::join!(write, child.wait_with_output()).1 };
+        let output = tokio::time::timeout(timeout, run)
+            .await
+            .map_err(|_| failed(format!("Timed out after {}s", timeout.as_secs()), None))?
+            .map_err(|e| failed(e.to_string(), None))?;
+
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let stderr = truncate_chars(stderr.trim(), MAX_LOGGED_STDERR_CHARS).into_owned();
+        if !output.status.success() {
+            return Err(failed(
+                format!("Exited with code {}", output.status.code().unwrap_or(-1)),
+                Some(stderr),
+            ));
+        }
+        let mut processed = String::from_utf8(output.stdout)
+            .map_err(|e| failed(format!("Output is not valid UTF-8: {}", e), Some(stderr)))?;
+        // Most filters end their output with a newline the response didn't have
+        if processed.ends_with('\n') {
+            processed.pop();
+        }
+        Ok(processed)
+    }
+
+    /// Validate `args.images` and replace each with the path of a file gemini-cli can read.
+    /// The returned images must be kept alive until gemini-cli has finished.
+    fn prepare_images(&self, args: &mut PromptGeminiArgs) -> Result<Vec<PreparedImage>, McpError> {
+        let Some(inputs) = args.images.as_ref().filter(|inputs| !inputs.is_empty()) else {
+            return Ok(Vec::new());
+        };
+        if let Some(model) = self.model(args)
+            && !images::model_supports_images(model)
+        {
+            return Err(McpError::invalid_params(
+                "gemini_model_not_multimodal",
+                Some(serde_json::json!({
+                    "error": format!("Model '{}' does not accept image input", model),
+                    "model": model,
+                })),
+            ));
+        }
+
+        let base_dir = self.workspace()?.map(PathBuf::from);
+        // Without an allowlist, image files must be inside the workspace gemini-cli runs in
+        let default_roots;
+        let allowed_roots = if self.config.allowed_workspace_roots.is_empty() {
+            let root = match &base_dir {
+                Some(base_dir) => Ok(base_dir.clone()),
+                None => std::env::current_dir(),
+            };
+            default_roots = root
+                .and_then(std::fs::canonicalize)
+                .into_iter()
+                .collect::<Vec<_>>();
+            &default_roots
+        } else {
+            &self.config.allowed_workspace_roots
+        };
+        let mut prepared = Vec::new();
+        let mut reasons = Vec::new();
+        for input in inputs {
+            match images::prepare_image(input, base_dir.as_deref(), allowed_roots) {
+                Ok(image) => prepared.push(image),
+                Err(reason) => reasons.push(reason),
+            }
+        }
+        if !reasons.is_empty() {
+            return Err(McpError::invalid_params(
+                "gemini_invalid_image",
+                Some(serde_json::json!({
+                    "reasons": reasons,
+                    "max_image_bytes": images::MAX_IMAGE_BYTES,
+                })),
+            ));
+        }
+
+        args.images = Some(
+            prepared
+                .iter()
+                .map(|image| image.path.to_string_lossy().into_owned())
+                .collect(),
+        );
+        Ok(prepared)
+    }
+
+    /// Wait for an execution slot and check the circuit breaker. The returned permit
+    /// must be held until gemini-cli has finished, and the outcome reported to the
+    /// circuit breaker.
+    async fn acquire_execution(&self) -> Result<Option<SemaphorePermit<'_>>, McpError> {
+        let notify = self.config.notify_queue_position;
+        let mut queued = None;
+        let on_queued = |position: usize| {
+            queued = Some(Instant::now());
+            tracing::debug!(position, "Waiting for an execution slot");
+            async move {
+                if notify {
+                    self.client_log
+                        .log(
+                            LoggingLevel::Info,
+                            "queue",
+                            serde_json::json!({
+                                "event": "queued",
+                                "position": position,
+                                "max_concurrency": self.state.queue.snapshot().max_concurrency,
+                            }),
+                        )
+                        .await;
+                }
+            }
+        };
+        let permit = self.state.queue.acquire_notifying(on_queued).await;
+        if notify && let Some(queued) = queued {
+            self.client_log
+                .log(
+                    LoggingLevel::Info,
+                    "queue",
+                    serde_json::json!({
+                        "event": "dequeued",
+                        "waited_ms": queued.elapsed().as_millis() as u64,
+                    }),
+                )
+                .await;
+        }
+        let permit = permit.map_err(|full| {
+            McpError::internal_error(
+                "gemini_queue_full",
+                Some(serde_json::json!({
+                    "error": "All execution slots are busy and the request queue is full",
+                    "max_queue_depth": full.max_queue_depth,
+                })),
+            )
+        })?;
+
+        if let Err(remaining) = self.state.circuit_breaker.try_acquire() {
+            return Err(McpError::internal_error(
+                "gemini_circuit_open",
+                Some(serde_json::json!({
+                    "error": "Gemini CLI is failing repeatedly; calls are suspended until the cooldown elapses",
+                    "retry_after_secs": self.config.retry_jitter.apply(remaining).as_secs_f64().ceil() as u64,
+                })),
+            ));
+        }
+        Ok(permit)
+    }
+
+    /// Wait until `min_spawn_interval_ms` has passed since the previous gemini-cli launch
+    async fn wait_for_spawn_slot(&self) {
+        let interval = Duration::from_millis(self.config.min_spawn_interval_ms);
+        let waited = self.state.spawn_throttle.wait(interval).await;
+        if !waited.is_zero() {
+            tracing::debug!(
+                waited_ms = waited.as_millis() as u64,
+                "Waited for the minimum spawn interval"
+            );
+        }
+    }
+
+    /// Run `command`, retrying once with the fallback command on recoverable failures
+    async fn execute_with_failover(
+        &self,
+        command: &str,
+        args: &PromptGeminiArgs,
+    ) -> Result<CallToolResult, McpError> {
+        self.state.retry_budget.record_call();
+        let result = self.run_command(command, args).await;
+
+        match (result, &self.config.fallback_command) {
+            (Err(mut error), Some(_))
+                if is_failover_error(&error) && !self.state.retry_budget.try_acquire_retry() =>
+            {
+                tracing::warn!(
+                    primary = %self.redact_command_line(command),
+                    error = %error.message,
+                    "Retry budget exhausted, not retrying with fallback"
+                );
+                if let Some(data) = error.data.as_mut().and_then(|d| d.as_object_mut()) {
+                    data.insert(
+                        "retry_budget_exhausted".to_string(),
+                        serde_json::json!(true),
+                    );
+                }
+                Err(error)
+            }
+            (Err(error), Some(fallback)) if is_failover_error(&error) => {
+                tracing::warn!(
+                    primary = %self.redact_command_line(command),
+                    fallback = %self.redact_command_line(fallback),
+                    error = %error.message,
+                    "Primary Gemini CLI command failed, retrying with fallback"
+                );
+
+                // The fallback is another launch, made while still holding the slot
+                self.wait_for_spawn_slot().await;
+                let mut result = self.run_command(fallback, args).await?;
+                // `_meta` is not covered by output redaction, so scrub it here
+                let mut failover = serde_json::json!({
+                    "primary_command": self.redact_command_line(command),
+                    "fallback_command": self.redact_command_line(fallback),
+                    "primary_error": error.message,
+                });
+                self.config.output_redactor.redact_json(&mut failover);
+                insert_meta(&mut result, "failover", failover);
+                Ok(result)
+            }
+            (result, _) => result,
+        }
+    }
+
+    /// Build the gemini-cli invocation for `command`, producing `output_format`, with
+    /// stdout and stderr piped
+    fn build_command(
+        &self,
+        command: &str,
+        args: &PromptGeminiArgs,
+        output_format: OutputFormat,
+    ) -> Result<Command, McpError> {
+        let mut cmd = self.base_command(command)?;
+
+        // Read-only requests only get here with the manual mode, which the read-only
+        // flags already cover
+        let approval_flags = args
+            .approval_mode
+            .as_deref()
+            .and_then(|mode| APPROVAL_MODES.iter().find(|(name, _)| *name == mode))
+            .map(|(_, flags)| *flags);
+        if self.config.read_only {
+            cmd.args(self.config.read_only_args.split_whitespace());
+        } else {
+            cmd.args(approval_flags.unwrap_or(&["--yolo"]));
+        }
+        if args.sandbox.unwrap_or(self.config.default_sandbox) {
+            cmd.arg("--sandbox");
+        }
+        if let Some(model) = self.model(args) {
+            cmd.arg("--model").arg(model);
+        }
+        for image in args.images.iter().flatten() {
+            cmd.arg("--image").arg(image);
+        }
+        if args.all_files.unwrap_or(false) {
+            cmd.arg(&self.config.all_files_flag);
+        }
+        if args.include_thoughts.unwrap_or(false)
+            && let Some(flag) = &self.config.thoughts_flag
+        {
+            cmd.arg(flag);
+        }
+        if let (Some(flag), Some(language)) = (&self.config.language_flag, &args.language) {
+            cmd.arg(flag).arg(language);
+        }
+        if let (Some(flag), Some(count)) = (&self.config.candidates_flag, args.candidate_count) {
+            cmd.arg(flag).arg(count.to_string());
+        }
+        let prompt_flag = self.config.prompt_flag.as_str();
+        if !prompt_flag.is_empty() {
+            cmd.arg(prompt_flag).arg(&args.prompt);
+        }
+        // JSON and YAML are both produced from gemini-cli's JSON output
+        if output_format != OutputFormat::Text {
+            cmd.arg("--output-format").arg("json");
+        }
+        // Wrappers taking positional input get the prompt after all flags, behind `--` so
+        // a prompt starting with `-` isn't parsed as one
+        if prompt_flag.is_empty() {
+            cmd.arg("--").arg(&args.prompt);
+        }
+
+        if let Some(ws) = self.workspace()? {
+            cmd.env("GEMINI_WORKSPACE", ws);
+        }
+        #[cfg(unix)]
+        if let Some(nice) = self.config.subprocess_nice {
+            // SAFETY: setpriority is async-signal-safe and the closure allocates nothing
+            unsafe {
+                cmd.pre_exec(move || {
+                    if libc::setpriority(libc::PRIO_PROCESS, 0, nice) == -1 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                    Ok(())
+                });
+            }
+        }
+        #[cfg(unix)]
+        if self.config.kill_process_group {
+            cmd.process_group(0);
+        }
+        cmd.kill_on_drop(true)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        Ok(cmd)
+    }
+
+    /// `cmd` as one line for logs, with secret flags and values redacted
+    fn command_line(&self, cmd: &Command) -> String {
+        let cmd = cmd.as_std();
+        let line = std::iter::once(cmd.get_program())
+            .chain(cmd.get_args())
+            .map(|part| part.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join(" ");
+        self.redact_command_line(&line)
+    }
+
+    /// Client log notification for a launched gemini-cli, with secrets scrubbed from the
+    /// command (client notifications are not redacted further)
+    fn spawned_event(&self, command: &str, pid: Option<u32>) -> serde_json::Value {
+        let mut event = serde_json::json!({
+            "event": "spawned",
+            "command": self.redact_command_line(command),
+            "pid": pid,
+        });
+        self.config.output_redactor.redact_json(&mut event);
+        event
+    }
+
+    /// `line` with credential arguments and secret values masked, for logs and clients
+    fn redact_command_line(&self, line: &str) -> String {
+        self.config
+            .redactor
+            .redact(&redact_command(line))
+            .into_owned()
+    }
+
+    /// Run the command prompts use by default with `--version` and return what it printed
+    pub async fn version(&self) -> Result<String, String> {
+        self.run_default_command("--version").await
+    }
+
+    /// Run the command prompts use by default with `--help` and return what it printed
+    pub async fn help(&self) -> Result<String, String> {
+        self.run_default_command("--help").await
+    }
+
+    /// Run the default command with a single informational `flag`, returning its stdout
+    async fn run_default_command(&self, flag: &str) -> Result<String, String> {
+        let this = self.with_current_config();
+        let command = this.resolve_command(None)?.to_string();
+        let mut cmd = this
+            .base_command(&command)
+            .map_err(|e| e.message.to_string())?;
+        cmd.arg(flag)
+            .kill_on_drop(true)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        let output = tokio::time::timeout(VERSION_TIMEOUT, cmd.output())
+            .await
+            .map_err(|_| format!("'{} {}' timed out", command, flag))?
+            .map_err(|e| format!("'{} {}' failed to run: {}", command, flag, e))?;
+        if !output.status.success() {
+            let stderr = decode_output(&output.stderr, this.config.output_encoding);
+            return Err(format!(
+                "'{} {}' exited with {}: {}",
+                command,
+                flag,
+                output.status,
+                this.config.redactor.redact(stderr.trim())
+            ));
+        }
+        let stdout = decode_output(&output.stdout, this.config.output_encoding)
+            .trim()
+            .to_string();
+        if stdout.is_empty() {
+            return Err(format!("'{} {}' printed nothing", command, flag));
+        }
+        Ok(stdout)
+    }
+
+    /// The command prompts run with when no backend is requested
+    pub fn default_command(&self) -> Result<String, String> {
+        self.with_current_config()
+            .resolve_command(None)
+            .map(str::to_string)
+    }
+
+    /// The program and fixed arguments of `command`, plus the separator task runners need
+    fn base_command(&self, command: &str) -> Result<Command, McpError> {
+        // Parse command string to handle commands with arguments (e.g., "task ai:run")
+        let parts: Vec<&str> = command.split_whitespace().collect();
+        if parts.is_empty() {
+            return Err(McpError::internal_error(
+                "empty_gemini_command",
+                Some(serde_json::json!({
+                    "error": "Gemini CLI command is empty"
+                })),
+            ));
+        }
+
+        // Execute gemini-cli command
+        let mut cmd = Command::new(parts[0]);
+        if parts.len() > 1 {
+            cmd.args(&parts[1..]);
+        }
+
+        // Task-runner style wrappers need a separator before the CLI args
+        let separator = match &self.config.args_separator {
+            Some(separator) => separator.as_str(),
+            None if parts[0] == "task" => "--",
+            None => "",
+        };
+        if !separator.is_empty() {
+            cmd.arg(separator);
+        }
+        if !self.config.client_identifier.is_empty() {
+            cmd.env("GEMINI_CLIENT_IDENTIFIER", &self.config.client_identifier);
+        }
+        Ok(cmd)
+    }
+
+    /// Spawn a single gemini-cli invocation with `command` and map its output to a tool result
+    async fn run_command(
+        &self,
+        command: &str,
+        args: &PromptGeminiArgs,
+    ) -> Result<CallToolResult, McpError> {
+        let output_format = self.output_format(args);
+        let mut cmd = self.build_command(command, args, output_format)?;
+
+        // Read into pooled buffers rather than allocating fresh ones per call
+        let meter = UsageMeter::start();
+        tracing::debug!(
+            command_line = %self.command_line(&cmd),
+            "Running Gemini CLI"
+        );
+        let output = match cmd.spawn() {
+            Ok(child) => {
+                self.client_log
+                    .log(
+                        LoggingLevel::Debug,
+                        "prompt-gemini",
+                        self.spawned_event(command, child.id()),
+                    )
+                    .await;
+                let timeout = self.execution_timeout(args);
+                let limits = OutputLimits {
+                    stdout: self.config.max_stdout_bytes,
+                    stderr: self.config.max_stderr_bytes,
+                };
+                let output = read_output(
+                    child,
+                    &self.state.buffer_pool,
+                    timeout,
+                    limits,
+                    self.config.kill_process_group,
+                )
+                .await;
+                self.session_stats.lock().unwrap().last_resource_usage = Some(meter.finish());
+                output
+            }
+            Err(e) => Err(e),
+        };
+
+        if let Ok(output) = &output {
+            let redactor = &self.config.redactor;
+            tracing::debug!(
+                stdout = %redactor.redact(&decode_output(&output.stdout, self.config.output_encoding)),
+                stderr = %redactor.redact(&decode_output(&output.stderr, self.config.output_encoding)),
+                "Gemini CLI raw output"
+            );
+        }
+
+        // Sizes of the raw output help explain truncated responses and parse failures
+        let output_sizes = output.as_ref().ok().map(|output| {
+            let mut sizes = vec![
+                ("stdout_bytes", output.stdout.len()),
+                ("stderr_bytes", output.stderr.len()),
+            ];
+            // Only when a limit cut the output short
+            if output.stdout_truncated > 0 {
+                sizes.push(("stdout_truncated_bytes", output.stdout_truncated));
+            }
+            if output.stderr_truncated > 0 {
+                sizes.push(("stderr_truncated_bytes", output.stderr_truncated));
+            }
+            sizes
+        });
+        let result = self.map_output(command, args, output_format, output).await;
+        let Some(output_sizes) = output_sizes else {
+            return result;
+        };
+        match result {
+            Ok(mut result) => {
+                for (key, bytes) in output_sizes {
+                    insert_meta(&mut result, key, serde_json::json!(bytes));
+                }
+                Ok(result)
+            }
+            Err(mut error) => {
+                if let Some(serde_json::Value::Object(data)) = &mut error.data {
+                    for (key, bytes) in output_sizes {
+                        data.entry(key).or_insert(serde_json::json!(bytes));
+                    }
+                }
+                Err(error)
+            }
+        }
+    }
+
+    /// Map gemini-cli's output, or the error running it, to a tool result
+    async fn map_output(
+        &self,
+        command: &str,
+        args: &PromptGeminiArgs,
+        output_format: OutputFormat,
+        output: std::io::Result<PooledOutput<'_>>,
+    ) -> Result<CallToolResult, McpError> {
+        match output {
+            Ok(output) => {
+                if output.timed_out {
+                    let timeout_secs = self.timeout_secs(args);
+                    // The deadline actually applied, after capping, when the request set one
+                    let deadline_ms = args
+                        .deadline_ms
+                        .and(self.execution_timeout(args))
+                        .map(|timeout| timeout.as_millis() as u64);
+                    tracing::warn!(
+                        command,
+                        timeout_secs,
+                        deadline_ms,
+                        "Gemini CLI timed out and was killed"
+                    );
+
+                    if args.return_partial_on_timeout.unwrap_or(false) {
+                        let mut partial =
+                            decode_output(&output.stdout, self.config.output_encoding);
+                        if self.config.strip_ansi {
+                            partial = ansi::strip(&partial).into_owned().into();
+                        }
+                        let partial =
+                            mark_truncated(partial.trim().to_string(), output.stdout_truncated);
+                        let mut result = CallToolResult::success(vec![Content::text(partial)]);
+                        insert_meta(&mut result, "partial", serde_json::json!(true));
+                        insert_meta(
+                            &mut result,
+                            "gemini_cli_timeout",
+                            serde_json::json!({
+                                "timeout_secs": timeout_secs,
+                                "deadline_ms": deadline_ms,
+                                "stdout_bytes": output.stdout.len(),
+                            }),
+                        );
+                        return Ok(result);
+                    }
+
+                    return Err(McpError::internal_error(
+                        "gemini_cli_timeout",
+                        Some(serde_json::json!({
+                            "timeout_secs": timeout_secs,
+                            "deadline_ms": deadline_ms,
+                            "stdout_bytes": output.stdout.len(),
+                            "prompt": args.prompt
+                        })),
+                    ));
+                }
+
+                if output.status.success() {
+                    // Deprecation notices and soft warnings would otherwise be invisible
+                    let stderr = decode_output(&output.stderr, self.config.output_encoding);
+                    if !stderr.trim().is_empty() {
+                        tracing::warn!(
+                            command,
+                            stderr = %truncate_chars(
+                                &self.config.redactor.redact(stderr.trim()),
+                                MAX_LOGGED_STDERR_CHARS
+                            ),
+                            "Gemini CLI succeeded but wrote to stderr"
+                        );
+                    }
+
+                    // Raw bytes, untouched by decoding and parsing
+                    if args.encode_base64.unwrap_or(false) {
+                        // Output with pattern matches can't stay byte for byte
+                        let text = String::from_utf8_lossy(&output.stdout);
+                        return Ok(match self.config.output_redactor.redact(&text) {
+                            Cow::Owned(redacted) => base64_result(redacted.as_bytes()),
+                            Cow::Borrowed(_) => base64_result(&output.stdout),
+                        });
+                    }
+
+                    // A cut-off JSON response can't parse; say why instead of failing to
+                    if output_format != OutputFormat::Text && output.stdout_truncated > 0 {
+                        return Err(McpError::internal_error(
+                            "gemini_output_truncated",
+                            Some(serde_json::json!({
+                                "error": "Gemini CLI's JSON output exceeded the server's stdout limit",
+                                "max_stdout_bytes": self.config.max_stdout_bytes,
+                                "prompt": args.prompt
+                            })),
+                        ));
+                    }
+
+                    // Replacement characters would corrupt the JSON and surface as a
+                    // confusing parse error, so report the encoding problem itself
+                    if output_format != OutputFormat::Text
+                        && self.config.output_encoding == encoding_rs::UTF_8
+                        && let Err(e) = std::str::from_utf8(&output.stdout)
+                    {
+                        let offset = e.valid_up_to();
+                        let bad_len = e.error_len().unwrap_or(output.stdout.len() - offset);
+                        return Err(McpError::internal_error(
+                            "gemini_invalid_utf8",
+                            Some(serde_json::json!({
+                                "error": e.to_string(),
+                                "byte_offset": offset,
+                                "invalid_bytes": format!("{:02x?}", &output.stdout[offset..offset + bad_len]),
+                                "prompt": args.prompt
+                            })),
+                        ));
+                    }
+
+                    // Transcode to UTF-8 (lossily, if it isn't valid in the configured encoding)
+                    let stdout = decode_output(&output.stdout, self.config.output_encoding);
+                    let raw_content = args
+                        .include_raw
+                        .unwrap_or(false)
+                        .then(|| Content::text(stdout.as_ref()));
+                    let stdout = if self.config.strip_ansi {
+                        ansi::strip(&stdout).into_owned()
+                    } else {
+                        stdout.into_owned()
+                    };
+                    let raw_response = stdout.trim();
+
+                    if raw_response.is_empty() {
+                        return Ok(CallToolResult::success(vec![Content::text(
+                            "Gemini CLI returned empty response".to_string(),
+                        )]));
+                    }
+
+                    let from_tail = args.output_lines_from.as_deref() == Some("tail");
+                    let truncation = args
+                        .max_output_lines
+                        .and_then(|max_lines| truncate_lines(raw_response, max_lines, from_tail));
+                    let truncation_meta = truncation.as_ref().map(|(_, truncation)| {
+                        serde_json::json!({
+                            "total_lines": truncation.total_lines,
+                            "kept_lines": truncation.kept_lines,
+                            "from": if from_tail { "tail" } else { "head" },
+                        })
+                    });
+                    let raw_response = truncation
+                        .as_ref()
+                        .map_or(raw_response, |(truncated, _)| truncated.as_str());
+
+                    if output_format == OutputFormat::Text {
+                        let response = self.post_process(raw_response.to_string()).await?;
+                        let response = mark_truncated(response, output.stdout_truncated);
+                        let mut result =
+                            CallToolResult::success(response_contents(&response, args));
+                        if let Some(truncation_meta) = truncation_meta {
+                            insert_meta(&mut result, "output_truncated", truncation_meta);
+                        }
+                        result.content.extend(raw_content);
+                        return Ok(result);
+                    }
+
+                    // Parse response as JSON
+                    let budget = &self.config.extraction_budget;
+                    let parsed = if self.config.lenient_json {
+                        parse_gemini_output_lenient(raw_response, budget)
+                    } else {
+                        parse_gemini_output_with_budget(raw_response, budget)
+                    };
+                    match parsed {
+                        ParseResult::JsonSuccess(mut json_response) => {
+                            // Check if there's an error in the JSON response
+                            if let Some(error) = &json_response.error {
+                                let mut data = serde_json::json!({
+                                    "error_type": error.error_type,
+                                    "message": error.message,
+                                    "code": error.code,
+                                    "prompt": args.prompt
+                                });
+                                if is_rate_limit_error(error) {
+                                    let retry_after_secs = parse_retry_after_secs(&error.message)
+                                        .unwrap_or(DEFAULT_RATE_LIMIT_BACKOFF_SECS);
+                                    data["retry_after_secs"] = serde_json::json!(
+                                        self.config.retry_jitter.apply_secs(retry_after_secs)
+                                    );
+                                }
+                                return Err(api_error(error, data));
+                            }
+
+                            // Reasoning is only returned when asked for, also in structured
+                            // content and YAML
+                            let thoughts = if args.include_thoughts.unwrap_or(false) {
+                                json_response.thoughts.as_ref().and_then(thoughts_text)
+                            } else {
+                                json_response.thoughts = None;
+                                None
+                            };
+
+                            // Return the response text for compatibility, plus the parsed
+                            // object as structured content for clients that understand it
+                            let structured_content = serde_json::to_value(&json_response).ok();
+                            let file_changes = json_response
+                                .stats
+                                .as_ref()
+                                .and_then(|stats| stats.files.clone());
+                            if let Some(stats) = &json_response.stats {
+                                self.session_stats.lock().unwrap().record(stats);
+                            }
+                            // Several candidates replace the single response
+                            let candidates = json_response.candidates.take_if(|candidates| {
+                                candidates.len() > 1
+                                    && args.candidate_count.is_some_and(|count| count > 1)
+                                    && output_format == OutputFormat::Json
+                            });
+                            let text = if candidates.is_some() {
+                                String::new()
+                            } else if let Some(pointer) = &args.response_json_pointer {
+                                select_json_pointer(&json_response.response, pointer)?
+                            } else if output_format == OutputFormat::Yaml {
+                                serde_yaml::to_string(&json_response).map_err(|e| {
+                                    McpError::internal_error(
+                                        "gemini_yaml_serialization_error",
+                                        Some(serde_json::json!({ "error": e.to_string() })),
+                                    )
+                                })?
+                            } else {
+                                json_response.response
+                            };
+                            let contents = if let Some(candidates) = candidates {
+                                self.candidate_contents(candidates, args).await?
+                            } else if output_format == OutputFormat::Yaml {
+                                vec![Content::text(self.post_process(text).await?)]
+                            } else {
+                                response_contents(&self.post_process(text).await?, args)
+                            };
+                            let mut result = CallToolResult::success(contents);
+                            if let Some(thoughts) = thoughts {
+                                let mut content = Content::text(thoughts);
+                                if let RawContent::Text(text_content) = &mut content.raw {
+                                    let mut meta = Meta::new();
+                                    meta.insert(
+                                        "content_type".to_string(),
+                                        serde_json::json!("thoughts"),
+                                    );
+                                    text_content.meta = Some(meta);
+                                }
+                                result.content.push(content);
+                            }
+                            result.structured_content = structured_content;
+                            if let Some(files) = file_changes
+                                && args.include_file_changes.unwrap_or(false)
+                            {
+                                insert_meta(
+                                    &mut result,
+                                    "file_changes",
+                                    serde_json::to_value(files).unwrap_or_default(),
+                                );
+                            }
+                            if let Some(truncation_meta) = truncation_meta {
+                                insert_meta(&mut result, "output_truncated", truncation_meta);
+                            }
+                            result.content.extend(raw_content);
+                            Ok(result)
+                        }
+                        ParseResult::ParseError { error, .. }
+                            if args.fallback_to_text.unwrap_or(false) =>
+                        {
+                            tracing::debug!(
+                                parse_error = %error,
+                                "Returning unparseable JSON output as text"
+                            );
+                            let response = self.post_process(raw_response.to_string()).await?;
+                            let mut result =
+                                CallToolResult::success(response_contents(&response, args));
+                            insert_meta(
+                                &mut result,
+                                "json_fallback",
+                                serde_json::json!({ "parse_error": error }),
+                            );
+                            if let Some(truncation_meta) = truncation_meta {
+                                insert_meta(&mut result, "output_truncated", truncation_meta);
+                            }
+                            result.content.extend(raw_content);
+                            Ok(result)
+                        }
+                        ParseResult::ParseError { raw_output, error } => {
+                            // JSON parsing failed, return error with raw output for debugging
+                            Err(McpError::internal_error(
+                                "gemini_json_parse_error",
+                                Some(serde_json::json!({
+                                    "parse_error": error,
+                                    "raw_output": raw_output,
+                                    "prompt": args.prompt
+                                })),
+                            ))
+                        }
+                    }
+                } else {
+                    // Handle non-zero exit code
+                    let stderr = decode_output(&output.stderr, self.config.output_encoding);
+                    let stderr = mark_truncated(stderr.trim().to_string(), output.stderr_truncated);
+                    let _error_msg = if stderr.trim().is_empty() {
+                        format!(
+                            "Gemini CLI exited with code {}",
+                            output.status.code().unwrap_or(-1)
+                        )
+                    } else {
+                        format!("Gemini CLI error: {}", stderr.trim())
+                    };
+
+                    Err(McpError::internal_error(
+                        "gemini_cli_execution_failed",
+                        Some(serde_json::json!({
+                            "exit_code": output.status.code(),
+                            "stderr": stderr,
+                            "prompt": args.prompt
+                        })),
+                    ))
+                }
+            }
+            Err(e) => {
+                // Handle command execution failure (e.g., command not found)
+                let _error_msg = if e.kind() == std::io::ErrorKind::NotFound {
+                    format!(
+                        "Gemini CLI command '{}' not found. Please ensure it's installed and accessible.",
+                        command
+                    )
+                } else {
+                    format!("Failed to execute Gemini CLI: {}", e)
+                };
+
+                Err(command_failed(command, &e, &args.prompt))
+            }
+        }
+    }
+}
+
+#[tool_handler]
+impl ServerHandler for GeminiCli {
+    fn get_info(&self) -> ServerInfo {
+        ServerInfo {
+            protocol_version: ProtocolVersion::V_2024_11_05,
+            capabilities: ServerCapabilities::builder()
+                .enable_tools()
+                .enable_resources()
+                .enable_prompts()
+                .enable_logging()
+                .build(),
+            server_info: Implementation::from_build_env(),
+            instructions: Some(
+                "This server provides Gemini CLI integration with structured JSON output. \
+                Tools: prompt-gemini (send prompts to Gemini CLI and receive JSON responses), \
+                validate-prompt (check a prompt without running Gemini CLI), \
+                debug-args (prompt-gemini arguments as parsed, for checking client input), \
+                estimate-tokens (token count of a prompt before sending it), \
+                compare-models (run one prompt against several models in parallel), \
+                batch-prompt (run several prompts in parallel, each with its own result), \
+                ping (connectivity check), \
+                server-metrics (execution counters and latency percentiles), \
+                list-active-requests (prompt executions currently running), \
+                diagnose (run Gemini CLI's diagnostics subcommand to check auth and environment), \
+                clear-cache (empty the response cache, when caching is enabled), \
+                describe-tools (tool schemas and parameter descriptions), \
+                last-file-changes (lines added/removed by the most recent prompt), \
+                tool-decisions (accept/reject rates of Gemini's tool calls), \
+                resource-usage (wall time of the last execution; CPU time and memory are server-wide aggregates), \
+                list-workspace-files (files in the workspace, optionally filtered by a glob), \
+                show-config (effective configuration, secrets redacted), \
+                reload-config (re-read the configuration file). \
+                Workspace files are also exposed as resources with file:// URIs, and reusable \
+                prompt templates as prompts whose result can be passed to prompt-gemini."
+                    .to_string(),
+            ),
+        }
+    }
+
+    async fn initialize(
+        &self,
+        _request: InitializeRequestParam,
+        context: RequestContext<RoleServer>,
+    ) -> Result<InitializeResult, McpError> {
+        self.client_log.set_peer(context.peer);
+        Ok(self.get_info())
+    }
+
+    async fn set_level(
+        &self,
+        request: SetLevelRequestParam,
+        _context: RequestContext<RoleServer>,
+    ) -> Result<(), McpError> {
+        self.client_log.set_level(request.level);
+        Ok(())
+    }
+
+    async fn list_prompts(
+        &self,
+        _request: Option<PaginatedRequestParam>,
+        _context: RequestContext<RoleServer>,
+    ) -> Result<ListPromptsResult, McpError> {
+        let prompts = self
+            .with_current_config()
+            .prompt_templates()?
+            .into_iter()
+            .map(|template| {
+                let arguments = template
+                    .variables
+                    .into_iter()
+                    .map(|variable| PromptArgument {
+                        name: variable,
+                        title: None,
+                        description: None,
+                        required: Some(true),
+                    })
+                    .collect();
+                Prompt::new(template.name, None::<String>, Some(arguments))
+            })
+            .collect();
+        Ok(ListPromptsResult {
+            prompts,
+            next_cursor: None,
+        })
+    }
+
+    async fn get_prompt(
+        &self,
+        request: GetPromptRequestParam,
+        _context: RequestContext<RoleServer>,
+    ) -> Result<GetPromptResult, McpError> {
+        self.with_current_config()
+            .get_prompt_template(&request.name, request.arguments)
+    }
+
+    async fn list_resources(
+        &self,
+        _request: Option<PaginatedRequestParam>,
+        _context: RequestContext<RoleServer>,
+    ) -> Result<ListResourcesResult, McpError> {
+        let workspace = self.with_current_config().workspace_dir()?;
+        // Listing is bounded like list-workspace-files; larger trees are better explored
+        // through that tool's glob filter
+        let options = workspace_files::ListOptions {
+            glob: None,
+            max_depth: workspace_files::DEFAULT_MAX_DEPTH,
+            max_files: workspace_files::DEFAULT_MAX_FILES,
+            respect_gitignore: true,
+        };
+        let root = workspace.clone();
+        // File sizes are read in the same blocking task as the walk
+        let files = tokio::task::spawn_blocking(move || {
+            let listing = workspace_files::list_files(&root, &options)?;
+            Ok(listing
+                .files
+                .into_iter()
+                .map(|relative| {
+                    let size = std::fs::metadata(root.join(&relative))
+                        .ok()
+                        .and_then(|metadata| u32::try_from(metadata.len()).ok());
+                    (relative, size)
+                })
+                .collect::<Vec<_>>())
+        })
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|files| files)
+        .map_err(|error: String| {
+            McpError::internal_error(
+                "gemini_workspace_listing_failed",
+                Some(serde_json::json!({
+                    "workspace": workspace,
+                    "error": error,
+                })),
+            )
+        })?;
+
+        let resources = files
+            .into_iter()
+            .map(|(relative, size)| {
+                let path = workspace.join(&relative);
+                RawResource {
+                    uri: workspace_files::file_uri(&path),
+                    mime_type: Some(workspace_files::mime_type(&path).to_string()),
+                    size,
+                    ..RawResource::new("", relative)
+                }
+                .no_annotation()
+            })
+            .collect();
+        Ok(ListResourcesResult {
+            resources,
+            next_cursor: None,
+        })
+    }
+
+    async fn read_resource(
+        &self,
+        request: ReadResourceRequestParam,
+        _context: RequestContext<RoleServer>,
+    ) -> Result<ReadResourceResult, McpError> {
+        let workspace = self.with_current_config().workspace_dir()?;
+        let uri = request.uri;
+        let resource_uri = uri.clone();
+        let read = tokio::task::spawn_blocking(move || {
+            let path = workspace_files::resolve_resource(&workspace, &resource_uri)?;
+            let bytes = std::fs::read(&path)
+                .map_err(|e| format!("'{}' cannot be read: {}", resource_uri, e))?;
+            Ok::<_, String>((path, bytes))
+        })
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|read| read);
+        let (path, bytes) = read.map_err(|error| {
+            McpError::resource_not_found(
+                "gemini_resource_unavailable",
+                Some(serde_json::json!({ "uri": uri, "error": error })),
+            )
+        })?;
+
+        let mime_type = workspace_files::mime_type(&path);
+        let contents = match String::from_utf8(bytes) {
+            Ok(text) => ResourceContents::TextResourceContents {
+                uri,
+                // Unknown extensions holding valid UTF-8 are served as plain text
+                mime_type: Some(match mime_type {
+                    "application/octet-stream" => "text/plain".to_string(),
+                    mime_type => mime_type.to_string(),
+                }),
+                text,
+                meta: None,
+            },
+            Err(e) => ResourceContents::BlobResourceContents {
+                uri,
+                mime_type: Some(mime_type.to_string()),
+                blob: base64::engine::general_purpose::STANDARD.encode(e.as_bytes()),
+                meta: None,
+            },
+        };
+        Ok(ReadResourceResult {
+            contents: vec![contents],
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::circuit_breaker::CircuitBreakerConfig;
+    use crate::redact::Redactor;
+    use crate::retry_budget::RetryBudgetConfig;
+    use crate::retry_jitter::RetryJitter;
+    use rmcp::handler::server::wrapper::Parameters;
+
+    #[tokio::test]
+    async fn test_prompt_gemini_command_not_found() {
+        let gemini_cli = GeminiCli::new("nonexistent_command_12345".to_string(), None);
+        let args = PromptGeminiArgs {
+            prompt: "test prompt".to_string(),
+            ..Default::default()
+        };
+
+        let result = gemini_cli
+            .prompt_gemini(Parameters(args), CancellationToken::new())
+            .await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
     async fn test_prompt_gemini_with_echo() {
         // Use echo command which outputs plain text - should result in JSON parse error
         let gemini_cli = GeminiCli::new("echo".to_string(), None);
         let args = PromptGeminiArgs {
-            prompt: "test response".to_string(),
+            prompt: "test response".to_string(),
+            ..Default::default()
+        };
+
+        let result = gemini_cli
+            .prompt_gemini(Parameters(args), CancellationToken::new())
+            .await;
+        assert!(result.is_err());
+
+        // Should be a JSON parse error since echo doesn't output valid JSON
+        if let Err(error) = result {
+            assert!(error.message.contains("gemini_json_parse_error"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_gemini_cli_new() {
+        let gemini_cli = GeminiCli::new("test_command".to_string(), None);
+        assert_eq!(gemini_cli.config.gemini_cli_command, "test_command");
+    }
+
+    #[tokio::test]
+    async fn test_prompt_gemini_with_multiword_command() {
+        // Test with a multi-word command like "echo hello"
+        // This should fail with JSON parse error since echo outputs plain text
+        let gemini_cli = GeminiCli::new("echo hello".to_string(), None);
+        let args = PromptGeminiArgs {
+            prompt: "world".to_string(),
+            ..Default::default()
+        };
+
+        let result = gemini_cli
+            .prompt_gemini(Parameters(args), CancellationToken::new())
+            .await;
+        assert!(result.is_err());
+
+        if let Err(error) = result {
+            assert!(error.message.contains("gemini_json_parse_error"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_prompt_gemini_with_empty_output() {
+        // Test with a simple command that we know will work (true does nothing but exit successfully)
+        // This test verifies the plain text response handling
+        let gemini_cli = GeminiCli::new("true".to_string(), None);
+        let args = PromptGeminiArgs {
+            prompt: "test prompt".to_string(),
+            ..Default::default()
+        };
+
+        let result = gemini_cli
+            .prompt_gemini(Parameters(args), CancellationToken::new())
+            .await;
+
+        // Since 'true' returns empty output, it should result in empty response content
+        assert!(result.is_ok());
+
+        if let Ok(call_result) = result {
+            assert!(!call_result.content.is_empty());
+            // Should contain empty response message
+            if let RawContent::Text(text_content) = &call_result.content[0].raw {
+                assert_eq!(text_content.text, "Gemini CLI returned empty response");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_prompt_gemini_with_text_output() {
+        // Test with a command that returns plain text - should result in JSON parse error
+        let gemini_cli = GeminiCli::new("echo 'Hello from Gemini'".to_string(), None);
+        let args = PromptGeminiArgs {
+            prompt: "test prompt".to_string(),
+            ..Default::default()
+        };
+
+        let result = gemini_cli
+            .prompt_gemini(Parameters(args), CancellationToken::new())
+            .await;
+        assert!(result.is_err());
+
+        if let Err(error) = result {
+            assert!(error.message.contains("gemini_json_parse_error"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_validate_prompt_does_not_spawn() {
+        // The command does not exist, so any spawn attempt would surface as an error
+        let gemini_cli = GeminiCli::new("nonexistent_command_12345".to_string(), None);
+        let args = PromptGeminiArgs {
+            prompt: "hello world!".to_string(),
+            ..Default::default()
+        };
+
+        let result = gemini_cli.validate_prompt(Parameters(args)).await.unwrap();
+        let RawContent::Text(text_content) = &result.content[0].raw else {
+            panic!("Expected text content");
+        };
+        let report: serde_json::Value = serde_json::from_str(&text_content.text).unwrap();
+        assert_eq!(report["valid"], true);
+        assert_eq!(report["prompt_chars"], 12);
+        assert_eq!(report["estimated_tokens"], 3);
+    }
+
+    #[tokio::test]
+    async fn test_validate_prompt_reports_reasons() {
+        let gemini_cli = GeminiCli::new("echo".to_string(), None);
+        let args = PromptGeminiArgs {
+            prompt: "   ".to_string(),
+            ..Default::default()
+        };
+
+        let result = gemini_cli.validate_prompt(Parameters(args)).await.unwrap();
+        let RawContent::Text(text_content) = &result.content[0].raw else {
+            panic!("Expected text content");
+        };
+        let report: serde_json::Value = serde_json::from_str(&text_content.text).unwrap();
+        assert_eq!(report["valid"], false);
+        assert_eq!(report["reasons"][0], "Prompt is empty");
+    }
+
+    #[tokio::test]
+    async fn test_debug_args_echoes_parsed_arguments() {
+        let gemini_cli = GeminiCli::new("nonexistent_command_12345".to_string(), None);
+        let args: PromptGeminiArgs = serde_json::from_value(serde_json::json!({
+            "prompt": "",
+            "output_format": "yaml",
+            "variables": {"name": "world"},
+        }))
+        .unwrap();
+
+        let result = gemini_cli.debug_args(Parameters(args)).await.unwrap();
+        let RawContent::Text(text_content) = &result.content[0].raw else {
+            panic!("Expected text content");
+        };
+        let parsed: serde_json::Value = serde_json::from_str(&text_content.text).unwrap();
+        // Not validated, and unset parameters are listed too
+        assert_eq!(parsed["prompt"], "");
+        assert_eq!(parsed["output_format"], "yaml");
+        assert_eq!(parsed["variables"]["name"], "world");
+        assert!(parsed["model"].is_null());
+    }
+
+    #[tokio::test]
+    async fn test_prompt_gemini_rejects_invalid_prompt() {
+        let gemini_cli = GeminiCli::new("echo".to_string(), None);
+        let args = PromptGeminiArgs {
+            prompt: "".to_string(),
+            ..Default::default()
+        };
+
+        let result = gemini_cli
+            .prompt_gemini(Parameters(args), CancellationToken::new())
+            .await;
+        assert!(result.is_err());
+
+        if let Err(error) = result {
+            assert!(error.message.contains("gemini_invalid_prompt"));
+        }
+    }
+
+    /// Log output collected by [`capture_logs`]
+    #[derive(Clone, Default)]
+    struct LogBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for LogBuffer {
+        fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(bytes);
+            Ok(bytes.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Collect this thread's log output matching `filter` until the guard is dropped
+    fn capture_logs(
+        filter: tracing_subscriber::EnvFilter,
+    ) -> (LogBuffer, tracing::subscriber::DefaultGuard) {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let logs = LogBuffer::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::registry().with(filter).with(
+            tracing_subscriber::fmt::layer()
+                .with_ansi(false)
+                .with_writer(move || writer.clone()),
+        );
+        (logs, tracing::subscriber::set_default(subscriber))
+    }
+
+    /// Write an executable shell script to the temp dir and return its path, for tests
+    /// that need a fake gemini-cli with specific behaviour (delays, stderr, exit codes)
+    fn script_command(name: &str, body: &str) -> String {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir().join(format!(
+            "mcp-gemini-cli-test-{}-{}.sh",
+            std::process::id(),
+            name
+        ));
+        std::fs::write(&path, format!("#!/bin/sh\n{}\n", body)).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    fn backend_config() -> GeminiCliConfig {
+        GeminiCliConfig {
+            gemini_cli_command: "nonexistent_command_12345".to_string(),
+            backends: [
+                (
+                    "fast".to_string(),
+                    r#"echo {"response": "fast"}"#.to_string(),
+                ),
+                (
+                    "slow".to_string(),
+                    r#"echo {"response": "slow"}"#.to_string(),
+                ),
+            ]
+            .into_iter()
+            .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_prompt_gemini_selects_named_backend() {
+        let gemini_cli = GeminiCli::with_config(Arc::new(backend_config()));
+        let args = PromptGeminiArgs {
+            prompt: "test".to_string(),
+            backend: Some("slow".to_string()),
+            ..Default::default()
+        };
+
+        let result = gemini_cli
+            .prompt_gemini(Parameters(args), CancellationToken::new())
+            .await
+            .unwrap();
+        let RawContent::Text(text_content) = &result.content[0].raw else {
+            panic!("Expected text content");
+        };
+        assert_eq!(text_content.text, "slow");
+    }
+
+    #[tokio::test]
+    async fn test_prompt_gemini_uses_default_backend() {
+        let gemini_cli = GeminiCli::with_config(Arc::new(GeminiCliConfig {
+            default_backend: Some("fast".to_string()),
+            ..backend_config()
+        }));
+        let args = PromptGeminiArgs {
+            prompt: "test".to_string(),
+            ..Default::default()
+        };
+
+        let result = gemini_cli
+            .prompt_gemini(Parameters(args), CancellationToken::new())
+            .await
+            .unwrap();
+        let RawContent::Text(text_content) = &result.content[0].raw else {
+            panic!("Expected text content");
+        };
+        assert_eq!(text_content.text, "fast");
+    }
+
+    #[tokio::test]
+    async fn test_prompt_gemini_unknown_backend() {
+        let gemini_cli = GeminiCli::with_config(Arc::new(backend_config()));
+        let args = PromptGeminiArgs {
+            prompt: "test".to_string(),
+            backend: Some("missing".to_string()),
+            ..Default::default()
+        };
+
+        let result = gemini_cli
+            .prompt_gemini(Parameters(args), CancellationToken::new())
+            .await;
+        assert!(result.is_err());
+
+        if let Err(error) = result {
+            assert!(error.message.contains("gemini_invalid_prompt"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_prompt_gemini_fails_over_when_command_not_found() {
+        let gemini_cli = GeminiCli::with_config(Arc::new(GeminiCliConfig {
+            gemini_cli_command: "nonexistent_command_12345".to_string(),
+            fallback_command: Some(r#"echo {"response": "from fallback"}"#.to_string()),
+            ..Default::default()
+        }));
+        let args = PromptGeminiArgs {
+            prompt: "test".to_string(),
+            ..Default::default()
+        };
+
+        let result = gemini_cli
+            .prompt_gemini(Parameters(args), CancellationToken::new())
+            .await
+            .unwrap();
+        let RawContent::Text(text_content) = &result.content[0].raw else {
+            panic!("Expected text content");
+        };
+        assert_eq!(text_content.text, "from fallback");
+
+        let meta = result.meta.expect("failover should be recorded in meta");
+        assert_eq!(
+            meta["failover"]["primary_command"],
+            "nonexistent_command_12345"
+        );
+    }
+
+    #[test]
+    fn test_spawned_event_is_redacted() {
+        let gemini_cli = GeminiCli::with_config(Arc::new(GeminiCliConfig {
+            output_redactor: Redactor::from_env(
+                &[] as &[&str],
+                vec![regex::Regex::new(r"tok-\d+").unwrap()],
+            ),
+            ..Default::default()
+        }));
+        let event = gemini_cli.spawned_event("gemini --api-key hunter2 --tag tok-123", Some(42));
+        assert_eq!(
+            event,
+            serde_json::json!({
+                "event": "spawned",
+                "command": "gemini --api-key [REDACTED] --tag [REDACTED]",
+                "pid": 42,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_prompt_gemini_failover_meta_is_redacted() {
+        let fallback = script_command("failover-secret", r#"echo '{"response": "ok"}'"#);
+        let gemini_cli = GeminiCli::with_config(Arc::new(GeminiCliConfig {
+            gemini_cli_command: "nonexistent_command_12345 GEMINI_API_KEY=hunter1".to_string(),
+            fallback_command: Some(format!("{fallback} --token hunter2")),
+            ..Default::default()
+        }));
+        let args = PromptGeminiArgs {
+            prompt: "test".to_string(),
+            ..Default::default()
+        };
+
+        let result = gemini_cli.prompt(args).await.unwrap();
+        let failover = &result.meta.unwrap()["failover"];
+        assert_eq!(
+            failover["primary_command"],
+            "nonexistent_command_12345 GEMINI_API_KEY=[REDACTED]"
+        );
+        assert_eq!(
+            failover["fallback_command"],
+            format!("{fallback} --token [REDACTED]")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_prompt_gemini_retry_budget_stops_failover() {
+        let gemini_cli = GeminiCli::with_config(Arc::new(GeminiCliConfig {
+            gemini_cli_command: "nonexistent_command_12345".to_string(),
+            fallback_command: Some(r#"echo {"response": "from fallback"}"#.to_string()),
+            retry_budget: RetryBudgetConfig {
+                percent: 50,
+                window: Duration::from_secs(60),
+                min_retries: 1,
+            },
+            ..Default::default()
+        }));
+        let args = PromptGeminiArgs {
+            prompt: "test".to_string(),
+            ..Default::default()
+        };
+
+        // One retry is allowed for the first two calls, so the second one fails fast
+        gemini_cli
+            .prompt_gemini(Parameters(args.clone()), CancellationToken::new())
+            .await
+            .unwrap();
+        let error = gemini_cli
+            .prompt_gemini(Parameters(args), CancellationToken::new())
+            .await
+            .unwrap_err();
+        assert_eq!(error.message, "gemini_cli_command_failed");
+        assert_eq!(error.data.unwrap()["retry_budget_exhausted"], true);
+
+        let budget = gemini_cli.state.retry_budget.snapshot();
+        assert_eq!(budget.calls_in_window, 2);
+        assert_eq!(budget.retries_in_window, 1);
+    }
+
+    #[tokio::test]
+    async fn test_prompt_gemini_fails_over_on_server_error() {
+        let gemini_cli = GeminiCli::with_config(Arc::new(GeminiCliConfig {
+            gemini_cli_command: r#"echo {"response": "", "error": {"type": "ApiError", "message": "unavailable", "code": 503}}"#.to_string(),
+            fallback_command: Some(r#"echo {"response": "from fallback"}"#.to_string()),
+            ..Default::default()
+        }));
+        let args = PromptGeminiArgs {
+            prompt: "test".to_string(),
+            ..Default::default()
+        };
+
+        let result = gemini_cli
+            .prompt_gemini(Parameters(args), CancellationToken::new())
+            .await;
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_prompt_gemini_does_not_fail_over_on_auth_error() {
+        let gemini_cli = GeminiCli::with_config(Arc::new(GeminiCliConfig {
+            gemini_cli_command: r#"echo {"response": "", "error": {"type": "AuthError", "message": "API key invalid", "code": 401}}"#.to_string(),
+            fallback_command: Some(r#"echo {"response": "from fallback"}"#.to_string()),
+            ..Default::default()
+        }));
+        let args = PromptGeminiArgs {
+            prompt: "test".to_string(),
+            ..Default::default()
+        };
+
+        let result = gemini_cli
+            .prompt_gemini(Parameters(args), CancellationToken::new())
+            .await;
+        assert!(result.is_err());
+
+        if let Err(error) = result {
+            assert_eq!(error.message, "gemini_auth_error");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_prompt_gemini_error_classes() {
+        let api_error = |code: u16| async move {
+            let error_json = format!(
+                r#"{{"response": "", "error": {{"type": "ApiError", "message": "failed", "code": {}}}}}"#,
+                code
+            );
+            GeminiCli::new(format!("echo '{}'", error_json), None)
+                .prompt_gemini(
+                    Parameters(PromptGeminiArgs {
+                        prompt: "test".to_string(),
+                        ..Default::default()
+                    }),
+                    CancellationToken::new(),
+                )
+                .await
+                .unwrap_err()
+        };
+
+        let error = api_error(400).await;
+        assert_eq!(
+            (error.code, error.message.as_ref()),
+            (ErrorCode::INVALID_PARAMS, "gemini_invalid_request")
+        );
+        let error = api_error(429).await;
+        assert_eq!(
+            (error.code, error.message.as_ref()),
+            (ErrorCode::INTERNAL_ERROR, "gemini_rate_limited")
+        );
+        let error = api_error(403).await;
+        assert_eq!(
+            (error.code, error.message.as_ref()),
+            (ErrorCode::INTERNAL_ERROR, "gemini_auth_error")
+        );
+        let error = api_error(503).await;
+        assert_eq!(
+            (error.code, error.message.as_ref()),
+            (ErrorCode::INTERNAL_ERROR, "gemini_api_error")
+        );
+
+        // Validation failures are the client's to fix
+        let error = GeminiCli::new("echo".to_string(), None)
+            .prompt_gemini(
+                Parameters(PromptGeminiArgs {
+                    prompt: "test".to_string(),
+                    output_format: Some("xml".to_string()),
+                    ..Default::default()
+                }),
+                CancellationToken::new(),
+            )
+            .await
+            .unwrap_err();
+        assert!(is_client_error(&error), "{error:?}");
+    }
+
+    #[tokio::test]
+    async fn test_prompt_gemini_circuit_opens_after_failures() {
+        let gemini_cli = GeminiCli::with_config(Arc::new(GeminiCliConfig {
+            gemini_cli_command: "nonexistent_command_12345".to_string(),
+            circuit_breaker: CircuitBreakerConfig {
+                failure_threshold: 2,
+                ..Default::default()
+            },
+            ..Default::default()
+        }));
+
+        for _ in 0..2 {
+            let args = PromptGeminiArgs {
+                prompt: "test".to_string(),
+                ..Default::default()
+            };
+            let error = gemini_cli
+                .prompt_gemini(Parameters(args), CancellationToken::new())
+                .await
+                .unwrap_err();
+            assert!(error.message.contains("gemini_cli_command_failed"));
+        }
+
+        let args = PromptGeminiArgs {
+            prompt: "test".to_string(),
+            ..Default::default()
+        };
+        let error = gemini_cli
+            .prompt_gemini(Parameters(args), CancellationToken::new())
+            .await
+            .unwrap_err();
+        assert!(error.message.contains("gemini_circuit_open"));
+    }
+
+    #[tokio::test]
+    async fn test_prompt_gemini_cancellation_kills_subprocess() {
+        let marker = std::env::temp_dir().join(format!(
+            "mcp-gemini-cli-test-{}-cancelled-marker",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&marker);
+        let command = script_command(
+            "cancellable",
+            &format!("sleep 1; touch {}", marker.display()),
+        );
+        let gemini_cli = GeminiCli::new(command, None);
+
+        let ct = CancellationToken::new();
+        let cancel = ct.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+            cancel.cancel();
+        });
+
+        let args = PromptGeminiArgs {
+            prompt: "hi".to_string(),
+            ..Default::default()
+        };
+        let started = Instant::now();
+        let error = gemini_cli
+            .prompt_gemini(Parameters(args), ct)
+            .await
+            .unwrap_err();
+        assert_eq!(error.message, "gemini_request_cancelled");
+        assert!(started.elapsed() < std::time::Duration::from_secs(1));
+
+        // The script was killed before it could finish
+        tokio::time::sleep(std::time::Duration::from_millis(1500)).await;
+        assert!(!marker.exists());
+    }
+
+    #[tokio::test]
+    async fn test_compare_models_aggregates_partial_failures() {
+        // Fails for the "broken" model, echoes the model name otherwise
+        let command = script_command(
+            "compare-models",
+            r#"while [ $# -gt 0 ]; do
+  if [ "$1" = "--model" ]; then model="$2"; fi
+  shift
+done
+if [ "$model" = "broken" ]; then echo 'quota exceeded' >&2; exit 1; fi
+echo "{\"response\": \"from $model\", \"stats\": {\"files\": null}}""#,
+        );
+        let gemini_cli = GeminiCli::new(command, None);
+
+        let args = CompareModelsArgs {
+            prompt: "hi".to_string(),
+            models: vec![
+                "gemini-2.5-pro".to_string(),
+                "broken".to_string(),
+                "gemini-2.5-flash".to_string(),
+            ],
+            backend: None,
+        };
+        let result = gemini_cli
+            .compare_models(Parameters(args), CancellationToken::new())
+            .await
+            .unwrap();
+        let RawContent::Text(text_content) = &result.content[0].raw else {
+            panic!("Expected text content");
+        };
+        let comparisons: serde_json::Value = serde_json::from_str(&text_content.text).unwrap();
+
+        assert_eq!(comparisons[0]["model"], "gemini-2.5-pro");
+        assert_eq!(comparisons[0]["response"], "from gemini-2.5-pro");
+        assert!(comparisons[0]["error"].is_null());
+        assert!(comparisons[0]["stats"].is_object());
+        assert_eq!(comparisons[1]["model"], "broken");
+        assert!(comparisons[1]["response"].is_null());
+        assert_eq!(
+            comparisons[1]["error"]["code"],
+            "gemini_cli_execution_failed"
+        );
+        assert_eq!(comparisons[2]["response"], "from gemini-2.5-flash");
+    }
+
+    #[tokio::test]
+    async fn test_batch_prompt_with_overrides_and_partial_failures() {
+        // Echoes the model and prompt as JSON, failing for the "fail" prompt
+        let command = script_command(
+            "batch-prompt",
+            r#"while [ $# -gt 0 ]; do
+  case "$1" in
+    --model) model="$2"; shift ;;
+    --prompt) prompt="$2"; shift ;;
+    --output-format) json=1 ;;
+  esac
+  shift
+done
+if [ "$prompt" = "fail" ]; then echo 'boom' >&2; exit 1; fi
+if [ -n "$json" ]; then echo "{\"response\": \"$model: $prompt\"}"; else echo "text $model: $prompt"; fi"#,
+        );
+        let gemini_cli = GeminiCli::new(command, None);
+
+        let args: BatchPromptArgs = serde_json::from_value(serde_json::json!({
+            "prompts": [
+                "first",
+                "fail",
+                {"prompt": "third", "model": "gemini-2.5-pro", "output_format": "text"},
+            ],
+            "model": "gemini-2.5-flash",
+        }))
+        .unwrap();
+        let result = gemini_cli
+            .batch_prompt(Parameters(args), CancellationToken::new())
+            .await
+            .unwrap();
+        let RawContent::Text(text_content) = &result.content[0].raw else {
+            panic!("Expected text content");
+        };
+        let outcomes: serde_json::Value = serde_json::from_str(&text_content.text).unwrap();
+
+        assert_eq!(outcomes[0]["response"], "gemini-2.5-flash: first");
+        assert!(outcomes[0]["error"].is_null());
+        assert!(outcomes[1]["response"].is_null());
+        assert_eq!(outcomes[1]["error"]["code"], "gemini_cli_execution_failed");
+        assert_eq!(outcomes[2]["response"], "text gemini-2.5-pro: third");
+        assert_eq!(gemini_cli.state.metrics.snapshot().total_calls, 3);
+
+        let args = BatchPromptArgs {
+            prompts: Vec::new(),
+            model: None,
+            output_format: None,
+            backend: None,
+        };
+        let error = gemini_cli
+            .batch_prompt(Parameters(args), CancellationToken::new())
+            .await
+            .unwrap_err();
+        assert_eq!(error.message, "gemini_invalid_prompts");
+
+        let args = BatchPromptArgs {
+            prompts: (0..=MAX_BATCH_PROMPTS)
+                .map(|i| BatchPromptItem::Prompt(i.to_string()))
+                .collect(),
+            model: None,
+            output_format: None,
+            backend: None,
+        };
+        let error = gemini_cli
+            .batch_prompt(Parameters(args), CancellationToken::new())
+            .await
+            .unwrap_err();
+        assert_eq!(error.message, "gemini_invalid_prompts");
+        assert_eq!(error.data.unwrap()["max_prompts"], MAX_BATCH_PROMPTS);
+    }
+
+    #[tokio::test]
+    async fn test_batch_prompt_counts_against_session_limit() {
+        let gemini_cli = GeminiCli::with_config(Arc::new(GeminiCliConfig {
+            gemini_cli_command: "echo".to_string(),
+            default_output_format: OutputFormat::Text,
+            max_in_flight_per_session: Some(2),
+            ..Default::default()
+        }));
+        let args: BatchPromptArgs = serde_json::from_value(serde_json::json!({
+            "prompts": ["one", "two", "three"],
+        }))
+        .unwrap();
+        let result = gemini_cli
+            .batch_prompt(Parameters(args), CancellationToken::new())
+            .await
+            .unwrap();
+        let RawContent::Text(text_content) = &result.content[0].raw else {
+            panic!("Expected text content");
+        };
+        let outcomes: serde_json::Value = serde_json::from_str(&text_content.text).unwrap();
+
+        assert!(outcomes[0]["error"].is_null());
+        assert!(outcomes[1]["error"].is_null());
+        assert_eq!(outcomes[2]["error"]["code"], "gemini_session_busy");
+        assert_eq!(gemini_cli.session_stats.lock().unwrap().in_flight, 0);
+    }
+
+    #[tokio::test]
+    async fn test_compare_models_requires_models() {
+        let gemini_cli = GeminiCli::new("echo".to_string(), None);
+        let args = CompareModelsArgs {
+            prompt: "hi".to_string(),
+            models: Vec::new(),
+            backend: None,
+        };
+        let error = gemini_cli
+            .compare_models(Parameters(args), CancellationToken::new())
+            .await
+            .unwrap_err();
+        assert_eq!(error.message, "gemini_invalid_models");
+
+        let compare = |models: Vec<String>| {
+            gemini_cli.compare_models(
+                Parameters(CompareModelsArgs {
+                    prompt: "hi".to_string(),
+                    models,
+                    backend: None,
+                }),
+                CancellationToken::new(),
+            )
+        };
+        let too_many = (0..=MAX_COMPARE_MODELS)
+            .map(|i| format!("model-{i}"))
+            .collect();
+        let error = compare(too_many).await.unwrap_err();
+        assert_eq!(error.message, "gemini_invalid_models");
+        assert_eq!(error.data.unwrap()["max_models"], MAX_COMPARE_MODELS);
+
+        let duplicated = vec!["gemini-2.5-pro".to_string(), "gemini-2.5-pro".to_string()];
+        let error = compare(duplicated).await.unwrap_err();
+        assert_eq!(error.message, "gemini_invalid_models");
+    }
+
+    #[tokio::test]
+    async fn test_prompt_gemini_timeout() {
+        let command = script_command("timeout", "echo 'partial answer'; exec sleep 5");
+        let gemini_cli = GeminiCli::with_config(Arc::new(GeminiCliConfig {
+            gemini_cli_command: command,
+            timeout_secs: Some(1),
+            ..Default::default()
+        }));
+
+        let args = PromptGeminiArgs {
+            prompt: "hi".to_string(),
+            ..Default::default()
+        };
+        let started = Instant::now();
+        let error = gemini_cli
+            .prompt_gemini(Parameters(args), CancellationToken::new())
+            .await
+            .unwrap_err();
+        assert_eq!(error.message, "gemini_cli_timeout");
+        assert_eq!(error.data.unwrap()["timeout_secs"], 1);
+        assert!(started.elapsed() < Duration::from_secs(3));
+        let metrics = gemini_cli.state.metrics.snapshot();
+        assert_eq!((metrics.timeouts, metrics.failures), (1, 0));
+
+        let args = PromptGeminiArgs {
+            prompt: "hi".to_string(),
+            return_partial_on_timeout: Some(true),
+            ..Default::default()
+        };
+        let result = gemini_cli
+            .prompt_gemini(Parameters(args), CancellationToken::new())
+            .await
+            .unwrap();
+        let RawContent::Text(text_content) = &result.content[0].raw else {
+            panic!("Expected text content");
+        };
+        assert_eq!(text_content.text, "partial answer");
+        let meta = result
+            .meta
+            .expect("partial output should be marked in meta");
+        assert_eq!(meta["partial"], true);
+        assert_eq!(meta["gemini_cli_timeout"]["timeout_secs"], 1);
+    }
+
+    #[tokio::test]
+    async fn test_prompt_gemini_strip_ansi() {
+        let command = script_command(
+            "strip-ansi",
+            r#"printf '\033[2K\033[1G{"response": "\033[1;32mall good\033[0m"}\n'"#,
+        );
+        let gemini_cli = GeminiCli::with_config(Arc::new(GeminiCliConfig {
+            gemini_cli_command: command,
+            strip_ansi: true,
+            ..Default::default()
+        }));
+
+        let args = PromptGeminiArgs {
+            prompt: "hi".to_string(),
+            ..Default::default()
+        };
+        let result = gemini_cli
+            .prompt_gemini(Parameters(args), CancellationToken::new())
+            .await
+            .unwrap();
+        let RawContent::Text(text_content) = &result.content[0].raw else {
+            panic!("Expected text content");
+        };
+        assert_eq!(text_content.text, "all good");
+    }
+
+    #[tokio::test]
+    async fn test_prompt_gemini_model_timeout() {
+        let command = script_command("model-timeout", "exec sleep 5");
+        let gemini_cli = GeminiCli::with_config(Arc::new(GeminiCliConfig {
+            gemini_cli_command: command,
+            timeout_secs: Some(60),
+            default_model: Some("gemini-2.5-flash".to_string()),
+            model_timeouts: HashMap::from([("gemini-2.5-flash".to_string(), 1)]),
+            ..Default::default()
+        }));
+
+        let args = PromptGeminiArgs {
+            prompt: "hi".to_string(),
+            ..Default::default()
+        };
+        let started = Instant::now();
+        let error = gemini_cli
+            .prompt_gemini(Parameters(args), CancellationToken::new())
+            .await
+            .unwrap_err();
+        assert!(started.elapsed() < Duration::from_secs(3));
+        assert_eq!(error.message, "gemini_cli_timeout");
+        assert_eq!(error.data.unwrap()["timeout_secs"], 1);
+
+        // Other models fall back to the global timeout
+        let args = PromptGeminiArgs {
+            prompt: "hi".to_string(),
+            model: Some("gemini-2.5-pro".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(gemini_cli.timeout_secs(&args), Some(60));
+    }
+
+    #[tokio::test]
+    async fn test_prompt_gemini_deadline() {
+        let command = script_command("deadline", "exec sleep 5");
+        let gemini_cli = GeminiCli::with_config(Arc::new(GeminiCliConfig {
+            gemini_cli_command: command,
+            timeout_secs: Some(60),
+            max_deadline_secs: 1,
+            ..Default::default()
+        }));
+
+        // A request deadline replaces the longer server timeout
+        let args = PromptGeminiArgs {
+            prompt: "hi".to_string(),
+            deadline_ms: Some(200),
+            ..Default::default()
+        };
+        let started = Instant::now();
+        let error = gemini_cli
+            .prompt_gemini(Parameters(args), CancellationToken::new())
+            .await
+            .unwrap_err();
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert_eq!(error.message, "gemini_cli_timeout");
+        assert_eq!(error.data.unwrap()["deadline_ms"], 200);
+
+        // and is capped at the server maximum
+        let args = PromptGeminiArgs {
+            prompt: "hi".to_string(),
+            deadline_ms: Some(60_000),
+            ..Default::default()
+        };
+        let started = Instant::now();
+        let error = gemini_cli
+            .prompt_gemini(Parameters(args), CancellationToken::new())
+            .await
+            .unwrap_err();
+        assert!(started.elapsed() < Duration::from_secs(3));
+        assert_eq!(error.data.unwrap()["deadline_ms"], 1000);
+    }
+
+    #[tokio::test]
+    async fn test_workspace_allowlist() {
+        let root = std::env::temp_dir().join(format!(
+            "mcp-gemini-cli-test-{}-workspaces",
+            std::process::id()
+        ));
+        let inside = root.join("project");
+        std::fs::create_dir_all(&inside).unwrap();
+
+        let gemini_cli_with_workspace = |workspace: String| {
+            let config = GeminiCliConfig {
+                gemini_cli_command: "echo".to_string(),
+                workspace: Some(workspace),
+                default_output_format: OutputFormat::Text,
+                allowed_workspace_roots: vec![root.clone()],
+                ..Default::default()
+            };
+            GeminiCli::with_config(Arc::new(config.resolve().unwrap()))
+        };
+        let prompt = |gemini_cli: GeminiCli| async move {
+            let args = PromptGeminiArgs {
+                prompt: "hi".to_string(),
+                ..Default::default()
+            };
+            gemini_cli
+                .prompt_gemini(Parameters(args), CancellationToken::new())
+                .await
+        };
+
+        let gemini_cli = gemini_cli_with_workspace(inside.to_string_lossy().into_owned());
+        assert!(prompt(gemini_cli).await.is_ok());
+
+        // `..` traversal out of the root is caught after canonicalization
+        let escaping = inside.join("..").join("..");
+        let gemini_cli = gemini_cli_with_workspace(escaping.to_string_lossy().into_owned());
+        let error = prompt(gemini_cli).await.unwrap_err();
+        assert_eq!(error.message, "gemini_workspace_not_allowed");
+        assert!(!is_client_error(&error));
+
+        let gemini_cli = gemini_cli_with_workspace("/nonexistent/workspace".to_string());
+        let error = prompt(gemini_cli).await.unwrap_err();
+        assert_eq!(error.message, "gemini_workspace_not_allowed");
+    }
+
+    #[tokio::test]
+    async fn test_prompt_gemini_invalid_utf8() {
+        let command = script_command("invalid-utf8", r#"printf '{"response": "caf\351"}\n'"#);
+        let gemini_cli = GeminiCli::new(command, None);
+
+        let args = PromptGeminiArgs {
+            prompt: "hi".to_string(),
+            ..Default::default()
+        };
+        let error = gemini_cli
+            .prompt_gemini(Parameters(args), CancellationToken::new())
+            .await
+            .unwrap_err();
+        assert_eq!(error.message, "gemini_invalid_utf8");
+        let data = error.data.unwrap();
+        assert_eq!(data["byte_offset"], 17);
+        assert_eq!(data["invalid_bytes"], "[e9]");
+
+        // Text mode passes the output through with replacement characters
+        let args = PromptGeminiArgs {
+            prompt: "hi".to_string(),
+            output_format: Some("text".to_string()),
+            ..Default::default()
+        };
+        let result = gemini_cli
+            .prompt_gemini(Parameters(args), CancellationToken::new())
+            .await
+            .unwrap();
+        let RawContent::Text(text_content) = &result.content[0].raw else {
+            panic!("Expected text content");
+        };
+        assert_eq!(text_content.text, "{\"response\": \"caf\u{fffd}\"}");
+    }
+
+    #[tokio::test]
+    async fn test_prompt_gemini_substitutes_variables() {
+        let gemini_cli = GeminiCli::new("echo".to_string(), None);
+
+        let args = PromptGeminiArgs {
+            prompt: "Review {{file}} for {{concern}}".to_string(),
+            variables: Some(HashMap::from([
+                ("file".to_string(), "main.rs".to_string()),
+                ("concern".to_string(), "races".to_string()),
+            ])),
+            output_format: Some("text".to_string()),
+            ..Default::default()
+        };
+        let result = gemini_cli
+            .prompt_gemini(Parameters(args), CancellationToken::new())
+            .await
+            .unwrap();
+        let RawContent::Text(text_content) = &result.content[0].raw else {
+            panic!("Expected text content");
+        };
+        assert_eq!(
+            text_content.text,
+            "--yolo --prompt Review main.rs for races"
+        );
+
+        let args = PromptGeminiArgs {
+            prompt: "Review {{file}}".to_string(),
+            strict_templating: Some(true),
+            ..Default::default()
+        };
+        let error = gemini_cli
+            .prompt_gemini(Parameters(args), CancellationToken::new())
+            .await
+            .unwrap_err();
+        assert_eq!(error.message, "gemini_template_error");
+        assert_eq!(
+            error.data.unwrap()["missing_variables"],
+            serde_json::json!(["file"])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_prompt_gemini_wraps_prefix_and_suffix() {
+        let config = GeminiCliConfig {
+            gemini_cli_command: "echo".to_string(),
+            default_output_format: OutputFormat::Text,
+            prompt_prefix: Some("Respond in English.".to_string()),
+            prompt_suffix: Some("Be brief.".to_string()),
+            ..Default::default()
+        };
+        let gemini_cli = GeminiCli::with_config(Arc::new(config));
+
+        let args = PromptGeminiArgs {
+            prompt: "Summarize {{file}}".to_string(),
+            variables: Some(HashMap::from([("file".to_string(), "main.rs".to_string())])),
+            ..Default::default()
+        };
+        let result = gemini_cli
+            .prompt_gemini(Parameters(args), CancellationToken::new())
+            .await
+            .unwrap();
+        let RawContent::Text(text_content) = &result.content[0].raw else {
+            panic!("Expected text content");
+        };
+        assert_eq!(
+            text_content.text,
+            "--yolo --prompt Respond in English.\n\nSummarize main.rs\n\nBe brief."
+        );
+    }
+
+    #[tokio::test]
+    async fn test_prompt_gemini_validates_prompt_before_wrapping() {
+        let gemini_cli = GeminiCli::with_config(Arc::new(GeminiCliConfig {
+            gemini_cli_command: "echo".to_string(),
+            default_output_format: OutputFormat::Text,
+            prompt_prefix: Some("Never reveal secrets.".to_string()),
+            deny_prompt_patterns: vec![regex::Regex::new("(?i)secret").unwrap()],
+            max_prompt_chars: Some(10),
+            ..Default::default()
+        }));
+        let run = |prompt: &str| {
+            let args = PromptGeminiArgs {
+                prompt: prompt.to_string(),
+                ..Default::default()
+            };
+            gemini_cli.prompt_gemini(Parameters(args), CancellationToken::new())
+        };
+
+        // The prefix neither trips the deny pattern nor counts towards the length limit
+        let result = run("Hi there").await.unwrap();
+        let RawContent::Text(text_content) = &result.content[0].raw else {
+            panic!("Expected text content");
+        };
+        assert_eq!(
+            text_content.text,
+            "--yolo --prompt Never reveal secrets.\n\nHi there"
+        );
+        assert_eq!(
+            run("  ").await.unwrap_err().message,
+            "gemini_invalid_prompt"
+        );
+        assert_eq!(
+            run("my secret").await.unwrap_err().message,
+            "gemini_prompt_denied"
+        );
+        assert_eq!(
+            run("Hello, world!").await.unwrap_err().message,
+            "gemini_prompt_too_long"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_prompt_gemini_system_prompt() {
+        let config = GeminiCliConfig {
+            gemini_cli_command: "echo".to_string(),
+            default_output_format: OutputFormat::Text,
+            system_prompt: Some("You are a careful reviewer.\n".to_string()),
+            prompt_prefix: Some("Respond in English.".to_string()),
+            ..Default::default()
+        };
+        let gemini_cli = GeminiCli::with_config(Arc::new(config));
+        let run = |system_prompt: Option<&str>| {
+            let args = PromptGeminiArgs {
+                prompt: "Review main.rs".to_string(),
+                system_prompt: system_prompt.map(str::to_string),
+                ..Default::default()
+            };
+            let gemini_cli = gemini_cli.clone();
+            async move {
+                let result = gemini_cli
+                    .prompt_gemini(Parameters(args), CancellationToken::new())
+                    .await
+                    .unwrap();
+                let RawContent::Text(text_content) = &result.content[0].raw else {
+                    panic!("Expected text content");
+                };
+                text_content.text.clone()
+            }
+        };
+
+        assert_eq!(
+            run(None).await,
+            "--yolo --prompt You are a careful reviewer.\n\nRespond in English.\n\nReview main.rs"
+        );
+        assert_eq!(
+            run(Some("Be terse.")).await,
+            "--yolo --prompt Be terse.\n\nRespond in English.\n\nReview main.rs"
+        );
+        assert_eq!(
+            run(Some("")).await,
+            "--yolo --prompt Respond in English.\n\nReview main.rs"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_prompt_gemini_language() {
+        let run_prompt = |language_flag: Option<&str>, language: &str, prompt: &str| {
+            let gemini_cli = GeminiCli::with_config(Arc::new(GeminiCliConfig {
+                gemini_cli_command: "echo".to_string(),
+                default_output_format: OutputFormat::Text,
+                language_flag: language_flag.map(str::to_string),
+                prompt_suffix: Some("Be brief.".to_string()),
+                ..Default::default()
+            }));
+            let args = PromptGeminiArgs {
+                prompt: prompt.to_string(),
+                language: Some(language.to_string()),
+                ..Default::default()
+            };
+            async move {
+                let result = gemini_cli
+                    .prompt_gemini(Parameters(args), CancellationToken::new())
+                    .await?;
+                let RawContent::Text(text_content) = &result.content[0].raw else {
+                    panic!("Expected text content");
+                };
+                Ok::<_, McpError>(text_content.text.clone())
+            }
+        };
+        let run = |language_flag: Option<&str>, language: &str| {
+            run_prompt(language_flag, language, "Explain main.rs")
+        };
+
+        assert_eq!(
+            run(None, "pt-BR").await.unwrap(),
+            "--yolo --prompt Explain main.rs\n\nRespond in Portuguese (pt-BR).\n\nBe brief."
+        );
+        assert_eq!(
+            run(Some("--language"), "fr").await.unwrap(),
+            "--yolo --language fr --prompt Explain main.rs\n\nBe brief."
+        );
+        // The instruction doesn't make an empty prompt valid
+        let error = run_prompt(None, "fr", " ").await.unwrap_err();
+        assert_eq!(error.message, "gemini_invalid_prompt");
+
+        let error = run(None, "klingon").await.unwrap_err();
+        assert_eq!(error.message, "gemini_invalid_prompt");
+        assert!(
+            error.data.unwrap()["reasons"][0]
+                .as_str()
+                .unwrap()
+                .contains("ISO 639-1")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_prompt_gemini_client_identifier() {
+        let command = script_command("client-identifier", "echo \"[$GEMINI_CLIENT_IDENTIFIER]\"");
+        let run = |client_identifier: &str| {
+            let gemini_cli = GeminiCli::with_config(Arc::new(GeminiCliConfig {
+                gemini_cli_command: command.clone(),
+                default_output_format: OutputFormat::Text,
+                client_identifier: client_identifier.to_string(),
+                ..Default::default()
+            }));
+            let args = PromptGeminiArgs {
+                prompt: "hi".to_string(),
+                ..Default::default()
+            };
+            async move {
+                let result = gemini_cli
+                    .prompt_gemini(Parameters(args), CancellationToken::new())
+                    .await
+                    .unwrap();
+                let RawContent::Text(text_content) = &result.content[0].raw else {
+                    panic!("Expected text content");
+                };
+                text_content.text.clone()
+            }
+        };
+
+        assert_eq!(
+            run(crate::config::DEFAULT_CLIENT_IDENTIFIER).await,
+            format!("[mcp-gemini-cli/{}]", env!("CARGO_PKG_VERSION"))
+        );
+        assert_eq!(run("team-a").await, "[team-a]");
+    }
+
+    #[tokio::test]
+    async fn test_prompt_gemini_content_type() {
+        let cmd = script_command(
+            "content-type",
+            r#"printf '%s\n' '{"response": "Intro\n```rust\nfn main() {}\n```\nDone"}'"#,
+        );
+        let gemini_cli = GeminiCli::new(cmd, None);
+        let args = PromptGeminiArgs {
+            prompt: "hi".to_string(),
+            content_type: Some("text/markdown; charset=utf-8".to_string()),
+            split_code_blocks: Some(true),
+            ..Default::default()
+        };
+        let result = gemini_cli
+            .prompt_gemini(Parameters(args.clone()), CancellationToken::new())
+            .await
+            .unwrap();
+        let metas: Vec<_> = result
+            .content
+            .iter()
+            .map(|content| match &content.raw {
+                RawContent::Text(text_content) => text_content.meta.clone().unwrap(),
+                _ => panic!("Expected text content"),
+            })
+            .collect();
+        assert_eq!(metas.len(), 3);
+        assert_eq!(metas[0]["mime_type"], "text/markdown; charset=utf-8");
+        assert_eq!(metas[1]["content_type"], "code");
+        assert!(!metas[1].contains_key("mime_type"));
+        assert_eq!(metas[2]["mime_type"], "text/markdown; charset=utf-8");
+
+        let error = gemini_cli
+            .prompt_gemini(
+                Parameters(PromptGeminiArgs {
+                    content_type: Some("markdown".to_string()),
+                    ..args
+                }),
+                CancellationToken::new(),
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(error.message, "gemini_invalid_prompt");
+    }
+
+    #[test]
+    fn test_is_mime_type() {
+        for valid in [
+            "text/markdown",
+            "text/x-rust",
+            "application/vnd.api+json",
+            "TEXT/HTML; charset=UTF-8",
+        ] {
+            assert!(is_mime_type(valid), "{valid}");
+        }
+        for invalid in [
+            "markdown",
+            "text/",
+            "/markdown",
+            "code/rust",
+            "text/mark down",
+            "text/plain; charset",
+        ] {
+            assert!(!is_mime_type(invalid), "{invalid}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ping_does_not_spawn() {
+        let gemini_cli = GeminiCli::new("nonexistent_command_12345".to_string(), None);
+
+        let result = gemini_cli.ping().await.unwrap();
+        let RawContent::Text(text_content) = &result.content[0].raw else {
+            panic!("Expected text content");
+        };
+        let pong: serde_json::Value = serde_json::from_str(&text_content.text).unwrap();
+        assert!(pong["timestamp_ms"].as_u64().unwrap() > 0);
+        assert_eq!(
+            pong["server_version"],
+            Implementation::from_build_env().version
+        );
+    }
+
+    #[tokio::test]
+    async fn test_server_metrics_counts_calls() {
+        let gemini_cli = GeminiCli::new(r#"echo {"response": "ok"}"#.to_string(), None);
+        for prompt in ["first", ""] {
+            let args = PromptGeminiArgs {
+                prompt: prompt.to_string(),
+                ..Default::default()
+            };
+            let _ = gemini_cli
+                .prompt_gemini(Parameters(args), CancellationToken::new())
+                .await;
+        }
+
+        let result = gemini_cli.server_metrics().await.unwrap();
+        let RawContent::Text(text_content) = &result.content[0].raw else {
+            panic!("Expected text content");
+        };
+        let metrics: serde_json::Value = serde_json::from_str(&text_content.text).unwrap();
+        assert_eq!(metrics["total_calls"], 2);
+        assert_eq!(metrics["successes"], 1);
+        assert_eq!(metrics["failures"], 1);
+    }
+
+    #[tokio::test]
+    async fn test_min_spawn_interval() {
+        let gemini_cli = GeminiCli::with_config(Arc::new(GeminiCliConfig {
+            gemini_cli_command: r#"echo {"response": "ok"}"#.to_string(),
+            min_spawn_interval_ms: 200,
+            ..Default::default()
+        }));
+        let started = Instant::now();
+        for prompt in ["first", "second"] {
+            let args = PromptGeminiArgs {
+                prompt: prompt.to_string(),
+                ..Default::default()
+            };
+            gemini_cli
+                .prompt_gemini(Parameters(args), CancellationToken::new())
+                .await
+                .unwrap();
+        }
+        assert!(started.elapsed() >= Duration::from_millis(200));
+
+        let throttle = gemini_cli.state.metrics_snapshot().spawn_throttle;
+        assert_eq!(throttle.throttled_launches, 1);
+    }
+
+    #[tokio::test]
+    async fn test_prompt_gemini_with_text_output_format() {
+        let gemini_cli = GeminiCli::new("echo".to_string(), None);
+        let args = PromptGeminiArgs {
+            prompt: "hello".to_string(),
+            output_format: Some("text".to_string()),
+            ..Default::default()
+        };
+
+        let result = gemini_cli
+            .prompt_gemini(Parameters(args), CancellationToken::new())
+            .await
+            .unwrap();
+        let RawContent::Text(text_content) = &result.content[0].raw else {
+            panic!("Expected text content");
+        };
+        // No --output-format flag is passed in text mode
+        assert_eq!(text_content.text, "--yolo --prompt hello");
+        assert!(result.structured_content.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_prompt_gemini_read_only_drops_yolo() {
+        let prompt = |read_only_args: &str| {
+            let gemini_cli = GeminiCli::with_config(Arc::new(GeminiCliConfig {
+                gemini_cli_command: "echo".to_string(),
+                read_only: true,
+                read_only_args: read_only_args.to_string(),
+                ..Default::default()
+            }));
+            async move {
+                let args = PromptGeminiArgs {
+                    prompt: "hello".to_string(),
+                    output_format: Some("text".to_string()),
+                    ..Default::default()
+                };
+                let result = gemini_cli
+                    .prompt_gemini(Parameters(args), CancellationToken::new())
+                    .await
+                    .unwrap();
+                let RawContent::Text(text_content) = &result.content[0].raw else {
+                    panic!("Expected text content");
+                };
+                text_content.text.clone()
+            }
+        };
+
+        assert_eq!(
+            prompt(crate::config::DEFAULT_READ_ONLY_ARGS).await,
+            "--approval-mode default --prompt hello"
+        );
+        assert_eq!(prompt("").await, "--prompt hello");
+    }
+
+    #[tokio::test]
+    async fn test_prompt_gemini_include_thoughts() {
+        let command = script_command(
+            "thoughts",
+            r#"case "$*" in
+  *--show-thoughts*) printf '%s\n' '{"response": "4", "thoughts": [{"subject": "Adding", "description": "2 plus 2 is 4"}, "Done"]}' ;;
+  *) printf '%s\n' '{"response": "4", "thoughts": "hidden"}' ;;
+esac"#,
+        );
+        let gemini_cli = GeminiCli::with_config(Arc::new(GeminiCliConfig {
+            gemini_cli_command: command,
+            thoughts_flag: Some("--show-thoughts".to_string()),
+            ..Default::default()
+        }));
+        let prompt = |include_thoughts: Option<bool>| {
+            let args = PromptGeminiArgs {
+                prompt: "2+2?".to_string(),
+                include_thoughts,
+                ..Default::default()
+            };
+            gemini_cli.prompt_gemini(Parameters(args), CancellationToken::new())
+        };
+
+        let result = prompt(Some(true)).await.unwrap();
+        assert_eq!(result.content.len(), 2);
+        let RawContent::Text(thoughts) = &result.content[1].raw else {
+            panic!("Expected text content");
+        };
+        assert_eq!(thoughts.text, "2 plus 2 is 4\n\nDone");
+        assert_eq!(thoughts.meta.as_ref().unwrap()["content_type"], "thoughts");
+        assert!(result.structured_content.unwrap()["thoughts"].is_array());
+
+        // Off by default, and then left out of the structured content too
+        let result = prompt(None).await.unwrap();
+        assert_eq!(result.content.len(), 1);
+        assert!(result.structured_content.unwrap().get("thoughts").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_prompt_gemini_candidate_count() {
+        let command = script_command(
+            "candidates",
+            r#"case "$*" in
+  *"--candidates 2"*) printf '%s\n' '{"response": "A", "candidates": ["A", "B"]}' ;;
+  *) printf '%s\n' '{"response": "A"}' ;;
+esac"#,
+        );
+        let gemini_cli = GeminiCli::with_config(Arc::new(GeminiCliConfig {
+            gemini_cli_command: command.clone(),
+            candidates_flag: Some("--candidates".to_string()),
+            ..Default::default()
+        }));
+        let args = |candidate_count: Option<u32>| PromptGeminiArgs {
+            prompt: "Name a letter".to_string(),
+            candidate_count,
+            ..Default::default()
+        };
+
+        let result = gemini_cli
+            .prompt_gemini(Parameters(args(Some(2))), CancellationToken::new())
+            .await
+            .unwrap();
+        let candidates: Vec<_> = result
+            .content
+            .iter()
+            .map(|content| match &content.raw {
+                RawContent::Text(text_content) => (
+                    text_content.text.clone(),
+                    text_content.meta.as_ref().unwrap()["candidate"].clone(),
+                ),
+                _ => panic!("Expected text content"),
+            })
+            .collect();
+        assert_eq!(
+            candidates,
+            [
+                ("A".to_string(), serde_json::json!(0)),
+                ("B".to_string(), serde_json::json!(1))
+            ]
+        );
+        assert_eq!(
+            result.structured_content.unwrap()["candidates"],
+            serde_json::json!(["A", "B"])
+        );
+
+        let result = gemini_cli
+            .prompt_gemini(Parameters(args(None)), CancellationToken::new())
+            .await
+            .unwrap();
+        assert_eq!(result.content.len(), 1);
+
+        for (candidates_flag, count) in [(Some("--candidates"), 9), (None, 2)] {
+            let gemini_cli = GeminiCli::with_config(Arc::new(GeminiCliConfig {
+                gemini_cli_command: command.clone(),
+                candidates_flag: candidates_flag.map(str::to_string),
+                ..Default::default()
+            }));
+            let error = gemini_cli
+                .prompt_gemini(Parameters(args(Some(count))), CancellationToken::new())
+                .await
+                .unwrap_err();
+            assert_eq!(error.message, "gemini_invalid_prompt");
+        }
+
+        // The pointer would select from one response and drop the other candidates
+        let error = gemini_cli
+            .prompt_gemini(
+                Parameters(PromptGeminiArgs {
+                    response_json_pointer: Some("/letter".to_string()),
+                    ..args(Some(2))
+                }),
+                CancellationToken::new(),
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(error.message, "gemini_invalid_prompt");
+    }
+
+    #[test]
+    fn test_thoughts_text() {
+        assert_eq!(
+            thoughts_text(&serde_json::json!("  step one ")).as_deref(),
+            Some("step one")
+        );
+        assert_eq!(
+            thoughts_text(&serde_json::json!([{"text": "a"}, {"other": 1}, ""])).as_deref(),
+            Some("a\n\n{\"other\":1}")
+        );
+        assert_eq!(thoughts_text(&serde_json::json!([])), None);
+        assert_eq!(thoughts_text(&serde_json::Value::Null), None);
+    }
+
+    #[tokio::test]
+    async fn test_prompt_gemini_sandbox() {
+        let prompt = |default_sandbox: bool, sandbox: Option<bool>| {
+            let gemini_cli = GeminiCli::with_config(Arc::new(GeminiCliConfig {
+                gemini_cli_command: "echo".to_string(),
+                default_output_format: OutputFormat::Text,
+                default_sandbox,
+                ..Default::default()
+            }));
+            let args = PromptGeminiArgs {
+                prompt: "hello".to_string(),
+                sandbox,
+                ..Default::default()
+            };
+            async move {
+                let result = gemini_cli
+                    .prompt_gemini(Parameters(args), CancellationToken::new())
+                    .await
+                    .unwrap();
+                let RawContent::Text(text_content) = &result.content[0].raw else {
+                    panic!("Expected text content");
+                };
+                text_content.text.clone()
+            }
+        };
+
+        assert_eq!(prompt(false, None).await, "--yolo --prompt hello");
+        assert_eq!(
+            prompt(false, Some(true)).await,
+            "--yolo --sandbox --prompt hello"
+        );
+        assert_eq!(prompt(true, None).await, "--yolo --sandbox --prompt hello");
+        assert_eq!(prompt(false, Some(false)).await, "--yolo --prompt hello");
+
+        // A client can't turn off the server's sandbox
+        let gemini_cli = GeminiCli::with_config(Arc::new(GeminiCliConfig {
+            gemini_cli_command: "echo".to_string(),
+            default_sandbox: true,
+            ..Default::default()
+        }));
+        let args = PromptGeminiArgs {
+            prompt: "hello".to_string(),
+            sandbox: Some(false),
+            ..Default::default()
+        };
+        let error = gemini_cli
+            .prompt_gemini(Parameters(args), CancellationToken::new())
+            .await
+            .unwrap_err();
+        assert_eq!(error.message, "gemini_sandbox_required");
+    }
+
+    #[tokio::test]
+    async fn test_prompt_gemini_approval_mode() {
+        let prompt = |read_only: bool, approval_mode: &str| {
+            let gemini_cli = GeminiCli::with_config(Arc::new(GeminiCliConfig {
+                gemini_cli_command: "echo".to_string(),
+                default_output_format: OutputFormat::Text,
+                read_only,
+                ..Default::default()
+            }));
+            let args = PromptGeminiArgs {
+                prompt: "hello".to_string(),
+                approval_mode: Some(approval_mode.to_string()),
+                ..Default::default()
+            };
+            async move {
+                let result = gemini_cli
+                    .prompt_gemini(Parameters(args), CancellationToken::new())
+                    .await?;
+                let RawContent::Text(text_content) = &result.content[0].raw else {
+                    panic!("Expected text content");
+                };
+                Ok::<_, McpError>(text_content.text.clone())
+            }
+        };
+
+        assert_eq!(
+            prompt(false, "manual").await.unwrap(),
+            "--approval-mode default --prompt hello"
+        );
+        assert_eq!(
+            prompt(false, "auto").await.unwrap(),
+            "--approval-mode auto_edit --prompt hello"
+        );
+        assert_eq!(
+            prompt(false, "yolo").await.unwrap(),
+            "--yolo --prompt hello"
+        );
+        assert_eq!(
+            prompt(false, "sometimes").await.unwrap_err().message,
+            "gemini_invalid_approval_mode"
+        );
+
+        // Read-only mode can't be loosened per request
+        assert_eq!(
+            prompt(true, "manual").await.unwrap(),
+            "--approval-mode default --prompt hello"
+        );
+        let error = prompt(true, "yolo").await.unwrap_err();
+        assert_eq!(error.message, "gemini_invalid_approval_mode");
+        assert_eq!(error.data.unwrap()["read_only"], true);
+    }
+
+    #[tokio::test]
+    async fn test_prompt_gemini_uses_default_output_format() {
+        let gemini_cli = GeminiCli::with_config(Arc::new(GeminiCliConfig {
+            gemini_cli_command: "echo".to_string(),
+            default_output_format: OutputFormat::Text,
+            ..Default::default()
+        }));
+        let args = PromptGeminiArgs {
+            prompt: "hello".to_string(),
+            ..Default::default()
+        };
+
+        let result = gemini_cli
+            .prompt_gemini(Parameters(args), CancellationToken::new())
+            .await
+            .unwrap();
+        let RawContent::Text(text_content) = &result.content[0].raw else {
+            panic!("Expected text content");
+        };
+        assert_eq!(text_content.text, "--yolo --prompt hello");
+
+        // An explicit per-request format still wins over the default
+        let args = PromptGeminiArgs {
+            prompt: "hello".to_string(),
+            output_format: Some("json".to_string()),
+            ..Default::default()
+        };
+        let error = gemini_cli
+            .prompt_gemini(Parameters(args), CancellationToken::new())
+            .await
+            .unwrap_err();
+        assert!(error.message.contains("gemini_json_parse_error"));
+    }
+
+    #[tokio::test]
+    async fn test_prompt_gemini_rejects_too_long_prompt() {
+        let gemini_cli = GeminiCli::with_config(Arc::new(GeminiCliConfig {
+            gemini_cli_command: "nonexistent_command_12345".to_string(),
+            max_prompt_chars: Some(5),
+            ..Default::default()
+        }));
+        let args = PromptGeminiArgs {
+            prompt: "héllo world".to_string(),
+            ..Default::default()
+        };
+
+        let error = gemini_cli
+            .prompt_gemini(Parameters(args), CancellationToken::new())
+            .await
+            .unwrap_err();
+        assert!(error.message.contains("gemini_prompt_too_long"));
+        let data = error.data.unwrap();
+        assert_eq!(data["length"], 11);
+        assert_eq!(data["max_length"], 5);
+    }
+
+    #[tokio::test]
+    async fn test_prompt_length_counts_bytes() {
+        let gemini_cli = GeminiCli::with_config(Arc::new(GeminiCliConfig {
+            max_prompt_chars: Some(5),
+            max_prompt_bytes: Some(5),
+            ..Default::default()
+        }));
+
+        // 5 characters but 6 bytes
+        let too_long = gemini_cli.check_prompt_length("héllo").unwrap_err();
+        assert_eq!(too_long.unit, "bytes");
+        assert_eq!(too_long.actual, 6);
+        assert!(gemini_cli.check_prompt_length("hello").is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_describe_tools() {
+        let gemini_cli = GeminiCli::with_config(Arc::new(backend_config()));
+
+        let result = gemini_cli.describe_tools().await.unwrap();
+        let RawContent::Text(text_content) = &result.content[0].raw else {
+            panic!("Expected text content");
+        };
+        let description: serde_json::Value = serde_json::from_str(&text_content.text).unwrap();
+
+        let tool_names: Vec<&str> = description["tools"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|tool| tool["name"].as_str().unwrap())
+            .collect();
+        assert!(tool_names.contains(&"prompt-gemini"));
+        assert!(tool_names.contains(&"describe-tools"));
+
+        let parameters = &description["prompt_gemini"]["parameters"];
+        assert_eq!(parameters["prompt"]["required"], true);
+        assert_eq!(
+            parameters["output_format"]["allowed_values"],
+            serde_json::json!(["json", "text", "yaml"])
+        );
+        assert_eq!(
+            parameters["backend"]["allowed_values"],
+            serde_json::json!(["fast", "slow"])
+        );
+
+        let output_schema = &description["prompt_gemini"]["output_schema"];
+        assert_eq!(output_schema["required"], serde_json::json!(["response"]));
+        assert!(output_schema["$defs"]["FileStats"]["properties"]["totalLinesAdded"].is_object());
+    }
+
+    #[tokio::test]
+    async fn test_prompt_gemini_with_yaml_output_format() {
+        let valid_json = r#"{"response": "Paris", "error": null, "stats": {"files": {"totalLinesAdded": 3, "totalLinesRemoved": 1}}}"#;
+        let gemini_cli = GeminiCli::new(format!("echo '{}'", valid_json), None);
+        let args = PromptGeminiArgs {
+            prompt: "What is the capital of France?".to_string(),
+            output_format: Some("yaml".to_string()),
+            ..Default::default()
+        };
+
+        let result = gemini_cli
+            .prompt_gemini(Parameters(args), CancellationToken::new())
+            .await
+            .unwrap();
+        let RawContent::Text(text_content) = &result.content[0].raw else {
+            panic!("Expected text content");
+        };
+        let yaml: serde_yaml::Value = serde_yaml::from_str(&text_content.text).unwrap();
+        assert_eq!(yaml["response"], "Paris");
+        assert_eq!(yaml["stats"]["files"]["totalLinesAdded"], 3);
+    }
+
+    #[test]
+    fn test_parse_retry_after_secs() {
+        assert_eq!(
+            parse_retry_after_secs("Quota exceeded. Please retry in 17.5s."),
+            Some(18)
+        );
+        assert_eq!(
+            parse_retry_after_secs("Rate limited, retry after 30 seconds"),
+            Some(30)
+        );
+        assert_eq!(
+            parse_retry_after_secs(r#"RESOURCE_EXHAUSTED {"retryDelay": "42s"}"#),
+            Some(42)
+        );
+        assert_eq!(parse_retry_after_secs("Retry-After: 20"), Some(20));
+        assert_eq!(parse_retry_after_secs("Try again in 2 minutes"), Some(120));
+        assert_eq!(parse_retry_after_secs("Please retry in 250ms"), Some(1));
+        assert_eq!(parse_retry_after_secs("Too many requests"), None);
+    }
+
+    #[tokio::test]
+    async fn test_prompt_gemini_rate_limit_includes_retry_after() {
+        let error_json = r#"{"response": "", "error": {"type": "ApiError", "message": "Quota exceeded, retry in 12s", "code": 429}}"#;
+        let gemini_cli = GeminiCli::new(format!("echo '{}'", error_json), None);
+        let args = PromptGeminiArgs {
+            prompt: "test".to_string(),
+            ..Default::default()
+        };
+
+        let error = gemini_cli
+            .prompt_gemini(Parameters(args), CancellationToken::new())
+            .await
+            .unwrap_err();
+        assert_eq!(error.data.unwrap()["retry_after_secs"], 12);
+    }
+
+    #[tokio::test]
+    async fn test_prompt_gemini_rate_limit_retry_jitter() {
+        let error_json = r#"{"response": "", "error": {"type": "ApiError", "message": "Quota exceeded, retry in 12s", "code": 429}}"#;
+        let gemini_cli = GeminiCli::with_config(Arc::new(GeminiCliConfig {
+            gemini_cli_command: format!("echo '{}'", error_json),
+            retry_jitter: RetryJitter::Equal,
+            ..Default::default()
+        }));
+
+        for _ in 0..5 {
+            let args = PromptGeminiArgs {
+                prompt: "test".to_string(),
+                ..Default::default()
+            };
+            let error = gemini_cli
+                .prompt_gemini(Parameters(args), CancellationToken::new())
+                .await
+                .unwrap_err();
+            let retry_after_secs = error.data.unwrap()["retry_after_secs"].as_u64().unwrap();
+            // Never earlier than the API asked
+            assert!((12..=18).contains(&retry_after_secs), "{retry_after_secs}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_prompt_gemini_rate_limit_default_backoff() {
+        let error_json = r#"{"response": "", "error": {"type": "ApiError", "message": "Too many requests", "code": 429}}"#;
+        let gemini_cli = GeminiCli::new(format!("echo '{}'", error_json), None);
+        let args = PromptGeminiArgs {
+            prompt: "test".to_string(),
+            ..Default::default()
+        };
+
+        let error = gemini_cli
+            .prompt_gemini(Parameters(args), CancellationToken::new())
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.data.unwrap()["retry_after_secs"],
+            DEFAULT_RATE_LIMIT_BACKOFF_SECS
+        );
+    }
+
+    #[tokio::test]
+    async fn test_prompt_gemini_queue_full() {
+        let gemini_cli = GeminiCli::with_config(Arc::new(GeminiCliConfig {
+            gemini_cli_command: script_command(
+                "queue_full",
+                r#"sleep 1; echo '{"response": "done"}'"#,
+            ),
+            max_concurrency: Some(1),
+            max_queue_depth: 0,
+            ..Default::default()
+        }));
+
+        // Occupy the only execution slot
+        let busy = {
+            let gemini_cli = gemini_cli.clone();
+            tokio::spawn(async move {
+                let args = PromptGeminiArgs {
+                    prompt: "slow".to_string(),
+                    ..Default::default()
+                };
+                gemini_cli
+                    .prompt_gemini(Parameters(args), CancellationToken::new())
+                    .await
+            })
+        };
+        while gemini_cli.state.queue.snapshot().in_flight == 0 {
+            tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        }
+
+        let args = PromptGeminiArgs {
+            prompt: "rejected".to_string(),
+            ..Default::default()
+        };
+        let error = gemini_cli
+            .prompt_gemini(Parameters(args), CancellationToken::new())
+            .await
+            .unwrap_err();
+        assert!(error.message.contains("gemini_queue_full"));
+
+        let _ = busy.await;
+        assert_eq!(gemini_cli.state.queue.snapshot().in_flight, 0);
+    }
+
+    #[tokio::test]
+    async fn test_diagnose() {
+        let diagnose = |config: GeminiCliConfig| async move {
+            let result = GeminiCli::with_config(Arc::new(config))
+                .diagnose(Parameters(DiagnoseArgs::default()))
+                .await
+                .unwrap();
+            let RawContent::Text(text_content) = &result.content[0].raw else {
+                panic!("Expected text content");
+            };
+            serde_json::from_str::<serde_json::Value>(&text_content.text).unwrap()
+        };
+
+        // A failing check is the report, not an error
+        let report = diagnose(GeminiCliConfig {
+            gemini_cli_command: script_command(
+                "diagnose",
+                r#"echo "checked $*"; echo 'auth: no credentials' >&2; exit 3"#,
+            ),
+            diagnose_subcommand: "auth status".to_string(),
+            ..Default::default()
+        })
+        .await;
+        assert_eq!(report["success"], false);
+        assert_eq!(report["exit_code"], 3);
+        assert_eq!(report["stdout"], "checked auth status");
+        assert_eq!(report["stderr"], "auth: no credentials");
+        assert!(
+            report["command"]
+                .as_str()
+                .unwrap()
+                .ends_with(" auth status")
+        );
+
+        let report = diagnose(GeminiCliConfig {
+            gemini_cli_command: "nonexistent_command_12345".to_string(),
+            ..Default::default()
+        })
+        .await;
+        assert_eq!(report["success"], false);
+        assert_eq!(report["lookup"]["path_kind"], "path_lookup");
+    }
+
+    #[tokio::test]
+    async fn test_list_active_requests() {
+        let gemini_cli = GeminiCli::with_config(Arc::new(GeminiCliConfig {
+            gemini_cli_command: script_command(
+                "active_requests",
+                r#"sleep 1; echo '{"response": "done"}'"#,
+            ),
+            default_model: Some("gemini-2.5-flash".to_string()),
+            ..Default::default()
+        }));
+        let list = || async {
+            let result = gemini_cli.list_active_requests().await.unwrap();
+            let RawContent::Text(text_content) = &result.content[0].raw else {
+                panic!("Expected text content");
+            };
+            serde_json::from_str::<serde_json::Value>(&text_content.text).unwrap()["requests"]
+                .as_array()
+                .unwrap()
+                .clone()
+        };
+        assert!(list().await.is_empty());
+
+        let running = {
+            let gemini_cli = gemini_cli.clone();
+            tokio::spawn(async move {
+                let args = PromptGeminiArgs {
+                    prompt: "slow".to_string(),
+                    ..Default::default()
+                };
+                gemini_cli
+                    .prompt_gemini(Parameters(args), CancellationToken::new())
+                    .await
+            })
+        };
+        let mut requests = list().await;
+        while requests.is_empty() {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            requests = list().await;
+        }
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0]["tool"], "prompt-gemini");
+        assert_eq!(requests[0]["model"], "gemini-2.5-flash");
+        assert_eq!(requests[0]["prompt_sha256"], audit::sha256_hex("slow"));
+        assert!(requests[0]["elapsed_ms"].is_u64());
+
+        running.await.unwrap().unwrap();
+        assert!(list().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_prompt_gemini_with_custom_prompt_flag() {
+        let gemini_cli = GeminiCli::with_config(Arc::new(GeminiCliConfig {
+            gemini_cli_command: "echo".to_string(),
+            prompt_flag: "-p".to_string(),
+            ..Default::default()
+        }));
+        let args = PromptGeminiArgs {
+            prompt: "hello".to_string(),
+            output_format: Some("text".to_string()),
+            ..Default::default()
+        };
+
+        let result = gemini_cli
+            .prompt_gemini(Parameters(args), CancellationToken::new())
+            .await
+            .unwrap();
+        let RawContent::Text(text_content) = &result.content[0].raw else {
+            panic!("Expected text content");
+        };
+        assert_eq!(text_content.text, "--yolo -p hello");
+    }
+
+    #[tokio::test]
+    async fn test_prompt_gemini_with_positional_prompt() {
+        let gemini_cli = GeminiCli::with_config(Arc::new(GeminiCliConfig {
+            gemini_cli_command: "echo".to_string(),
+            prompt_flag: String::new(),
+            ..Default::default()
+        }));
+        let args = |prompt: &str| PromptGeminiArgs {
+            prompt: prompt.to_string(),
+            ..Default::default()
+        };
+
+        // The prompt comes last, after the managed flags
+        let error = gemini_cli
+            .prompt_gemini(Parameters(args("hello")), CancellationToken::new())
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.data.unwrap()["raw_output"],
+            "--yolo --output-format json -- hello"
+        );
+
+        // A prompt that looks like a flag is still only the prompt
+        let error = gemini_cli
+            .prompt_gemini(Parameters(args("--sandbox")), CancellationToken::new())
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.data.unwrap()["raw_output"],
+            "--yolo --output-format json -- --sandbox"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_prompt_gemini_with_args_separator() {
+        let gemini_cli = GeminiCli::with_config(Arc::new(GeminiCliConfig {
+            gemini_cli_command: "echo run gemini".to_string(),
+            args_separator: Some("--".to_string()),
+            ..Default::default()
+        }));
+        let args = PromptGeminiArgs {
+            prompt: "hello".to_string(),
+            output_format: Some("text".to_string()),
+            ..Default::default()
+        };
+
+        let result = gemini_cli
+            .prompt_gemini(Parameters(args), CancellationToken::new())
+            .await
+            .unwrap();
+        let RawContent::Text(text_content) = &result.content[0].raw else {
+            panic!("Expected text content");
+        };
+        assert_eq!(text_content.text, "run gemini -- --yolo --prompt hello");
+    }
+
+    #[tokio::test]
+    async fn test_prompt_gemini_all_files() {
+        let root = std::env::temp_dir().join(format!(
+            "mcp-gemini-cli-test-{}-all-files",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        let gemini_cli = |command: &str, all_files_flag: &str| {
+            GeminiCli::with_config(Arc::new(GeminiCliConfig {
+                gemini_cli_command: command.to_string(),
+                all_files_flag: all_files_flag.to_string(),
+                workspace: Some(root.display().to_string()),
+                ..Default::default()
+            }))
+        };
+        let args = || PromptGeminiArgs {
+            prompt: "summarize".to_string(),
+            output_format: Some("text".to_string()),
+            all_files: Some(true),
+            ..Default::default()
+        };
+        let text = |result: &CallToolResult| {
+            let RawContent::Text(text_content) = &result.content[0].raw else {
+                panic!("Expected text content");
+            };
+            text_content.text.clone()
+        };
+
+        let result = gemini_cli("echo", "--all-files")
+            .prompt_gemini(Parameters(args()), CancellationToken::new())
+            .await
+            .unwrap();
+        assert_eq!(text(&result), "--yolo --all-files --prompt summarize");
+        assert!(!result.meta.unwrap().contains_key("all_files_warning"));
+
+        // Wrappers get their own flag name, after the separator
+        let result = gemini_cli("task ai:run", "--include-everything")
+            .build_command("task ai:run", &args(), OutputFormat::Text)
+            .unwrap();
+        let argv: Vec<_> = result.as_std().get_args().collect();
+        assert_eq!(
+            argv,
+            [
+                "ai:run",
+                "--",
+                "--yolo",
+                "--include-everything",
+                "--prompt",
+                "summarize"
+            ]
+        );
+
+        // Large workspaces come with a token cost warning
+        for i in 0..=ALL_FILES_WARN_FILES {
+            std::fs::write(root.join(format!("file-{i}.txt")), "").unwrap();
+        }
+        let result = gemini_cli("echo", "--all-files")
+            .prompt_gemini(Parameters(args()), CancellationToken::new())
+            .await
+            .unwrap();
+        let meta = result.meta.expect("large workspace should be warned about");
+        assert_eq!(
+            meta["all_files_warning"]["file_threshold"],
+            ALL_FILES_WARN_FILES
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reload_config_applies_to_new_requests() {
+        let path = std::env::temp_dir().join(format!(
+            "mcp-gemini-cli-test-{}-reload.toml",
+            std::process::id()
+        ));
+        std::fs::write(&path, "gemini_cli_command = \"echo first\"\n").unwrap();
+
+        let state = Arc::new(ServerState::new(GeminiCliConfig {
+            default_output_format: OutputFormat::Text,
+            config_file: Some(path.clone()),
+            ..Default::default()
+        }));
+        state.reload_config().unwrap();
+        let gemini_cli = GeminiCli::with_state(state);
+
+        let prompt = |gemini_cli: GeminiCli| async move {
+            let args = PromptGeminiArgs {
+                prompt: "hi".to_string(),
+                ..Default::default()
+            };
+            let result = gemini_cli
+                .prompt_gemini(Parameters(args), CancellationToken::new())
+                .await
+                .unwrap();
+            let RawContent::Text(text_content) = &result.content[0].raw else {
+                panic!("Expected text content");
+            };
+            text_content.text.clone()
+        };
+        assert_eq!(prompt(gemini_cli.clone()).await, "first --yolo --prompt hi");
+
+        std::fs::write(&path, "gemini_cli_command = \"echo second\"\n").unwrap();
+        gemini_cli.reload_config().await.unwrap();
+        assert_eq!(
+            prompt(gemini_cli.clone()).await,
+            "second --yolo --prompt hi"
+        );
+
+        // A broken file is rejected and the previous configuration stays active
+        std::fs::write(&path, "not valid toml =").unwrap();
+        let error = gemini_cli.reload_config().await.unwrap_err();
+        assert!(error.message.contains("gemini_config_reload_failed"));
+        assert_eq!(prompt(gemini_cli).await, "second --yolo --prompt hi");
+    }
+
+    #[tokio::test]
+    async fn test_reload_config_without_file() {
+        let gemini_cli = GeminiCli::new("echo".to_string(), None);
+        let error = gemini_cli.reload_config().await.unwrap_err();
+        assert!(error.message.contains("gemini_config_reload_failed"));
+    }
+
+    #[test]
+    fn test_truncate_chars() {
+        assert_eq!(truncate_chars("short", 10), "short");
+        assert_eq!(truncate_chars("héllo world", 5), "héllo...");
+    }
+
+    #[tokio::test]
+    async fn test_prompt_gemini_ignores_stderr_on_success() {
+        let (logs, _guard) = capture_logs(tracing_subscriber::EnvFilter::new("warn"));
+        let command = script_command("stderr-warning", "echo 'deprecated flag' >&2; echo ok");
+        let gemini_cli = GeminiCli::new(command, None);
+
+        let args = PromptGeminiArgs {
+            prompt: "hi".to_string(),
+            output_format: Some("text".to_string()),
+            ..Default::default()
+        };
+        let result = gemini_cli
+            .prompt_gemini(Parameters(args), CancellationToken::new())
+            .await
+            .unwrap();
+        let RawContent::Text(text_content) = &result.content[0].raw else {
+            panic!("Expected text content");
+        };
+        assert_eq!(text_content.text, "ok");
+
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        assert!(
+            logs.contains("WARN") && logs.contains("Gemini CLI succeeded but wrote to stderr"),
+            "{logs}"
+        );
+        assert!(logs.contains("stderr=deprecated flag"), "{logs}");
+    }
+
+    #[tokio::test]
+    async fn test_prompt_gemini_output_limits() {
+        let command = script_command(
+            "noisy-stderr",
+            "head -c 100000 /dev/zero | tr '\\0' w >&2; echo 'ok and more'",
+        );
+        let gemini_cli = GeminiCli::with_config(Arc::new(GeminiCliConfig {
+            gemini_cli_command: command,
+            max_stdout_bytes: Some(3),
+            max_stderr_bytes: Some(16),
+            ..Default::default()
+        }));
+
+        let args = PromptGeminiArgs {
+            prompt: "hi".to_string(),
+            output_format: Some("text".to_string()),
+            ..Default::default()
+        };
+        let result = gemini_cli
+            .prompt_gemini(Parameters(args), CancellationToken::new())
+            .await
+            .unwrap();
+        let RawContent::Text(text_content) = &result.content[0].raw else {
+            panic!("Expected text content");
+        };
+        assert_eq!(text_content.text, "ok\n[... 9 bytes truncated ...]");
+        let meta = result.meta.unwrap();
+        // The sizes are of the output kept, without the marker
+        assert_eq!(meta["stdout_bytes"], 3);
+        assert_eq!(meta["stdout_truncated_bytes"], 9);
+        assert_eq!(meta["stderr_bytes"], 16);
+        assert_eq!(meta["stderr_truncated_bytes"], 100000 - 16);
+
+        // A truncated JSON response is reported as such rather than as a parse error
+        let args = PromptGeminiArgs {
+            prompt: "hi".to_string(),
+            ..Default::default()
+        };
+        let error = gemini_cli
+            .prompt_gemini(Parameters(args), CancellationToken::new())
+            .await
+            .unwrap_err();
+        assert_eq!(error.message, "gemini_output_truncated");
+        let data = error.data.unwrap();
+        assert_eq!(data["max_stdout_bytes"], 3);
+        assert_eq!(data["stdout_truncated_bytes"], 9);
+    }
+
+    #[test]
+    fn test_truncate_lines() {
+        assert_eq!(truncate_lines("a\nb", 2, false), None);
+
+        let (head, truncation) = truncate_lines("a\nb\nc\nd", 2, false).unwrap();
+        assert_eq!(head, "a\nb\n[... 2 lines truncated ...]");
+        assert_eq!(
+            truncation,
+            LineTruncation {
+                total_lines: 4,
+                kept_lines: 2
+            }
+        );
+
+        let (tail, _) = truncate_lines("a\nb\nc\nd", 1, true).unwrap();
+        assert_eq!(tail, "[... 3 lines truncated ...]\nd");
+    }
+
+    #[tokio::test]
+    async fn test_prompt_gemini_truncates_output_lines() {
+        let command = script_command("many-lines", "printf 'one\\ntwo\\nthree\\nfour\\n'");
+        let gemini_cli = GeminiCli::new(command, None);
+
+        let args = PromptGeminiArgs {
+            prompt: "hi".to_string(),
+            output_format: Some("text".to_string()),
+            max_output_lines: Some(2),
+            output_lines_from: Some("tail".to_string()),
+            ..Default::default()
+        };
+        let result = gemini_cli
+            .prompt_gemini(Parameters(args), CancellationToken::new())
+            .await
+            .unwrap();
+        let RawContent::Text(text_content) = &result.content[0].raw else {
+            panic!("Expected text content");
+        };
+        assert_eq!(
+            text_content.text,
+            "[... 2 lines truncated ...]\nthree\nfour"
+        );
+        let meta = result.meta.expect("truncation should be noted in meta");
+        assert_eq!(meta["output_truncated"]["total_lines"], 4);
+        assert_eq!(meta["output_truncated"]["kept_lines"], 2);
+        assert_eq!(meta["output_truncated"]["from"], "tail");
+
+        let args = PromptGeminiArgs {
+            prompt: "hi".to_string(),
+            output_format: Some("text".to_string()),
+            max_output_lines: Some(10),
+            ..Default::default()
+        };
+        let result = gemini_cli
+            .prompt_gemini(Parameters(args), CancellationToken::new())
+            .await
+            .unwrap();
+        assert!(!result.meta.unwrap().contains_key("output_truncated"));
+    }
+
+    // JSON parsing tests
+    #[test]
+    fn test_parse_clean_json() {
+        let json_output = r#"{"response": "Hello world", "stats": null, "error": null}"#;
+        let result = parse_gemini_output(json_output);
+        match result {
+            ParseResult::JsonSuccess(response) => {
+                assert_eq!(response.response, "Hello world");
+                assert!(response.stats.is_none());
+                assert!(response.error.is_none());
+            }
+            _ => panic!("Expected JsonSuccess"),
+        }
+    }
+
+    #[test]
+    fn test_parse_mixed_content() {
+        let mixed_output = r#"Loading model...
+Generating response...
+{"response": "Hello world", "stats": null, "error": null}
+Done."#;
+        let result = parse_gemini_output(mixed_output);
+        match result {
+            ParseResult::JsonSuccess(response) => {
+                assert_eq!(response.response, "Hello world");
+            }
+            _ => panic!("Expected JsonSuccess from mixed content"),
+        }
+    }
+
+    #[test]
+    fn test_parse_error_response() {
+        let error_json = r#"{"response": "", "error": {"type": "ApiError", "message": "Test error", "code": 400}, "stats": null}"#;
+        let result = parse_gemini_output(error_json);
+        match result {
+            ParseResult::JsonSuccess(response) => {
+                assert!(response.error.is_some());
+                let error = response.error.unwrap();
+                assert_eq!(error.error_type, "ApiError");
+                assert_eq!(error.message, "Test error");
+                assert_eq!(error.code, Some(400));
+            }
+            _ => panic!("Expected JsonSuccess with error"),
+        }
+    }
+
+    #[test]
+    fn test_parse_fallback_to_error() {
+        let invalid_json = "This is not JSON at all";
+        let result = parse_gemini_output(invalid_json);
+        match result {
+            ParseResult::ParseError { raw_output, error } => {
+                assert_eq!(raw_output, invalid_json);
+                assert!(error.contains("Failed to parse JSON"));
+            }
+            _ => panic!("Expected ParseError"),
+        }
+    }
+
+    #[test]
+    fn test_parse_lenient_trailing_commas() {
+        let output = "Loaded cached credentials.\n{\n  \"response\": \"Hello, world\", // greeting\n  \"stats\": {\"models\": {},},\n}\n";
+        assert!(matches!(
+            parse_gemini_output(output),
+            ParseResult::ParseError { .. }
+        ));
+        match parse_gemini_output_lenient(output, &ExtractionBudget::default()) {
+            ParseResult::JsonSuccess(response) => assert_eq!(response.response, "Hello, world"),
+            _ => panic!("Expected JsonSuccess after repair"),
+        }
+
+        // Output that is not JSON at all still fails, with the original text
+        match parse_gemini_output_lenient("Hello world // not JSON", &ExtractionBudget::default()) {
+            ParseResult::ParseError { raw_output, .. } => {
+                assert_eq!(raw_output, "Hello world // not JSON")
+            }
+            _ => panic!("Expected ParseError"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_prompt_gemini_lenient_json() {
+        let command = script_command("lenient-json", r#"printf '%s\n' '{"response": "ok",}'"#);
+        let config = |lenient_json| GeminiCliConfig {
+            gemini_cli_command: command.clone(),
+            lenient_json,
+            ..Default::default()
+        };
+        let args = || PromptGeminiArgs {
+            prompt: "hi".to_string(),
+            ..Default::default()
+        };
+
+        let error = GeminiCli::with_config(Arc::new(config(false)))
+            .prompt_gemini(Parameters(args()), CancellationToken::new())
+            .await
+            .unwrap_err();
+        assert_eq!(error.message, "gemini_json_parse_error");
+
+        let result = GeminiCli::with_config(Arc::new(config(true)))
+            .prompt_gemini(Parameters(args()), CancellationToken::new())
+            .await
+            .unwrap();
+        let RawContent::Text(text_content) = &result.content[0].raw else {
+            panic!("Expected text content");
+        };
+        assert_eq!(text_content.text, "ok");
+    }
+
+    #[tokio::test]
+    async fn test_prompt_gemini_fallback_to_text() {
+        let gemini_cli = GeminiCli::new("echo Plain answer, no JSON".to_string(), None);
+        let args = |fallback_to_text| PromptGeminiArgs {
+            prompt: "hi".to_string(),
+            fallback_to_text,
+            ..Default::default()
+        };
+
+        // Strict by default
+        let error = gemini_cli
+            .prompt_gemini(Parameters(args(None)), CancellationToken::new())
+            .await
+            .unwrap_err();
+        assert_eq!(error.message, "gemini_json_parse_error");
+
+        let result = gemini_cli
+            .prompt_gemini(Parameters(args(Some(true))), CancellationToken::new())
+            .await
+            .unwrap();
+        let RawContent::Text(text_content) = &result.content[0].raw else {
+            panic!("Expected text content");
+        };
+        assert!(text_content.text.starts_with("Plain answer, no JSON"));
+        let meta = result.meta.unwrap();
+        assert!(meta["json_fallback"]["parse_error"].is_string());
+        assert!(result.structured_content.is_none());
+    }
+
+    #[test]
+    fn test_non_json_input_returns_error() {
+        let text_output = "Hello world";
+        let result = parse_gemini_output(text_output);
+        match result {
+            ParseResult::ParseError { raw_output, error } => {
+                assert_eq!(raw_output, text_output);
+                assert!(error.contains("Failed to parse JSON"));
+            }
+            _ => panic!("Expected ParseError for non-JSON input"),
+        }
+    }
+
+    #[test]
+    fn test_extract_json_from_mixed_content() {
+        let mixed_content = r#"Some log output
+        {"response": "test", "error": null, "stats": null}
+        More output"#;
+
+        let json_str = extract_json_from_mixed_content(mixed_content);
+        assert!(json_str.is_some());
+
+        let json_obj: serde_json::Value = serde_json::from_str(json_str.unwrap()).unwrap();
+        assert_eq!(json_obj["response"], "test");
+    }
+
+    #[test]
+    fn test_parse_adversarial_input_completes_quickly() {
+        let inputs = [
+            // Deep nesting that never forms valid JSON
+            format!("{}{}", "{".repeat(200_000), "}".repeat(200_000)),
+            // Many plausible-looking but invalid candidates
+            r#"{"x" oops} "#.repeat(100_000),
+            // Unbalanced braces and unterminated strings
+            r#"{ "{ "#.repeat(100_000),
+        ];
+
+        for input in inputs {
+            let started = Instant::now();
+            assert!(matches!(
+                parse_gemini_output(&input),
+                ParseResult::ParseError { .. }
+            ));
+            assert!(
+                started.elapsed() < std::time::Duration::from_secs(2),
+                "parsing {} bytes took {:?}",
+                input.len(),
+                started.elapsed()
+            );
+        }
+    }
+
+    #[test]
+    fn test_oversized_candidates_are_skipped() {
+        let padding = "x".repeat(ExtractionBudget::default().max_candidate_bytes);
+        let content = format!(
+            "log\n{{\"response\": \"{}\"}}\n{{\"response\": \"small\"}}",
+            padding
+        );
+        assert_eq!(
+            json_object_candidates(&content, ExtractionBudget::default().max_candidate_bytes),
+            (vec![r#"{"response": "small"}"#], true)
+        );
+    }
+
+    #[test]
+    fn test_extraction_budget() {
+        let budget = ExtractionBudget {
+            max_lines: 3,
+            max_candidate_bytes: 64,
+        };
+        // More log objects than the candidates tried leave the response to the
+        // line-by-line strategy, which stops at `max_lines`
+        let late = r#"{"level": "debug", "message": "loading"}"#.to_string() + "\n";
+        let late = late.repeat(20) + r#"{"response": "late"}"#;
+        match parse_gemini_output_with_budget(&late, &budget) {
+            ParseResult::ParseError { error, .. } => {
+                assert!(error.contains("exceeded extraction budget"), "{error}")
+            }
+            _ => panic!("Expected ParseError beyond the line budget"),
+        }
+        let roomy = ExtractionBudget {
+            max_lines: 100,
+            ..budget
+        };
+        assert!(matches!(
+            parse_gemini_output_with_budget(&late, &roomy),
+            ParseResult::JsonSuccess(_)
+        ));
+
+        // Oversized objects are skipped in favour of ones within the budget
+        let padding = "x".repeat(64);
+        let output = format!("{{\"response\": \"{padding}\"}}\n{{\"response\": \"ok\"}}\nmore log");
+        match parse_gemini_output_with_budget(&output, &budget) {
+            ParseResult::JsonSuccess(response) => assert_eq!(response.response, "ok"),
+            _ => panic!("Expected JsonSuccess within the budget"),
+        }
+        let output = format!("log {{\"response\": \"{padding}\"}}");
+        match parse_gemini_output_with_budget(&output, &budget) {
+            ParseResult::ParseError { error, .. } => {
+                assert!(error.contains("exceeded extraction budget"), "{error}")
+            }
+            _ => panic!("Expected ParseError for an oversized object"),
+        }
+
+        // Failures within the budget keep the plain message
+        match parse_gemini_output_with_budget("not json", &budget) {
+            ParseResult::ParseError { error, .. } => {
+                assert_eq!(error, "Failed to parse JSON from gemini CLI output")
+            }
+            _ => panic!("Expected ParseError"),
+        }
+    }
+
+    #[test]
+    fn test_json_object_candidates() {
+        let content = r#"[info] loading {module}
+{"level": "debug"}
+{"response": "use { and \" inside strings }", "stats": null}
+trailing } and { unbalanced"#;
+
+        let (candidates, oversized) = json_object_candidates(content, 1024);
+        assert_eq!(
+            candidates,
+            vec![
+                r#"{"response": "use { and \" inside strings }", "stats": null}"#,
+                r#"{"level": "debug"}"#,
+            ]
+        );
+        assert!(!oversized);
+
+        match parse_gemini_output(content) {
+            ParseResult::JsonSuccess(response) => {
+                assert_eq!(response.response, r#"use { and " inside strings }"#);
+            }
+            _ => panic!("Expected JsonSuccess from mixed content"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_prompt_gemini_with_json_output() {
+        // Test JSON output mode with a command that returns valid JSON
+        let valid_json =
+            r#"{"response": "Paris is the capital of France", "error": null, "stats": null}"#;
+        let gemini_cli = GeminiCli::new(format!("echo '{}'", valid_json), None);
+        let args = PromptGeminiArgs {
+            prompt: "What is the capital of France?".to_string(),
+            ..Default::default()
+        };
+
+        let result = gemini_cli
+            .prompt_gemini(Parameters(args), CancellationToken::new())
+            .await;
+        assert!(result.is_ok());
+
+        if let Ok(call_result) = result {
+            assert!(!call_result.content.is_empty());
+            if let RawContent::Text(text_content) = &call_result.content[0].raw {
+                assert_eq!(text_content.text, "Paris is the capital of France");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_prompt_gemini_returns_structured_content() {
+        let valid_json = r#"{"response": "Paris", "error": null, "stats": {"files": {"totalLinesAdded": 3, "totalLinesRemoved": 1}}}"#;
+        let gemini_cli = GeminiCli::new(format!("echo '{}'", valid_json), None);
+        let args = PromptGeminiArgs {
+            prompt: "What is the capital of France?".to_string(),
+            ..Default::default()
+        };
+
+        let result = gemini_cli
+            .prompt_gemini(Parameters(args), CancellationToken::new())
+            .await
+            .unwrap();
+        let structured = result
+            .structured_content
+            .expect("JSON responses should carry structured content");
+        assert_eq!(structured["response"], "Paris");
+        assert_eq!(structured["stats"]["files"]["totalLinesAdded"], 3);
+    }
+
+    #[tokio::test]
+    async fn test_prompt_gemini_output_sizes() {
+        let command = script_command(
+            "output-sizes",
+            r#"printf 'warn\n' >&2; printf '%s\n' '{"response": "ok"}'"#,
+        );
+        let gemini_cli = GeminiCli::new(command, None);
+        let args = PromptGeminiArgs {
+            prompt: "hi".to_string(),
+            ..Default::default()
+        };
+        let result = gemini_cli
+            .prompt_gemini(Parameters(args), CancellationToken::new())
+            .await
+            .unwrap();
+        let meta = result.meta.unwrap();
+        assert_eq!(meta["stdout_bytes"], 19);
+        assert_eq!(meta["stderr_bytes"], 5);
+
+        // Parse failures report them too
+        let gemini_cli = GeminiCli::new("echo".to_string(), None);
+        let args = PromptGeminiArgs {
+            prompt: "hi".to_string(),
+            ..Default::default()
+        };
+        let error = gemini_cli
+            .prompt_gemini(Parameters(args), CancellationToken::new())
+            .await
+            .unwrap_err();
+        assert_eq!(error.message, "gemini_json_parse_error");
+        let data = error.data.unwrap();
+        assert_eq!(
+            data["stdout_bytes"],
+            "--yolo --prompt hi --output-format json\n".len()
+        );
+        assert_eq!(data["stderr_bytes"], 0);
+    }
+
+    #[tokio::test]
+    async fn test_prompt_gemini_includes_file_changes() {
+        let valid_json = r#"{"response": "Done", "error": null, "stats": {"files": {"totalLinesAdded": 3, "totalLinesRemoved": 1}}}"#;
+        let gemini_cli = GeminiCli::new(format!("echo '{}'", valid_json), None);
+
+        // Not requested: left out of meta, but the changes are still remembered
+        let args = PromptGeminiArgs {
+            prompt: "Fix the bug".to_string(),
+            ..Default::default()
+        };
+        let result = gemini_cli
+            .prompt_gemini(Parameters(args), CancellationToken::new())
+            .await
+            .unwrap();
+        assert!(!result.meta.unwrap().contains_key("file_changes"));
+
+        let args = PromptGeminiArgs {
+            prompt: "Fix the bug".to_string(),
+            include_file_changes: Some(true),
+            ..Default::default()
+        };
+        let result = gemini_cli
+            .prompt_gemini(Parameters(args), CancellationToken::new())
+            .await
+            .unwrap();
+        let meta = result
+            .meta
+            .expect("file changes should be recorded in meta");
+        assert_eq!(meta["file_changes"]["totalLinesAdded"], 3);
+        assert_eq!(meta["file_changes"]["totalLinesRemoved"], 1);
+    }
+
+    #[tokio::test]
+    async fn test_last_file_changes() {
+        let gemini_cli = GeminiCli::new(
+            r#"echo {"response": "Done", "stats": {"files": {"totalLinesAdded": 5, "totalLinesRemoved": 2}}}"#
+                .to_string(),
+            None,
+        );
+
+        let last_file_changes = |gemini_cli: GeminiCli| async move {
+            let result = gemini_cli.last_file_changes().await.unwrap();
+            let RawContent::Text(text_content) = &result.content[0].raw else {
+                panic!("Expected text content");
+            };
+            serde_json::from_str::<serde_json::Value>(&text_content.text).unwrap()
+        };
+        assert!(last_file_changes(gemini_cli.clone()).await["file_changes"].is_null());
+
+        let args = PromptGeminiArgs {
+            prompt: "Fix the bug".to_string(),
+            ..Default::default()
+        };
+        gemini_cli
+            .prompt_gemini(Parameters(args), CancellationToken::new())
+            .await
+            .unwrap();
+        let changes = last_file_changes(gemini_cli).await;
+        assert_eq!(changes["file_changes"]["totalLinesAdded"], 5);
+        assert_eq!(changes["file_changes"]["totalLinesRemoved"], 2);
+    }
+
+    #[tokio::test]
+    async fn test_prompt_gemini_includes_raw_output() {
+        let command = script_command(
+            "raw-output",
+            r#"echo 'Loaded cached credentials.'; echo '{"response": "Paris"}'"#,
+        );
+        let gemini_cli = GeminiCli::new(command, None);
+
+        let args = PromptGeminiArgs {
+            prompt: "What is the capital of France?".to_string(),
+            ..Default::default()
+        };
+        let result = gemini_cli
+            .prompt_gemini(Parameters(args), CancellationToken::new())
+            .await
+            .unwrap();
+        assert_eq!(result.content.len(), 1);
+
+        let args = PromptGeminiArgs {
+            prompt: "What is the capital of France?".to_string(),
+            include_raw: Some(true),
+            ..Default::default()
+        };
+        let result = gemini_cli
+            .prompt_gemini(Parameters(args), CancellationToken::new())
+            .await
+            .unwrap();
+        assert_eq!(result.content.len(), 2);
+        let RawContent::Text(response) = &result.content[0].raw else {
+            panic!("Expected text content");
+        };
+        assert_eq!(response.text, "Paris");
+        let RawContent::Text(raw) = &result.content[1].raw else {
+            panic!("Expected text content");
+        };
+        assert_eq!(
+            raw.text,
+            "Loaded cached credentials.\n{\"response\": \"Paris\"}\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tool_decisions() {
+        let command = script_command(
+            "tool-decisions",
+            r#"echo '{"response": "Done", "stats": {"tools": {"totalDecisions": {"accept": 3, "reject": 1, "modify": 0, "auto_accept": 0}}}}'"#,
+        );
+        let gemini_cli = GeminiCli::new(command, None);
+
+        for _ in 0..2 {
+            let args = PromptGeminiArgs {
+                prompt: "Refactor".to_string(),
+                ..Default::default()
+            };
+            gemini_cli
+                .prompt_gemini(Parameters(args), CancellationToken::new())
+                .await
+                .unwrap();
+        }
+
+        let result = gemini_cli.tool_decisions().await.unwrap();
+        let RawContent::Text(text_content) = &result.content[0].raw else {
+            panic!("Expected text content");
+        };
+        let decisions: serde_json::Value = serde_json::from_str(&text_content.text).unwrap();
+        assert_eq!(decisions["last"]["accept"], 3);
+        assert_eq!(decisions["cumulative"]["prompts"], 2);
+        assert_eq!(decisions["cumulative"]["accept"], 6);
+        assert_eq!(decisions["cumulative"]["reject"], 2);
+        assert_eq!(decisions["cumulative_rates"]["accept"], 0.75);
+        assert_eq!(decisions["cumulative_rates"]["reject"], 0.25);
+    }
+
+    #[test]
+    fn test_decision_rates_without_decisions() {
+        let rates = DecisionTotals::default().rates();
+        assert_eq!(rates["accept"], 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_prompt_gemini_with_json_error_response() {
+        // Test JSON output mode with an error response
+        let error_json = r#"{"response": "", "error": {"type": "AuthError", "message": "API key invalid", "code": 401}, "stats": null}"#;
+        let gemini_cli = GeminiCli::new(format!("echo '{}'", error_json), None);
+        let args = PromptGeminiArgs {
+            prompt: "test".to_string(),
+            ..Default::default()
+        };
+
+        let result = gemini_cli
+            .prompt_gemini(Parameters(args), CancellationToken::new())
+            .await;
+        assert!(result.is_err());
+
+        if let Err(error) = result {
+            // Authentication failures have their own code
+            assert_eq!(error.message, "gemini_auth_error");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_prompt_gemini_json_parse_fallback() {
+        // Test JSON mode with invalid JSON (should fall back to parse error)
+        let invalid_json = "This is not valid JSON";
+        let gemini_cli = GeminiCli::new(format!("echo '{}'", invalid_json), None);
+        let args = PromptGeminiArgs {
+            prompt: "test".to_string(),
+            ..Default::default()
+        };
+
+        let result = gemini_cli
+            .prompt_gemini(Parameters(args), CancellationToken::new())
+            .await;
+        assert!(result.is_err());
+
+        if let Err(error) = result {
+            // Should be a JSON parse error
+            assert!(error.message.contains("gemini_json_parse_error"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_prompt_gemini_writes_audit_log() {
+        let path = std::env::temp_dir().join(format!(
+            "mcp-gemini-cli-test-{}-tools-audit.jsonl",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let mut config = backend_config();
+        config
+            .backends
+            .insert("keyed".to_string(), "echo --token hunter2".to_string());
+        let mut state = ServerState::new(config);
+        state.audit_log = Some(crate::audit::AuditLog::open(&path, false).unwrap());
+        let gemini_cli = GeminiCli::with_state(Arc::new(state));
+
+        for backend in ["fast", "missing", "keyed"] {
+            let args = PromptGeminiArgs {
+                prompt: "audited".to_string(),
+                backend: Some(backend.to_string()),
+                output_format: Some("text".to_string()),
+                ..Default::default()
+            };
+            let _ = gemini_cli
+                .prompt_gemini(Parameters(args), CancellationToken::new())
+                .await;
+        }
+
+        let mut contents = String::new();
+        for _ in 0..50 {
+            contents = std::fs::read_to_string(&path).unwrap_or_default();
+            if contents.lines().count() == 3 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let records: Vec<serde_json::Value> = contents
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0]["tool"], "prompt-gemini");
+        assert_eq!(records[0]["command"], r#"echo {"response": "fast"}"#);
+        assert_eq!(records[0]["outcome"], "success");
+        assert!(records[0].get("prompt").is_none());
+        assert_eq!(records[1]["command"], serde_json::Value::Null);
+        assert_eq!(records[1]["outcome"], "gemini_invalid_prompt");
+        assert_eq!(records[0]["prompt_sha256"], records[1]["prompt_sha256"]);
+        assert_eq!(records[2]["command"], "echo --token [REDACTED]");
+        assert_eq!(records[2]["outcome"], "success");
+    }
+
+    #[tokio::test]
+    async fn test_max_in_flight_per_session() {
+        let command = script_command("session-busy", r#"sleep 1; echo '{"response": "ok"}'"#);
+        let state = Arc::new(ServerState::new(GeminiCliConfig {
+            gemini_cli_command: command,
+            max_in_flight_per_session: Some(1),
+            ..Default::default()
+        }));
+        let session = GeminiCli::with_state(state.clone());
+        let other_session = GeminiCli::with_state(state);
+        let args = || PromptGeminiArgs {
+            prompt: "hi".to_string(),
+            ..Default::default()
+        };
+
+        let (first, second, other) = tokio::join!(
+            session.prompt_gemini(Parameters(args()), CancellationToken::new()),
+            async {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                session
+                    .prompt_gemini(Parameters(args()), CancellationToken::new())
+                    .await
+            },
+            async {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                other_session
+                    .prompt_gemini(Parameters(args()), CancellationToken::new())
+                    .await
+            },
+        );
+        assert!(first.is_ok());
+        assert!(other.is_ok());
+        let busy = second.unwrap_err();
+        assert_eq!(busy.message, "gemini_session_busy");
+        assert_eq!(busy.data.unwrap()["max_in_flight_per_session"], 1);
+
+        // The slot is released once the first call finishes
+        assert_eq!(session.session_stats.lock().unwrap().in_flight, 0);
+        assert!(
+            session
+                .prompt_gemini(Parameters(args()), CancellationToken::new())
+                .await
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn test_select_json_pointer() {
+        let response = r#"{"items": [{"name": "a", "tags": ["x"]}]}"#;
+        assert_eq!(select_json_pointer(response, "/items/0/name").unwrap(), "a");
+        assert_eq!(
+            select_json_pointer(response, "/items/0/tags").unwrap(),
+            r#"["x"]"#
+        );
+        assert_eq!(
+            select_json_pointer(response, "/items/1")
+                .unwrap_err()
+                .message,
+            "gemini_json_pointer_not_found"
+        );
+        assert_eq!(
+            select_json_pointer("plain text", "/items")
+                .unwrap_err()
+                .message,
+            "gemini_response_not_json"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_prompt_gemini_response_json_pointer() {
+        let command = script_command(
+            "json-pointer",
+            r#"echo '{"response": "{\"summary\": {\"title\": \"Hello\"}}"}'"#,
+        );
+        let gemini_cli = GeminiCli::new(command, None);
+
+        let args = PromptGeminiArgs {
+            prompt: "hi".to_string(),
+            response_json_pointer: Some("/summary/title".to_string()),
+            ..Default::default()
+        };
+        let result = gemini_cli
+            .prompt_gemini(Parameters(args), CancellationToken::new())
+            .await
+            .unwrap();
+        let RawContent::Text(text_content) = &result.content[0].raw else {
+            panic!("Expected text content");
+        };
+        assert_eq!(text_content.text, "Hello");
+
+        for (pointer, output_format) in [("summary", None), ("/summary", Some("text"))] {
+            let args = PromptGeminiArgs {
+                prompt: "hi".to_string(),
+                response_json_pointer: Some(pointer.to_string()),
+                output_format: output_format.map(str::to_string),
+                ..Default::default()
+            };
+            let error = gemini_cli
+                .prompt_gemini(Parameters(args), CancellationToken::new())
+                .await
+                .unwrap_err();
+            assert_eq!(error.message, "gemini_invalid_prompt");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_default_model() {
+        let gemini_cli = GeminiCli::with_config(Arc::new(GeminiCliConfig {
+            gemini_cli_command: "echo".to_string(),
+            default_model: Some("gemini-2.5-flash".to_string()),
+            default_output_format: OutputFormat::Text,
+            ..Default::default()
+        }));
+
+        for (model, expected) in [
+            (None, "--yolo --model gemini-2.5-flash --prompt hi"),
+            (
+                Some("gemini-2.5-pro"),
+                "--yolo --model gemini-2.5-pro --prompt hi",
+            ),
+        ] {
+            let args = PromptGeminiArgs {
+                prompt: "hi".to_string(),
+                model: model.map(str::to_string),
+                ..Default::default()
+            };
+            let result = gemini_cli
+                .prompt_gemini(Parameters(args), CancellationToken::new())
+                .await
+                .unwrap();
+            let RawContent::Text(text_content) = &result.content[0].raw else {
+                panic!("Expected text content");
+            };
+            assert_eq!(text_content.text, expected);
+        }
+    }
+
+    #[test]
+    fn test_take_utf8_prefix() {
+        let mut pending = "héllo".as_bytes()[..2].to_vec();
+        assert_eq!(take_utf8_prefix(&mut pending), "h");
+        assert_eq!(pending, [0xc3]);
+        pending.extend_from_slice(&"héllo".as_bytes()[2..]);
+        assert_eq!(take_utf8_prefix(&mut pending), "éllo");
+        assert!(pending.is_empty());
+
+        let mut pending = vec![b'a', 0xff, b'b'];
+        assert_eq!(take_utf8_prefix(&mut pending), "a\u{fffd}b");
+        assert!(pending.is_empty());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_prompt_stream_kills_gemini_cli_when_dropped() {
+        let pid_file = std::env::temp_dir().join(format!(
+            "mcp-gemini-cli-test-{}-stream.pid",
+            std::process::id()
+        ));
+        let command = script_command(
+            "stream-drop",
+            &format!(
+                "echo $$ > {}; echo first; exec sleep 30",
+                pid_file.display()
+            ),
+        );
+        let gemini_cli = GeminiCli::new(command, None);
+
+        let mut events = gemini_cli
+            .prompt_stream(PromptGeminiArgs {
+                prompt: "hi".to_string(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let Some(StreamEvent::Output(text)) = events.recv().await else {
+            panic!("Expected output");
+        };
+        assert_eq!(text, "first\n");
+        drop(events);
+
+        let pid = std::fs::read_to_string(&pid_file).unwrap();
+        assert!(
+            crate::process_group::wait_for_exit(&pid).await,
+            "gemini-cli was not killed"
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_subprocess_nice() {
+        // Field 19 of /proc/<pid>/stat is the nice value
+        let command = script_command("nice", "cut -d' ' -f19 /proc/$$/stat");
+        let gemini_cli = GeminiCli::with_config(Arc::new(GeminiCliConfig {
+            gemini_cli_command: command,
+            default_output_format: OutputFormat::Text,
+            subprocess_nice: Some(7),
+            ..Default::default()
+        }));
+
+        let result = gemini_cli
+            .prompt_gemini(
+                Parameters(PromptGeminiArgs {
+                    prompt: "hi".to_string(),
+                    ..Default::default()
+                }),
+                CancellationToken::new(),
+            )
+            .await
+            .unwrap();
+        let RawContent::Text(text_content) = &result.content[0].raw else {
+            panic!("Expected text content");
+        };
+        assert_eq!(text_content.text, "7");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_kill_process_group_on_timeout() {
+        let pid_file = std::env::temp_dir().join(format!(
+            "mcp-gemini-cli-test-{}-group.pid",
+            std::process::id()
+        ));
+        let command = script_command(
+            "process-group",
+            &format!(
+                "sleep 30 > /dev/null & echo $! > {}; exec sleep 30",
+                pid_file.display()
+            ),
+        );
+        let gemini_cli = GeminiCli::with_config(Arc::new(GeminiCliConfig {
+            gemini_cli_command: command,
+            timeout_secs: Some(1),
+            kill_process_group: true,
+            ..Default::default()
+        }));
+
+        let error = gemini_cli
+            .prompt_gemini(
+                Parameters(PromptGeminiArgs {
+                    prompt: "hi".to_string(),
+                    ..Default::default()
+                }),
+                CancellationToken::new(),
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(error.message, "gemini_cli_timeout");
+
+        // The tool gemini-cli started is killed with it
+        let pid = std::fs::read_to_string(&pid_file).unwrap();
+        assert!(
+            crate::process_group::wait_for_exit(&pid).await,
+            "grandchild survived the timeout"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resource_usage() {
+        let gemini_cli = GeminiCli::new(r#"echo {"response": "ok"}"#.to_string(), None);
+        let result = gemini_cli.resource_usage().await.unwrap();
+        let RawContent::Text(text_content) = &result.content[0].raw else {
+            panic!("Expected text content");
+        };
+        assert!(text_content.text.contains(r#""resource_usage":null"#));
+
+        gemini_cli
+            .prompt_gemini(
+                Parameters(PromptGeminiArgs {
+                    prompt: "hi".to_string(),
+                    ..Default::default()
+                }),
+                CancellationToken::new(),
+            )
+            .await
+            .unwrap();
+        let usage = gemini_cli
+            .session_stats
+            .lock()
+            .unwrap()
+            .last_resource_usage
+            .clone()
+            .unwrap();
+        assert!(usage.cpu_user_ms.is_some());
+        assert!(usage.max_child_rss_kb.unwrap() > 0);
+    }
+
+    #[test]
+    fn test_decode_output() {
+        let latin1 = b"caf\xe9";
+        assert_eq!(decode_output(latin1, encoding_rs::UTF_8), "caf\u{fffd}");
+        assert_eq!(decode_output(latin1, encoding_rs::WINDOWS_1252), "café");
+        // Not valid Shift_JIS: falls back to lossy UTF-8
+        assert_eq!(decode_output("†".as_bytes(), encoding_rs::SHIFT_JIS), "†");
+
+        let mut decoder = encoding_rs::SHIFT_JIS.new_decoder();
+        let bytes = encoding_rs::SHIFT_JIS.encode("日本").0;
+        assert_eq!(decode_chunk(&mut decoder, &bytes[..3], false), "日");
+        assert_eq!(decode_chunk(&mut decoder, &bytes[3..], true), "本");
+    }
+
+    #[tokio::test]
+    async fn test_prompt_gemini_output_encoding() {
+        let command = script_command("latin1", r#"printf '{"response": "caf\351"}'"#);
+        let gemini_cli = GeminiCli::with_config(Arc::new(GeminiCliConfig {
+            gemini_cli_command: command,
+            output_encoding: encoding_rs::WINDOWS_1252,
+            ..Default::default()
+        }));
+
+        let result = gemini_cli
+            .prompt_gemini(
+                Parameters(PromptGeminiArgs {
+                    prompt: "hi".to_string(),
+                    ..Default::default()
+                }),
+                CancellationToken::new(),
+            )
+            .await
+            .unwrap();
+        let RawContent::Text(text_content) = &result.content[0].raw else {
+            panic!("Expected text content");
+        };
+        assert_eq!(text_content.text, "café");
+    }
+
+    #[test]
+    fn test_tool_description_override() {
+        let description = |gemini_cli: &GeminiCli| {
+            gemini_cli
+                .tool_router
+                .list_all()
+                .into_iter()
+                .find(|tool| tool.name == "prompt-gemini")
+                .and_then(|tool| tool.description)
+                .unwrap()
         };
 
-        let result = gemini_cli.prompt_gemini(Parameters(args)).await;
-        assert!(result.is_err());
+        let default = GeminiCli::new("gemini".to_string(), None);
+        assert!(description(&default).starts_with("Send a prompt to Gemini CLI"));
 
-        // Should be a JSON parse error since echo doesn't output valid JSON
-        if let Err(error) = result {
-            assert!(error.message.contains("gemini_json_parse_error"));
+        let overridden = GeminiCli::with_config(Arc::new(GeminiCliConfig {
+            tool_description: Some("Ask Gemini 2.5 Pro (code review only)".to_string()),
+            ..Default::default()
+        }));
+        assert_eq!(
+            description(&overridden),
+            "Ask Gemini 2.5 Pro (code review only)"
+        );
+    }
+
+    #[test]
+    fn test_instructions_list_registered_tools() {
+        let gemini_cli = GeminiCli::with_config(Arc::new(GeminiCliConfig {
+            response_cache_max_entries: 8,
+            ..Default::default()
+        }));
+        let instructions = gemini_cli.get_info().instructions.unwrap();
+        for tool in gemini_cli.tool_router.list_all() {
+            assert!(
+                instructions.contains(&format!("{} (", tool.name)),
+                "{} is missing from the instructions",
+                tool.name
+            );
         }
+        assert!(!instructions.contains("prompt_gemini"));
     }
 
     #[tokio::test]
-    async fn test_gemini_cli_new() {
-        let gemini_cli = GeminiCli::new("test_command".to_string(), None);
-        assert_eq!(gemini_cli.gemini_cli_command, "test_command");
+    async fn test_prompt_gemini_images() {
+        let gemini_cli = GeminiCli::with_config(Arc::new(GeminiCliConfig {
+            gemini_cli_command: "echo".to_string(),
+            default_output_format: OutputFormat::Text,
+            ..Default::default()
+        }));
+        let args = |images: &[&str], model: Option<&str>| PromptGeminiArgs {
+            prompt: "describe".to_string(),
+            model: model.map(str::to_string),
+            images: Some(images.iter().map(|image| image.to_string()).collect()),
+            ..Default::default()
+        };
+
+        let result = gemini_cli
+            .prompt_gemini(
+                Parameters(args(&["data:image/png;base64,aGVsbG8="], None)),
+                CancellationToken::new(),
+            )
+            .await
+            .unwrap();
+        let RawContent::Text(text_content) = &result.content[0].raw else {
+            panic!("Expected text content");
+        };
+        let image_path = text_content
+            .text
+            .strip_prefix("--yolo --image ")
+            .and_then(|rest| rest.strip_suffix(" --prompt describe"))
+            .unwrap();
+        assert!(image_path.ends_with(".png"));
+        // The decoded data URL is removed once gemini-cli has finished
+        assert!(!std::path::Path::new(image_path).exists());
+
+        let error = gemini_cli
+            .prompt_gemini(
+                Parameters(args(&["/nonexistent/image.png"], None)),
+                CancellationToken::new(),
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(error.message, "gemini_invalid_image");
+
+        // Without an allowlist, files outside the workspace (here the current
+        // directory) are rejected
+        let outside = std::env::temp_dir().join(format!(
+            "mcp-gemini-cli-test-{}-outside.png",
+            std::process::id()
+        ));
+        std::fs::write(&outside, b"png").unwrap();
+        let error = gemini_cli
+            .prompt_gemini(
+                Parameters(args(&[outside.to_str().unwrap()], None)),
+                CancellationToken::new(),
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(error.message, "gemini_invalid_image");
+        assert!(
+            error.data.unwrap()["reasons"][0]
+                .as_str()
+                .unwrap()
+                .contains("not under any allowed workspace root")
+        );
+        let _ = std::fs::remove_file(&outside);
+
+        let error = gemini_cli
+            .prompt_gemini(
+                Parameters(args(
+                    &["data:image/png;base64,aGVsbG8="],
+                    Some("gemini-1.0-pro"),
+                )),
+                CancellationToken::new(),
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(error.message, "gemini_model_not_multimodal");
+    }
+
+    #[test]
+    fn test_parse_token_count() {
+        assert_eq!(parse_token_count("42\n"), Some(42));
+        assert_eq!(parse_token_count(r#"{"totalTokens": 7}"#), Some(7));
+        assert_eq!(parse_token_count(r#"{"total_tokens": 8}"#), Some(8));
+        assert_eq!(parse_token_count("Tokens: 9"), None);
     }
 
     #[tokio::test]
-    async fn test_prompt_gemini_with_multiword_command() {
-        // Test with a multi-word command like "echo hello"
-        // This should fail with JSON parse error since echo outputs plain text
-        let gemini_cli = GeminiCli::new("echo hello".to_string(), None);
-        let args = PromptGeminiArgs {
-            prompt: "world".to_string(),
+    async fn test_estimate_tokens() {
+        let estimate = |count_tokens_command: Option<String>| async move {
+            let gemini_cli = GeminiCli::with_config(Arc::new(GeminiCliConfig {
+                count_tokens_command,
+                ..Default::default()
+            }));
+            let result = gemini_cli
+                .estimate_tokens(Parameters(PromptGeminiArgs {
+                    prompt: "{{word}} world!".to_string(),
+                    variables: Some(HashMap::from([("word".to_string(), "hello".to_string())])),
+                    ..Default::default()
+                }))
+                .await
+                .unwrap();
+            let RawContent::Text(text_content) = &result.content[0].raw else {
+                panic!("Expected text content");
+            };
+            serde_json::from_str::<serde_json::Value>(&text_content.text).unwrap()
         };
 
-        let result = gemini_cli.prompt_gemini(Parameters(args)).await;
-        assert!(result.is_err());
+        let heuristic = estimate(None).await;
+        assert_eq!(heuristic["method"], "heuristic");
+        assert_eq!(heuristic["estimated_tokens"], 3);
+        assert_eq!(heuristic["prompt_chars"], 12);
+        assert!(heuristic.get("fallback_reason").is_none());
 
-        if let Err(error) = result {
-            assert!(error.message.contains("gemini_json_parse_error"));
-        }
+        let counted = estimate(Some(script_command(
+            "count-tokens",
+            r#"[ "$1" = "--" ] && echo "{\"totalTokens\": ${#2}}""#,
+        )))
+        .await;
+        assert_eq!(counted["method"], "count_tokens_command");
+        assert_eq!(counted["estimated_tokens"], 12);
+
+        let fallback = estimate(Some("nonexistent_command_12345".to_string())).await;
+        assert_eq!(fallback["method"], "heuristic");
+        assert!(
+            fallback["fallback_reason"]
+                .as_str()
+                .unwrap()
+                .contains("nonexistent_command_12345")
+        );
+
+        // Denied prompts never reach the count-tokens command
+        let gemini_cli = GeminiCli::with_config(Arc::new(GeminiCliConfig {
+            count_tokens_command: Some("nonexistent_command_12345".to_string()),
+            deny_prompt_patterns: vec![regex::Regex::new("secret").unwrap()],
+            ..Default::default()
+        }));
+        let error = gemini_cli
+            .estimate_tokens(Parameters(PromptGeminiArgs {
+                prompt: "the secret".to_string(),
+                ..Default::default()
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(error.message, "gemini_prompt_denied");
+    }
+
+    #[test]
+    fn test_split_code_blocks() {
+        let text_of = |content: &Content| match &content.raw {
+            RawContent::Text(text_content) => {
+                (text_content.text.clone(), text_content.meta.clone())
+            }
+            _ => panic!("Expected text content"),
+        };
+
+        let contents = split_code_blocks(
+            "Here is the fix:\n\n```rust\nfn main() {}\n```\n\nThen run:\n```\ncargo run\n```",
+        );
+        assert_eq!(contents.len(), 4);
+        assert_eq!(
+            text_of(&contents[0]),
+            ("Here is the fix:".to_string(), None)
+        );
+        let (code, meta) = text_of(&contents[1]);
+        assert_eq!(code, "fn main() {}");
+        let meta = meta.unwrap();
+        assert_eq!(meta.get("content_type").unwrap(), "code");
+        assert_eq!(meta.get("language").unwrap(), "rust");
+        assert_eq!(text_of(&contents[2]).0, "Then run:");
+        let (code, meta) = text_of(&contents[3]);
+        assert_eq!(code, "cargo run");
+        assert!(meta.unwrap().get("language").is_none());
+
+        // No fences, or an unclosed one, leaves the text whole
+        assert_eq!(split_code_blocks("plain").len(), 1);
+        let contents = split_code_blocks("before\n```python\nprint(1)");
+        assert_eq!(contents.len(), 1);
+        assert_eq!(text_of(&contents[0]).0, "before\n```python\nprint(1)");
     }
 
     #[tokio::test]
-    async fn test_prompt_gemini_with_empty_output() {
-        // Test with a simple command that we know will work (true does nothing but exit successfully)
-        // This test verifies the plain text response handling
-        let gemini_cli = GeminiCli::new("true".to_string(), None);
+    async fn test_prompt_gemini_split_code_blocks() {
+        let cmd = script_command(
+            "split-code",
+            r#"printf '%s\n' '{"response": "Use:\n```sh\nls -la\n```"}'"#,
+        );
+        let gemini_cli = GeminiCli::new(cmd, None);
         let args = PromptGeminiArgs {
-            prompt: "test prompt".to_string(),
+            prompt: "hi".to_string(),
+            split_code_blocks: Some(true),
+            ..Default::default()
+        };
+        let result = gemini_cli
+            .prompt_gemini(Parameters(args), CancellationToken::new())
+            .await
+            .unwrap();
+        assert_eq!(result.content.len(), 2);
+        let RawContent::Text(code) = &result.content[1].raw else {
+            panic!("Expected text content");
+        };
+        assert_eq!(code.text, "ls -la");
+        assert_eq!(code.meta.as_ref().unwrap().get("language").unwrap(), "sh");
+    }
+
+    #[tokio::test]
+    async fn test_show_config() {
+        let gemini_cli = GeminiCli::with_config(Arc::new(GeminiCliConfig {
+            gemini_cli_command: "env GEMINI_API_KEY=abc gemini".to_string(),
+            workspace: Some("/srv/project".to_string()),
+            timeout_secs: Some(300),
+            ..Default::default()
+        }));
+        let result = gemini_cli.show_config().await.unwrap();
+        let RawContent::Text(text_content) = &result.content[0].raw else {
+            panic!("Expected text content");
         };
+        let config: serde_json::Value = serde_json::from_str(&text_content.text).unwrap();
+        assert_eq!(
+            config["gemini_cli_command"],
+            "env GEMINI_API_KEY=[REDACTED] gemini"
+        );
+        assert_eq!(config["workspace"], "/srv/project");
+        assert_eq!(config["timeout_secs"], 300);
+    }
 
-        let result = gemini_cli.prompt_gemini(Parameters(args)).await;
+    #[tokio::test]
+    async fn test_prompt_gemini_rejects_unknown_output_format() {
+        let gemini_cli = GeminiCli::new("echo".to_string(), None);
+        let args = PromptGeminiArgs {
+            prompt: "hello".to_string(),
+            output_format: Some("xml".to_string()),
+            ..Default::default()
+        };
 
-        // Since 'true' returns empty output, it should result in empty response content
-        assert!(result.is_ok());
+        let error = gemini_cli
+            .prompt_gemini(Parameters(args.clone()), CancellationToken::new())
+            .await
+            .unwrap_err();
+        assert_eq!(error.message, "gemini_invalid_output_format");
+        let data = error.data.unwrap();
+        assert_eq!(data["output_format"], "xml");
+        assert_eq!(
+            data["allowed_values"],
+            serde_json::json!(["json", "text", "yaml"])
+        );
 
-        if let Ok(call_result) = result {
-            assert!(!call_result.content.is_empty());
-            // Should contain empty response message
-            if let RawContent::Text(text_content) = &call_result.content[0].raw {
-                assert_eq!(text_content.text, "Gemini CLI returned empty response");
-            }
+        let result = gemini_cli.validate_prompt(Parameters(args)).await.unwrap();
+        let RawContent::Text(text_content) = &result.content[0].raw else {
+            panic!("Expected text content");
+        };
+        assert!(
+            text_content
+                .text
+                .contains("Unsupported output_format 'xml'")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_prompt_gemini_response_cache() {
+        let counter = std::env::temp_dir().join(format!(
+            "mcp-gemini-cli-test-{}-cache-count",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&counter);
+        let cmd = script_command(
+            "cache",
+            &format!(
+                r#"echo run >> {}; echo '{{"response": "cached answer"}}'"#,
+                counter.display()
+            ),
+        );
+        let config = GeminiCliConfig {
+            gemini_cli_command: cmd,
+            response_cache_max_entries: 8,
+            read_only: true,
+            ..Default::default()
+        };
+        let writable = GeminiCli::with_config(Arc::new(GeminiCliConfig {
+            read_only: false,
+            ..config.clone()
+        }));
+        for _ in 0..2 {
+            let args = PromptGeminiArgs {
+                prompt: "hi".to_string(),
+                ..Default::default()
+            };
+            writable.prompt(args).await.unwrap();
         }
+        let cache = writable.state.metrics_snapshot().response_cache.unwrap();
+        assert_eq!((cache.entries, cache.hits, cache.misses), (0, 0, 0));
+        let _ = std::fs::remove_file(&counter);
+
+        let gemini_cli = GeminiCli::with_config(Arc::new(config));
+        let prompt = |prompt: &str, model: Option<&str>| {
+            let args = PromptGeminiArgs {
+                prompt: prompt.to_string(),
+                model: model.map(str::to_string),
+                ..Default::default()
+            };
+            gemini_cli.prompt_gemini(Parameters(args), CancellationToken::new())
+        };
+        let cached = |result: &CallToolResult| {
+            result
+                .meta
+                .as_ref()
+                .is_some_and(|meta| meta.get("cached").is_some())
+        };
+
+        let first = prompt("hi", None).await.unwrap();
+        let second = prompt("hi", None).await.unwrap();
+        let other_model = prompt("hi", Some("gemini-2.5-pro")).await.unwrap();
+        assert!(!cached(&first));
+        assert!(cached(&second));
+        assert!(!cached(&other_model));
+        assert_eq!(first.content, second.content);
+        assert_eq!(
+            std::fs::read_to_string(&counter).unwrap().lines().count(),
+            2
+        );
+
+        let cache = gemini_cli.state.metrics_snapshot().response_cache.unwrap();
+        assert_eq!((cache.entries, cache.hits, cache.misses), (2, 1, 2));
+
+        let cleared = gemini_cli.clear_cache().await.unwrap();
+        let RawContent::Text(text_content) = &cleared.content[0].raw else {
+            panic!("Expected text content");
+        };
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&text_content.text).unwrap()["removed"],
+            2
+        );
+        assert!(!cached(&prompt("hi", None).await.unwrap()));
+        assert!(gemini_cli.tool_router.has_route("clear-cache"));
+
+        let uncached = GeminiCli::new("gemini".to_string(), None);
+        assert!(!uncached.tool_router.has_route("clear-cache"));
+        assert_eq!(
+            uncached.clear_cache().await.unwrap_err().message,
+            "gemini_cache_disabled"
+        );
     }
 
     #[tokio::test]
-    async fn test_prompt_gemini_with_text_output() {
-        // Test with a command that returns plain text - should result in JSON parse error
-        let gemini_cli = GeminiCli::new("echo 'Hello from Gemini'".to_string(), None);
-        let args = PromptGeminiArgs {
-            prompt: "test prompt".to_string(),
+    async fn test_response_cache_keys_on_image_contents() {
+        let workspace = std::env::temp_dir().join(format!(
+            "mcp-gemini-cli-test-{}-cache-images",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&workspace).unwrap();
+        let image = workspace.join("chart.png");
+        std::fs::write(&image, b"first").unwrap();
+        let gemini_cli = GeminiCli::with_config(Arc::new(GeminiCliConfig {
+            gemini_cli_command: script_command("cache-images", r#"echo '{"response": "ok"}'"#),
+            workspace: Some(workspace.to_string_lossy().into_owned()),
+            response_cache_max_entries: 8,
+            read_only: true,
+            ..Default::default()
+        }));
+        let cached = || async {
+            let args = PromptGeminiArgs {
+                prompt: "describe".to_string(),
+                images: Some(vec!["chart.png".to_string()]),
+                ..Default::default()
+            };
+            let result = gemini_cli.prompt(args).await.unwrap();
+            result.meta.is_some_and(|meta| meta.get("cached").is_some())
         };
 
-        let result = gemini_cli.prompt_gemini(Parameters(args)).await;
-        assert!(result.is_err());
+        assert!(!cached().await);
+        assert!(cached().await);
+        // Same path, new contents
+        std::fs::write(&image, b"second").unwrap();
+        assert!(!cached().await);
+        assert!(cached().await);
+        let _ = std::fs::remove_dir_all(&workspace);
+    }
 
-        if let Err(error) = result {
-            assert!(error.message.contains("gemini_json_parse_error"));
-        }
+    #[tokio::test]
+    async fn test_prompt_gemini_coalesces_identical_requests() {
+        let counter = std::env::temp_dir().join(format!(
+            "mcp-gemini-cli-test-{}-coalesce-count",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&counter);
+        let cmd = script_command(
+            "coalesce",
+            &format!(
+                r#"echo run >> {}; echo '{{"response": "shared"}}'"#,
+                counter.display()
+            ),
+        );
+        let gemini_cli = GeminiCli::with_config(Arc::new(GeminiCliConfig {
+            gemini_cli_command: cmd,
+            coalesce_window_ms: 100,
+            ..Default::default()
+        }));
+        let other_session = GeminiCli::with_state(gemini_cli.state.clone());
+        let prompt = |session: &GeminiCli, prompt: &str| {
+            let (session, args) = (
+                session.clone(),
+                PromptGeminiArgs {
+                    prompt: prompt.to_string(),
+                    ..Default::default()
+                },
+            );
+            async move { session.prompt(args).await }
+        };
+
+        let (first, second, other, other_session) = tokio::join!(
+            prompt(&gemini_cli, "hi"),
+            prompt(&gemini_cli, "hi"),
+            prompt(&gemini_cli, "other"),
+            prompt(&other_session, "hi")
+        );
+        let (first, second, other, other_session) = (
+            first.unwrap(),
+            second.unwrap(),
+            other.unwrap(),
+            other_session.unwrap(),
+        );
+        assert_eq!(
+            std::fs::read_to_string(&counter).unwrap().lines().count(),
+            3
+        );
+
+        let coalesced = |result: &CallToolResult| {
+            result
+                .meta
+                .as_ref()
+                .is_some_and(|meta| meta.get("coalesced").is_some())
+        };
+        assert!(coalesced(&first) != coalesced(&second));
+        assert!(!coalesced(&other));
+        assert!(!coalesced(&other_session));
+        assert_eq!(first.content, second.content);
     }
 
-    // JSON parsing tests
-    #[test]
-    fn test_parse_clean_json() {
-        let json_output = r#"{"response": "Hello world", "stats": null, "error": null}"#;
-        let result = parse_gemini_output(json_output);
-        match result {
-            ParseResult::JsonSuccess(response) => {
-                assert_eq!(response.response, "Hello world");
-                assert!(response.stats.is_none());
-                assert!(response.error.is_none());
+    #[tokio::test]
+    async fn test_command_not_found_reports_lookup() {
+        let prompt = |command: &str| {
+            let gemini_cli = GeminiCli::new(command.to_string(), None);
+            async move {
+                let args = PromptGeminiArgs {
+                    prompt: "hi".to_string(),
+                    ..Default::default()
+                };
+                let error = gemini_cli
+                    .prompt_gemini(Parameters(args), CancellationToken::new())
+                    .await
+                    .unwrap_err();
+                assert_eq!(error.message, "gemini_cli_command_failed");
+                error.data.unwrap()["lookup"].clone()
             }
-            _ => panic!("Expected JsonSuccess"),
-        }
+        };
+
+        let relative = prompt("./nonexistent_gemini_12345 --flag").await;
+        assert_eq!(relative["path_kind"], "relative");
+        assert_eq!(relative["exists"], false);
+        let cwd = std::env::current_dir().unwrap();
+        assert_eq!(relative["working_directory"], serde_json::json!(cwd));
+        assert_eq!(
+            relative["resolved_path"],
+            serde_json::json!(cwd.join("./nonexistent_gemini_12345"))
+        );
+
+        let bare = prompt("nonexistent_gemini_12345").await;
+        assert_eq!(bare["path_kind"], "path_lookup");
+        assert!(!bare["searched_directories"].as_array().unwrap().is_empty());
+
+        let absolute = prompt("/nonexistent/gemini").await;
+        assert_eq!(absolute["path_kind"], "absolute");
     }
 
-    #[test]
-    fn test_parse_mixed_content() {
-        let mixed_output = r#"Loading model...
-Generating response...
-{"response": "Hello world", "stats": null, "error": null}
-Done."#;
-        let result = parse_gemini_output(mixed_output);
-        match result {
-            ParseResult::JsonSuccess(response) => {
-                assert_eq!(response.response, "Hello world");
+    #[tokio::test]
+    async fn test_prompt_gemini_post_process_command() {
+        let prompt = |post_process_command: String| {
+            let gemini_cli = GeminiCli::with_config(Arc::new(GeminiCliConfig {
+                gemini_cli_command: r#"echo {"response": "secret answer"}"#.to_string(),
+                post_process_command: Some(post_process_command),
+                ..Default::default()
+            }));
+            async move {
+                let args = PromptGeminiArgs {
+                    prompt: "hi".to_string(),
+                    ..Default::default()
+                };
+                gemini_cli
+                    .prompt_gemini(Parameters(args), CancellationToken::new())
+                    .await
             }
-            _ => panic!("Expected JsonSuccess from mixed content"),
-        }
+        };
+
+        let result = prompt("sed -e s/secret/[redacted]/ -e $a\\".to_string())
+            .await
+            .unwrap();
+        let RawContent::Text(text_content) = &result.content[0].raw else {
+            panic!("Expected text content");
+        };
+        assert_eq!(text_content.text, "[redacted] answer");
+
+        let failing = script_command("failing-hook", "cat > /dev/null; echo nope >&2; exit 3");
+        let error = prompt(failing).await.unwrap_err();
+        assert_eq!(error.message, "gemini_post_process_failed");
+        let data = error.data.unwrap();
+        assert_eq!(data["error"], "Exited with code 3");
+        assert_eq!(data["stderr"], "nope");
+
+        // Larger than a pipe buffer in both directions
+        let gemini_cli = GeminiCli::with_config(Arc::new(GeminiCliConfig {
+            gemini_cli_command: script_command(
+                "large-output",
+                "head -c 1000000 /dev/zero | tr '\\0' a",
+            ),
+            default_output_format: OutputFormat::Text,
+            post_process_command: Some("cat".to_string()),
+            post_process_timeout_secs: 5,
+            ..Default::default()
+        }));
+        let args = PromptGeminiArgs {
+            prompt: "hi".to_string(),
+            ..Default::default()
+        };
+        let result = gemini_cli
+            .prompt_gemini(Parameters(args), CancellationToken::new())
+            .await
+            .unwrap();
+        let RawContent::Text(text_content) = &result.content[0].raw else {
+            panic!("Expected text content");
+        };
+        assert_eq!(text_content.text.len(), 1_000_000);
     }
 
     #[test]
-    fn test_parse_error_response() {
-        let error_json = r#"{"response": "", "error": {"type": "ApiError", "message": "Test error", "code": 400}, "stats": null}"#;
-        let result = parse_gemini_output(error_json);
-        match result {
-            ParseResult::JsonSuccess(response) => {
-                assert!(response.error.is_some());
-                let error = response.error.unwrap();
-                assert_eq!(error.error_type, "ApiError");
-                assert_eq!(error.message, "Test error");
-                assert_eq!(error.code, Some(400));
+    fn test_request_peer() {
+        assert_eq!(
+            RequestPeer::from_extensions(&Extensions::new()),
+            RequestPeer {
+                address: "stdio".to_string(),
+                forwarded_for: None,
             }
-            _ => panic!("Expected JsonSuccess with error"),
-        }
-    }
+        );
 
-    #[test]
-    fn test_parse_fallback_to_error() {
-        let invalid_json = "This is not JSON at all";
-        let result = parse_gemini_output(invalid_json);
-        match result {
-            ParseResult::ParseError { raw_output, error } => {
-                assert_eq!(raw_output, invalid_json);
-                assert!(error.contains("Failed to parse JSON"));
+        let (mut parts, _) = axum::http::Request::builder()
+            .header("x-forwarded-for", "203.0.113.7")
+            .body(())
+            .unwrap()
+            .into_parts();
+        parts.extensions.insert(axum::extract::ConnectInfo(
+            "127.0.0.1:50000".parse::<std::net::SocketAddr>().unwrap(),
+        ));
+        let mut extensions = Extensions::new();
+        extensions.insert(parts);
+        assert_eq!(
+            RequestPeer::from_extensions(&extensions),
+            RequestPeer {
+                address: "127.0.0.1:50000".to_string(),
+                forwarded_for: Some("203.0.113.7".to_string()),
             }
-            _ => panic!("Expected ParseError"),
-        }
+        );
     }
 
     #[test]
-    fn test_non_json_input_returns_error() {
-        let text_output = "Hello world";
-        let result = parse_gemini_output(text_output);
-        match result {
-            ParseResult::ParseError { raw_output, error } => {
-                assert_eq!(raw_output, text_output);
-                assert!(error.contains("Failed to parse JSON"));
-            }
-            _ => panic!("Expected ParseError for non-JSON input"),
-        }
+    fn test_shared_state_is_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync + 'static>() {}
+        assert_send_sync::<GeminiCli>();
+        assert_send_sync::<ServerState>();
     }
 
-    #[test]
-    fn test_extract_json_from_mixed_content() {
-        let mixed_content = r#"Some log output
-        {"response": "test", "error": null, "stats": null}
-        More output"#;
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_prompt_gemini_concurrent_calls() {
+        let gemini_cli = GeminiCli::with_config(Arc::new(GeminiCliConfig {
+            gemini_cli_command: "echo".to_string(),
+            default_output_format: OutputFormat::Text,
+            max_concurrency: Some(8),
+            ..Default::default()
+        }));
 
-        let json_str = extract_json_from_mixed_content(mixed_content);
-        assert!(json_str.is_some());
+        let calls = (0..50).map(|i| {
+            let gemini_cli = gemini_cli.clone();
+            tokio::spawn(async move {
+                let args = PromptGeminiArgs {
+                    prompt: format!("call-{i}"),
+                    ..Default::default()
+                };
+                let result = gemini_cli
+                    .prompt_gemini(Parameters(args), CancellationToken::new())
+                    .await
+                    .unwrap();
+                let RawContent::Text(text_content) = &result.content[0].raw else {
+                    panic!("Expected text content");
+                };
+                (i, text_content.text.clone())
+            })
+        });
+        for call in futures::future::join_all(calls).await {
+            let (i, text) = call.unwrap();
+            assert_eq!(text, format!("--yolo --prompt call-{i}"));
+        }
 
-        let json_obj: serde_json::Value = serde_json::from_str(&json_str.unwrap()).unwrap();
-        assert_eq!(json_obj["response"], "test");
+        let metrics = gemini_cli.state.metrics_snapshot();
+        assert_eq!(metrics.calls.total_calls, 50);
+        assert_eq!(metrics.calls.successes, 50);
+        assert_eq!(gemini_cli.session_stats.lock().unwrap().in_flight, 0);
     }
 
     #[tokio::test]
-    async fn test_prompt_gemini_with_json_output() {
-        // Test JSON output mode with a command that returns valid JSON
-        let valid_json =
-            r#"{"response": "Paris is the capital of France", "error": null, "stats": null}"#;
-        let gemini_cli = GeminiCli::new(format!("echo '{}'", valid_json), None);
+    async fn test_prompt_gemini_encode_base64() {
+        let cmd = script_command("binary-output", r"printf 'a\001\377b'");
+        let gemini_cli = GeminiCli::new(cmd, None);
         let args = PromptGeminiArgs {
-            prompt: "What is the capital of France?".to_string(),
+            prompt: "hi".to_string(),
+            encode_base64: Some(true),
+            ..Default::default()
         };
 
-        let result = gemini_cli.prompt_gemini(Parameters(args)).await;
-        assert!(result.is_ok());
+        let result = gemini_cli
+            .prompt_gemini(Parameters(args), CancellationToken::new())
+            .await
+            .unwrap();
+        let RawContent::Text(text_content) = &result.content[0].raw else {
+            panic!("Expected text content");
+        };
+        let decoded = base64::engine::general_purpose::STANDARD
+            .decode(&text_content.text)
+            .unwrap();
+        assert_eq!(decoded, b"a\x01\xffb");
+        assert_eq!(
+            text_content.meta.as_ref().unwrap().get("encoding").unwrap(),
+            "base64"
+        );
+        assert_eq!(result.meta.unwrap()["encoding"], "base64");
+    }
 
-        if let Ok(call_result) = result {
-            assert!(!call_result.content.is_empty());
-            if let RawContent::Text(text_content) = &call_result.content[0].raw {
-                assert_eq!(text_content.text, "Paris is the capital of France");
+    #[tokio::test]
+    async fn test_prompt_gemini_redacts_error_payloads() {
+        let cmd = script_command(
+            "leaky",
+            "echo 'auth failed: token tok-12345 rejected' >&2; exit 1",
+        );
+        let gemini_cli = GeminiCli::with_config(Arc::new(GeminiCliConfig {
+            gemini_cli_command: format!("{cmd} --api-key hunter22"),
+            redactor: Redactor::from_env(
+                &[] as &[&str],
+                vec![regex::Regex::new(r"tok-\d+").unwrap()],
+            ),
+            ..Default::default()
+        }));
+        let args = PromptGeminiArgs {
+            prompt: "hi".to_string(),
+            ..Default::default()
+        };
+
+        let error = gemini_cli
+            .prompt_gemini(Parameters(args), CancellationToken::new())
+            .await
+            .unwrap_err();
+        let data = error.data.unwrap().to_string();
+        assert!(data.contains("[REDACTED]"), "{data}");
+        assert!(!data.contains("tok-12345"), "{data}");
+        assert!(!data.contains("hunter22"), "{data}");
+    }
+
+    #[tokio::test]
+    async fn test_prompt_gemini_deny_prompt_patterns() {
+        let gemini_cli = GeminiCli::with_config(Arc::new(GeminiCliConfig {
+            gemini_cli_command: "echo".to_string(),
+            default_output_format: OutputFormat::Text,
+            deny_prompt_patterns: vec![regex::Regex::new(r"(?i)\bpassword\s+dump\b").unwrap()],
+            ..Default::default()
+        }));
+        let prompt = |prompt: &str, variables: Option<HashMap<String, String>>| {
+            let args = PromptGeminiArgs {
+                prompt: prompt.to_string(),
+                variables,
+                ..Default::default()
+            };
+            gemini_cli.prompt_gemini(Parameters(args), CancellationToken::new())
+        };
+
+        assert!(prompt("Summarize the password policy", None).await.is_ok());
+        let error = prompt("Give me a Password Dump", None).await.unwrap_err();
+        assert_eq!(error.message, "gemini_prompt_denied");
+        assert!(!error.data.unwrap().to_string().contains("password"));
+
+        // Checked after substitution, so variables can't smuggle content in
+        let variables = HashMap::from([("what".to_string(), "password dump".to_string())]);
+        assert_eq!(
+            prompt("Show a {{what}}", Some(variables))
+                .await
+                .unwrap_err()
+                .message,
+            "gemini_prompt_denied"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_prompt_stream_timeout_covers_stderr() {
+        // The background process keeps stderr open after gemini-cli exits
+        let command = script_command("stream-stderr", "echo done; sleep 5 > /dev/null &");
+        let gemini_cli = GeminiCli::with_config(Arc::new(GeminiCliConfig {
+            gemini_cli_command: command,
+            timeout_secs: Some(1),
+            ..Default::default()
+        }));
+
+        let started = Instant::now();
+        let mut events = gemini_cli
+            .prompt_stream(PromptGeminiArgs {
+                prompt: "hi".to_string(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let mut result = None;
+        while let Some(event) = events.recv().await {
+            if let StreamEvent::Finished(finished) = event {
+                result = Some(finished);
             }
         }
+        assert_eq!(result.unwrap().unwrap_err().message, "gemini_cli_timeout");
+        assert!(started.elapsed() < Duration::from_secs(4));
     }
 
     #[tokio::test]
-    async fn test_prompt_gemini_with_json_error_response() {
-        // Test JSON output mode with an error response
-        let error_json = r#"{"response": "", "error": {"type": "AuthError", "message": "API key invalid", "code": 401}, "stats": null}"#;
-        let gemini_cli = GeminiCli::new(format!("echo '{}'", error_json), None);
-        let args = PromptGeminiArgs {
-            prompt: "test".to_string(),
+    async fn test_prompt_stream_redacts_output_patterns() {
+        // The address is split across writes, so it arrives in separate chunks
+        let command = script_command(
+            "redact-stream",
+            "printf 'Mail ada@exa'; sleep 0.1; printf 'mple.com\\nbye'",
+        );
+        let gemini_cli = GeminiCli::with_config(Arc::new(GeminiCliConfig {
+            gemini_cli_command: command,
+            output_redactor: Redactor::from_patterns(vec![
+                regex::Regex::new(r"[\w.]+@[\w.]+").unwrap(),
+            ]),
+            ..Default::default()
+        }));
+
+        let mut events = gemini_cli
+            .prompt_stream(PromptGeminiArgs {
+                prompt: "contacts?".to_string(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let mut output = String::new();
+        while let Some(event) = events.recv().await {
+            match event {
+                StreamEvent::Output(text) => output.push_str(&text),
+                StreamEvent::Finished(result) => result.unwrap(),
+            }
+        }
+        assert_eq!(output, "Mail [REDACTED]\nbye");
+    }
+
+    #[tokio::test]
+    async fn test_prompt_gemini_redacts_output_patterns() {
+        let command = script_command(
+            "redact-output",
+            r#"printf '%s\n' '{"response": "Mail ada@example.com or bob@example.org"}'"#,
+        );
+        let gemini_cli = GeminiCli::with_config(Arc::new(GeminiCliConfig {
+            gemini_cli_command: command,
+            output_redactor: Redactor::from_patterns(vec![
+                regex::Regex::new(r"[\w.]+@[\w.]+").unwrap(),
+            ]),
+            ..Default::default()
+        }));
+        let prompt = |args: PromptGeminiArgs| {
+            gemini_cli.prompt_gemini(Parameters(args), CancellationToken::new())
+        };
+        let text = |result: &CallToolResult, index: usize| {
+            let RawContent::Text(text_content) = &result.content[index].raw else {
+                panic!("Expected text content");
+            };
+            text_content.text.clone()
         };
 
-        let result = gemini_cli.prompt_gemini(Parameters(args)).await;
-        assert!(result.is_err());
+        let result = prompt(PromptGeminiArgs {
+            prompt: "contacts?".to_string(),
+            include_raw: Some(true),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        assert_eq!(text(&result, 0), "Mail [REDACTED] or [REDACTED]");
+        assert!(!text(&result, 1).contains("ada@example.com"));
+        assert_eq!(
+            result.structured_content.unwrap()["response"],
+            "Mail [REDACTED] or [REDACTED]"
+        );
 
-        if let Err(error) = result {
-            // Should be a gemini API error
-            assert!(
-                error.message.contains("gemini_api_error") || error.message.contains("AuthError")
-            );
-        }
+        // Base64 output is redacted before it is encoded
+        let result = prompt(PromptGeminiArgs {
+            prompt: "contacts?".to_string(),
+            encode_base64: Some(true),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let decoded = base64::engine::general_purpose::STANDARD
+            .decode(text(&result, 0))
+            .unwrap();
+        let decoded = String::from_utf8(decoded).unwrap();
+        assert!(
+            decoded.contains("Mail [REDACTED] or [REDACTED]"),
+            "{decoded}"
+        );
     }
 
     #[tokio::test]
-    async fn test_prompt_gemini_json_parse_fallback() {
-        // Test JSON mode with invalid JSON (should fall back to parse error)
-        let invalid_json = "This is not valid JSON";
-        let gemini_cli = GeminiCli::new(format!("echo '{}'", invalid_json), None);
-        let args = PromptGeminiArgs {
-            prompt: "test".to_string(),
+    async fn test_prompt_gemini_debug_request_logging() {
+        let (logs, _guard) = capture_logs(
+            tracing_subscriber::EnvFilter::new("info")
+                .add_directive(DEBUG_REQUEST_DIRECTIVE.parse().unwrap()),
+        );
+
+        let cmd = script_command("debug-log", "echo 'answer from tok-67890'");
+        let gemini_cli = GeminiCli::with_config(Arc::new(GeminiCliConfig {
+            gemini_cli_command: format!("{cmd} --api-key hunter22"),
+            default_output_format: OutputFormat::Text,
+            redactor: Redactor::from_env(
+                &[] as &[&str],
+                vec![regex::Regex::new(r"tok-\d+").unwrap()],
+            ),
+            ..Default::default()
+        }));
+        let prompt = |debug: Option<bool>| {
+            let args = PromptGeminiArgs {
+                prompt: "check tok-12345".to_string(),
+                debug,
+                ..Default::default()
+            };
+            gemini_cli.prompt_gemini_tool(
+                Parameters(args),
+                CancellationToken::new(),
+                Extensions::new(),
+            )
         };
+        let take_logs = || String::from_utf8(std::mem::take(&mut *logs.0.lock().unwrap())).unwrap();
 
-        let result = gemini_cli.prompt_gemini(Parameters(args)).await;
-        assert!(result.is_err());
+        prompt(None).await.unwrap();
+        let quiet = take_logs();
+        assert!(quiet.contains("prompt-gemini request succeeded"), "{quiet}");
+        assert!(!quiet.contains("Running Gemini CLI"), "{quiet}");
 
-        if let Err(error) = result {
-            // Should be a JSON parse error
-            assert!(error.message.contains("gemini_json_parse_error"));
-        }
+        prompt(Some(true)).await.unwrap();
+        let verbose = take_logs();
+        assert!(verbose.contains("Running Gemini CLI"), "{verbose}");
+        assert!(
+            verbose.contains("stdout=answer from [REDACTED]"),
+            "{verbose}"
+        );
+        assert!(verbose.contains("--api-key [REDACTED]"), "{verbose}");
+        assert!(!verbose.contains("hunter22"), "{verbose}");
+        assert!(!verbose.contains("tok-12345"), "{verbose}");
     }
-}
 
+    #[tokio::test]
+    async fn test_list_workspace_files() {
+        let root = std::fs::canonicalize(std::env::temp_dir())
+            .unwrap()
+            .join(format!(
+                "mcp-gemini-cli-test-{}-list-tool",
+                std::process::id()
+            ));
+        std::fs::create_dir_all(root.join("src")).unwrap();
+        std::fs::write(root.join("src/main.rs"), "").unwrap();
+        std::fs::write(root.join("notes.txt"), "").unwrap();
+
+        let gemini_cli = GeminiCli::new("echo".to_string(), Some(root.display().to_string()));
+        let result = gemini_cli
+            .list_workspace_files(Parameters(ListWorkspaceFilesArgs {
+                glob: Some("**/*.rs".to_string()),
+                ..Default::default()
+            }))
+            .await
+            .unwrap();
+        let RawContent::Text(text_content) = &result.content[0].raw else {
+            panic!("Expected text content");
+        };
+        let listing: serde_json::Value = serde_json::from_str(&text_content.text).unwrap();
+        assert_eq!(listing["files"], serde_json::json!(["src/main.rs"]));
+        assert_eq!(listing["count"], 1);
+        assert_eq!(listing["truncated"], false);
+
+        let error = gemini_cli
+            .list_workspace_files(Parameters(ListWorkspaceFilesArgs {
+                glob: Some("[".to_string()),
+                ..Default::default()
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(error.message, "gemini_invalid_glob");
+        assert!(is_client_error(&error));
+
+        // The workspace allowlist applies as it does to prompts
+        let outside = GeminiCli::with_config(Arc::new(GeminiCliConfig {
+            workspace: Some(root.display().to_string()),
+            allowed_workspace_roots: vec![PathBuf::from("/nonexistent")],
+            ..Default::default()
+        }));
+        let error = outside
+            .list_workspace_files(Parameters(ListWorkspaceFilesArgs::default()))
+            .await
+            .unwrap_err();
+        assert_eq!(error.message, "gemini_workspace_not_allowed");
+
+        // Including the working directory used without a workspace
+        let current_dir = GeminiCli::with_config(Arc::new(GeminiCliConfig {
+            allowed_workspace_roots: vec![PathBuf::from("/nonexistent")],
+            ..Default::default()
+        }));
+        let error = current_dir.workspace_dir().unwrap_err();
+        assert_eq!(error.message, "gemini_workspace_not_allowed");
+    }
+
+    #[test]
+    fn test_get_prompt_template() {
+        let dir = std::env::temp_dir().join(format!(
+            "mcp-gemini-cli-test-{}-prompt-templates",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("review.md"), "Review {{file}} in {{lines}} lines").unwrap();
+
+        let gemini_cli = GeminiCli::with_config(Arc::new(GeminiCliConfig {
+            prompts_dir: Some(dir),
+            ..Default::default()
+        }));
+        let arguments = serde_json::json!({"file": "main.rs", "lines": 20});
+        let result = gemini_cli
+            .get_prompt_template("review", arguments.as_object().cloned())
+            .unwrap();
+        assert_eq!(
+            serde_json::to_value(&result.messages).unwrap(),
+            serde_json::json!([{
+                "role": "user",
+                "content": {"type": "text", "text": "Review main.rs in 20 lines"}
+            }])
+        );
+
+        let error = gemini_cli.get_prompt_template("review", None).unwrap_err();
+        assert_eq!(error.code, ErrorCode::INVALID_PARAMS);
+        assert_eq!(
+            error.data.unwrap()["missing_arguments"],
+            serde_json::json!(["file", "lines"])
+        );
+        let error = gemini_cli.get_prompt_template("missing", None).unwrap_err();
+        assert_eq!(error.message, "gemini_prompt_not_found");
+        assert_eq!(
+            error.data.unwrap()["available"],
+            serde_json::json!(["review"])
+        );
+
+        // Without a prompts directory no templates are offered
+        let gemini_cli = GeminiCli::new("echo".to_string(), None);
+        assert!(gemini_cli.prompt_templates().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,377 @@
+use globset::{Glob, GlobMatcher};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+
+/// Directory depth walked when a request does not set one
+pub const DEFAULT_MAX_DEPTH: usize = 10;
+/// Paths returned when a request does not set a limit, and the most it may ask for
+pub const DEFAULT_MAX_FILES: usize = 1000;
+pub const MAX_FILES_LIMIT: usize = 10_000;
+/// Largest file served through `resources/read`
+pub const MAX_RESOURCE_BYTES: u64 = 10 * 1024 * 1024;
+
+/// How to walk a workspace
+#[derive(Debug, Clone)]
+pub struct ListOptions {
+    /// Only return paths matching this glob (relative to the root, e.g. `src/**/*.rs`)
+    pub glob: Option<String>,
+    pub max_depth: usize,
+    pub max_files: usize,
+    /// Skip files excluded by `.gitignore` (and `.ignore`) files
+    pub respect_gitignore: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FileListing {
+    /// Paths relative to the root, `/`-separated, in walk order
+    pub files: Vec<String>,
+    /// Whether `max_files` was reached before the walk finished
+    pub truncated: bool,
+}
+
+/// List the files under `root`, never descending into `.git`
+pub fn list_files(root: &Path, options: &ListOptions) -> Result<FileListing, String> {
+    if !root.is_dir() {
+        return Err(format!("{} is not a directory", root.display()));
+    }
+    let matcher = options.glob.as_deref().map(glob_matcher).transpose()?;
+
+    let walker = walk_builder(root, options.respect_gitignore)
+        .max_depth(Some(options.max_depth))
+        .sort_by_file_name(|a, b| a.cmp(b))
+        .filter_entry(|entry| entry.file_name() != ".git")
+        .build();
+
+    let mut files = Vec::new();
+    for entry in walker {
+        // Unreadable entries (e.g. permission denied) are skipped rather than failing the walk
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                tracing::debug!(error = %e, "Skipping unreadable workspace entry");
+                continue;
+            }
+        };
+        if !entry
+            .file_type()
+            .is_some_and(|file_type| file_type.is_file())
+        {
+            continue;
+        }
+        let Ok(relative) = entry.path().strip_prefix(root) else {
+            continue;
+        };
+        if matcher
+            .as_ref()
+            .is_some_and(|matcher| !matcher.is_match(relative))
+        {
+            continue;
+        }
+        if files.len() == options.max_files {
+            return Ok(FileListing {
+                files,
+                truncated: true,
+            });
+        }
+        let components: Vec<_> = relative
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect();
+        files.push(components.join("/"));
+    }
+    Ok(FileListing {
+        files,
+        truncated: false,
+    })
+}
+
+/// Compile a path glob such as `src/**/*.rs`
+pub fn glob_matcher(glob: &str) -> Result<GlobMatcher, String> {
+    Glob::new(glob)
+        .map(|glob| glob.compile_matcher())
+        .map_err(|e| format!("Invalid glob '{}': {}", glob, e))
+}
+
+fn walk_builder(root: &Path, respect_gitignore: bool) -> ignore::WalkBuilder {
+    let mut builder = ignore::WalkBuilder::new(root);
+    builder
+        .hidden(false)
+        .parents(respect_gitignore)
+        .ignore(respect_gitignore)
+        .git_ignore(respect_gitignore)
+        .git_global(false)
+        .git_exclude(respect_gitignore)
+        .require_git(false);
+    builder
+}
+
+/// Whether `path` (under `root`) is excluded by `.gitignore` and `.ignore` files, by the
+/// same rules [`list_files`] applies. Only the directories leading to it are walked.
+fn is_ignored(root: &Path, path: &Path) -> bool {
+    let target = path.to_path_buf();
+    let found = walk_builder(root, true)
+        .filter_entry(move |entry| target.starts_with(entry.path()))
+        .build()
+        .filter_map(Result::ok)
+        .any(|entry| entry.path() == path);
+    !found
+}
+
+/// `file://` URI for an absolute path, percent-encoding anything outside the unreserved set
+pub fn file_uri(path: &Path) -> String {
+    let mut uri = String::from("file://");
+    for byte in path.to_string_lossy().bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                uri.push(byte as char)
+            }
+            _ => uri.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    uri
+}
+
+/// Inverse of [`file_uri`]; `None` for other schemes or malformed escapes
+pub fn path_from_file_uri(uri: &str) -> Option<PathBuf> {
+    let encoded = uri.strip_prefix("file://")?;
+    let mut bytes = Vec::with_capacity(encoded.len());
+    let mut rest = encoded.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    let path = PathBuf::from(String::from_utf8(bytes).ok()?);
+    path.is_absolute().then_some(path)
+}
+
+/// MIME type guessed from the file extension, `application/octet-stream` when unknown
+pub fn mime_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("md" | "markdown") => "text/markdown",
+        Some("html" | "htm") => "text/html",
+        Some("css") => "text/css",
+        Some("csv") => "text/csv",
+        Some("js" | "mjs" | "cjs") => "text/javascript",
+        Some("json") => "application/json",
+        Some("toml") => "application/toml",
+        Some("yaml" | "yml") => "application/yaml",
+        Some("xml") => "application/xml",
+        Some("pdf") => "application/pdf",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("svg") => "image/svg+xml",
+        Some(
+            "txt" | "rs" | "py" | "ts" | "tsx" | "jsx" | "go" | "java" | "c" | "h" | "cpp" | "hpp"
+            | "rb" | "sh" | "nix" | "lock" | "log" | "ini" | "cfg",
+        ) => "text/plain",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Resolve a `file://` resource URI to a readable file under `root`. Symlinks and `..`
+/// are resolved first, so nothing outside the workspace can be reached through them.
+/// Files excluded by `.gitignore`, which resource listings leave out, are refused too.
+pub fn resolve_resource(root: &Path, uri: &str) -> Result<PathBuf, String> {
+    let path = path_from_file_uri(uri)
+        .ok_or_else(|| format!("'{}' is not an absolute file:// URI", uri))?;
+    let root =
+        std::fs::canonicalize(root).map_err(|e| format!("Workspace cannot be resolved: {}", e))?;
+    let canonical =
+        std::fs::canonicalize(&path).map_err(|e| format!("'{}' cannot be read: {}", uri, e))?;
+    if !canonical.starts_with(&root) {
+        return Err(format!("'{}' is outside the workspace", uri));
+    }
+    if canonical
+        .strip_prefix(&root)
+        .is_ok_and(|relative| relative.components().any(|c| c.as_os_str() == ".git"))
+    {
+        return Err(format!("'{}' is inside the .git directory", uri));
+    }
+    if is_ignored(&root, &canonical) {
+        return Err(format!("'{}' is excluded by .gitignore", uri));
+    }
+    let metadata =
+        std::fs::metadata(&canonical).map_err(|e| format!("'{}' cannot be read: {}", uri, e))?;
+    if !metadata.is_file() {
+        return Err(format!("'{}' is not a file", uri));
+    }
+    if metadata.len() > MAX_RESOURCE_BYTES {
+        return Err(format!(
+            "'{}' is {} bytes, exceeding the maximum of {}",
+            uri,
+            metadata.len(),
+            MAX_RESOURCE_BYTES
+        ));
+    }
+    Ok(canonical)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn workspace(name: &str) -> std::path::PathBuf {
+        let root =
+            std::env::temp_dir().join(format!("mcp-gemini-cli-test-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        for (path, contents) in [
+            ("src/main.rs", ""),
+            ("src/nested/deep/lib.rs", ""),
+            ("README.md", ""),
+            ("build.log", ""),
+            ("secrets/api.key", ""),
+            (".gitignore", "*.log\nsecrets/\n"),
+            (".git/config", ""),
+        ] {
+            let path = root.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        }
+        root
+    }
+
+    fn options() -> ListOptions {
+        ListOptions {
+            glob: None,
+            max_depth: DEFAULT_MAX_DEPTH,
+            max_files: DEFAULT_MAX_FILES,
+            respect_gitignore: false,
+        }
+    }
+
+    #[test]
+    fn test_lists_files_without_git_dir() {
+        let root = workspace("list-files");
+        let listing = list_files(&root, &options()).unwrap();
+        assert_eq!(
+            listing.files,
+            [
+                ".gitignore",
+                "README.md",
+                "build.log",
+                "secrets/api.key",
+                "src/main.rs",
+                "src/nested/deep/lib.rs"
+            ]
+        );
+        assert!(!listing.truncated);
+
+        let ignored = list_files(
+            &root,
+            &ListOptions {
+                respect_gitignore: true,
+                ..options()
+            },
+        )
+        .unwrap();
+        assert!(!ignored.files.contains(&"build.log".to_string()));
+    }
+
+    #[test]
+    fn test_glob_depth_and_limit() {
+        let root = workspace("list-files-glob");
+        let rust = list_files(
+            &root,
+            &ListOptions {
+                glob: Some("src/**/*.rs".to_string()),
+                ..options()
+            },
+        )
+        .unwrap();
+        assert_eq!(rust.files, ["src/main.rs", "src/nested/deep/lib.rs"]);
+
+        let shallow = list_files(
+            &root,
+            &ListOptions {
+                max_depth: 2,
+                ..options()
+            },
+        )
+        .unwrap();
+        assert!(shallow.files.contains(&"src/main.rs".to_string()));
+        assert!(
+            !shallow
+                .files
+                .contains(&"src/nested/deep/lib.rs".to_string())
+        );
+
+        let limited = list_files(
+            &root,
+            &ListOptions {
+                max_files: 2,
+                ..options()
+            },
+        )
+        .unwrap();
+        assert_eq!(limited.files.len(), 2);
+        assert!(limited.truncated);
+
+        assert!(
+            list_files(
+                &root,
+                &ListOptions {
+                    glob: Some("[".to_string()),
+                    ..options()
+                }
+            )
+            .is_err()
+        );
+        assert!(list_files(&root.join("README.md"), &options()).is_err());
+    }
+
+    #[test]
+    fn test_file_uris_and_mime_types() {
+        let path = Path::new("/work space/100%/main.rs");
+        let uri = file_uri(path);
+        assert_eq!(uri, "file:///work%20space/100%25/main.rs");
+        assert_eq!(path_from_file_uri(&uri).unwrap(), path);
+        assert!(path_from_file_uri("file://relative").is_none());
+        assert!(path_from_file_uri("file:///bad%2").is_none());
+        assert!(path_from_file_uri("https://example.com/").is_none());
+
+        assert_eq!(mime_type(path), "text/plain");
+        assert_eq!(mime_type(Path::new("README.MD")), "text/markdown");
+        assert_eq!(mime_type(Path::new("logo.png")), "image/png");
+        assert_eq!(mime_type(Path::new("blob")), "application/octet-stream");
+    }
+
+    #[test]
+    fn test_resolve_resource() {
+        let root = std::fs::canonicalize(workspace("resolve-resource")).unwrap();
+        let readme = root.join("README.md");
+        assert_eq!(resolve_resource(&root, &file_uri(&readme)).unwrap(), readme);
+
+        let escape = root.join("src").join("..").join("..");
+        assert!(
+            resolve_resource(&root, &file_uri(&escape))
+                .unwrap_err()
+                .contains("outside the workspace")
+        );
+        assert!(resolve_resource(&root, &file_uri(&root.join(".git/config"))).is_err());
+        assert!(resolve_resource(&root, &file_uri(&root.join("src"))).is_err());
+        assert!(resolve_resource(&root, &file_uri(&root.join("missing.rs"))).is_err());
+
+        // Ignored files and files in ignored directories aren't served
+        for ignored in ["build.log", "secrets/api.key"] {
+            assert!(
+                resolve_resource(&root, &file_uri(&root.join(ignored)))
+                    .unwrap_err()
+                    .contains("excluded by .gitignore")
+            );
+        }
+        let nested = root.join("src/nested/deep/lib.rs");
+        assert_eq!(resolve_resource(&root, &file_uri(&nested)).unwrap(), nested);
+        assert!(resolve_resource(&root, "README.md").is_err());
+    }
+}